@@ -2,6 +2,7 @@ use std::io::Result;
 
 fn main() -> Result<()> {
     let protos = ["proto/priv_msgs/v1.proto"];
+    println!("cargo:rerun-if-changed=src/database/migrations");
     prost_build::compile_protos(&protos, &["proto/"])?;
     Ok(())
 }
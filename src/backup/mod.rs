@@ -0,0 +1,579 @@
+//! Encrypted, compressed snapshots of the database, taken while it is in use.
+//!
+//! A backup file is a header followed by the gzipped database, cut into chunks that are each
+//! encrypted as a [`CryptData`], so that neither taking nor restoring a backup holds the database
+//! in memory:
+//!
+//! ```text
+//! "DSBACKUP" | format version (1 byte) | backup key id (4 bytes, big-endian)
+//!     | Argon2 variant (1 byte, 0 for keys not derived from a password)
+//!     | Argon2 version | memory cost in KiB | passes | parallelism (4 bytes each, big-endian)
+//!     | (last (1 byte) | length (4 bytes, big-endian) | chunk)...
+//! ```
+//!
+//! Chunks are authenticated along with the header, their index and whether they are the last one,
+//! so they can't be reordered, dropped or cut off without restoring failing. The key is derived
+//! again with the Argon2 parameters of the header, whatever the defaults have become since.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use flate2::{
+    Compression,
+    read::{GzDecoder, GzEncoder},
+};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+use crate::database::{
+    crypto::{CryptData, CryptoKey, KdfParams},
+    is_postgres,
+};
+
+const MAGIC: &[u8; 8] = b"DSBACKUP";
+const FORMAT_VERSION: u8 = 1;
+const KDF_LEN: usize = 1 + 4 * 4;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + KDF_LEN;
+/// Argon2 variants, by their number in headers minus one.
+const ALGORITHMS: [argon2::Algorithm; 3] = [
+    argon2::Algorithm::Argon2d,
+    argon2::Algorithm::Argon2i,
+    argon2::Algorithm::Argon2id,
+];
+/// Compressed bytes encrypted at a time.
+const CHUNK_LEN: u64 = 1 << 20;
+const PREFIX: &str = "backup-";
+const EXTENSION: &str = ".dsbk";
+
+type Header = [u8; HEADER_LEN];
+
+/// Where the database of `url` lives on disk.
+pub fn db_path(url: &str) -> anyhow::Result<PathBuf> {
+    if is_postgres(url) {
+        return Err(anyhow!(
+            "{url} is a PostgreSQL database, back it up with pg_dump instead"
+        ));
+    }
+    let options = SqliteConnectOptions::from_str(url)?;
+    let path = options.get_filename();
+    if path.as_os_str().is_empty() || path == Path::new(":memory:") {
+        return Err(anyhow!("{url} is not a database file"));
+    }
+    Ok(path.to_owned())
+}
+
+/// A file or directory deleted once dropped, whether what it was needed for went well or not.
+struct Scratch(PathBuf);
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let removed = if self.0.is_dir() {
+            std::fs::remove_dir_all(&self.0)
+        } else {
+            std::fs::remove_file(&self.0)
+        };
+        match removed {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                log::error!("Failed to delete {}: {e}", self.0.display());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Creates the directory `path`, which only the current user can look into.
+fn private_dir(path: PathBuf) -> io::Result<Scratch> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&path)?;
+    Ok(Scratch(path))
+}
+
+/// Snapshots the database at `url` into a new backup in `dir`, encrypted with `key`. Returns the
+/// path of the backup.
+pub async fn create(
+    url: &str,
+    dir: &Path,
+    key_id: u32,
+    key: &CryptoKey,
+) -> anyhow::Result<PathBuf> {
+    db_path(url)?;
+    std::fs::create_dir_all(dir)?;
+    let name = format!(
+        "{PREFIX}{}{EXTENSION}",
+        Utc::now().format("%Y%m%dT%H%M%S%.6fZ")
+    );
+    // The snapshot isn't encrypted yet: staged where no one else can read it, and always deleted.
+    let staging = private_dir(dir.join(format!(".{name}.staging")))?;
+    let snapshot = staging.0.join("snapshot.sqlite3");
+
+    let options = SqliteConnectOptions::from_str(url)?.read_only(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+    // Not sqlite's backup API, which sqlx doesn't expose: `VACUUM INTO` copies the database as of
+    // a single read transaction, a consistent snapshot, while the server keeps writing.
+    let copied = sqlx::query("VACUUM INTO ?")
+        .bind(snapshot.to_string_lossy())
+        .execute(&pool)
+        .await;
+    pool.close().await;
+    copied?;
+
+    // Written aside then renamed, so that a backup in `dir` is always complete.
+    let partial = staging.0.join(&name);
+    let mut backup = BufWriter::new(File::create(&partial)?);
+    seal(File::open(&snapshot)?, &mut backup, key_id, key)?;
+    backup
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .sync_all()?;
+    let path = dir.join(&name);
+    std::fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+/// Deletes the oldest backups in `dir` until only `keep` remain. Returns how many were deleted.
+pub fn prune(dir: &Path, keep: usize) -> anyhow::Result<usize> {
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(PREFIX) && name.ends_with(EXTENSION) {
+            backups.push(name);
+        }
+    }
+    // Names start with the time they were taken at.
+    backups.sort_unstable();
+    let expired = backups.len().saturating_sub(keep);
+    for name in &backups[..expired] {
+        std::fs::remove_file(dir.join(name))?;
+    }
+    Ok(expired)
+}
+
+/// Id of the key the backup at `path` was encrypted with, and how it was derived from its
+/// password if it was.
+pub fn key_params(path: &Path) -> anyhow::Result<(u32, Option<KdfParams>)> {
+    let header = header(&mut File::open(path)?)?;
+    let at = MAGIC.len() + 1;
+    let id: [u8; 4] = header[at..at + 4].try_into()?;
+    Ok((
+        u32::from_be_bytes(id),
+        decode_kdf(header[at + 4..].try_into()?)?,
+    ))
+}
+
+fn encode_kdf(kdf: Option<&KdfParams>) -> [u8; KDF_LEN] {
+    let mut encoded = [0; KDF_LEN];
+    let Some(kdf) = kdf else {
+        return encoded;
+    };
+    encoded[0] = (1..)
+        .zip(ALGORITHMS)
+        .find(|(_, algorithm)| *algorithm == kdf.algorithm)
+        .map_or(0, |(number, _)| number);
+    let costs = [
+        u32::from(kdf.version),
+        kdf.memory_kib,
+        kdf.iterations,
+        kdf.parallelism,
+    ];
+    for (field, cost) in encoded[1..].chunks_exact_mut(4).zip(costs) {
+        field.copy_from_slice(&cost.to_be_bytes());
+    }
+    encoded
+}
+
+fn decode_kdf(encoded: &[u8; KDF_LEN]) -> anyhow::Result<Option<KdfParams>> {
+    let Some(algorithm) = encoded[0].checked_sub(1) else {
+        return Ok(None);
+    };
+    let field = |i: usize| {
+        let at = 1 + 4 * i;
+        u32::from_be_bytes([
+            encoded[at],
+            encoded[at + 1],
+            encoded[at + 2],
+            encoded[at + 3],
+        ])
+    };
+    Ok(Some(KdfParams {
+        algorithm: *ALGORITHMS
+            .get(usize::from(algorithm))
+            .ok_or_else(|| anyhow!("Backup key has unknown Argon2 variant #{}", encoded[0]))?,
+        version: argon2::Version::try_from(field(0))
+            .map_err(|_| anyhow!("Backup key has unknown Argon2 version {}", field(0)))?,
+        memory_kib: field(1),
+        iterations: field(2),
+        parallelism: field(3),
+    }))
+}
+
+/// Replaces the database at `url` with the backup at `path`. Nothing is touched until the backup
+/// is known to decrypt and to hold a sound database. An existing database is only replaced if
+/// `force`.
+///
+/// The server must not be running.
+pub async fn restore(path: &Path, url: &str, key: &CryptoKey, force: bool) -> anyhow::Result<()> {
+    let target = db_path(url)?;
+    if target.exists() && !force {
+        return Err(anyhow!(
+            "{} already exists, use --force to replace it",
+            target.display()
+        ));
+    }
+
+    let mut staging = target.clone().into_os_string();
+    staging.push(".restoring");
+    let staging = Scratch(PathBuf::from(staging));
+    let mut database = BufWriter::new(File::create(&staging.0)?);
+    open(BufReader::new(File::open(path)?), &mut database, key)?;
+    database
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .sync_all()?;
+    integrity_check(&staging.0).await?;
+
+    // A journal left over from the replaced database would be replayed onto the restored one.
+    for suffix in ["-wal", "-shm", "-journal"] {
+        let mut journal = target.clone().into_os_string();
+        journal.push(suffix);
+        if Path::new(&journal).exists() {
+            std::fs::remove_file(journal)?;
+        }
+    }
+    std::fs::rename(&staging.0, &target)?;
+    Ok(())
+}
+
+async fn integrity_check(path: &Path) -> anyhow::Result<()> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+    let result: Result<String, _> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&pool)
+        .await;
+    pool.close().await;
+    match result? {
+        ok if ok == "ok" => Ok(()),
+        problem => Err(anyhow!("Restored database is corrupted: {problem}")),
+    }
+}
+
+/// What a chunk is authenticated with besides its contents.
+fn chunk_aad(header: &Header, index: u64, last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last.into());
+    aad
+}
+
+/// Up to [`CHUNK_LEN`] bytes of `reader`, fewer only at its end.
+fn read_chunk(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut chunk = vec![];
+    reader.take(CHUNK_LEN).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn seal(
+    database: impl Read,
+    backup: &mut impl Write,
+    key_id: u32,
+    key: &CryptoKey,
+) -> anyhow::Result<()> {
+    let mut header: Header = [0; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()] = FORMAT_VERSION;
+    header[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&key_id.to_be_bytes());
+    header[MAGIC.len() + 5..].copy_from_slice(&encode_kdf(key.kdf()));
+    backup.write_all(&header)?;
+
+    let mut compressed = GzEncoder::new(BufReader::new(database), Compression::default());
+    let mut chunk = read_chunk(&mut compressed)?;
+    for index in 0.. {
+        // Read ahead: the last chunk is only known once nothing follows it.
+        let next = read_chunk(&mut compressed)?;
+        let last = next.is_empty();
+        let aad = chunk_aad(&header, index, last);
+        let sealed: Vec<u8> =
+            CryptData::encrypt(ciborium::Value::Bytes(chunk), key, &aad, &mut rand::rng())?.into();
+        backup.write_all(&[last.into()])?;
+        backup.write_all(&u32::try_from(sealed.len())?.to_be_bytes())?;
+        backup.write_all(&sealed)?;
+        if last {
+            break;
+        }
+        chunk = next;
+    }
+    Ok(())
+}
+
+/// Writes the database held in `backup` to `database`.
+fn open(mut backup: impl Read, database: &mut impl Write, key: &CryptoKey) -> anyhow::Result<()> {
+    let header = header(&mut backup)?;
+    let mut chunks = Chunks {
+        backup,
+        header,
+        key,
+        index: 0,
+        done: false,
+        chunk: vec![],
+        read: 0,
+    };
+    io::copy(&mut GzDecoder::new(&mut chunks), database)?;
+    // Whatever gzip left unread must still be there, and authentic.
+    io::copy(&mut chunks, &mut io::sink())?;
+    Ok(())
+}
+
+/// The compressed database, decrypted a chunk at a time.
+struct Chunks<'a, R> {
+    backup: R,
+    header: Header,
+    key: &'a CryptoKey,
+    index: u64,
+    /// The last chunk was read.
+    done: bool,
+    chunk: Vec<u8>,
+    /// How much of `chunk` was handed out.
+    read: usize,
+}
+
+impl<R: Read> Chunks<'_, R> {
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let invalid =
+            |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let mut frame = [0; 5];
+        self.backup
+            .read_exact(&mut frame)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid(&"Backup is truncated"),
+                _ => e,
+            })?;
+        let last = match frame[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid(&"Backup is malformed")),
+        };
+        let len = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        let mut sealed = vec![];
+        (&mut self.backup)
+            .take(len.into())
+            .read_to_end(&mut sealed)?;
+        if sealed.len() != len as usize {
+            return Err(invalid(&"Backup is truncated"));
+        }
+        let aad = chunk_aad(&self.header, self.index, last);
+        let ciborium::Value::Bytes(chunk) = CryptData::<ciborium::Value>::from(sealed)
+            .decrypt(self.key, &aad)
+            .map_err(|e| {
+                invalid(&format!(
+                    "Backup doesn't decrypt, wrong key or altered file: {e}"
+                ))
+            })?
+        else {
+            return Err(invalid(&"Backup holds no database"));
+        };
+        if last && self.backup.read(&mut [0])? != 0 {
+            return Err(invalid(&"Backup has trailing data"));
+        }
+        self.chunk = chunk;
+        self.read = 0;
+        self.index += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Chunks<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = buf.len().min(self.chunk.len() - self.read);
+        buf[..n].copy_from_slice(&self.chunk[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+fn header(backup: &mut impl Read) -> anyhow::Result<Header> {
+    let mut header: Header = [0; HEADER_LEN];
+    if backup.read_exact(&mut header).is_err() || !header.starts_with(MAGIC) {
+        return Err(anyhow!("Not a backup"));
+    }
+    match header[MAGIC.len()] {
+        FORMAT_VERSION => Ok(header),
+        version => Err(anyhow!("Backup format #{version} is not supported")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::database::{
+        Database,
+        crypto::tests::key,
+        model::{ConversationId, Message, Product, UserProfile},
+        sqlite::SQLiteDB,
+    };
+
+    #[tokio::test]
+    async fn backup_and_restore() -> anyhow::Result<()> {
+        let backup_key = CryptoKey::from_raw(&[3; 32])?;
+
+        let dir = std::env::temp_dir().join(format!("backup_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let url = format!("sqlite:{}", dir.join("live.sqlite3").display());
+        let db = SQLiteDB::new(&url, key()?).await?;
+        let alice_id = db
+            .add_user(&UserProfile::new_clone(11, "alice_11", "Alice Arnold"))
+            .await?;
+        let bob_id = db
+            .add_user(&UserProfile::new_clone(22, "bobert22", "Bob Bellows"))
+            .await?;
+        let prod_id = db
+            .add_product(&Product::new("Dill Dough".to_owned(), bob_id, 1))
+            .await?;
+        let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
+        let hello = db
+            .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+
+        let backups = dir.join("backups");
+        let first = create(&url, &backups, 7, &backup_key).await?;
+        create(&url, &backups, 7, &backup_key).await?;
+        assert_eq!(prune(&backups, 1)?, 1);
+        assert!(!first.exists());
+
+        let backup = std::fs::read_dir(&backups)?
+            .next()
+            .ok_or_else(|| anyhow!("No backup"))??
+            .path();
+        assert_eq!(key_params(&backup)?, (7, None));
+        // Only the backups are left behind, not their unencrypted snapshots.
+        assert_eq!(std::fs::read_dir(&backups)?.count(), 1);
+
+        let restored = format!("sqlite:{}", dir.join("restored.sqlite3").display());
+        let other_key = CryptoKey::from_raw(&[4; 32])?;
+        assert!(
+            restore(&backup, &restored, &other_key, false)
+                .await
+                .is_err()
+        );
+        let contents = std::fs::read(&backup)?;
+        let altered = dir.join("altered.dsbk");
+        let mut bytes = contents.clone();
+        *bytes.last_mut().ok_or_else(|| anyhow!("Empty backup"))? ^= 1;
+        std::fs::write(&altered, &bytes)?;
+        assert!(
+            restore(&altered, &restored, &backup_key, false)
+                .await
+                .is_err()
+        );
+        // Cut off after its first chunk.
+        std::fs::write(&altered, &contents[..HEADER_LEN + 5 + 10])?;
+        assert!(
+            restore(&altered, &restored, &backup_key, false)
+                .await
+                .is_err()
+        );
+        assert!(!dir.join("restored.sqlite3.restoring").exists());
+
+        restore(&backup, &restored, &backup_key, false).await?;
+        assert!(
+            restore(&backup, &restored, &backup_key, false)
+                .await
+                .is_err()
+        );
+        let db = SQLiteDB::new(&restored, key()?).await?;
+        assert_eq!(db.get_message(&hello).await?.1.contents(), "Hello Bob!");
+        assert_eq!(db.verify_message_chain(&ConversationId(1)).await?, None);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn kdf_params_are_recorded() -> anyhow::Result<()> {
+        // Anything but the defaults, to tell the header is what the key is derived with.
+        let kdf = KdfParams {
+            memory_kib: 1024,
+            iterations: 1,
+            ..KdfParams::default()
+        };
+        let derive = |kdf| {
+            CryptoKey::derive("backup password", "backup salt", kdf)
+                .map_err(|e| anyhow!("Error: {e}"))
+        };
+        let path = std::env::temp_dir().join(format!("kdf_{}.dsbk", std::process::id()));
+        let mut backup = vec![];
+        seal(b"database".as_slice(), &mut backup, 7, &derive(kdf)?)?;
+        std::fs::write(&path, &backup)?;
+        assert_eq!(key_params(&path)?, (7, Some(kdf)));
+        std::fs::remove_file(&path)?;
+
+        assert!(
+            open(
+                backup.as_slice(),
+                &mut vec![],
+                &derive(KdfParams::default())?
+            )
+            .is_err()
+        );
+        let mut opened = vec![];
+        open(backup.as_slice(), &mut opened, &derive(kdf)?)?;
+        assert_eq!(opened, b"database");
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_cant_be_dropped_or_reordered() -> anyhow::Result<()> {
+        use rand::RngCore;
+
+        let key = CryptoKey::from_raw(&[3; 32])?;
+        // Doesn't compress, so that it spans several chunks.
+        let mut database = vec![0; 5 * usize::try_from(CHUNK_LEN)? / 2];
+        rand::rng().fill_bytes(&mut database);
+        let mut backup = vec![];
+        seal(database.as_slice(), &mut backup, 7, &key)?;
+
+        let mut opened = vec![];
+        open(backup.as_slice(), &mut opened, &key)?;
+        assert!(opened == database);
+
+        let mut frames = vec![];
+        let mut at = HEADER_LEN;
+        while at < backup.len() {
+            let len: [u8; 4] = backup[at + 1..at + 5].try_into()?;
+            let end = at + 5 + usize::try_from(u32::from_be_bytes(len))?;
+            frames.push(&backup[at..end]);
+            at = end;
+        }
+        assert_eq!(frames.len(), 3);
+        let header = &backup[..HEADER_LEN];
+        for altered in [
+            [header, frames[0], frames[1]].concat(),
+            [header, frames[0], frames[2]].concat(),
+            [header, frames[1], frames[0], frames[2]].concat(),
+            [header, frames[0], frames[1], frames[2], frames[2]].concat(),
+        ] {
+            assert!(open(altered.as_slice(), &mut vec![], &key).is_err());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,808 @@
+//! Behaviour every [`Backend`] must share. Each case is written once against the trait and run
+//! on every backend by [`conformance!`]; the Postgres runs are ignored unless asked for, see
+//! [`fresh_db`].
+
+use crate::database::{
+    Backend,
+    crypto::tests::key,
+    memory::MemoryDB,
+    model::{
+        AuditAction, AuditEntry, AuditFilter, ConversationFilter, ConversationId,
+        ConversationStatus, DbError, DeliveryStatus, Message, MessageId, Product, ProductId, Store,
+        UserId, UserProfile,
+    },
+    postgres::test::fresh_db,
+    sqlite::SQLiteDB,
+};
+
+/// Alice (11) and Bob (22), with a conversation about Bob's product.
+pub(super) async fn alice_and_bob(
+    db: &impl Backend,
+) -> anyhow::Result<(UserId, UserId, ProductId, ConversationId)> {
+    let alice_id = db
+        .add_user(&UserProfile::new_clone(11, "alice_11", "Alice Arnold"))
+        .await?;
+    let bob_id = db
+        .add_user(&UserProfile::new_clone(22, "bobert22", "Bob Bellows"))
+        .await?;
+    let prod_id = db
+        .add_product(&Product::new("Dill Dough".to_owned(), bob_id, 1))
+        .await?;
+    let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
+    Ok((alice_id, bob_id, prod_id, convo_id))
+}
+
+/// Contents of the last messages of `conversation`, oldest first.
+async fn contents(db: &impl Backend, conversation: &ConversationId) -> anyhow::Result<Vec<String>> {
+    let (messages, _) = db.get_most_recent_messages(conversation).await?;
+    Ok(messages
+        .iter()
+        .map(|(_, _, m)| m.contents().to_owned())
+        .collect())
+}
+
+/// Who the audit entries of the tests are by, admins being whoever the server is started with.
+pub(super) const ADMIN: UserId = UserId(99);
+
+/// What an admin erasing `user` records.
+pub(super) fn erasure(user: UserId) -> AuditEntry {
+    AuditEntry::new(
+        Some(ADMIN),
+        AuditAction::UserErasure,
+        "Customer deleted their account".to_owned(),
+    )
+    .subject(user)
+}
+
+/// What an admin shredding `conversation` records.
+pub(super) fn shredding(conversation: ConversationId) -> AuditEntry {
+    AuditEntry::new(
+        Some(ADMIN),
+        AuditAction::ConversationShred,
+        "Court order".to_owned(),
+    )
+    .conversation(conversation)
+}
+
+async fn profiles_and_products(db: impl Backend) -> anyhow::Result<()> {
+    let alice = UserProfile::new_clone(11, "alice_11", "Alice Arnold");
+    let alice_id = db.add_user(&alice).await?;
+    assert_eq!(db.add_user(&alice).await?, alice_id);
+    assert_eq!(db.get_user_profile(&alice_id).await?, alice);
+    assert_eq!(db.get_user_id_from_username("alice_11").await?, alice_id);
+    assert!(db.get_user_id_from_username("alice").await.is_err());
+    assert!(db.get_user_profile(&UserId(404)).await.is_err());
+
+    // Profiles are keyed by their Jumpseller id, and follow its changes.
+    let renamed = UserProfile::new_clone(11, "alice_a", "Alice Abbott");
+    assert_eq!(db.add_user(&renamed).await?, alice_id);
+    assert_eq!(db.get_user_profile(&alice_id).await?, renamed);
+    assert_eq!(db.get_user_id_from_username("alice_a").await?, alice_id);
+
+    let prod_id = db
+        .add_product(&Product::new("Dill Dough".to_owned(), alice_id, 1))
+        .await?;
+    assert_eq!(prod_id, ProductId(1));
+    assert_eq!(db.get_product(&prod_id).await?.name, "Dill Dough");
+    db.add_product(&Product::new("Rye Dough".to_owned(), alice_id, 1))
+        .await?;
+    assert_eq!(db.get_product(&prod_id).await?.name, "Rye Dough");
+    db.add_product(&Product::new("Spelt Dough".to_owned(), alice_id, 2))
+        .await?;
+    let names: Vec<_> = db
+        .get_seller_products(&alice_id)
+        .await?
+        .into_iter()
+        .map(|product| product.name)
+        .collect();
+    assert_eq!(names, ["Rye Dough", "Spelt Dough"]);
+    assert!(db.get_seller_products(&UserId(1)).await?.is_empty());
+    assert!(db.belongs_to_seller(&alice_id, &prod_id).await.is_ok());
+    assert!(matches!(
+        db.belongs_to_seller(&UserId(1), &prod_id).await,
+        Err(DbError::PermissionDenied)
+    ));
+    assert!(db.get_product(&ProductId(404)).await.is_err());
+    Ok(())
+}
+
+async fn conversation_dedup(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, prod_id, convo_id) = alice_and_bob(&db).await?;
+    let carol_id = db
+        .add_user(&UserProfile::new_clone(33, "carol33", "Carol Carver"))
+        .await?;
+
+    // Either side starting it again gets the same conversation, per product.
+    assert_eq!(
+        db.start_conversation(&alice_id, &bob_id, &prod_id).await?,
+        convo_id
+    );
+    assert_eq!(
+        db.start_conversation(&bob_id, &alice_id, &prod_id).await?,
+        convo_id
+    );
+    let cake = db
+        .add_product(&Product::new("Carrot Cake".to_owned(), bob_id, 2))
+        .await?;
+    let cake_convo = db.start_conversation(&alice_id, &bob_id, &cake).await?;
+    assert_ne!(cake_convo, convo_id);
+    assert_eq!(
+        db.get_product_id_from_conversation_id(&cake_convo).await?,
+        cake
+    );
+
+    assert_eq!(
+        db.get_conversations(&alice_id).await?,
+        vec![convo_id, cake_convo]
+    );
+    assert!(db.get_conversations(&carol_id).await?.is_empty());
+    assert_eq!(db.get_peer(&alice_id, &convo_id).await?, bob_id);
+    assert_eq!(db.get_peer(&bob_id, &convo_id).await?, alice_id);
+    assert!(db.belongs_to_conversation(&bob_id, &convo_id).await.is_ok());
+    assert!(matches!(
+        db.belongs_to_conversation(&carol_id, &convo_id).await,
+        Err(DbError::PermissionDenied)
+    ));
+    assert!(matches!(
+        db.get_peer(&carol_id, &convo_id).await,
+        Err(DbError::PermissionDenied)
+    ));
+    assert!(matches!(
+        db.belongs_to_conversation(&alice_id, &ConversationId(404))
+            .await,
+        Err(DbError::PermissionDenied)
+    ));
+    Ok(())
+}
+
+async fn message_pointers(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, convo_id) = alice_and_bob(&db).await?;
+    assert_eq!(db.get_latest_message(&convo_id).await?, None);
+
+    let hello_id = db
+        .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+        .await?;
+    assert_eq!(db.get_latest_message(&convo_id).await?, Some(hello_id));
+    let reply_id = db
+        .post_msg(Message::from("Hello Alice!"), &bob_id, &convo_id)
+        .await?;
+    assert_eq!(db.get_latest_message(&convo_id).await?, Some(reply_id));
+
+    let (sender, reply, previous) = db.get_message(&reply_id).await?;
+    assert_eq!(sender, bob_id);
+    assert_eq!(reply.contents(), "Hello Alice!");
+    assert_eq!(previous, Some(hello_id));
+    assert_eq!(db.get_conversation_from_message(&reply_id).await?, convo_id);
+    assert!(db.get_message(&MessageId(404)).await.is_err());
+
+    let (messages, previous) = db.get_most_recent_messages(&convo_id).await?;
+    let senders: Vec<_> = messages
+        .iter()
+        .map(|(id, sender, _)| (*id, *sender))
+        .collect();
+    assert_eq!(senders, [(hello_id, alice_id), (reply_id, bob_id)]);
+    assert_eq!(previous, None);
+
+    let page = db.get_messages_after(&convo_id, None, 1).await?;
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].0, hello_id);
+    let page = db
+        .get_messages_after(&convo_id, Some(&hello_id), 32)
+        .await?;
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].0, reply_id);
+
+    // Batch reads leave out the ids that don't exist.
+    let ids = [reply_id, MessageId(404), hello_id];
+    let mut conversations = db.get_conversations_from_messages(&ids).await?;
+    conversations.sort_by_key(|(id, _)| id.0);
+    assert_eq!(conversations, [(hello_id, convo_id), (reply_id, convo_id)]);
+    let messages: Vec<_> = db
+        .get_messages(&ids)
+        .await?
+        .into_iter()
+        .map(|(id, sender, m, previous)| (id, sender, m.contents().to_owned(), previous))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            (hello_id, alice_id, "Hello Bob!".to_owned(), None),
+            (reply_id, bob_id, "Hello Alice!".to_owned(), Some(hello_id)),
+        ]
+    );
+    Ok(())
+}
+
+async fn store_inbox(db: impl Backend) -> anyhow::Result<()> {
+    let alice_id = db
+        .add_user(&UserProfile::new_clone(11, "alice_11", "Alice Arnold"))
+        .await?;
+    let bob_id = db
+        .add_user(&UserProfile::new_clone(22, "bobert22", "Bob Bellows"))
+        .await?;
+    let carol_id = db
+        .add_user(&UserProfile::new_clone(33, "carol33", "Carol Carver"))
+        .await?;
+    let dave_id = db
+        .add_user(&UserProfile::new_clone(44, "dave44", "Dave Dawson"))
+        .await?;
+
+    let store_id = db
+        .add_store(&bob_id, &Store::new("Bellows & Co.".to_owned()))
+        .await?;
+    db.add_store_member(&bob_id, &store_id, &carol_id).await?;
+    db.add_store_member(&bob_id, &store_id, &carol_id).await?;
+    assert_eq!(
+        db.get_store_members(&store_id).await?,
+        vec![bob_id, carol_id]
+    );
+    assert!(db.belongs_to_store(&carol_id, &store_id).await.is_ok());
+    assert!(db.belongs_to_store(&dave_id, &store_id).await.is_err());
+    // Only owners add staff.
+    assert!(matches!(
+        db.add_store_member(&carol_id, &store_id, &dave_id).await,
+        Err(DbError::PermissionDenied)
+    ));
+    assert_eq!(
+        db.get_user_stores(&carol_id).await?,
+        [(store_id, Store::new("Bellows & Co.".to_owned()))]
+    );
+    assert!(db.get_user_stores(&dave_id).await?.is_empty());
+
+    let prod_id = db
+        .add_product(&Product::new("Dill Dough".to_owned(), bob_id, 1))
+        .await?;
+    let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
+    assert_eq!(
+        db.start_conversation(&alice_id, &carol_id, &prod_id)
+            .await?,
+        convo_id
+    );
+
+    // Any staff member can see and answer the conversation.
+    assert!(
+        db.belongs_to_conversation(&carol_id, &convo_id)
+            .await
+            .is_ok()
+    );
+    assert!(
+        db.belongs_to_conversation(&dave_id, &convo_id)
+            .await
+            .is_err()
+    );
+    assert_eq!(db.get_conversations(&carol_id).await?, vec![convo_id]);
+    assert_eq!(db.get_peer(&carol_id, &convo_id).await?, alice_id);
+    assert_eq!(db.get_peer(&alice_id, &convo_id).await?, bob_id);
+    let reply_id = db
+        .post_msg(Message::from("Hi, Carol here!"), &carol_id, &convo_id)
+        .await?;
+    assert_eq!(db.get_message(&reply_id).await?.0, carol_id);
+
+    // Assignment is restricted to staff, on both ends.
+    assert_eq!(db.get_assignee(&convo_id).await?, None);
+    db.assign_conversation(&bob_id, &convo_id, Some(&carol_id))
+        .await?;
+    assert_eq!(db.get_assignee(&convo_id).await?, Some(carol_id));
+    assert!(
+        db.assign_conversation(&bob_id, &convo_id, Some(&dave_id))
+            .await
+            .is_err()
+    );
+    assert!(
+        db.assign_conversation(&alice_id, &convo_id, None)
+            .await
+            .is_err()
+    );
+
+    // Staff answer for the seller: only the client reopens what one of them resolved.
+    db.set_status(&carol_id, &convo_id, ConversationStatus::Resolved)
+        .await?;
+    db.post_msg(Message::from("Enjoy!"), &bob_id, &convo_id)
+        .await?;
+    assert_eq!(
+        db.get_status(&convo_id).await?,
+        ConversationStatus::Resolved
+    );
+    db.post_msg(Message::from("One more thing"), &alice_id, &convo_id)
+        .await?;
+    assert_eq!(db.get_status(&convo_id).await?, ConversationStatus::Open);
+
+    // Shared inboxes have no single key to encrypt for.
+    db.set_public_key(&alice_id, "alice-pk").await?;
+    db.set_public_key(&bob_id, "bob-pk").await?;
+    assert!(matches!(
+        db.enable_e2e(&convo_id).await,
+        Err(DbError::E2EUnavailable(_))
+    ));
+    Ok(())
+}
+
+async fn primary_store(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, prod_id, convo_id) = alice_and_bob(&db).await?;
+    let carol_id = db
+        .add_user(&UserProfile::new_clone(33, "carol33", "Carol Carver"))
+        .await?;
+    // Conversations from before the seller had a store stay out of its inbox.
+    let store_id = db
+        .add_store(&bob_id, &Store::new("Bellows & Co.".to_owned()))
+        .await?;
+    db.add_store_member(&bob_id, &store_id, &carol_id).await?;
+    assert_eq!(
+        db.start_conversation(&alice_id, &bob_id, &prod_id).await?,
+        convo_id
+    );
+    assert!(
+        db.belongs_to_conversation(&carol_id, &convo_id)
+            .await
+            .is_err()
+    );
+
+    // Conversations land in the seller's primary store: their first, until they pick another.
+    let annex = db
+        .add_store(&bob_id, &Store::new("Bellows Annex".to_owned()))
+        .await?;
+    let rye = db
+        .add_product(&Product::new("Rye Dough".to_owned(), bob_id, 2))
+        .await?;
+    let rye_convo = db.start_conversation(&alice_id, &bob_id, &rye).await?;
+    assert!(
+        db.belongs_to_conversation(&carol_id, &rye_convo)
+            .await
+            .is_ok()
+    );
+    assert!(matches!(
+        db.set_primary_store(&carol_id, &annex).await,
+        Err(DbError::PermissionDenied)
+    ));
+    db.set_primary_store(&bob_id, &annex).await?;
+    let spelt = db
+        .add_product(&Product::new("Spelt Dough".to_owned(), bob_id, 3))
+        .await?;
+    let spelt_convo = db.start_conversation(&alice_id, &bob_id, &spelt).await?;
+    assert!(
+        db.belongs_to_conversation(&carol_id, &spelt_convo)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+async fn labels_and_status(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, dough_convo) = alice_and_bob(&db).await?;
+    let cake = db
+        .add_product(&Product::new("Carrot Cake".to_owned(), bob_id, 2))
+        .await?;
+    let cake_convo = db.start_conversation(&alice_id, &bob_id, &cake).await?;
+
+    // Labels are private to whoever set them.
+    db.add_label(&bob_id, &dough_convo, "wholesale").await?;
+    db.add_label(&bob_id, &dough_convo, "priority").await?;
+    db.add_label(&bob_id, &dough_convo, "priority").await?;
+    assert_eq!(
+        db.get_labels(&bob_id, &dough_convo).await?,
+        vec!["priority".to_owned(), "wholesale".to_owned()]
+    );
+    assert!(db.get_labels(&alice_id, &dough_convo).await?.is_empty());
+    db.remove_label(&bob_id, &dough_convo, "priority").await?;
+    assert_eq!(
+        db.get_labels(&bob_id, &dough_convo).await?,
+        vec!["wholesale".to_owned()]
+    );
+
+    let wholesale = ConversationFilter {
+        label: Some("wholesale".to_owned()),
+        ..Default::default()
+    };
+    assert_eq!(
+        db.filter_conversations(&bob_id, &wholesale).await?,
+        vec![dough_convo]
+    );
+    assert!(
+        db.filter_conversations(&alice_id, &wholesale)
+            .await?
+            .is_empty()
+    );
+
+    // Resolving a conversation and getting a reply from the peer reopens it.
+    assert_eq!(db.get_status(&cake_convo).await?, ConversationStatus::Open);
+    db.set_status(&bob_id, &cake_convo, ConversationStatus::Resolved)
+        .await?;
+    let resolved = ConversationFilter {
+        status: Some(ConversationStatus::Resolved),
+        ..Default::default()
+    };
+    assert_eq!(
+        db.filter_conversations(&bob_id, &resolved).await?,
+        vec![cake_convo]
+    );
+    db.post_msg(Message::from("Anything else?"), &bob_id, &cake_convo)
+        .await?;
+    assert_eq!(
+        db.get_status(&cake_convo).await?,
+        ConversationStatus::Resolved
+    );
+    db.post_msg(Message::from("Actually, yes!"), &alice_id, &cake_convo)
+        .await?;
+    assert_eq!(db.get_status(&cake_convo).await?, ConversationStatus::Open);
+    Ok(())
+}
+
+async fn idempotent_post(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, convo_id) = alice_and_bob(&db).await?;
+
+    let client_id = Some("1b4e28ba-2fa1-11d2-883f-0016d3cca427");
+    let (first, inserted) = db
+        .post_msg_idempotent(Message::from("Hello Bob!"), &alice_id, &convo_id, client_id)
+        .await?;
+    assert!(inserted);
+    let (retry, inserted) = db
+        .post_msg_idempotent(Message::from("Hello Bob!"), &alice_id, &convo_id, client_id)
+        .await?;
+    assert!(!inserted);
+    assert_eq!(first, retry);
+    assert_eq!(db.get_latest_message(&convo_id).await?, Some(first));
+
+    // The same client id from another sender is a different message.
+    let (reply, inserted) = db
+        .post_msg_idempotent(Message::from("Hello Alice!"), &bob_id, &convo_id, client_id)
+        .await?;
+    assert!(inserted);
+    assert_ne!(first, reply);
+
+    // Messages without a client id are never deduplicated.
+    let a = db
+        .post_msg(Message::from("Ping"), &alice_id, &convo_id)
+        .await?;
+    let b = db
+        .post_msg(Message::from("Ping"), &alice_id, &convo_id)
+        .await?;
+    assert_ne!(a, b);
+    assert!(
+        db.post_msg(Message::from("Hello?"), &alice_id, &ConversationId(404))
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+async fn delivery_status(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, convo_id) = alice_and_bob(&db).await?;
+
+    let first = db
+        .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+        .await?;
+    let second = db
+        .post_msg(Message::from("Are you there?"), &alice_id, &convo_id)
+        .await?;
+    assert_eq!(
+        db.get_message(&second).await?.1.status(),
+        DeliveryStatus::Sent
+    );
+
+    // The sender fetching their own messages doesn't count.
+    assert_eq!(
+        db.mark_delivered(&alice_id, &convo_id, &[first, second])
+            .await?,
+        None
+    );
+    // Only the messages fetched were delivered, not the older ones.
+    assert_eq!(
+        db.mark_delivered(&bob_id, &convo_id, &[second]).await?,
+        Some(second)
+    );
+    assert_eq!(
+        db.get_message(&first).await?.1.status(),
+        DeliveryStatus::Sent
+    );
+    assert_eq!(
+        db.mark_delivered(&bob_id, &convo_id, &[first, second])
+            .await?,
+        Some(first)
+    );
+    assert_eq!(
+        db.mark_delivered(&bob_id, &convo_id, &[second]).await?,
+        None
+    );
+    assert_eq!(
+        db.get_message(&second).await?.1.status(),
+        DeliveryStatus::Delivered
+    );
+
+    assert_eq!(db.mark_read(&bob_id, &convo_id).await?, Some(second));
+    assert_eq!(db.mark_read(&bob_id, &convo_id).await?, None);
+    let (messages, _) = db.get_most_recent_messages(&convo_id).await?;
+    assert!(
+        messages
+            .iter()
+            .all(|(_, _, m)| m.status() == DeliveryStatus::Read)
+    );
+
+    // In a store's inbox, staff reading only marks what the client sent: the seller and the
+    // other staff are on their side.
+    let carol_id = db
+        .add_user(&UserProfile::new_clone(33, "carol33", "Carol Carver"))
+        .await?;
+    let store_id = db
+        .add_store(&bob_id, &Store::new("Bellows & Co.".to_owned()))
+        .await?;
+    db.add_store_member(&bob_id, &store_id, &carol_id).await?;
+    let rye = db
+        .add_product(&Product::new("Rye Dough".to_owned(), bob_id, 2))
+        .await?;
+    let rye_convo = db.start_conversation(&alice_id, &bob_id, &rye).await?;
+    let question = db
+        .post_msg(Message::from("Is it vegan?"), &alice_id, &rye_convo)
+        .await?;
+    let answer = db
+        .post_msg(Message::from("It is."), &bob_id, &rye_convo)
+        .await?;
+    let follow_up = db
+        .post_msg(Message::from("Carol here, more?"), &carol_id, &rye_convo)
+        .await?;
+    assert_eq!(
+        db.mark_delivered(&carol_id, &rye_convo, &[question, answer, follow_up])
+            .await?,
+        Some(question)
+    );
+    assert_eq!(db.mark_read(&carol_id, &rye_convo).await?, Some(question));
+    assert_eq!(
+        db.get_message(&answer).await?.1.status(),
+        DeliveryStatus::Sent
+    );
+    // The client marks what any of them sent.
+    assert_eq!(db.mark_read(&alice_id, &rye_convo).await?, Some(follow_up));
+    assert_eq!(
+        db.get_message(&answer).await?.1.status(),
+        DeliveryStatus::Read
+    );
+    Ok(())
+}
+
+async fn erase_user(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, convo_id) = alice_and_bob(&db).await?;
+    let hello_id = db
+        .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+        .await?;
+    db.post_msg(Message::from("Hello Alice!"), &bob_id, &convo_id)
+        .await?;
+    db.add_label(&alice_id, &convo_id, "bread").await?;
+    db.set_public_key(&alice_id, "alice-pk").await?;
+
+    db.erase_user(&alice_id, &erasure(alice_id)).await?;
+    let audit = db.get_audit_log(&AuditFilter::default()).await?;
+    assert_eq!(audit.len(), 1);
+    assert_eq!(audit[0].entry, erasure(alice_id));
+
+    let profile = db.get_user_profile(&alice_id).await?;
+    assert_eq!(profile.username(), "erased_11");
+    assert_eq!(profile.name(), "Deleted user");
+    assert!(db.get_user_id_from_username("alice_11").await.is_err());
+    assert!(db.get_public_key(&alice_id).await.is_err());
+    // Jumpseller can't bring the profile back.
+    db.add_user(&UserProfile::new_clone(11, "alice_11", "Alice Arnold"))
+        .await?;
+    assert_eq!(
+        db.get_user_profile(&alice_id).await?.username(),
+        "erased_11"
+    );
+
+    // Bob keeps the conversation, minus what Alice said.
+    assert_eq!(db.get_peer(&bob_id, &convo_id).await?, alice_id);
+    assert_eq!(contents(&db, &convo_id).await?, ["", "Hello Alice!"]);
+    assert_eq!(db.get_message(&hello_id).await?.0, alice_id);
+
+    assert!(db.get_labels(&alice_id, &convo_id).await?.is_empty());
+    assert!(matches!(
+        db.post_msg(Message::from("I'm back"), &alice_id, &convo_id)
+            .await,
+        Err(DbError::PermissionDenied)
+    ));
+    assert!(matches!(
+        db.erase_user(&UserId(404), &erasure(UserId(404))).await,
+        Err(DbError::Db(sqlx::Error::RowNotFound))
+    ));
+    // Nothing is recorded of what didn't happen.
+    assert_eq!(db.get_audit_log(&AuditFilter::default()).await?.len(), 1);
+    Ok(())
+}
+
+async fn shred_conversation(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, shredded) = alice_and_bob(&db).await?;
+    let cake = db
+        .add_product(&Product::new("Carrot Cake".to_owned(), bob_id, 2))
+        .await?;
+    let kept = db.start_conversation(&alice_id, &bob_id, &cake).await?;
+    for convo in [&shredded, &kept] {
+        db.post_msg(Message::from("Hello Bob!"), &alice_id, convo)
+            .await?;
+        db.post_msg(Message::from("Hello Alice!"), &bob_id, convo)
+            .await?;
+    }
+
+    db.shred_conversation(&shredded, &shredding(shredded))
+        .await?;
+    assert_eq!(contents(&db, &shredded).await?, ["", ""]);
+    assert_eq!(contents(&db, &kept).await?, ["Hello Bob!", "Hello Alice!"]);
+
+    // The conversation can go on.
+    db.post_msg(Message::from("Still there?"), &alice_id, &shredded)
+        .await?;
+    assert_eq!(contents(&db, &shredded).await?, ["", "", "Still there?"]);
+    assert!(matches!(
+        db.shred_conversation(&ConversationId(404), &shredding(ConversationId(404)))
+            .await,
+        Err(DbError::Db(sqlx::Error::RowNotFound))
+    ));
+    let audit = db.get_audit_log(&AuditFilter::default()).await?;
+    let shreds: Vec<_> = audit.iter().map(|record| record.entry.clone()).collect();
+    assert_eq!(shreds, [shredding(shredded)]);
+    Ok(())
+}
+
+async fn end_to_end(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, convo_id) = alice_and_bob(&db).await?;
+    db.post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+        .await?;
+
+    assert!(!db.is_e2e(&convo_id).await?);
+    db.set_public_key(&alice_id, "alice-old").await?;
+    db.set_public_key(&alice_id, "alice-pk").await?;
+    assert_eq!(db.get_public_key(&alice_id).await?, "alice-pk");
+    assert!(matches!(
+        db.enable_e2e(&convo_id).await,
+        Err(DbError::E2EUnavailable(_))
+    ));
+    db.set_public_key(&bob_id, "bob-pk").await?;
+    db.enable_e2e(&convo_id).await?;
+    assert!(db.is_e2e(&convo_id).await?);
+
+    let sealed_id = db
+        .post_msg(
+            Message::from("c2VhbGVkIGZvciBBbGljZQ=="),
+            &bob_id,
+            &convo_id,
+        )
+        .await?;
+    let (_, sealed, _) = db.get_message(&sealed_id).await?;
+    assert!(sealed.is_e2e());
+    assert_eq!(sealed.contents(), "c2VhbGVkIGZvciBBbGljZQ==");
+
+    // Earlier messages are still the server's to read.
+    let (messages, _) = db.get_most_recent_messages(&convo_id).await?;
+    let flags: Vec<_> = messages
+        .iter()
+        .map(|(_, _, m)| (m.contents(), m.is_e2e()))
+        .collect();
+    assert_eq!(
+        flags,
+        [("Hello Bob!", false), ("c2VhbGVkIGZvciBBbGljZQ==", true)]
+    );
+    Ok(())
+}
+
+async fn message_chain(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, bob_id, _, convo_id) = alice_and_bob(&db).await?;
+    assert_eq!(db.get_chain_head(&convo_id).await?, None);
+    assert_eq!(db.verify_message_chain(&convo_id).await?, None);
+
+    db.post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+        .await?;
+    let offer = db
+        .post_msg(Message::from("5 loaves for 10$"), &bob_id, &convo_id)
+        .await?;
+    let head = db.get_chain_head(&convo_id).await?;
+    assert_eq!(head.as_ref().map(|h| h.message_id), Some(offer));
+    assert_eq!(db.verify_message_chain(&convo_id).await?, None);
+
+    // Erasing contents keeps the chain whole.
+    db.erase_user(&alice_id, &erasure(alice_id)).await?;
+    assert_eq!(db.verify_message_chain(&convo_id).await?, None);
+    assert_eq!(db.get_chain_head(&convo_id).await?, head);
+    db.shred_conversation(&convo_id, &shredding(convo_id))
+        .await?;
+    assert_eq!(db.verify_message_chain(&convo_id).await?, None);
+    assert!(db.get_chain_head(&ConversationId(404)).await.is_err());
+    Ok(())
+}
+
+async fn audit_log(db: impl Backend) -> anyhow::Result<()> {
+    assert_eq!(db.verify_audit_log().await?, None);
+
+    let export = AuditEntry::new(
+        Some(UserId(11)),
+        AuditAction::TranscriptExport,
+        "Participant export".to_owned(),
+    )
+    .conversation(ConversationId(1));
+    db.append_audit(&export).await?;
+    db.append_audit(&erasure(UserId(11))).await?;
+    db.append_audit(&erasure(UserId(11))).await?;
+
+    let all = db.get_audit_log(&AuditFilter::default()).await?;
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[2].entry, export);
+    assert!(all[0].id > all[2].id);
+    let by_admin = AuditFilter {
+        actor_id: Some(ADMIN.0),
+        ..AuditFilter::default()
+    };
+    assert_eq!(db.get_audit_log(&by_admin).await?.len(), 2);
+    let about_conversation = AuditFilter {
+        conversation_id: Some(1),
+        limit: 1,
+        ..AuditFilter::default()
+    };
+    assert_eq!(
+        db.get_audit_log(&about_conversation).await?[0].entry,
+        export
+    );
+    assert_eq!(db.verify_audit_log().await?, None);
+    Ok(())
+}
+
+async fn reencrypt(db: impl Backend) -> anyhow::Result<()> {
+    let (alice_id, _, _, convo_id) = alice_and_bob(&db).await?;
+    db.post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+        .await?;
+    // Everything is already under the only master key.
+    assert_eq!(db.reencrypt_batch(100).await?, 0);
+    assert_eq!(contents(&db, &convo_id).await?, ["Hello Bob!"]);
+    Ok(())
+}
+
+/// Runs each case on a fresh database of every backend.
+macro_rules! conformance {
+    ($($case:ident),* $(,)?) => {
+        mod on_memory {
+            $(
+                #[tokio::test]
+                async fn $case() -> anyhow::Result<()> {
+                    super::$case(super::MemoryDB::new()).await
+                }
+            )*
+        }
+
+        mod on_sqlite {
+            $(
+                #[tokio::test]
+                async fn $case() -> anyhow::Result<()> {
+                    let db = super::SQLiteDB::new("sqlite::memory:", super::key()?).await?;
+                    super::$case(db).await
+                }
+            )*
+        }
+
+        mod on_postgres {
+            use sqlx::{Postgres, migrate::MigrateDatabase};
+
+            $(
+                #[tokio::test]
+                #[ignore = "needs POSTGRES_TEST_URL"]
+                async fn $case() -> anyhow::Result<()> {
+                    let name = concat!("ds_conformance_", stringify!($case));
+                    let (url, db) = super::fresh_db(name, super::key()?).await?;
+                    super::$case(db).await?;
+                    Postgres::force_drop_database(&url).await?;
+                    Ok(())
+                }
+            )*
+        }
+    };
+}
+
+conformance!(
+    profiles_and_products,
+    conversation_dedup,
+    message_pointers,
+    store_inbox,
+    primary_store,
+    labels_and_status,
+    idempotent_post,
+    delivery_status,
+    erase_user,
+    shred_conversation,
+    end_to_end,
+    message_chain,
+    audit_log,
+    reencrypt,
+);
@@ -1,19 +1,195 @@
-use std::{io::Cursor, marker::PhantomData, ops::Deref};
+use std::{collections::BTreeMap, io::Cursor, marker::PhantomData, ops::Deref};
 
 use actix_web::{ResponseError, http::StatusCode};
-use argon2::Argon2;
-use chacha20poly1305::{ChaCha20Poly1305, ChaChaPoly1305, KeyInit, aead::Aead};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit, XChaCha20Poly1305,
+    aead::{Aead, Payload},
+};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::Sha256;
 use sqlx::{Decode, Encode};
+use zeroize::Zeroizing;
 
-pub struct CryptoKey(ChaCha20Poly1305);
+/// Raw key bytes, wiped from memory when dropped. Serialized like a plain `Vec<u8>`, so it can be
+/// wrapped and unwrapped with [`CryptData`].
+pub type RawKey = Zeroizing<Vec<u8>>;
+
+/// The same 256-bit key drives both the current cipher and the one it replaced, which is only
+/// used to read data written before.
+pub struct CryptoKey {
+    current: XChaCha20Poly1305,
+    legacy: ChaCha20Poly1305,
+    kdf: Option<KdfParams>,
+}
 
 impl CryptoKey {
     pub fn new(password: &str, salt: &str) -> argon2::Result<Self> {
-        let mut buf = [0; 32];
-        Argon2::default().hash_password_into(password.as_bytes(), salt.as_bytes(), &mut buf)?;
-        let key = ChaChaPoly1305::new(&buf.into());
-        Ok(Self(key))
+        Self::derive(password, salt, KdfParams::default())
+    }
+
+    pub fn derive(password: &str, salt: &str, kdf: KdfParams) -> argon2::Result<Self> {
+        let mut buf = Zeroizing::new([0; 32]);
+        kdf.argon2()?
+            .hash_password_into(password.as_bytes(), salt.as_bytes(), buf.as_mut())?;
+        let mut key = Self::from_bytes(&buf);
+        key.kdf = Some(kdf);
+        Ok(key)
+    }
+
+    /// A random key, returned along with its raw bytes so it can be stored wrapped.
+    pub fn generate<RNG: rand::CryptoRng>(rng: &mut RNG) -> (RawKey, Self) {
+        let mut buf = Zeroizing::new([0; 32]);
+        rng.fill_bytes(buf.as_mut());
+        let key = Self::from_bytes(&buf);
+        (RawKey::new(buf.to_vec()), key)
+    }
+
+    pub fn from_raw(raw: &[u8]) -> Result<Self, CryptError> {
+        let raw: &[u8; 32] = raw.try_into().map_err(|_| CryptError::KeyWrongSize)?;
+        Ok(Self::from_bytes(raw))
+    }
+
+    fn from_bytes(raw: &[u8; 32]) -> Self {
+        Self {
+            current: XChaCha20Poly1305::new(raw.into()),
+            legacy: ChaCha20Poly1305::new(raw.into()),
+            kdf: None,
+        }
+    }
+
+    /// How the key was derived from a password, if it was.
+    pub fn kdf(&self) -> Option<&KdfParams> {
+        self.kdf.as_ref()
+    }
+}
+
+/// Argon2 parameters a master key is derived with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: Algorithm,
+    pub version: Version,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    pub fn argon2(&self) -> argon2::Result<Argon2<'static>> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)?;
+        Ok(Argon2::new(self.algorithm, self.version, params))
+    }
+}
+
+impl Default for KdfParams {
+    /// What `Argon2::default()` was when keys started being derived, spelled out so that they
+    /// don't change along with the crate.
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Argon2id,
+            version: Version::V0x13,
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Keyed hash for equality search over encrypted columns: equal inputs give equal digests, which
+/// say nothing about the input to whoever doesn't hold the key.
+pub struct BlindIndex(Hmac<Sha256>);
+
+impl BlindIndex {
+    pub fn from_raw(raw: &[u8]) -> Result<Self, CryptError> {
+        if raw.len() != 32 {
+            return Err(CryptError::KeyWrongSize);
+        }
+        <Hmac<Sha256> as Mac>::new_from_slice(raw)
+            .map(Self)
+            .map_err(|_| CryptError::KeyWrongSize)
+    }
+
+    pub fn digest(&self, value: &str) -> Vec<u8> {
+        let mut mac = self.0.clone();
+        mac.update(value.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Keyed hash chaining the audit log: whoever can write the database but doesn't hold the key
+/// can't rewrite entries with hashes that still match.
+pub struct AuditKey(Hmac<Sha256>);
+
+impl AuditKey {
+    pub fn from_raw(raw: &[u8]) -> Result<Self, CryptError> {
+        if raw.len() != 32 {
+            return Err(CryptError::KeyWrongSize);
+        }
+        <Hmac<Sha256> as Mac>::new_from_slice(raw)
+            .map(Self)
+            .map_err(|_| CryptError::KeyWrongSize)
+    }
+
+    /// A random key, for a log that doesn't outlive the process.
+    pub fn random<RNG: rand::CryptoRng>(rng: &mut RNG) -> Self {
+        let mut raw = Zeroizing::new([0; 64]);
+        rng.fill_bytes(raw.as_mut());
+        Self(<Hmac<Sha256> as KeyInit>::new(&(*raw).into()))
+    }
+
+    /// Hash of `entry` chained onto `prev_hash`.
+    pub fn chain(&self, prev_hash: &[u8], entry: &[u8]) -> Vec<u8> {
+        let mut mac = self.0.clone();
+        mac.update(prev_hash);
+        mac.update(entry);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Master keys by id. Only the active one is used for writing, the others are kept around to read
+/// rows that were not re-encrypted yet.
+pub struct Keyring {
+    active: u32,
+    keys: BTreeMap<u32, CryptoKey>,
+}
+
+impl Keyring {
+    pub fn new(active_id: u32, active: CryptoKey) -> Self {
+        Self {
+            active: active_id,
+            keys: BTreeMap::from([(active_id, active)]),
+        }
+    }
+
+    /// Adds a key that can only be used to read. Doesn't replace the active key.
+    #[must_use]
+    pub fn with_retired(mut self, id: u32, key: CryptoKey) -> Self {
+        self.keys.entry(id).or_insert(key);
+        self
+    }
+
+    pub fn active_id(&self) -> u32 {
+        self.active
+    }
+
+    pub fn active(&self) -> &CryptoKey {
+        &self.keys[&self.active]
+    }
+
+    pub fn get(&self, id: u32) -> Result<&CryptoKey, CryptError> {
+        self.keys.get(&id).ok_or(CryptError::UnknownKey(id))
+    }
+
+    /// Every loaded key, active and retired.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &CryptoKey)> {
+        self.keys.iter().map(|(&id, key)| (id, key))
+    }
+}
+
+impl From<CryptoKey> for Keyring {
+    fn from(key: CryptoKey) -> Self {
+        Self::new(1, key)
     }
 }
 
@@ -46,6 +222,16 @@ pub enum CryptError {
     CiborDer(#[from] ciborium::de::Error<std::io::Error>),
     #[error("Crypto error: {0}")]
     ChaCha(chacha20poly1305::Error),
+    #[error("Wrong Key Size. Must be exactly 32 bytes.")]
+    KeyWrongSize,
+    #[error("Key #{0} was not loaded.")]
+    UnknownKey(u32),
+    #[error("Ciphertext is truncated or of an unknown format.")]
+    Malformed,
+    #[error(
+        "Master key #{0} is not the one the database was written with. Wrong password or salt?"
+    )]
+    WrongKey(u32),
 }
 
 impl ResponseError for CryptError {
@@ -60,30 +246,108 @@ impl From<chacha20poly1305::Error> for CryptError {
     }
 }
 
+/// First byte of a ciphertext, telling how to read the rest.
+#[repr(u8)]
+enum Format {
+    /// Followed by a 192-bit nonce then the XChaCha20-Poly1305 ciphertext.
+    XChaCha20Poly1305 = 1,
+}
+
+const XNONCE_LEN: usize = 24;
+const LEGACY_NONCE_LEN: usize = 12;
+
 impl<T: Serialize + DeserializeOwned> CryptData<T> {
+    /// Encrypts `data`, authenticating `aad` along with it: decryption only succeeds with the
+    /// same `aad`, which binds the ciphertext to whatever it describes.
+    ///
+    /// The result is self-contained: format version, nonce and ciphertext. Nonces are random and
+    /// large enough for a key to never wear out.
     #[allow(clippy::needless_pass_by_value)]
     pub fn encrypt<RNG: rand::CryptoRng>(
         data: T,
         key: &CryptoKey,
+        aad: &[u8],
         rng: &mut RNG,
-    ) -> Result<(Self, [u8; 12]), CryptError> {
+    ) -> Result<Self, CryptError> {
+        let mut buf = Zeroizing::new(Vec::new());
+        ciborium::into_writer(&data, &mut *buf)?;
+        let mut nonce = [0u8; XNONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        let payload = Payload {
+            msg: buf.as_slice(),
+            aad,
+        };
+        let ciphertext = key.current.encrypt(&nonce.into(), payload)?;
+        let mut data = Vec::with_capacity(1 + XNONCE_LEN + ciphertext.len());
+        data.push(Format::XChaCha20Poly1305 as u8);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(Self {
+            data,
+            _pd: PhantomData,
+        })
+    }
+
+    /// Decrypts what [`Self::encrypt`] produced.
+    pub fn decrypt(self, key: &CryptoKey, aad: &[u8]) -> Result<T, CryptError> {
+        let Some((&version, rest)) = self.data.split_first() else {
+            return Err(CryptError::Malformed);
+        };
+        let buf = match version {
+            v if v == Format::XChaCha20Poly1305 as u8 => {
+                if rest.len() < XNONCE_LEN {
+                    return Err(CryptError::Malformed);
+                }
+                let (nonce, msg) = rest.split_at(XNONCE_LEN);
+                Zeroizing::new(key.current.decrypt(nonce.into(), Payload { msg, aad })?)
+            }
+            _ => return Err(CryptError::Malformed),
+        };
+        Ok(ciborium::de::from_reader(Cursor::new(buf.as_slice()))?)
+    }
+
+    /// Decrypts data from before the format was versioned: a bare ChaCha20-Poly1305 ciphertext,
+    /// with its 96-bit `nonce` stored apart.
+    pub fn decrypt_legacy(
+        self,
+        key: &CryptoKey,
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<T, CryptError> {
+        if nonce.len() != LEGACY_NONCE_LEN {
+            return Err(CryptError::Malformed);
+        }
+        let payload = Payload {
+            msg: self.data.as_slice(),
+            aad,
+        };
+        let buf = Zeroizing::new(key.legacy.decrypt(nonce.into(), payload)?);
+        Ok(ciborium::de::from_reader(Cursor::new(buf.as_slice()))?)
+    }
+
+    /// Encrypts `data` in the format [`Self::decrypt_legacy`] reads, as earlier versions did.
+    #[cfg(test)]
+    pub(crate) fn encrypt_legacy(
+        data: &T,
+        key: &CryptoKey,
+        nonce: [u8; LEGACY_NONCE_LEN],
+    ) -> Result<Self, CryptError> {
         let mut buf = Vec::new();
-        ciborium::into_writer(&data, &mut buf)?;
-        let mut nonce_buf = [0u8; 12];
-        rng.fill_bytes(&mut nonce_buf);
-
-        let data = key.0.encrypt(&nonce_buf.into(), buf.as_slice())?;
-        Ok((
-            Self {
-                data,
-                _pd: PhantomData,
-            },
-            nonce_buf,
+        ciborium::into_writer(data, &mut buf)?;
+        Ok(Self::from(
+            key.legacy.encrypt(&nonce.into(), buf.as_slice())?,
         ))
     }
-    pub fn decrypt(self, key: &CryptoKey, nonce: &[u8; 12]) -> Result<T, CryptError> {
-        let buf = key.0.decrypt(nonce.into(), self.data.as_slice())?;
-        Ok(ciborium::de::from_reader(Cursor::new(buf))?)
+
+    /// Decrypts data in either format: rows written before versioning kept their nonce apart,
+    /// newer ones leave it empty.
+    pub fn decrypt_any(self, key: &CryptoKey, nonce: &[u8], aad: &[u8]) -> Result<T, CryptError> {
+        if nonce.is_empty() {
+            self.decrypt(key, aad)
+        } else {
+            self.decrypt_legacy(key, nonce, aad)
+        }
     }
 }
 
@@ -141,11 +405,26 @@ where
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use chacha20poly1305::aead::Aead;
     use rand::{SeedableRng, rngs::StdRng};
 
     use crate::database::crypto::{CryptData, CryptoKey};
 
+    /// Password and salt of the master key the tests open their databases with.
+    pub(crate) const PASSWORD: &str = "very_$ecure_and_$trong_P4$$w0rd_in_2025";
+    pub(crate) const SALT: &str = "even_more_$ecure_$alt";
+
+    /// The master key the tests open their databases with.
+    pub(crate) fn key() -> anyhow::Result<CryptoKey> {
+        key_from(PASSWORD)
+    }
+
+    /// Another master key, from `password` with the same salt.
+    pub(crate) fn key_from(password: &str) -> anyhow::Result<CryptoKey> {
+        CryptoKey::new(password, SALT).map_err(|e| anyhow::anyhow!("Error: {e}"))
+    }
+
     #[test]
     fn crypto_test() -> anyhow::Result<()> {
         let mut rng = StdRng::from_os_rng();
@@ -156,11 +435,32 @@ mod tests {
         assert!(key.is_ok());
         let Ok(key) = key else { unreachable!() };
         let data = b"Very secretive data!".to_vec();
-        let (enc, salt) = CryptData::encrypt(data.clone(), &key, rng)?;
+        let enc = CryptData::encrypt(data.clone(), &key, b"row #1", rng)?;
         assert_ne!(data.as_slice(), enc.as_slice());
-        let dec = enc.decrypt(&key, &salt)?;
+        assert_eq!(enc[0], 1);
+        assert!(enc.clone().decrypt(&key, b"row #2").is_err());
+        let dec = enc.decrypt(&key, b"row #1")?;
         assert_eq!(data.as_slice(), dec.as_slice());
 
         Ok(())
     }
+
+    #[test]
+    fn legacy_format() -> anyhow::Result<()> {
+        let key =
+            CryptoKey::new("test_password", "test_salt").map_err(|e| anyhow::anyhow!("{e}"))?;
+        let data = b"Written in 2025".to_vec();
+        let mut plaintext = Vec::new();
+        ciborium::into_writer(&data, &mut plaintext)?;
+        let nonce = [7u8; 12];
+        let legacy = key
+            .legacy
+            .encrypt(&nonce.into(), plaintext.as_slice())
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+
+        let enc = CryptData::<Vec<u8>>::from(legacy);
+        assert!(enc.clone().decrypt(&key, &[]).is_err());
+        assert_eq!(enc.decrypt_any(&key, &nonce, &[])?, data);
+        Ok(())
+    }
 }
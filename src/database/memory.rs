@@ -0,0 +1,1108 @@
+//! [`Database`] kept in memory, for tests and the demonstration mode.
+//!
+//! Rows are plain structs mirroring the sqlite tables, and every method keeps the semantics of
+//! [`SQLiteDB`](super::sqlite::SQLiteDB): the conformance suite holds both to it. Nothing is
+//! encrypted, there being no file to protect: erasing and shredding drop the contents instead of
+//! the keys.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use chrono::{DateTime, Utc};
+use rand::{SeedableRng, rngs::StdRng};
+use sha2::{Digest, Sha256};
+
+use crate::database::{
+    Database,
+    crypto::AuditKey,
+    model::{
+        AuditChain, AuditEntry, AuditFilter, AuditRecord, ChainHead, ConversationFilter,
+        ConversationId, ConversationStatus, DbError, DeliveryStatus, Message, MessageId, Product,
+        ProductId, Store, StoreId, UserId, UserProfile,
+    },
+    sealing::chain_hash,
+};
+
+#[derive(Debug, Clone)]
+pub struct UserRow {
+    pub(crate) profile: UserProfile,
+    pub(crate) erased_at: Option<DateTime<Utc>>,
+    pub(crate) primary_store_id: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversationRow {
+    pub(crate) client_id: i64,
+    pub(crate) seller_id: i64,
+    pub(crate) product_id: i64,
+    pub(crate) store_id: Option<i64>,
+    pub(crate) assignee_id: Option<i64>,
+    pub(crate) status: ConversationStatus,
+    pub(crate) status_updated_by: Option<i64>,
+    pub(crate) e2e: bool,
+    pub(crate) last_message_id: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageRow {
+    pub(crate) sender_id: i64,
+    pub(crate) conversation_id: i64,
+    /// `None` once erased or shredded.
+    pub(crate) content: Option<String>,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) previous_message_id: Option<i64>,
+    pub(crate) client_message_id: Option<String>,
+    pub(crate) e2e: bool,
+    pub(crate) delivered_at: Option<DateTime<Utc>>,
+    pub(crate) read_at: Option<DateTime<Utc>>,
+    pub(crate) content_hash: Vec<u8>,
+    pub(crate) chain_hash: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRow {
+    pub(crate) id: i64,
+    pub(crate) timestamp: String,
+    pub(crate) entry: AuditEntry,
+    pub(crate) prev_hash: Vec<u8>,
+    pub(crate) hash: Vec<u8>,
+}
+
+/// The tables, by primary key.
+#[derive(Debug, Default)]
+pub struct Tables {
+    pub(crate) users: BTreeMap<i64, UserRow>,
+    pub(crate) products: BTreeMap<i64, Product>,
+    pub(crate) stores: BTreeMap<i64, Store>,
+    /// `(store_id, user_id)`
+    pub(crate) store_members: BTreeSet<(i64, i64)>,
+    /// `(store_id, user_id)`, a subset of `store_members`
+    pub(crate) store_owners: BTreeSet<(i64, i64)>,
+    pub(crate) conversations: BTreeMap<i64, ConversationRow>,
+    pub(crate) messages: BTreeMap<i64, MessageRow>,
+    /// `(conversation_id, user_id, label)`
+    pub(crate) labels: BTreeSet<(i64, i64, String)>,
+    pub(crate) public_keys: BTreeMap<i64, String>,
+    pub(crate) audit_log: Vec<AuditRow>,
+}
+
+/// What sqlite answers when a row, or a row it references, is missing.
+fn not_found() -> DbError {
+    sqlx::Error::RowNotFound.into()
+}
+
+/// Id the next row of `table` gets, like an `AUTOINCREMENT` column.
+fn next_id<V>(table: &BTreeMap<i64, V>) -> i64 {
+    table.last_key_value().map_or(1, |(id, _)| id + 1)
+}
+
+impl Tables {
+    fn audit(&mut self, key: &AuditKey, entry: &AuditEntry) -> Result<(), DbError> {
+        let log = &mut self.audit_log;
+        let prev_hash = log
+            .last()
+            .map_or_else(|| vec![0; 32], |row| row.hash.clone());
+        let timestamp = Utc::now().to_rfc3339();
+        let hash = entry.hash(key, &prev_hash, &timestamp)?;
+        log.push(AuditRow {
+            id: log.last().map_or(1, |row| row.id + 1),
+            timestamp,
+            entry: entry.clone(),
+            prev_hash,
+            hash,
+        });
+        Ok(())
+    }
+
+    fn conversation(&self, id: ConversationId) -> Result<&ConversationRow, DbError> {
+        self.conversations.get(&id.0).ok_or_else(not_found)
+    }
+
+    fn conversation_mut(&mut self, id: ConversationId) -> Result<&mut ConversationRow, DbError> {
+        self.conversations.get_mut(&id.0).ok_or_else(not_found)
+    }
+
+    fn user(&self, id: UserId) -> Result<&UserRow, DbError> {
+        self.users.get(&id.0).ok_or_else(not_found)
+    }
+
+    fn is_staff(&self, store_id: Option<i64>, user: UserId) -> bool {
+        store_id.is_some_and(|store| self.store_members.contains(&(store, user.0)))
+    }
+
+    /// Whether `user` takes part in `conversation`, directly or as staff of its store.
+    fn is_participant(&self, user: UserId, conversation: &ConversationRow) -> bool {
+        conversation.client_id == user.0
+            || conversation.seller_id == user.0
+            || self.is_staff(conversation.store_id, user)
+    }
+
+    fn open_message(message: &MessageRow) -> Message {
+        let status = if message.read_at.is_some() {
+            DeliveryStatus::Read
+        } else if message.delivered_at.is_some() {
+            DeliveryStatus::Delivered
+        } else {
+            DeliveryStatus::Sent
+        };
+        Message::new(
+            message.content.clone().unwrap_or_default(),
+            message.timestamp,
+        )
+        .with_status(status)
+        .end_to_end(message.e2e)
+    }
+
+    fn messages_of(
+        &self,
+        conversation: &ConversationId,
+    ) -> impl DoubleEndedIterator<Item = (&i64, &MessageRow)> {
+        self.messages
+            .iter()
+            .filter(move |(_, m)| m.conversation_id == conversation.0)
+    }
+}
+
+pub struct MemoryDB {
+    /// Every method takes the lock for its whole body, which makes it atomic like a transaction.
+    tables: RwLock<Tables>,
+    /// Chains the audit log. Random, the log not outliving it.
+    audit_key: AuditKey,
+}
+
+impl Default for MemoryDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDB {
+    pub fn new() -> Self {
+        let admin = UserProfile::new_clone(1, "admin", "Admin");
+        let mut tables = Tables::default();
+        tables.users.insert(
+            1,
+            UserRow {
+                profile: admin,
+                erased_at: None,
+                primary_store_id: None,
+            },
+        );
+        Self {
+            tables: RwLock::new(tables),
+            audit_key: AuditKey::random(&mut StdRng::from_os_rng()),
+        }
+    }
+
+    // Poisoning is ignored: no method panics while holding the lock.
+    fn read(&self) -> RwLockReadGuard<'_, Tables> {
+        self.tables.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Tables> {
+        self.tables.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub async fn kiosk() -> anyhow::Result<Self> {
+        let db = Self::default();
+        for user in Self::kiosk_users() {
+            db.add_user(&user).await?;
+        }
+        for product in Self::kiosk_products() {
+            db.add_product(&product).await?;
+        }
+
+        for (my_id, their_id, prod_id) in Self::kiosk_conversations() {
+            db.start_conversation(&my_id, &their_id, &prod_id).await?;
+        }
+        for (msg, sender, convo) in Self::kiosk_messages() {
+            db.post_msg(msg, &sender, &convo).await?;
+        }
+        Ok(db)
+    }
+
+    fn kiosk_products() -> Vec<Product> {
+        vec![
+            Product {
+                name: "Orange".to_owned(),
+                seller_id: UserId(2),
+                jumpseller_id: 9_347_673,
+            },
+            Product {
+                name: "Orange Cake".to_owned(),
+                seller_id: UserId(1),
+                jumpseller_id: 9_347_699,
+            },
+        ]
+    }
+
+    fn kiosk_users() -> Vec<UserProfile> {
+        vec![
+            UserProfile::new_clone(1, "john", "John Doe"),
+            UserProfile::new_clone(2, "jane", "Jane Doe"),
+            UserProfile::new_clone(3, "fred", "Fred Nerk"),
+        ]
+    }
+
+    fn kiosk_conversations() -> Vec<(UserId, UserId, ProductId)> {
+        vec![
+            (UserId(1), UserId(2), ProductId(9_347_673)),
+            (UserId(3), UserId(1), ProductId(9_347_699)),
+        ]
+    }
+
+    fn kiosk_messages() -> Vec<(Message, UserId, ConversationId)> {
+        vec![
+            (
+                Message::from("Hi Jane! I would like to buy a few oranges, are they fresh?"),
+                UserId(1),
+                ConversationId(1),
+            ),
+            (
+                Message::from("Yes John! I just collected them this morning!"),
+                UserId(2),
+                ConversationId(1),
+            ),
+            (
+                Message::from("Thank you for the clarification!"),
+                UserId(1),
+                ConversationId(1),
+            ),
+            (
+                Message::from("Hi John! Is your orange cake made from fresh oranges?"),
+                UserId(3),
+                ConversationId(2),
+            ),
+            (
+                Message::from("Yes Fred! I bought them today from Jane!"),
+                UserId(1),
+                ConversationId(2),
+            ),
+            (
+                Message::from("Amazing! That makes me relieved, thank you!"),
+                UserId(3),
+                ConversationId(2),
+            ),
+        ]
+    }
+}
+
+impl Database for MemoryDB {
+    type Error = DbError;
+
+    type UserId = UserId;
+
+    type UserProfile = UserProfile;
+
+    type ConversationId = ConversationId;
+
+    type MessageId = MessageId;
+
+    type Message = Message;
+
+    type ProductId = ProductId;
+
+    type Product = Product;
+
+    type StoreId = StoreId;
+
+    type Store = Store;
+
+    type ConversationStatus = ConversationStatus;
+
+    type ConversationFilter = ConversationFilter;
+
+    type AuditEntry = AuditEntry;
+
+    type AuditRecord = AuditRecord;
+
+    type AuditFilter = AuditFilter;
+
+    type ChainHead = ChainHead;
+
+    type Querier<'a> = RwLockReadGuard<'a, Tables>;
+
+    async fn get_conversations(
+        &self,
+        my_id: &Self::UserId,
+    ) -> Result<Vec<Self::ConversationId>, Self::Error> {
+        self.filter_conversations(my_id, &ConversationFilter::default())
+            .await
+    }
+
+    async fn filter_conversations(
+        &self,
+        my_id: &Self::UserId,
+        filter: &Self::ConversationFilter,
+    ) -> Result<Vec<Self::ConversationId>, Self::Error> {
+        let t = self.read();
+        Ok(t.conversations
+            .iter()
+            .filter(|(_, c)| t.is_participant(*my_id, c))
+            .filter(|(_, c)| filter.status.is_none_or(|status| c.status == status))
+            .filter(|(id, _)| {
+                filter
+                    .label
+                    .as_ref()
+                    .is_none_or(|label| t.labels.contains(&(**id, my_id.0, label.clone())))
+            })
+            .map(|(id, _)| ConversationId(*id))
+            .collect())
+    }
+
+    async fn get_peer(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::UserId, Self::Error> {
+        let t = self.read();
+        // Staff members of the store answer on behalf of the seller, so their peer is the client.
+        match t.conversations.get(&conversation.0) {
+            Some(c) if !t.is_participant(*my_id, c) => Err(DbError::PermissionDenied),
+            Some(c) if c.client_id == my_id.0 => Ok(UserId(c.seller_id)),
+            Some(c) => Ok(UserId(c.client_id)),
+            None => Err(DbError::PermissionDenied),
+        }
+    }
+
+    async fn get_user_profile(
+        &self,
+        their_id: &Self::UserId,
+    ) -> Result<Self::UserProfile, Self::Error> {
+        let t = self.read();
+        let user = t.user(*their_id)?;
+        if user.erased_at.is_some() {
+            return Ok(UserProfile::erased(their_id.0));
+        }
+        Ok(user.profile.clone())
+    }
+
+    async fn get_message(
+        &self,
+        message: &Self::MessageId,
+    ) -> Result<(Self::UserId, Self::Message, Option<Self::MessageId>), Self::Error> {
+        let t = self.read();
+        let row = t.messages.get(&message.0).ok_or_else(not_found)?;
+        Ok((
+            UserId(row.sender_id),
+            Tables::open_message(row),
+            row.previous_message_id.map(MessageId),
+        ))
+    }
+
+    async fn get_messages(
+        &self,
+        messages: &[Self::MessageId],
+    ) -> Result<
+        Vec<(
+            Self::MessageId,
+            Self::UserId,
+            Self::Message,
+            Option<Self::MessageId>,
+        )>,
+        Self::Error,
+    > {
+        let t = self.read();
+        let ids: BTreeSet<i64> = messages.iter().map(|id| id.0).collect();
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| Some((id, t.messages.get(&id)?)))
+            .map(|(id, row)| {
+                (
+                    MessageId(id),
+                    UserId(row.sender_id),
+                    Tables::open_message(row),
+                    row.previous_message_id.map(MessageId),
+                )
+            })
+            .collect())
+    }
+
+    async fn get_most_recent_messages(
+        &self,
+        conversation_id: &Self::ConversationId,
+    ) -> Result<
+        (
+            Vec<(Self::MessageId, Self::UserId, Self::Message)>,
+            Option<Self::MessageId>,
+        ),
+        Self::Error,
+    > {
+        let t = self.read();
+        let mut rows: Vec<_> = t.messages_of(conversation_id).rev().take(32).collect();
+        rows.reverse();
+        let previous = rows
+            .first()
+            .and_then(|(_, row)| row.previous_message_id)
+            .map(MessageId);
+        let messages = rows
+            .into_iter()
+            .map(|(id, row)| {
+                (
+                    MessageId(*id),
+                    UserId(row.sender_id),
+                    Tables::open_message(row),
+                )
+            })
+            .collect();
+        Ok((messages, previous))
+    }
+
+    async fn get_messages_after(
+        &self,
+        conversation_id: &Self::ConversationId,
+        after: Option<&Self::MessageId>,
+        limit: u32,
+    ) -> Result<Vec<(Self::MessageId, Self::UserId, Self::Message)>, Self::Error> {
+        let t = self.read();
+        let after = after.map_or(0, |x| x.0);
+        Ok(t.messages_of(conversation_id)
+            .filter(|(id, _)| **id > after)
+            .take(limit as usize)
+            .map(|(id, row)| {
+                (
+                    MessageId(*id),
+                    UserId(row.sender_id),
+                    Tables::open_message(row),
+                )
+            })
+            .collect())
+    }
+
+    async fn get_querier(&self) -> Result<Self::Querier<'_>, Self::Error> {
+        Ok(self.read())
+    }
+
+    async fn add_user(&self, profile: &Self::UserProfile) -> Result<Self::UserId, Self::Error> {
+        let t = &mut *self.write();
+        let id = profile.id();
+        match t.users.get_mut(&id.0) {
+            // Erased users keep their pseudonym, whatever Jumpseller still says about them.
+            Some(user) if user.erased_at.is_some() => {}
+            Some(user) => user.profile = profile.clone(),
+            None => {
+                let user = UserRow {
+                    profile: profile.clone(),
+                    erased_at: None,
+                    primary_store_id: None,
+                };
+                t.users.insert(id.0, user);
+            }
+        }
+        Ok(id)
+    }
+
+    async fn start_conversation(
+        &self,
+        my_id: &Self::UserId,
+        their_id: &Self::UserId,
+        prod_id: &Self::ProductId,
+    ) -> Result<Self::ConversationId, Self::Error> {
+        let t = &mut *self.write();
+        let product = t.products.get(&prod_id.0).ok_or_else(not_found)?;
+        // Conversations about a seller's products go to the shared inbox of their primary store.
+        let store_id = t.user(product.seller_id)?.primary_store_id;
+
+        let existing = t.conversations.iter().find(|(_, c)| {
+            c.product_id == prod_id.0
+                && ((c.client_id == my_id.0 && c.seller_id == their_id.0)
+                    || (c.seller_id == my_id.0 && c.client_id == their_id.0)
+                    || (c.client_id == my_id.0 && store_id.is_some() && c.store_id == store_id))
+        });
+        if let Some((id, _)) = existing {
+            return Ok(ConversationId(*id));
+        }
+
+        t.user(*my_id)?;
+        t.user(*their_id)?;
+        let id = next_id(&t.conversations);
+        let conversation = ConversationRow {
+            client_id: my_id.0,
+            seller_id: their_id.0,
+            product_id: prod_id.0,
+            store_id,
+            assignee_id: None,
+            status: ConversationStatus::Open,
+            status_updated_by: None,
+            e2e: false,
+            last_message_id: None,
+        };
+        t.conversations.insert(id, conversation);
+        Ok(ConversationId(id))
+    }
+
+    async fn post_msg(
+        &self,
+        msg: Self::Message,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::MessageId, Self::Error> {
+        self.post_msg_idempotent(msg, my_id, conversation, None)
+            .await
+            .map(|(id, _)| id)
+    }
+
+    async fn post_msg_idempotent(
+        &self,
+        msg: Self::Message,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        client_message_id: Option<&str>,
+    ) -> Result<(Self::MessageId, bool), Self::Error> {
+        let t = &mut *self.write();
+        let existing = t.messages.iter().find(|(_, m)| {
+            m.sender_id == my_id.0
+                && m.conversation_id == conversation.0
+                && client_message_id.is_some()
+                && m.client_message_id.as_deref() == client_message_id
+        });
+        if let Some((id, _)) = existing {
+            return Ok((MessageId(*id), false));
+        }
+
+        let convo = t.conversation(*conversation)?;
+        // Erased users can't have anything written on their behalf.
+        if t.user(*my_id)?.erased_at.is_some() {
+            return Err(DbError::PermissionDenied);
+        }
+        let (prev_id, e2e) = (convo.last_message_id, convo.e2e);
+        let prev_hash = prev_id
+            .and_then(|id| t.messages.get(&id))
+            .map_or_else(|| vec![0; 32], |m| m.chain_hash.clone());
+
+        let id = next_id(&t.messages);
+        let timestamp = *msg.timestamp();
+        let content_hash = Sha256::digest(msg.contents()).to_vec();
+        let chain_hash = chain_hash(&prev_hash, my_id.0, timestamp.naive_utc(), &content_hash);
+        let message = MessageRow {
+            sender_id: my_id.0,
+            conversation_id: conversation.0,
+            content: Some(msg.contents().to_owned()),
+            timestamp,
+            previous_message_id: prev_id,
+            client_message_id: client_message_id.map(str::to_owned),
+            e2e,
+            delivered_at: None,
+            read_at: None,
+            content_hash,
+            chain_hash,
+        };
+        t.messages.insert(id, message);
+
+        let convo = t.conversation_mut(*conversation)?;
+        convo.last_message_id = Some(id);
+        // A message from the other side reopens a conversation that was waiting or resolved. The
+        // seller and the store's staff are on the same side.
+        let client_side = |user| user == convo.client_id;
+        if convo.status != ConversationStatus::Open
+            && convo
+                .status_updated_by
+                .is_none_or(|by| client_side(by) != client_side(my_id.0))
+        {
+            convo.status = ConversationStatus::Open;
+            convo.status_updated_by = None;
+        }
+        Ok((MessageId(id), true))
+    }
+
+    async fn get_latest_message(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let t = self.read();
+        let convo = t.conversation(*conversation)?;
+        Ok(convo.last_message_id.map(MessageId))
+    }
+
+    async fn get_user_id_from_username(&self, username: &str) -> Result<Self::UserId, Self::Error> {
+        let t = self.read();
+        t.users
+            .iter()
+            .find(|(_, u)| u.erased_at.is_none() && u.profile.username() == username)
+            .map(|(id, _)| UserId(*id))
+            .ok_or_else(not_found)
+    }
+
+    async fn belongs_to_conversation(
+        &self,
+        id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<(), Self::Error> {
+        let t = self.read();
+        match t.conversations.get(&conversation.0) {
+            Some(c) if t.is_participant(*id, c) => Ok(()),
+            _ => Err(DbError::PermissionDenied),
+        }
+    }
+
+    async fn get_conversation_from_message(
+        &self,
+        msg_id: &Self::MessageId,
+    ) -> Result<Self::ConversationId, Self::Error> {
+        let t = self.read();
+        let row = t.messages.get(&msg_id.0).ok_or_else(not_found)?;
+        Ok(ConversationId(row.conversation_id))
+    }
+
+    async fn get_conversations_from_messages(
+        &self,
+        msg_ids: &[Self::MessageId],
+    ) -> Result<Vec<(Self::MessageId, Self::ConversationId)>, Self::Error> {
+        let t = self.read();
+        let ids: BTreeSet<i64> = msg_ids.iter().map(|id| id.0).collect();
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| {
+                let row = t.messages.get(&id)?;
+                Some((MessageId(id), ConversationId(row.conversation_id)))
+            })
+            .collect())
+    }
+
+    async fn get_product(&self, prod_id: &Self::ProductId) -> Result<Self::Product, Self::Error> {
+        let t = self.read();
+        t.products.get(&prod_id.0).cloned().ok_or_else(not_found)
+    }
+
+    async fn get_product_id_from_conversation_id(
+        &self,
+        conversation_id: &Self::ConversationId,
+    ) -> Result<Self::ProductId, Self::Error> {
+        let t = self.read();
+        let convo = t.conversation(*conversation_id)?;
+        Ok(ProductId(convo.product_id))
+    }
+
+    async fn add_product(&self, product: &Self::Product) -> Result<Self::ProductId, Self::Error> {
+        let t = &mut *self.write();
+        t.user(product.seller_id)?;
+        t.products.insert(product.jumpseller_id, product.clone());
+        Ok(ProductId(product.jumpseller_id))
+    }
+
+    async fn get_seller_products(
+        &self,
+        seller: &Self::UserId,
+    ) -> Result<Vec<Self::Product>, Self::Error> {
+        let t = self.read();
+        Ok(t.products
+            .values()
+            .filter(|product| product.seller_id == *seller)
+            .cloned()
+            .collect())
+    }
+
+    async fn belongs_to_seller(
+        &self,
+        seller_id: &Self::UserId,
+        product_id: &Self::ProductId,
+    ) -> Result<(), Self::Error> {
+        let t = self.read();
+        match t.products.get(&product_id.0) {
+            Some(product) if product.seller_id == *seller_id => Ok(()),
+            _ => Err(DbError::PermissionDenied),
+        }
+    }
+
+    async fn add_store(
+        &self,
+        owner_id: &Self::UserId,
+        store: &Self::Store,
+    ) -> Result<Self::StoreId, Self::Error> {
+        let t = &mut *self.write();
+        let id = next_id(&t.stores);
+        let owner = t.users.get_mut(&owner_id.0).ok_or_else(not_found)?;
+        owner.primary_store_id.get_or_insert(id);
+        t.stores.insert(id, store.clone());
+        t.store_members.insert((id, owner_id.0));
+        t.store_owners.insert((id, owner_id.0));
+        Ok(StoreId(id))
+    }
+
+    async fn add_store_member(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+        user_id: &Self::UserId,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        if !t.store_owners.contains(&(store_id.0, my_id.0)) {
+            return Err(DbError::PermissionDenied);
+        }
+        let user = t.users.get_mut(&user_id.0).ok_or_else(not_found)?;
+        user.primary_store_id.get_or_insert(store_id.0);
+        t.store_members.insert((store_id.0, user_id.0));
+        Ok(())
+    }
+
+    async fn set_primary_store(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        if !t.store_members.contains(&(store_id.0, my_id.0)) {
+            return Err(DbError::PermissionDenied);
+        }
+        t.users
+            .get_mut(&my_id.0)
+            .ok_or_else(not_found)?
+            .primary_store_id = Some(store_id.0);
+        Ok(())
+    }
+
+    async fn get_store_members(
+        &self,
+        store_id: &Self::StoreId,
+    ) -> Result<Vec<Self::UserId>, Self::Error> {
+        let t = self.read();
+        Ok(t.store_members
+            .iter()
+            .filter(|(store, _)| *store == store_id.0)
+            .map(|(_, user)| UserId(*user))
+            .collect())
+    }
+
+    async fn get_user_stores(
+        &self,
+        user: &Self::UserId,
+    ) -> Result<Vec<(Self::StoreId, Self::Store)>, Self::Error> {
+        let t = self.read();
+        Ok(t.store_members
+            .iter()
+            .filter(|(_, member)| *member == user.0)
+            .filter_map(|(store, _)| Some((StoreId(*store), t.stores.get(store)?.clone())))
+            .collect())
+    }
+
+    async fn belongs_to_store(
+        &self,
+        id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error> {
+        let t = self.read();
+        if t.is_staff(Some(store_id.0), *id) {
+            Ok(())
+        } else {
+            Err(DbError::PermissionDenied)
+        }
+    }
+
+    async fn assign_conversation(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        assignee: Option<&Self::UserId>,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        let convo = t.conversation(*conversation)?;
+        // Only the seller's side of the conversation may assign it, and only to one of themselves.
+        for user in std::iter::once(my_id).chain(assignee) {
+            if user.0 != convo.seller_id && !t.is_staff(convo.store_id, *user) {
+                return Err(DbError::PermissionDenied);
+            }
+        }
+        t.conversation_mut(*conversation)?.assignee_id = assignee.map(|a| a.0);
+        Ok(())
+    }
+
+    async fn get_assignee(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::UserId>, Self::Error> {
+        let t = self.read();
+        let convo = t.conversation(*conversation)?;
+        Ok(convo.assignee_id.map(UserId))
+    }
+
+    async fn get_status(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::ConversationStatus, Self::Error> {
+        let t = self.read();
+        Ok(t.conversation(*conversation)?.status)
+    }
+
+    async fn set_status(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        status: Self::ConversationStatus,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        if let Some(convo) = t.conversations.get_mut(&conversation.0) {
+            convo.status = status;
+            convo.status_updated_by = (status != ConversationStatus::Open).then_some(my_id.0);
+        }
+        Ok(())
+    }
+
+    async fn get_labels(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Vec<String>, Self::Error> {
+        let t = self.read();
+        Ok(t.labels
+            .iter()
+            .filter(|(c, u, _)| *c == conversation.0 && *u == my_id.0)
+            .map(|(_, _, label)| label.clone())
+            .collect())
+    }
+
+    async fn add_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        t.conversation(*conversation)?;
+        t.user(*my_id)?;
+        t.labels.insert((conversation.0, my_id.0, label.to_owned()));
+        Ok(())
+    }
+
+    async fn remove_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        t.labels
+            .remove(&(conversation.0, my_id.0, label.to_owned()));
+        Ok(())
+    }
+
+    async fn set_public_key(&self, user: &Self::UserId, key: &str) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        t.user(*user)?;
+        t.public_keys.insert(user.0, key.to_owned());
+        Ok(())
+    }
+
+    async fn get_public_key(&self, user: &Self::UserId) -> Result<String, Self::Error> {
+        let t = self.read();
+        t.public_keys.get(&user.0).cloned().ok_or_else(not_found)
+    }
+
+    async fn is_e2e(&self, conversation: &Self::ConversationId) -> Result<bool, Self::Error> {
+        let t = self.read();
+        Ok(t.conversation(*conversation)?.e2e)
+    }
+
+    async fn enable_e2e(&self, conversation: &Self::ConversationId) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        let convo = t.conversation(*conversation)?;
+        // Staff of a shared inbox come and go, there is no single key to encrypt for.
+        if convo.store_id.is_some() {
+            return Err(DbError::E2EUnavailable("conversation is in a shared inbox"));
+        }
+        let participants = BTreeSet::from([convo.client_id, convo.seller_id]);
+        let keys = participants
+            .iter()
+            .filter(|user| t.public_keys.contains_key(user))
+            .count();
+        if keys < 2 {
+            return Err(DbError::E2EUnavailable(
+                "both participants must register a public key",
+            ));
+        }
+        t.conversation_mut(*conversation)?.e2e = true;
+        Ok(())
+    }
+
+    async fn mark_delivered(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+        messages: &[Self::MessageId],
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let t = &mut *self.write();
+        let now = Utc::now();
+        let mut newest = None;
+        let Some(client_id) = t.conversations.get(&conversation.0).map(|c| c.client_id) else {
+            return Ok(None);
+        };
+        for (id, message) in &mut t.messages {
+            if message.conversation_id == conversation.0
+                && (message.sender_id == client_id) != (reader.0 == client_id)
+                && messages.contains(&MessageId(*id))
+                && message.delivered_at.is_none()
+            {
+                message.delivered_at = Some(now);
+                newest = Some(MessageId(*id));
+            }
+        }
+        Ok(newest)
+    }
+
+    async fn mark_read(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let t = &mut *self.write();
+        let now = Utc::now();
+        let mut newest = None;
+        let Some(client_id) = t.conversations.get(&conversation.0).map(|c| c.client_id) else {
+            return Ok(None);
+        };
+        for (id, message) in &mut t.messages {
+            if message.conversation_id == conversation.0
+                && (message.sender_id == client_id) != (reader.0 == client_id)
+                && message.read_at.is_none()
+            {
+                message.read_at = Some(now);
+                message.delivered_at.get_or_insert(now);
+                newest = Some(MessageId(*id));
+            }
+        }
+        Ok(newest)
+    }
+
+    async fn append_audit(&self, entry: &Self::AuditEntry) -> Result<(), Self::Error> {
+        self.write().audit(&self.audit_key, entry)
+    }
+
+    async fn get_audit_log(
+        &self,
+        filter: &Self::AuditFilter,
+    ) -> Result<Vec<Self::AuditRecord>, Self::Error> {
+        let t = &mut *self.write();
+        let matches = |wanted: Option<i64>, value: Option<i64>| wanted.is_none() || wanted == value;
+        Ok(t.audit_log
+            .iter()
+            .rev()
+            .filter(|row| {
+                let entry = &row.entry;
+                matches(filter.actor_id, entry.actor_id.map(|id| id.0))
+                    && matches(filter.subject_id, entry.subject_id.map(|id| id.0))
+                    && matches(filter.conversation_id, entry.conversation_id.map(|id| id.0))
+            })
+            .take(filter.limit as usize)
+            .map(|row| AuditRecord {
+                id: row.id,
+                timestamp: row.timestamp.clone(),
+                entry: row.entry.clone(),
+                hash: hex::encode(&row.hash),
+            })
+            .collect())
+    }
+
+    async fn verify_audit_log(&self) -> Result<Option<i64>, Self::Error> {
+        let t = &mut *self.write();
+        let mut chain = AuditChain::new(&self.audit_key);
+        for row in &t.audit_log {
+            if !chain.links(&row.entry, &row.timestamp, &row.prev_hash, &row.hash)? {
+                return Ok(Some(row.id));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn verify_message_chain(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let t = self.read();
+        let last = t.conversation(*conversation)?.last_message_id;
+        let mut prev: Option<(i64, Vec<u8>)> = None;
+        for (id, m) in t.messages_of(conversation) {
+            let broken = Ok(Some(MessageId(*id)));
+            if m.previous_message_id != prev.as_ref().map(|(id, _)| *id) {
+                return broken;
+            }
+            if m.content
+                .as_ref()
+                .is_some_and(|content| Sha256::digest(content).as_slice() != m.content_hash)
+            {
+                return broken;
+            }
+            let prev_hash = prev.map_or_else(|| vec![0; 32], |(_, hash)| hash);
+            let hash = chain_hash(
+                &prev_hash,
+                m.sender_id,
+                m.timestamp.naive_utc(),
+                &m.content_hash,
+            );
+            if m.chain_hash != hash {
+                return broken;
+            }
+            prev = Some((*id, hash));
+        }
+        // A removed tail leaves the conversation pointing past the end of the chain.
+        Ok(match (last, prev) {
+            (Some(last), Some((id, _))) if last == id => None,
+            (None, None) => None,
+            (Some(last), _) | (None, Some((last, _))) => Some(MessageId(last)),
+        })
+    }
+
+    async fn get_chain_head(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::ChainHead>, Self::Error> {
+        let t = self.read();
+        let last = t.conversation(*conversation)?.last_message_id;
+        Ok(last.and_then(|id| {
+            let message = t.messages.get(&id)?;
+            Some(ChainHead {
+                message_id: MessageId(id),
+                hash: hex::encode(&message.chain_hash),
+            })
+        }))
+    }
+
+    /// Nothing is encrypted in memory, so there is never anything to move.
+    async fn reencrypt_batch(&self, _batch: u32) -> Result<u64, Self::Error> {
+        Ok(0)
+    }
+
+    async fn erase_user(
+        &self,
+        user: &Self::UserId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        // The row stays so the peer's conversations keep pointing somewhere.
+        let row = t.users.get_mut(&user.0).ok_or_else(not_found)?;
+        row.profile = UserProfile::erased(user.0);
+        row.erased_at.get_or_insert_with(Utc::now);
+        row.primary_store_id = None;
+
+        t.public_keys.remove(&user.0);
+        for message in t.messages.values_mut() {
+            if message.sender_id == user.0 {
+                message.content = None;
+            }
+        }
+        t.labels.retain(|(_, labeller, _)| *labeller != user.0);
+        t.store_members.retain(|(_, member)| *member != user.0);
+        t.store_owners.retain(|(_, owner)| *owner != user.0);
+        for convo in t.conversations.values_mut() {
+            if convo.assignee_id == Some(user.0) {
+                convo.assignee_id = None;
+            }
+        }
+        t.audit(&self.audit_key, audit)
+    }
+
+    async fn shred_conversation(
+        &self,
+        conversation: &Self::ConversationId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error> {
+        let t = &mut *self.write();
+        t.conversation(*conversation)?;
+        for message in t.messages.values_mut() {
+            if message.conversation_id == conversation.0 {
+                message.content = None;
+            }
+        }
+        t.audit(&self.audit_key, audit)
+    }
+}
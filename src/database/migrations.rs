@@ -0,0 +1,526 @@
+//! Versioned schema migrations, one directory per backend under `src/database/migrations/`.
+//!
+//! sqlx records every applied migration in `_sqlx_migrations` along with a checksum of its SQL,
+//! so a released migration must never be edited: add a new one instead. The sqlite baseline is
+//! the schema of the first release, which earlier versions created with a script rather than
+//! migrations: see [`adopt_legacy`] for those databases.
+
+use anyhow::anyhow;
+use serde::Serialize;
+use sqlx::{
+    Pool, Sqlite,
+    migrate::{Migrate, Migrator},
+};
+
+pub static SQLITE: Migrator = sqlx::migrate!("src/database/migrations/sqlite");
+pub static POSTGRES: Migrator = sqlx::migrate!("src/database/migrations/postgres");
+
+/// Version of the `indexes` migration of each backend, which makes conversations unique.
+static UNIQUE_CONVERSATIONS: [(&Migrator, i64); 2] = [(&SQLITE, 15), (&POSTGRES, 2)];
+
+/// Query finding `_sqlx_migrations` on each backend, without creating it like sqlx does.
+static MIGRATIONS_TABLE: [(&Migrator, &str); 2] = [
+    (
+        &SQLITE,
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    ),
+    (
+        &POSTGRES,
+        "SELECT 1 FROM information_schema.tables
+        WHERE table_schema = current_schema() AND table_name = '_sqlx_migrations'",
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Applied,
+    Pending,
+    /// Applied, but its SQL changed since: the database may not match what this build expects.
+    Modified,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: State,
+}
+
+/// Schema version this build understands, that of its newest migration.
+pub fn latest(migrator: &Migrator) -> i64 {
+    migrator.iter().map(|m| m.version).max().unwrap_or(0)
+}
+
+/// Every migration as pending, for a database that doesn't exist yet.
+pub fn unapplied(migrator: &Migrator) -> Vec<MigrationStatus> {
+    migrator
+        .iter()
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            state: State::Pending,
+        })
+        .collect()
+}
+
+/// State of every migration known to `migrator`, oldest first. Only reads the database: until
+/// migrations create `_sqlx_migrations`, every one of them is pending.
+///
+/// Fails if the database was migrated by a newer build, whose schema this one can't vouch for.
+pub async fn status<DB>(
+    migrator: &Migrator,
+    pool: &Pool<DB>,
+) -> anyhow::Result<Vec<MigrationStatus>>
+where
+    DB: sqlx::Database,
+    DB::Connection: Migrate,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: sqlx::IntoArguments<'q, DB>,
+{
+    let tracked = match MIGRATIONS_TABLE
+        .iter()
+        .find(|(known, _)| std::ptr::eq(*known, migrator))
+    {
+        Some((_, query)) => sqlx::query(query).fetch_optional(pool).await?.is_some(),
+        None => return Err(anyhow!("No migrations table query for this backend")),
+    };
+    let applied = if tracked {
+        pool.acquire().await?.list_applied_migrations().await?
+    } else {
+        Vec::new()
+    };
+    let latest = latest(migrator);
+    if let Some(newer) = applied
+        .iter()
+        .map(|m| m.version)
+        .filter(|v| *v > latest)
+        .max()
+    {
+        return Err(anyhow!(
+            "Database schema is at version {newer}, newer than version {latest} understood by this build"
+        ));
+    }
+
+    let mut migrations = unapplied(migrator);
+    for (migration, known) in migrations.iter_mut().zip(migrator.iter()) {
+        if let Some(done) = applied.iter().find(|a| a.version == known.version) {
+            migration.state = if done.checksum == known.checksum {
+                State::Applied
+            } else {
+                State::Modified
+            };
+        }
+    }
+    Ok(migrations)
+}
+
+/// Records the baseline as applied on a sqlite database created before migrations, by the schema
+/// script of the first release, so that only the later migrations run. Does nothing to databases
+/// that track their migrations, or are empty.
+///
+/// Returns whether the database was adopted.
+pub async fn adopt_legacy(pool: &Pool<Sqlite>) -> anyhow::Result<bool> {
+    if has(pool, "_sqlx_migrations").await? || !has(pool, "message").await? {
+        return Ok(false);
+    }
+
+    let baseline = SQLITE
+        .iter()
+        .find(|m| m.version == 1)
+        .ok_or_else(|| anyhow!("No baseline migration"))?;
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+         VALUES (?, ?, TRUE, ?, 0)",
+    )
+    .bind(baseline.version)
+    .bind(&*baseline.description)
+    .bind(&*baseline.checksum)
+    .execute(&mut *conn)
+    .await?;
+    log::info!("Adopted a database from before migrations, at the baseline schema.");
+    Ok(true)
+}
+
+/// Whether the sqlite database has `table`.
+async fn has(pool: &Pool<Sqlite>, table: &str) -> anyhow::Result<bool> {
+    let count: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(table)
+            .fetch_one(pool)
+            .await?;
+    Ok(count > 0)
+}
+
+/// Applies the pending migrations, after checking the schema isn't newer than this build.
+/// Returns the migrations applied.
+pub async fn run<DB>(migrator: &Migrator, pool: &Pool<DB>) -> anyhow::Result<Vec<MigrationStatus>>
+where
+    DB: sqlx::Database,
+    DB::Connection: Migrate,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: sqlx::IntoArguments<'q, DB>,
+    (i64, i64, i64, i64): for<'r> sqlx::FromRow<'r, DB::Row>,
+{
+    let migrations = status(migrator, pool).await?;
+    // On a new database, there is nothing to check yet: the baseline creates the tables.
+    let existing = migrations.iter().any(|m| m.state != State::Pending);
+    let mut pending: Vec<_> = migrations
+        .into_iter()
+        .filter(|m| m.state == State::Pending)
+        .collect();
+    let unique = UNIQUE_CONVERSATIONS
+        .iter()
+        .find(|(known, _)| std::ptr::eq(*known, migrator))
+        .map(|(_, version)| *version);
+    if existing && pending.iter().any(|m| Some(m.version) == unique) {
+        check_unique_conversations(pool).await?;
+    }
+    migrator.run(pool).await?;
+    for migration in &mut pending {
+        migration.state = State::Applied;
+        log::info!(
+            "Applied migration {} ({})",
+            migration.version,
+            migration.description
+        );
+    }
+    Ok(pending)
+}
+
+/// Fails on conversations the `indexes` migration would make unique. Earlier versions only looked
+/// the conversation up before creating it, serialized within one server: several servers sharing a
+/// database, or rows written by hand, could duplicate it. Naming them, instead of letting the
+/// migration fail on a bare constraint error, to be merged or deleted by hand: their hash chains
+/// and keys can't be merged blindly.
+async fn check_unique_conversations<DB>(pool: &Pool<DB>) -> anyhow::Result<()>
+where
+    DB: sqlx::Database,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: sqlx::IntoArguments<'q, DB>,
+    (i64, i64, i64, i64): for<'r> sqlx::FromRow<'r, DB::Row>,
+{
+    let duplicates: Vec<(i64, i64, i64, i64)> = sqlx::query_as(
+        "SELECT DISTINCT c.client_id, c.seller_id, c.product_id, c.id
+        FROM conversation c JOIN conversation d
+            ON d.client_id = c.client_id AND d.seller_id = c.seller_id
+            AND d.product_id = c.product_id AND d.id != c.id
+        ORDER BY c.client_id, c.seller_id, c.product_id, c.id",
+    )
+    .fetch_all(pool)
+    .await?;
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+
+    let mut groups: Vec<((i64, i64, i64), Vec<i64>)> = Vec::new();
+    for (client, seller, product, id) in duplicates {
+        match groups.last_mut() {
+            Some((participants, ids)) if *participants == (client, seller, product) => ids.push(id),
+            _ => groups.push(((client, seller, product), vec![id])),
+        }
+    }
+    let groups: Vec<_> = groups
+        .iter()
+        .map(|((client, seller, product), ids)| {
+            format!("{ids:?} (client {client}, seller {seller}, product {product})")
+        })
+        .collect();
+    Err(anyhow!(
+        "Conversations must be unique per client, seller and product, merge or delete these \
+        duplicates before migrating: {}",
+        groups.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+    use sqlx::{Sqlite, migrate::MigrateDatabase, sqlite::SqlitePoolOptions};
+
+    use super::*;
+    use crate::database::{
+        Database,
+        crypto::{
+            CryptData, CryptoKey,
+            tests::{key, key_from},
+        },
+        model::{ConversationId, DbError, MessageId, ProductId, UserId},
+        sqlite::SQLiteDB,
+    };
+    use crate::maintenance;
+
+    #[actix_web::test]
+    async fn test_migrations() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("migrations_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let _ = std::fs::remove_file(&path);
+        Sqlite::create_database(&url).await?;
+        let pool = SqlitePoolOptions::new().connect(&url).await?;
+
+        let before = status(&SQLITE, &pool).await?;
+        assert_eq!(before, unapplied(&SQLITE));
+        let applied = run(&SQLITE, &pool).await?;
+        assert_eq!(applied.len(), before.len());
+        let after = status(&SQLITE, &pool).await?;
+        assert_eq!(after, applied);
+        assert!(after.iter().all(|m| m.state == State::Applied));
+        assert!(run(&SQLITE, &pool).await?.is_empty());
+        for (migrator, version) in &UNIQUE_CONVERSATIONS {
+            assert!(
+                migrator
+                    .iter()
+                    .any(|m| m.version == *version && m.description == "indexes")
+            );
+        }
+
+        // A newer build migrated this database: refuse to touch it.
+        let newer = latest(&SQLITE) + 1;
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+             VALUES (?, 'from the future', TRUE, x'00', 0)",
+        )
+        .bind(newer)
+        .execute(&pool)
+        .await?;
+        assert!(run(&SQLITE, &pool).await.is_err());
+        pool.close().await;
+
+        let Err(err) = SQLiteDB::new(&url, key()?).await else {
+            return Err(anyhow!("Opened a database with a newer schema"));
+        };
+        assert!(err.to_string().contains(&format!("version {newer}")));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// Database written by the first release, whose schema script created what the baseline
+    /// migration does. Holds a conversation of Alice with Bob, names in the clear.
+    async fn legacy_db(url: &str) -> anyhow::Result<Pool<Sqlite>> {
+        let _ = Sqlite::drop_database(url).await;
+        Sqlite::create_database(url).await?;
+        let pool = SqlitePoolOptions::new().connect(url).await?;
+        let baseline = SQLITE
+            .iter()
+            .find(|m| m.version == 1)
+            .ok_or_else(|| anyhow!("No baseline migration"))?;
+        sqlx::raw_sql(&baseline.sql).execute(&pool).await?;
+        sqlx::raw_sql(
+            "INSERT INTO user (id, username, name) VALUES (11, 'alice_11', 'Alice Arnold');
+            INSERT INTO user (id, username, name) VALUES (22, 'bobert22', 'Bob Bellows');
+            INSERT INTO product (id, seller_id, name) VALUES (1, 22, 'Dill Dough');
+            INSERT INTO conversation (client_id, seller_id, product_id) VALUES (11, 22, 1);",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(pool)
+    }
+
+    /// Appends message `id` to the conversation, encrypted in the unversioned format with `key`.
+    async fn post_legacy(
+        pool: &Pool<Sqlite>,
+        id: i64,
+        sender: i64,
+        contents: &str,
+        key: &CryptoKey,
+    ) -> anyhow::Result<()> {
+        let nonce = [u8::try_from(id)?; 12];
+        let content = CryptData::encrypt_legacy(&contents.to_owned(), key, nonce)?;
+        sqlx::query(
+            "INSERT INTO message
+                (id, sender_id, conversation_id, content, salt, timestamp, previous_message_id)
+            VALUES (?, ?, 1, ?, ?, datetime('now'), NULLIF(? - 1, 0));
+            UPDATE conversation SET last_message_id = ?1 WHERE id = 1",
+        )
+        .bind(id)
+        .bind(sender)
+        .bind(content)
+        .bind(nonce.as_slice())
+        .bind(id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Whether every migration of the database at `url` is applied.
+    async fn up_to_date(url: &str) -> anyhow::Result<bool> {
+        let pool = SqlitePoolOptions::new().connect(url).await?;
+        let migrations = status(&SQLITE, &pool).await?;
+        pool.close().await;
+        Ok(migrations.len() == SQLITE.iter().count()
+            && migrations.iter().all(|m| m.state == State::Applied))
+    }
+
+    #[actix_web::test]
+    async fn test_upgrade_first_release() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("first_release_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        // Messages were encrypted with the master key itself.
+        let pool = legacy_db(&url).await?;
+        post_legacy(&pool, 1, 11, "Hello Bob!", &key()?).await?;
+        post_legacy(&pool, 2, 22, "Hello Alice!", &key()?).await?;
+        assert!(adopt_legacy(&pool).await?);
+        assert!(!adopt_legacy(&pool).await?);
+        pool.close().await;
+
+        let db = SQLiteDB::new(&url, key()?).await?;
+        let profile = db.get_user_profile(&UserId(11)).await?;
+        assert_eq!(
+            (profile.username(), profile.name()),
+            ("alice_11".to_owned(), "Alice Arnold".to_owned())
+        );
+        assert_eq!(db.get_product(&ProductId(1)).await?.name, "Dill Dough");
+        assert_eq!(db.get_user_id_from_username("bobert22").await?, UserId(22));
+        assert_eq!(
+            db.get_conversations(&UserId(11)).await?,
+            [ConversationId(1)]
+        );
+        assert_eq!(db.verify_message_chain(&ConversationId(1)).await?, None);
+        let (messages, _) = db.get_most_recent_messages(&ConversationId(1)).await?;
+        let contents: Vec<_> = messages.iter().map(|(_, _, m)| m.contents()).collect();
+        assert_eq!(contents, ["Hello Bob!", "Hello Alice!"]);
+
+        // A ciphertext without its key doesn't read as an empty message.
+        sqlx::raw_sql("DELETE FROM conversation_key WHERE user_id = 22")
+            .execute(&SqlitePoolOptions::new().connect(&url).await?)
+            .await?;
+        assert!(matches!(
+            db.get_message(&MessageId(2)).await,
+            Err(DbError::Decryption(MessageId(2)))
+        ));
+        drop(db);
+
+        assert!(up_to_date(&url).await?);
+        Sqlite::drop_database(&url).await?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_upgrade_wrong_key() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("wrong_key_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let pool = legacy_db(&url).await?;
+        post_legacy(&pool, 1, 11, "Hello Bob!", &key()?).await?;
+        pool.close().await;
+
+        // Only its messages can tell the key is wrong, which mustn't get a canary stored.
+        let wrong = key_from("wrong password")?;
+        let Err(err) = SQLiteDB::new(&url, wrong).await else {
+            return Err(anyhow!("Booted with the wrong key"));
+        };
+        assert!(err.to_string().contains("Master key #1"));
+        let (_, hello, _) = SQLiteDB::new(&url, key()?)
+            .await?
+            .get_message(&MessageId(1))
+            .await?;
+        assert_eq!(hello.contents(), "Hello Bob!");
+        Sqlite::drop_database(&url).await?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_upgrade_unreadable_message() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("unreadable_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let pool = legacy_db(&url).await?;
+        post_legacy(&pool, 1, 11, "Hello Bob!", &key()?).await?;
+        post_legacy(&pool, 2, 22, "Hello Alice!", &key()?).await?;
+        sqlx::query("UPDATE message SET content = x'00' || content WHERE id = 2")
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+
+        // Still boots, and only the damaged message fails to read.
+        let db = SQLiteDB::new(&url, key()?).await?;
+        let (_, hello, _) = db.get_message(&MessageId(1)).await?;
+        assert_eq!(hello.contents(), "Hello Bob!");
+        assert!(matches!(
+            db.get_message(&MessageId(2)).await,
+            Err(DbError::Decryption(MessageId(2)))
+        ));
+        drop(db);
+        Sqlite::drop_database(&url).await?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_duplicate_conversations() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("duplicates_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let pool = legacy_db(&url).await?;
+        adopt_legacy(&pool).await?;
+        sqlx::raw_sql(
+            "INSERT INTO conversation (client_id, seller_id, product_id) VALUES (11, 22, 1);
+            INSERT INTO conversation (client_id, seller_id, product_id) VALUES (22, 11, 1);
+            INSERT INTO conversation (client_id, seller_id, product_id) VALUES (11, 22, 1);",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Named before anything is migrated, rather than failing on the unique index.
+        let Err(err) = run(&SQLITE, &pool).await else {
+            return Err(anyhow!("Migrated duplicate conversations"));
+        };
+        assert!(
+            err.to_string()
+                .ends_with("[1, 2, 4] (client 11, seller 22, product 1)"),
+            "{err}"
+        );
+        let pending = status(&SQLITE, &pool).await?;
+        assert!(
+            pending
+                .iter()
+                .all(|m| m.version == 1 || m.state == State::Pending)
+        );
+
+        sqlx::query("DELETE FROM conversation WHERE id IN (2, 4)")
+            .execute(&pool)
+            .await?;
+        run(&SQLITE, &pool).await?;
+        pool.close().await;
+        assert!(up_to_date(&url).await?);
+        Sqlite::drop_database(&url).await?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_dry_run_first() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("dry_run_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let pool = legacy_db(&url).await?;
+        sqlx::raw_sql(
+            "INSERT INTO conversation (client_id, seller_id, product_id) VALUES (11, 22, 1);",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Looking at the migrations leaves the database to be adopted when applying them...
+        maintenance::migrate(&url, true, false).await?;
+        maintenance::migrate(&url, false, true).await?;
+        assert!(!has(&pool, "_sqlx_migrations").await?);
+        pool.close().await;
+
+        // ...which still names the duplicates, when booting as well as migrating.
+        let Err(err) = SQLiteDB::new(&url, key()?).await else {
+            return Err(anyhow!("Booted with duplicate conversations"));
+        };
+        assert!(
+            err.to_string()
+                .ends_with("[1, 2] (client 11, seller 22, product 1)"),
+            "{err}"
+        );
+        let Err(err) = maintenance::migrate(&url, false, false).await else {
+            return Err(anyhow!("Migrated duplicate conversations"));
+        };
+        assert!(
+            err.to_string()
+                .ends_with("[1, 2] (client 11, seller 22, product 1)"),
+            "{err}"
+        );
+        Sqlite::drop_database(&url).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+-- Same tables as the sqlite baseline. Only the current formats are stored: there are no
+-- legacy rows to upgrade, so nonces always travel inside the ciphertext.
+
+CREATE TABLE IF NOT EXISTS store (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "user" (
+    id BIGINT PRIMARY KEY,
+    -- Encrypted with the 'profile' metadata key, NULL once erased.
+    username BYTEA,
+    name BYTEA,
+    -- Keyed hash of the username, to look users up without decrypting every row.
+    username_index BYTEA,
+    erased_at TIMESTAMPTZ,
+    -- Store whose inbox conversations about a seller's products land in.
+    primary_store_id BIGINT REFERENCES store(id)
+);
+
+CREATE INDEX IF NOT EXISTS user_username_index ON "user"(username_index);
+
+-- Master keys the database was written with, recorded when first used.
+CREATE TABLE IF NOT EXISTS master_key (
+    id BIGINT PRIMARY KEY,
+    -- Known plaintext encrypted with the key, which only decrypts with the right password.
+    canary BYTEA NOT NULL,
+    -- How the key was derived from its password, NULL for keys that weren't.
+    kdf_algorithm TEXT,
+    kdf_version BIGINT,
+    kdf_memory_kib BIGINT,
+    kdf_iterations BIGINT,
+    kdf_parallelism BIGINT,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+-- Data keys for what isn't a message, wrapped with master key `key_id`.
+CREATE TABLE IF NOT EXISTS metadata_key (
+    purpose TEXT PRIMARY KEY,
+    key BYTEA NOT NULL,
+    key_id BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS public_key (
+    user_id BIGINT PRIMARY KEY REFERENCES "user"(id),
+    key TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS product (
+    id BIGINT PRIMARY KEY,
+    seller_id BIGINT NOT NULL REFERENCES "user"(id),
+    -- Encrypted with the 'profile' metadata key.
+    name BYTEA NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS store_member (
+    store_id BIGINT NOT NULL REFERENCES store(id),
+    user_id BIGINT NOT NULL REFERENCES "user"(id),
+    -- Only owners add members to the store.
+    owner BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY(store_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS conversation (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    client_id BIGINT NOT NULL REFERENCES "user"(id),
+    seller_id BIGINT NOT NULL REFERENCES "user"(id),
+    product_id BIGINT NOT NULL REFERENCES product(id),
+    last_message_id BIGINT,
+    store_id BIGINT REFERENCES store(id),
+    assignee_id BIGINT REFERENCES "user"(id),
+    status TEXT NOT NULL DEFAULT 'open',
+    status_updated_by BIGINT REFERENCES "user"(id),
+    e2e BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS message (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    sender_id BIGINT NOT NULL REFERENCES "user"(id),
+    conversation_id BIGINT NOT NULL REFERENCES conversation(id),
+    content BYTEA,
+    timestamp TIMESTAMPTZ NOT NULL,
+    previous_message_id BIGINT REFERENCES message(id),
+    client_message_id TEXT,
+    delivered_at TIMESTAMPTZ,
+    read_at TIMESTAMPTZ,
+    e2e BOOLEAN NOT NULL DEFAULT FALSE,
+    -- SHA-256 of `content` as first written, kept when the content is erased.
+    content_hash BYTEA,
+    -- Link of the conversation's hash chain, see `chain_hash` in sqlite.rs.
+    chain_hash BYTEA,
+    UNIQUE(sender_id, conversation_id, client_message_id)
+);
+
+-- Conversations and messages point at each other, so one of the two references comes after.
+DO $$
+BEGIN
+    ALTER TABLE conversation ADD CONSTRAINT conversation_last_message_id_fkey
+        FOREIGN KEY (last_message_id) REFERENCES message(id);
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
+
+CREATE TABLE IF NOT EXISTS conversation_key (
+    conversation_id BIGINT NOT NULL REFERENCES conversation(id),
+    user_id BIGINT NOT NULL REFERENCES "user"(id),
+    key BYTEA NOT NULL,
+    key_id BIGINT NOT NULL,
+    PRIMARY KEY(conversation_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS conversation_label (
+    conversation_id BIGINT NOT NULL REFERENCES conversation(id),
+    user_id BIGINT NOT NULL REFERENCES "user"(id),
+    label TEXT NOT NULL,
+    PRIMARY KEY(conversation_id, user_id, label)
+);
+
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    -- RFC 3339, hashed as written.
+    timestamp TEXT NOT NULL,
+    actor_id BIGINT,
+    action TEXT NOT NULL,
+    subject_id BIGINT,
+    conversation_id BIGINT,
+    message_id BIGINT,
+    reason TEXT NOT NULL,
+    prev_hash BYTEA NOT NULL,
+    -- HMAC-SHA256 of `prev_hash` and this entry, keyed with the `audit` metadata key.
+    hash BYTEA NOT NULL
+);
+
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END $$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+
+CREATE OR REPLACE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
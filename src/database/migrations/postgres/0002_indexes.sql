@@ -0,0 +1,12 @@
+-- Same indexes as the sqlite migration.
+
+CREATE INDEX message_conversation_id ON message(conversation_id, id);
+
+-- Also what `start_conversation` falls back on when a concurrent call created the conversation.
+ALTER TABLE conversation
+    ADD CONSTRAINT conversation_participants UNIQUE (client_id, seller_id, product_id);
+
+CREATE INDEX conversation_seller_id ON conversation(seller_id);
+CREATE INDEX conversation_store_id ON conversation(store_id);
+CREATE INDEX store_member_user_id ON store_member(user_id);
+CREATE INDEX conversation_label_user_id ON conversation_label(user_id, label);
@@ -0,0 +1,21 @@
+CREATE TABLE store (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL
+);
+
+CREATE TABLE store_member (
+    store_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    -- Only owners add members to the store.
+    owner BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY(store_id, user_id),
+    FOREIGN KEY(store_id) REFERENCES store(id),
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
+
+-- Store whose inbox conversations about a seller's products land in.
+ALTER TABLE user ADD COLUMN primary_store_id INTEGER REFERENCES store(id);
+
+-- Inbox the conversation landed in, if the seller is staff of a store, and who handles it there.
+ALTER TABLE conversation ADD COLUMN store_id INTEGER REFERENCES store(id);
+ALTER TABLE conversation ADD COLUMN assignee_id INTEGER REFERENCES user(id);
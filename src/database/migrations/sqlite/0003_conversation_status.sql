@@ -0,0 +1,11 @@
+ALTER TABLE conversation ADD COLUMN status TEXT NOT NULL DEFAULT 'open';
+ALTER TABLE conversation ADD COLUMN status_updated_by INTEGER REFERENCES user(id);
+
+CREATE TABLE conversation_label (
+    conversation_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    label TEXT NOT NULL,
+    PRIMARY KEY(conversation_id, user_id, label),
+    FOREIGN KEY(conversation_id) REFERENCES conversation(id),
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
@@ -0,0 +1,6 @@
+-- Id the client generated for a message, so that retrying a post doesn't duplicate it.
+ALTER TABLE message ADD COLUMN client_message_id TEXT;
+
+-- sqlite can't add a constraint to an existing table: a unique index does.
+CREATE UNIQUE INDEX message_client_message_id
+    ON message(sender_id, conversation_id, client_message_id);
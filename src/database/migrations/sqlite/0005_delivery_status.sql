@@ -0,0 +1,2 @@
+ALTER TABLE message ADD COLUMN delivered_at DATETIME;
+ALTER TABLE message ADD COLUMN read_at DATETIME;
@@ -0,0 +1,9 @@
+ALTER TABLE user ADD COLUMN erased_at DATETIME;
+
+-- Data key each user's messages are encrypted with, wrapped with the master key.
+CREATE TABLE user_key (
+    user_id INTEGER PRIMARY KEY,
+    key BLOB NOT NULL,
+    nonce BLOB NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
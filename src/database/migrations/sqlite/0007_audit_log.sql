@@ -0,0 +1,23 @@
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp DATETIME NOT NULL,
+    actor_id INTEGER,
+    action TEXT NOT NULL,
+    subject_id INTEGER,
+    conversation_id INTEGER,
+    message_id INTEGER,
+    reason TEXT NOT NULL,
+    prev_hash BLOB NOT NULL,
+    -- HMAC-SHA256 of `prev_hash` and this entry, keyed with the `audit` metadata key.
+    hash BLOB NOT NULL
+);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
@@ -0,0 +1,2 @@
+-- Master key the data keys are wrapped with. Those wrapped before were wrapped with the first.
+ALTER TABLE user_key ADD COLUMN key_id INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,21 @@
+-- Per-user data keys give way to keys per conversation and sender.
+CREATE TABLE conversation_key (
+    conversation_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    key BLOB NOT NULL,
+    -- Only set for keys wrapped in the unversioned format, newer ones carry their nonce.
+    nonce BLOB NOT NULL,
+    key_id INTEGER NOT NULL DEFAULT 1,
+    PRIMARY KEY(conversation_id, user_id),
+    FOREIGN KEY(conversation_id) REFERENCES conversation(id),
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
+
+-- A user's messages stay encrypted with their user key, which becomes their key in every
+-- conversation they posted in.
+INSERT INTO conversation_key (conversation_id, user_id, key, nonce, key_id)
+SELECT DISTINCT message.conversation_id, user_key.user_id, user_key.key, user_key.nonce,
+    user_key.key_id
+FROM message JOIN user_key ON user_key.user_id = message.sender_id;
+
+DROP TABLE user_key;
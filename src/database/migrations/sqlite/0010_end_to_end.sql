@@ -0,0 +1,9 @@
+CREATE TABLE public_key (
+    user_id INTEGER PRIMARY KEY,
+    key TEXT NOT NULL,
+    updated_at DATETIME NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
+
+ALTER TABLE conversation ADD COLUMN e2e INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE message ADD COLUMN e2e INTEGER NOT NULL DEFAULT 0;
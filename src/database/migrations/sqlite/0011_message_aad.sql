@@ -0,0 +1,3 @@
+-- Whether the ciphertext is bound to the message's metadata. Those written before are bound on
+-- boot, see `bind_legacy_messages` in sqlite.rs.
+ALTER TABLE message ADD COLUMN aad_bound INTEGER NOT NULL DEFAULT 0;
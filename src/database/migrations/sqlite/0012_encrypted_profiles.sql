@@ -0,0 +1,14 @@
+-- Data keys for what isn't a message, wrapped with master key `key_id`.
+CREATE TABLE metadata_key (
+    purpose TEXT PRIMARY KEY,
+    key BLOB NOT NULL,
+    key_id INTEGER NOT NULL
+);
+
+-- `user.username`, `user.name` and `product.name` now hold ciphertext encrypted with the
+-- 'profile' metadata key, NULL once erased. sqlite can't change their declared type, which
+-- leaves blobs as they are: names stored in the clear are encrypted on boot.
+
+-- Keyed hash of the username, to look users up without decrypting every row.
+ALTER TABLE user ADD COLUMN username_index BLOB;
+CREATE INDEX user_username_index ON user(username_index);
@@ -0,0 +1,13 @@
+-- Master keys the database was written with, recorded when first used.
+CREATE TABLE master_key (
+    id INTEGER PRIMARY KEY,
+    -- Known plaintext encrypted with the key, which only decrypts with the right password.
+    canary BLOB NOT NULL,
+    -- How the key was derived from its password, NULL for keys that weren't.
+    kdf_algorithm TEXT,
+    kdf_version INTEGER,
+    kdf_memory_kib INTEGER,
+    kdf_iterations INTEGER,
+    kdf_parallelism INTEGER,
+    created_at DATETIME NOT NULL
+);
@@ -0,0 +1,5 @@
+-- SHA-256 of `content` as first written, kept when the content is erased.
+ALTER TABLE message ADD COLUMN content_hash BLOB;
+-- Link of the conversation's hash chain, see `chain_hash` in sqlite.rs. Messages written before
+-- are linked on boot.
+ALTER TABLE message ADD COLUMN chain_hash BLOB;
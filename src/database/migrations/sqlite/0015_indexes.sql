@@ -0,0 +1,16 @@
+-- Indexes for the lookups that scanned whole tables, which grew with every message.
+
+-- Messages of a conversation in order: pages, receipts and hash chain checks.
+CREATE INDEX message_conversation_id ON message(conversation_id, id);
+
+-- One conversation per client, seller and product, which `start_conversation` looks up before
+-- creating one. Duplicates from earlier versions are named before this runs, see
+-- `check_unique_conversations`. sqlite can't add a constraint to an existing table: a unique
+-- index does.
+CREATE UNIQUE INDEX conversation_participants ON conversation(client_id, seller_id, product_id);
+
+-- Conversations of a user as seller, or as staff of the store whose inbox they are in.
+CREATE INDEX conversation_seller_id ON conversation(seller_id);
+CREATE INDEX conversation_store_id ON conversation(store_id);
+CREATE INDEX store_member_user_id ON store_member(user_id);
+CREATE INDEX conversation_label_user_id ON conversation_label(user_id, label);
@@ -1,6 +1,25 @@
+#[cfg(test)]
+mod conformance;
 pub mod crypto;
+pub mod memory;
+pub mod migrations;
+pub mod model;
+pub mod postgres;
+mod sealing;
 pub mod sqlite;
 
+use model::{
+    AuditEntry, AuditFilter, AuditRecord, ChainHead, ConversationFilter, ConversationId,
+    ConversationStatus, DbError, Message, MessageId, Product, ProductId, Store, StoreId, UserId,
+    UserProfile,
+};
+
+// Only implemented and called within this crate, whose futures needn't be `Send`: the server
+// runs them on a local task set.
+//
+// Every method takes `&self`, writes included: backends handle concurrent callers themselves, so
+// the server shares one between all requests without locking it.
+#[allow(async_fn_in_trait)]
 pub trait Database {
     type Error;
     type UserId;
@@ -10,6 +29,14 @@ pub trait Database {
     type Message;
     type ProductId;
     type Product;
+    type StoreId;
+    type Store;
+    type ConversationStatus;
+    type ConversationFilter;
+    type AuditEntry;
+    type AuditRecord;
+    type AuditFilter;
+    type ChainHead;
     type Querier<'a>
     where
         Self: 'a;
@@ -19,6 +46,12 @@ pub trait Database {
         my_id: &Self::UserId,
     ) -> Result<Vec<Self::ConversationId>, Self::Error>;
 
+    async fn filter_conversations(
+        &self,
+        my_id: &Self::UserId,
+        filter: &Self::ConversationFilter,
+    ) -> Result<Vec<Self::ConversationId>, Self::Error>;
+
     async fn get_peer(
         &self,
         my_id: &Self::UserId,
@@ -38,10 +71,38 @@ pub trait Database {
         message: &Self::MessageId,
     ) -> Result<(Self::UserId, Self::Message, Option<Self::MessageId>), Self::Error>;
 
+    /// Fetches and decrypts several messages at once. Ids that don't exist are left out.
+    async fn get_messages(
+        &self,
+        messages: &[Self::MessageId],
+    ) -> Result<
+        Vec<(
+            Self::MessageId,
+            Self::UserId,
+            Self::Message,
+            Option<Self::MessageId>,
+        )>,
+        Self::Error,
+    >;
+
     async fn get_most_recent_messages(
         &self,
         conversation_id: &Self::ConversationId,
-    ) -> Result<(Vec<(Self::UserId, Self::Message)>, Option<Self::MessageId>), Self::Error>;
+    ) -> Result<
+        (
+            Vec<(Self::MessageId, Self::UserId, Self::Message)>,
+            Option<Self::MessageId>,
+        ),
+        Self::Error,
+    >;
+
+    /// Oldest-first page of the messages in a conversation posted after `after`.
+    async fn get_messages_after(
+        &self,
+        conversation_id: &Self::ConversationId,
+        after: Option<&Self::MessageId>,
+        limit: u32,
+    ) -> Result<Vec<(Self::MessageId, Self::UserId, Self::Message)>, Self::Error>;
 
     #[allow(dead_code)]
     async fn get_querier(&self) -> Result<Self::Querier<'_>, Self::Error>;
@@ -53,27 +114,41 @@ pub trait Database {
         conversation_id: &Self::ConversationId,
     ) -> Result<Self::ProductId, Self::Error>;
 
-    async fn add_product(
-        &mut self,
-        product: &Self::Product,
-    ) -> Result<Self::ProductId, Self::Error>;
+    async fn add_product(&self, product: &Self::Product) -> Result<Self::ProductId, Self::Error>;
+
+    /// Products `seller` sells, by id.
+    async fn get_seller_products(
+        &self,
+        seller: &Self::UserId,
+    ) -> Result<Vec<Self::Product>, Self::Error>;
 
-    async fn add_user(&mut self, profile: &Self::UserProfile) -> Result<Self::UserId, Self::Error>;
+    async fn add_user(&self, profile: &Self::UserProfile) -> Result<Self::UserId, Self::Error>;
 
     async fn start_conversation(
-        &mut self,
+        &self,
         my_id: &Self::UserId,
         their_id: &Self::UserId,
         prod_id: &Self::ProductId,
     ) -> Result<Self::ConversationId, Self::Error>;
 
     async fn post_msg(
-        &mut self,
+        &self,
         msg: Self::Message,
         my_id: &Self::UserId,
         conversation: &Self::ConversationId,
     ) -> Result<Self::MessageId, Self::Error>;
 
+    /// Like `post_msg`, but a retry carrying the same `client_message_id` from the same sender
+    /// returns the original message instead of inserting it again. The flag tells whether the
+    /// message was inserted by this call.
+    async fn post_msg_idempotent(
+        &self,
+        msg: Self::Message,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        client_message_id: Option<&str>,
+    ) -> Result<(Self::MessageId, bool), Self::Error>;
+
     async fn get_latest_message(
         &self,
         conversation: &Self::ConversationId,
@@ -96,432 +171,232 @@ pub trait Database {
         &self,
         msg_id: &Self::MessageId,
     ) -> Result<Self::ConversationId, Self::Error>;
+
+    async fn get_conversations_from_messages(
+        &self,
+        msg_ids: &[Self::MessageId],
+    ) -> Result<Vec<(Self::MessageId, Self::ConversationId)>, Self::Error>;
+
+    /// Creates a store owned by `owner_id`. It becomes their primary store if they had none.
+    async fn add_store(
+        &self,
+        owner_id: &Self::UserId,
+        store: &Self::Store,
+    ) -> Result<Self::StoreId, Self::Error>;
+
+    /// Adds `user_id` to the staff of `store_id`, which only its owners may do. It becomes their
+    /// primary store if they had none.
+    async fn add_store_member(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+        user_id: &Self::UserId,
+    ) -> Result<(), Self::Error>;
+
+    /// Makes `store_id`, which `my_id` is staff of, the store whose inbox conversations about
+    /// their products land in.
+    async fn set_primary_store(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error>;
+
+    async fn get_store_members(
+        &self,
+        store_id: &Self::StoreId,
+    ) -> Result<Vec<Self::UserId>, Self::Error>;
+
+    /// Stores `user` is staff of, by id.
+    async fn get_user_stores(
+        &self,
+        user: &Self::UserId,
+    ) -> Result<Vec<(Self::StoreId, Self::Store)>, Self::Error>;
+
+    async fn belongs_to_store(
+        &self,
+        id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error>;
+
+    async fn assign_conversation(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        assignee: Option<&Self::UserId>,
+    ) -> Result<(), Self::Error>;
+
+    async fn get_assignee(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::UserId>, Self::Error>;
+
+    /// Records that `reader` fetched `messages` from `conversation`. Only the messages sent by the
+    /// other side are marked: the seller and the store's staff are on the same side. Returns the
+    /// newest message that was not yet marked as delivered.
+    async fn mark_delivered(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+        messages: &[Self::MessageId],
+    ) -> Result<Option<Self::MessageId>, Self::Error>;
+
+    /// Marks every message the other side sent in `conversation` as read by `reader`. Returns the
+    /// newest message that was not yet marked as read.
+    async fn mark_read(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error>;
+
+    async fn get_status(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::ConversationStatus, Self::Error>;
+
+    async fn set_status(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        status: Self::ConversationStatus,
+    ) -> Result<(), Self::Error>;
+
+    async fn get_labels(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Vec<String>, Self::Error>;
+
+    async fn add_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error>;
+
+    async fn remove_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error>;
+
+    /// Registers (or replaces) the public key other clients encrypt for `user` with. The key is
+    /// opaque to the server.
+    async fn set_public_key(&self, user: &Self::UserId, key: &str) -> Result<(), Self::Error>;
+
+    async fn get_public_key(&self, user: &Self::UserId) -> Result<String, Self::Error>;
+
+    /// Whether new messages of `conversation` are end-to-end encrypted by the clients.
+    async fn is_e2e(&self, conversation: &Self::ConversationId) -> Result<bool, Self::Error>;
+
+    /// Switches `conversation` to end-to-end encryption, for good. Both participants need a
+    /// public key. Messages posted before stay readable by the server.
+    async fn enable_e2e(&self, conversation: &Self::ConversationId) -> Result<(), Self::Error>;
+
+    /// Pseudonymizes `user` and destroys the keys their messages were encrypted with. Their
+    /// messages stay in place, with empty contents, so the peer's conversations remain coherent.
+    ///
+    /// `audit` is appended to the audit log along with it: neither happens without the other.
+    async fn erase_user(
+        &self,
+        user: &Self::UserId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error>;
+
+    /// Destroys the keys the messages of `conversation` were encrypted with, emptying all of
+    /// them. The conversation itself, its participants and its metadata stay.
+    ///
+    /// `audit` is appended to the audit log along with it: neither happens without the other.
+    async fn shred_conversation(
+        &self,
+        conversation: &Self::ConversationId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error>;
+
+    /// Recomputes the hash chain of the messages of `conversation`. Returns the first message
+    /// that doesn't match, if any.
+    async fn verify_message_chain(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error>;
+
+    /// Latest link of the message chain of `conversation`, `None` before the first message.
+    /// Whoever keeps it can later tell whether the history up to it was altered.
+    async fn get_chain_head(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::ChainHead>, Self::Error>;
+
+    /// Moves up to `batch` rows still encrypted with a retired master key to the active one.
+    /// Returns how many were moved: the job is done once it returns 0, and can be stopped and
+    /// resumed at any point.
+    async fn reencrypt_batch(&self, batch: u32) -> Result<u64, Self::Error>;
+
+    /// Appends to the audit log of privileged accesses. Each entry is chained to the previous one
+    /// with a keyed hash, and can't be changed or removed afterwards.
+    async fn append_audit(&self, entry: &Self::AuditEntry) -> Result<(), Self::Error>;
+
+    /// Most recent entries first.
+    async fn get_audit_log(
+        &self,
+        filter: &Self::AuditFilter,
+    ) -> Result<Vec<Self::AuditRecord>, Self::Error>;
+
+    /// Recomputes the hash chain. Returns the id of the first entry that doesn't match, if any.
+    async fn verify_audit_log(&self) -> Result<Option<i64>, Self::Error>;
+}
+
+/// A [`Database`] speaking the row types of [`model`], which every backend shares. What the REST
+/// layer and the command line are written against.
+pub trait Backend:
+    Database<
+        Error = DbError,
+        UserId = UserId,
+        UserProfile = UserProfile,
+        ConversationId = ConversationId,
+        MessageId = MessageId,
+        Message = Message,
+        ProductId = ProductId,
+        Product = Product,
+        StoreId = StoreId,
+        Store = Store,
+        ConversationStatus = ConversationStatus,
+        ConversationFilter = ConversationFilter,
+        AuditEntry = AuditEntry,
+        AuditRecord = AuditRecord,
+        AuditFilter = AuditFilter,
+        ChainHead = ChainHead,
+    > + Send
+    + Sync
+    + 'static
+{
 }
 
-// /// Example implementation: Mock Database
-// #[allow(dead_code)]
-// pub mod mock {
-//     use anyhow::anyhow;
-//     use std::collections::HashMap;
-//     use tokio::sync::{RwLock, RwLockReadGuard};
-
-//     #[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-//     pub struct ConversationId(u64);
-
-//     #[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-//     pub struct UserId(u64);
-
-//     #[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-//     pub struct MessageId(u64);
-
-//     #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-//     pub struct Message(String);
-
-//     #[derive(Debug, Clone)]
-//     pub struct UserProfile {
-//         username: String,
-//         name: String,
-//         age: u8,
-//     }
-
-//     #[derive(Default)]
-//     pub struct MockDbInternal {
-//         users: HashMap<UserId, UserProfile>,
-//         /// (sender, receiver, last_message)
-//         conversations: HashMap<ConversationId, (UserId, UserId, ProductId, Option<MessageId>)>,
-//         /// (sender, conversation, string content, previous_message)
-//         messages: HashMap<MessageId, (UserId, ConversationId, Message, Option<MessageId>)>,
-
-//         products: HashMap<ProductId, Product>,
-//     }
-
-//     #[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-//     pub struct ProductId(u64);
-
-//     #[derive(Debug, Hash, PartialEq, Clone)]
-//     pub struct Product {
-//         name: String,
-//         seller_id: UserId,
-//     }
-
-//     #[derive(Default)]
-//     pub struct MockDb {
-//         db: RwLock<MockDbInternal>,
-//     }
-
-//     impl super::Database for MockDb {
-//         type Error = anyhow::Error;
-
-//         type UserId = UserId;
-
-//         type UserProfile = UserProfile;
-
-//         type ConversationId = ConversationId;
-
-//         type MessageId = MessageId;
-
-//         type Message = Message;
-
-//         type ProductId = ProductId;
-
-//         type Product = Product;
-
-//         async fn get_conversations(
-//             &self,
-//             my_id: &Self::UserId,
-//         ) -> Result<Vec<Self::ConversationId>, Self::Error> {
-//             Ok(self
-//                 .db
-//                 .read()
-//                 .await
-//                 .conversations
-//                 .iter()
-//                 .filter(|(_, (u1, u2, _, _))| my_id == u1 || my_id == u2)
-//                 .map(|(id, _)| *id)
-//                 .collect())
-//         }
-
-//         async fn get_peer(
-//             &self,
-//             my_id: &Self::UserId,
-//             conversation: &Self::ConversationId,
-//         ) -> Result<Self::UserId, Self::Error> {
-//             match self.db.read().await.conversations.get(conversation) {
-//                 Some(c) => {
-//                     if &c.0 == my_id {
-//                         Ok(c.1)
-//                     } else {
-//                         Ok(c.0)
-//                     }
-//                 }
-//                 None => Err(anyhow::anyhow!("Conversation not found")),
-//             }
-//         }
-
-//         async fn get_user_profile(
-//             &self,
-//             their_id: &Self::UserId,
-//         ) -> Result<Self::UserProfile, Self::Error> {
-//             match self.db.read().await.users.get(their_id) {
-//                 Some(u) => Ok(u.clone()),
-//                 None => Err(anyhow!("No such user")),
-//             }
-//         }
-
-//         async fn start_conversation(
-//             &mut self,
-//             my_id: &Self::UserId,
-//             their_id: &Self::UserId,
-//             prod_id: &Self::ProductId,
-//         ) -> Result<Self::ConversationId, Self::Error> {
-//             let my_convos = self.get_conversations(my_id).await?;
-//             for c in self.get_conversations(their_id).await? {
-//                 if my_convos.contains(&c) {
-//                     return Ok(c);
-//                 }
-//             }
-
-//             let mut querier = self.db.write().await;
-
-//             let id = querier
-//                 .conversations
-//                 .keys()
-//                 .max_by_key(|x| x.0)
-//                 .map(|x| x.0 + 1)
-//                 .unwrap_or(0);
-//             let id = ConversationId(id);
-//             querier.conversations.insert(id, (*my_id, *their_id, None));
-//             Ok(id)
-//         }
-
-//         async fn post_msg(
-//             &mut self,
-//             msg: Self::Message,
-//             my_id: &Self::UserId,
-//             conversation: &Self::ConversationId,
-//         ) -> Result<Self::MessageId, Self::Error> {
-//             let mut querier = self.db.write().await;
-
-//             // Get the ID of the previous message
-//             let prev_id = *querier
-//                 .conversations
-//                 .get(conversation)
-//                 .map(|(_, _, prev)| prev)
-//                 .ok_or(anyhow!(
-//                     "Conversation with ID {conversation:?} was not found."
-//                 ))?;
-
-//             // Get the highest ID and generate the next one
-//             let id = querier
-//                 .messages
-//                 .keys()
-//                 .max_by_key(|x| x.0)
-//                 .map(|x| x.0 + 1)
-//                 .unwrap_or(0);
-//             let id = MessageId(id);
-
-//             // Write message
-//             querier
-//                 .messages
-//                 .insert(id, (*my_id, *conversation, msg, prev_id));
-
-//             // Change the last message pointer in the conversation table
-//             if let Some((_, _, prev)) = querier.conversations.get_mut(conversation) {
-//                 *prev = Some(id);
-//             }
-
-//             Ok(id)
-//         }
-
-//         async fn add_user(
-//             &mut self,
-//             profile: &Self::UserProfile,
-//         ) -> Result<Self::UserId, Self::Error> {
-//             if let Some(id) = self
-//                 .db
-//                 .read()
-//                 .await
-//                 .users
-//                 .iter()
-//                 .filter(|(_, prof)| prof.username == profile.username)
-//                 .map(|(id, _)| id)
-//                 .next()
-//             {
-//                 return Ok(*id);
-//             }
-
-//             let mut querier = self.db.write().await;
-
-//             let id = querier
-//                 .users
-//                 .keys()
-//                 .max_by_key(|x| x.0)
-//                 .map(|x| x.0 + 1)
-//                 .unwrap_or(0);
-//             let id = UserId(id);
-//             querier.users.insert(id, profile.clone());
-//             Ok(id)
-//         }
-
-//         type Querier<'a>
-//             = RwLockReadGuard<'a, MockDbInternal>
-//         where
-//             Self: 'a;
-
-//         async fn get_querier<'a>(&'a self) -> Result<Self::Querier<'a>, Self::Error> {
-//             Ok(self.db.read().await)
-//         }
-
-//         async fn get_message(
-//             &self,
-//             message: &Self::MessageId,
-//         ) -> Result<(Self::UserId, Self::Message, Option<Self::MessageId>), Self::Error> {
-//             match self.db.read().await.messages.get(message) {
-//                 Some((usr, _conv, msg, prev)) => Ok((*usr, msg.clone(), *prev)),
-//                 None => Err(anyhow!("Message with ID {message:?} was not found.")),
-//             }
-//         }
-
-//         // FIXME: implement this for the mock
-//         async fn get_most_recent_messages(
-//             &self,
-//             _conversation_id: &Self::ConversationId,
-//         ) -> Result<(Vec<(Self::UserId, Self::Message)>, Option<Self::MessageId>), Self::Error>
-//         {
-//             unimplemented!()
-//         }
-
-//         async fn get_latest_message(
-//             &self,
-//             conversation: &Self::ConversationId,
-//         ) -> Result<Option<MessageId>, Self::Error> {
-//             match self.db.read().await.conversations.get(conversation) {
-//                 Some((_, _, prev)) => Ok(*prev),
-//                 None => Err(anyhow!(
-//                     "Conversation with ID {conversation:?} was not found."
-//                 )),
-//             }
-//         }
-
-//         async fn get_user_id_from_username(
-//             &self,
-//             username: &str,
-//         ) -> Result<Self::UserId, Self::Error> {
-//             self.db
-//                 .read()
-//                 .await
-//                 .users
-//                 .iter()
-//                 .filter(|(_, p)| p.username == username)
-//                 .map(|(id, _)| id)
-//                 .next()
-//                 .ok_or(anyhow!("No such user with username '{username}'."))
-//                 .copied()
-//         }
-
-//         async fn belongs_to_conversation(
-//             &self,
-//             id: &Self::UserId,
-//             conversation: &Self::ConversationId,
-//         ) -> Result<(), Self::Error> {
-//             match self.db.read().await.conversations.get(conversation) {
-//                 Some((id1, id2, _)) if id1 == id || id2 == id => Ok(()),
-//                 _ => Err(anyhow!("No such conversation {conversation:?}.")),
-//             }
-//         }
-
-//         async fn get_conversation_from_message(
-//             &self,
-//             msg_id: &Self::MessageId,
-//         ) -> Result<Self::ConversationId, Self::Error> {
-//             match self.db.read().await.messages.get(msg_id) {
-//                 Some((_, convo_id, _, _)) => Ok(*convo_id),
-//                 None => Err(anyhow!("No such message {msg_id:?}.")),
-//             }
-//         }
-
-//         async fn get_product(
-//             &self,
-//             prod_id: &Self::ProductId,
-//         ) -> Result<Self::Product, Self::Error> {
-//             match self.db.read().await.products.get(prod_id) {
-//                 Some(p) => Ok(p.clone()),
-//                 None => Err(anyhow!("No such product {prod_id:?}.")),
-//             }
-//         }
-
-//         async fn get_product_id_from_conversation(
-//             &self,
-//             conversation_id: &Self::ConversationId,
-//         ) -> Result<Self::ProductId, Self::Error> {
-//             match self.db.read().await.conversations.get(conversation_id) {
-//                 Some(_) => todo!(),
-//                 None => todo!(),
-//             }
-//         }
-
-//         async fn add_product(
-//             &mut self,
-//             product: &Self::Product,
-//         ) -> Result<Self::ProductId, Self::Error> {
-//             todo!()
-//         }
-//     }
-
-//     #[cfg(test)]
-//     mod test {
-//         use crate::database::Database;
-//         use crate::database::mock::*;
-
-//         #[tokio::test]
-//         async fn test_mock() -> anyhow::Result<()> {
-//             let alice = UserProfile {
-//                 username: "alice_11".to_owned(),
-//                 name: "Alice Arnold".to_owned(),
-//                 age: 24,
-//             };
-
-//             let bob = UserProfile {
-//                 username: "bobert22".to_owned(),
-//                 name: "Bob Bellows".to_owned(),
-//                 age: 47,
-//             };
-
-//             let mut db = MockDb::default();
-
-//             let alice_id = db.add_user(&alice).await?;
-//             let bob_id = db.add_user(&bob).await?;
-//             assert_ne!(alice_id, bob_id);
-
-//             let alice_again = db.add_user(&alice).await?;
-//             assert_eq!(alice_id, alice_again);
-
-//             let convo_id = db.start_conversation(&alice_id, &bob_id).await?;
-//             let same_id = db.start_conversation(&bob_id, &alice_id).await?;
-
-//             assert_eq!(convo_id, same_id);
-
-//             let hello = Message("Hello Bob!".to_owned());
-//             let hello_id = db.post_msg(hello, &alice_id, &convo_id).await?;
-
-//             // Example queries. Notice it is inside a scope, so that the querier lock automatically frees.
-//             let alice_bob_messages = {
-//                 let querier = db.get_querier().await?;
-//                 // Count all messages between Alice and Bob
-//                 let msg_count = querier
-//                     .messages
-//                     .values()
-//                     .filter(|(_, c, _, _)| c == &convo_id)
-//                     .count();
-//                 assert_eq!(msg_count, 1);
-//                 // Make sure that the only message is the 'Hello Bob!' one.
-//                 let msg_id = querier
-//                     .messages
-//                     .iter()
-//                     .filter(|(_, (_, c, _, _))| c == &convo_id)
-//                     .map(|(id, _)| id)
-//                     .all(|x| x == &hello_id);
-//                 assert!(msg_id);
-//                 // Retrieve all messages between Alice and Bob
-//                 let messages = querier
-//                     .messages
-//                     .values()
-//                     .filter(|(_, c, _, _)| c == &convo_id)
-//                     .map(|(_, _, m, _)| m.clone())
-//                     .collect::<Vec<_>>();
-//                 messages
-//             };
-
-//             assert_eq!(alice_bob_messages, vec![Message("Hello Bob!".to_owned())]);
-
-//             Ok(())
-//         }
-
-//         #[tokio::test]
-//         async fn test_conversation_pointer() -> anyhow::Result<()> {
-//             // Preparation
-//             let alice = UserProfile {
-//                 username: "alice_11".to_owned(),
-//                 name: "Alice Arnold".to_owned(),
-//                 age: 24,
-//             };
-
-//             let bob = UserProfile {
-//                 username: "bobert22".to_owned(),
-//                 name: "Bob Bellows".to_owned(),
-//                 age: 47,
-//             };
-
-//             let mut db = MockDb::default();
-
-//             let alice_id = db.add_user(&alice).await?;
-//             let bob_id = db.add_user(&bob).await?;
-//             assert_ne!(alice_id, bob_id);
-
-//             let alice_again = db.add_user(&alice).await?;
-//             assert_eq!(alice_id, alice_again);
-
-//             let convo_id = db.start_conversation(&alice_id, &bob_id).await?;
-
-//             // Test
-//             let last_msg = db.get_latest_message(&convo_id).await?;
-//             assert_eq!(last_msg, None);
-
-//             let hello = Message("Hello Bob!".to_owned());
-//             let first_hello_id = db.post_msg(hello, &alice_id, &convo_id).await?;
-
-//             let last_msg = db.get_latest_message(&convo_id).await?;
-//             assert_eq!(last_msg, Some(first_hello_id));
-
-//             let hello = Message("Hello Alice!".to_owned());
-//             let second_hello_id = db.post_msg(hello, &bob_id, &convo_id).await?;
-
-//             let last_msg = db.get_latest_message(&convo_id).await?.unwrap();
-//             assert_eq!(last_msg, second_hello_id);
-
-//             let (_, _, last_msg) = db.get_message(&last_msg).await?;
-//             assert_eq!(last_msg, Some(first_hello_id));
-//             Ok(())
-//         }
-//     }
-// }
+impl<D> Backend for D where
+    D: Database<
+            Error = DbError,
+            UserId = UserId,
+            UserProfile = UserProfile,
+            ConversationId = ConversationId,
+            MessageId = MessageId,
+            Message = Message,
+            ProductId = ProductId,
+            Product = Product,
+            StoreId = StoreId,
+            Store = Store,
+            ConversationStatus = ConversationStatus,
+            ConversationFilter = ConversationFilter,
+            AuditEntry = AuditEntry,
+            AuditRecord = AuditRecord,
+            AuditFilter = AuditFilter,
+            ChainHead = ChainHead,
+        > + Send
+        + Sync
+        + 'static
+{
+}
+
+/// Whether `url` points at a Postgres server rather than an sqlite file.
+pub fn is_postgres(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
@@ -0,0 +1,445 @@
+//! Types the backends have in common: ids, rows as the API sees them, and errors.
+
+use actix_web::{ResponseError, http::StatusCode};
+use chrono::{DateTime, Utc};
+
+use crate::database::crypto::{AuditKey, CryptError};
+
+#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[sqlx(transparent)]
+pub struct UserId(pub i64);
+
+#[derive(Debug, sqlx::Type, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct UserProfile {
+    pub(super) id: i64,
+    pub(super) username: String,
+    pub(super) name: String,
+}
+
+impl UserProfile {
+    #[allow(dead_code)]
+    pub fn new(jumpseller_id: i64, username: String, name: String) -> Self {
+        Self {
+            username,
+            name,
+            id: jumpseller_id,
+        }
+    }
+
+    pub fn new_clone(id: i64, username: &str, name: &str) -> Self {
+        Self {
+            username: username.to_owned(),
+            name: name.to_owned(),
+            id,
+        }
+    }
+
+    /// Stands in for an erased user, of whom nothing is stored anymore.
+    pub fn erased(id: i64) -> Self {
+        Self::new_clone(id, &format!("erased_{id}"), "Deleted user")
+    }
+
+    #[allow(dead_code)]
+    pub fn username(&self) -> String {
+        self.username.clone()
+    }
+
+    pub fn id(&self) -> UserId {
+        self.id.into()
+    }
+
+    #[allow(dead_code)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[sqlx(transparent)]
+pub struct ConversationId(pub i64);
+
+#[derive(Debug, sqlx::Type, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Message {
+    contents: String,
+    timestamp: DateTime<Utc>,
+    /// `contents` is ciphertext from the sender's client, which only the participants can open.
+    #[serde(default)]
+    e2e: bool,
+    /// Filled in when read from the database; exposed separately by the API.
+    #[serde(skip)]
+    status: DeliveryStatus,
+}
+
+/// How far a message got towards its recipient.
+#[derive(
+    Debug, sqlx::Type, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize,
+)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Stored, but not yet fetched by the recipient.
+    #[default]
+    Sent,
+    /// Fetched by the recipient.
+    Delivered,
+    /// The recipient marked the conversation as read.
+    Read,
+}
+
+impl std::fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryStatus::Sent => write!(f, "sent"),
+            DeliveryStatus::Delivered => write!(f, "delivered"),
+            DeliveryStatus::Read => write!(f, "read"),
+        }
+    }
+}
+
+impl Message {
+    pub fn new(contents: String, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            contents,
+            timestamp,
+            e2e: false,
+            status: DeliveryStatus::Sent,
+        }
+    }
+
+    pub(super) fn with_status(mut self, status: DeliveryStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub(super) fn end_to_end(mut self, e2e: bool) -> Self {
+        self.e2e = e2e;
+        self
+    }
+
+    pub fn is_e2e(&self) -> bool {
+        self.e2e
+    }
+
+    pub fn status(&self) -> DeliveryStatus {
+        self.status
+    }
+
+    pub fn contents(&self) -> &str {
+        &self.contents
+    }
+
+    pub fn timestamp(&self) -> &DateTime<Utc> {
+        &self.timestamp
+    }
+}
+
+impl From<&str> for Message {
+    fn from(value: &str) -> Self {
+        let owned = value.to_owned();
+        let timestamp = Utc::now();
+        Self::new(owned, timestamp)
+    }
+}
+
+#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[sqlx(transparent)]
+pub struct MessageId(pub i64);
+
+#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[sqlx(transparent)]
+pub struct ProductId(pub i64);
+
+#[derive(Debug, sqlx::Type, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Product {
+    pub(crate) name: String,
+    pub(crate) seller_id: UserId,
+    pub(crate) jumpseller_id: i64,
+}
+
+impl Product {
+    pub fn new(name: String, seller_id: UserId, jumpseller_id: i64) -> Self {
+        Self {
+            name,
+            seller_id,
+            jumpseller_id,
+        }
+    }
+    pub fn product_info(&self) -> i64 {
+        self.jumpseller_id
+    }
+}
+
+#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[sqlx(transparent)]
+pub struct StoreId(pub i64);
+
+/// A shop operated by several staff accounts sharing a single inbox.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Store {
+    pub(crate) name: String,
+}
+
+impl Store {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+}
+
+/// Triage state of a conversation, as set by its participants.
+#[derive(
+    Debug, sqlx::Type, PartialEq, Eq, Copy, Clone, Default, serde::Serialize, serde::Deserialize,
+)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ConversationStatus {
+    #[default]
+    Open,
+    Waiting,
+    Resolved,
+}
+
+impl std::fmt::Display for ConversationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversationStatus::Open => write!(f, "open"),
+            ConversationStatus::Waiting => write!(f, "waiting"),
+            ConversationStatus::Resolved => write!(f, "resolved"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ConversationFilter {
+    pub(crate) status: Option<ConversationStatus>,
+    /// Only conversations the user tagged with this label.
+    pub(crate) label: Option<String>,
+}
+
+impl ConversationFilter {
+    pub fn is_empty(&self) -> bool {
+        self.status.is_none() && self.label.is_none()
+    }
+}
+
+/// Privileged operations that can reveal message contents or personal data.
+#[derive(Debug, sqlx::Type, PartialEq, Eq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    TranscriptExport,
+    PersonalDataExport,
+    UserErasure,
+    ConversationShred,
+}
+
+/// Who did what, to whom and why. Hashed in field order, so don't reorder them.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    /// `None` when done from the command line.
+    pub(crate) actor_id: Option<UserId>,
+    pub(crate) action: AuditAction,
+    pub(crate) subject_id: Option<UserId>,
+    pub(crate) conversation_id: Option<ConversationId>,
+    pub(crate) message_id: Option<MessageId>,
+    pub(crate) reason: String,
+}
+
+impl AuditEntry {
+    pub fn new(actor_id: Option<UserId>, action: AuditAction, reason: String) -> Self {
+        Self {
+            actor_id,
+            action,
+            subject_id: None,
+            conversation_id: None,
+            message_id: None,
+            reason,
+        }
+    }
+
+    #[must_use]
+    pub fn subject(mut self, user: UserId) -> Self {
+        self.subject_id = Some(user);
+        self
+    }
+
+    #[must_use]
+    pub fn conversation(mut self, conversation: ConversationId) -> Self {
+        self.conversation_id = Some(conversation);
+        self
+    }
+
+    /// Chains `self`, stamped with `timestamp`, onto the entry hashed as `prev_hash`.
+    pub(super) fn hash(
+        &self,
+        key: &AuditKey,
+        prev_hash: &[u8],
+        timestamp: &str,
+    ) -> Result<Vec<u8>, DbError> {
+        Ok(key.chain(prev_hash, &self.fields(timestamp)?))
+    }
+
+    fn fields(&self, timestamp: &str) -> Result<Vec<u8>, DbError> {
+        Ok(serde_json::to_vec(&(timestamp, self)).map_err(|e| sqlx::Error::Encode(e.into()))?)
+    }
+}
+
+/// Checks the audit log, fed oldest entry first.
+pub(super) struct AuditChain<'a> {
+    key: &'a AuditKey,
+    prev_hash: Vec<u8>,
+}
+
+impl<'a> AuditChain<'a> {
+    pub(super) fn new(key: &'a AuditKey) -> Self {
+        Self {
+            key,
+            prev_hash: vec![0; 32],
+        }
+    }
+
+    /// Whether `entry` links onto the previous one.
+    pub(super) fn links(
+        &mut self,
+        entry: &AuditEntry,
+        timestamp: &str,
+        prev_hash: &[u8],
+        hash: &[u8],
+    ) -> Result<bool, DbError> {
+        if prev_hash != self.prev_hash {
+            return Ok(false);
+        }
+        let expected = entry.hash(self.key, prev_hash, timestamp)?;
+        if expected != hash {
+            return Ok(false);
+        }
+        self.prev_hash = expected;
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct AuditRecord {
+    pub(super) id: i64,
+    pub(super) timestamp: String,
+    #[serde(flatten)]
+    pub(super) entry: AuditEntry,
+    /// Hex encoded HMAC-SHA256 of the previous entry's hash and this entry.
+    pub(super) hash: String,
+}
+
+/// Latest link of a conversation's message chain, committing to the history up to `message_id`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ChainHead {
+    pub message_id: MessageId,
+    /// Hex encoded, see [`chain_hash`](super::sealing::chain_hash).
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditFilter {
+    pub(crate) actor_id: Option<i64>,
+    pub(crate) subject_id: Option<i64>,
+    pub(crate) conversation_id: Option<i64>,
+    #[serde(default = "AuditFilter::default_limit")]
+    pub(crate) limit: u32,
+}
+
+impl AuditFilter {
+    fn default_limit() -> u32 {
+        100
+    }
+}
+
+impl Default for AuditFilter {
+    fn default() -> Self {
+        Self {
+            actor_id: None,
+            subject_id: None,
+            conversation_id: None,
+            limit: Self::default_limit(),
+        }
+    }
+}
+
+impl From<i64> for UserId {
+    fn from(value: i64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<i64> for ConversationId {
+    fn from(value: i64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<i64> for ProductId {
+    fn from(value: i64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<i64> for MessageId {
+    fn from(value: i64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<i64> for StoreId {
+    fn from(value: i64) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    #[error(transparent)]
+    Db(#[from] sqlx::Error),
+    #[error("Attempted to access something without needed priviledges")]
+    PermissionDenied,
+    #[error(transparent)]
+    Crypto(#[from] CryptError),
+    #[error("End-to-end encryption is unavailable: {0}")]
+    E2EUnavailable(&'static str),
+    #[error("Message #{} failed its integrity check: it was corrupted or tampered with", .0.0)]
+    Decryption(MessageId),
+}
+
+impl ResponseError for DbError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match &self {
+            DbError::Db(error) => match error {
+                sqlx::Error::InvalidSavePointStatement | sqlx::Error::InvalidArgument(_) => {
+                    StatusCode::BAD_REQUEST
+                }
+                sqlx::Error::ColumnIndexOutOfBounds { index: _, len: _ }
+                | sqlx::Error::ColumnDecode {
+                    index: _,
+                    source: _,
+                }
+                | sqlx::Error::Encode(_)
+                | sqlx::Error::Decode(_)
+                | sqlx::Error::AnyDriverError(_)
+                | sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed
+                | sqlx::Error::Migrate(_)
+                | sqlx::Error::BeginFailed
+                | sqlx::Error::ColumnNotFound(_)
+                | sqlx::Error::TypeNotFound { type_name: _ }
+                | sqlx::Error::Configuration(_)
+                | sqlx::Error::Database(_)
+                | sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::Protocol(_) => StatusCode::INTERNAL_SERVER_ERROR,
+
+                sqlx::Error::WorkerCrashed => StatusCode::TOO_MANY_REQUESTS,
+                sqlx::Error::RowNotFound => StatusCode::NO_CONTENT,
+                _ => StatusCode::IM_A_TEAPOT,
+            },
+            DbError::PermissionDenied => StatusCode::FORBIDDEN,
+            DbError::Crypto(e) => e.status_code(),
+            DbError::E2EUnavailable(_) => StatusCode::CONFLICT,
+            DbError::Decryption(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
@@ -0,0 +1,1866 @@
+//! [`Database`] on Postgres, for deployments that outgrow a single database file.
+//!
+//! Rows, encryption and hash chains are the same as in [`sqlite`](super::sqlite), see
+//! [`sealing`](super::sealing). Queries are checked at runtime: the offline query cache is
+//! prepared against sqlite.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, Pool, Postgres, migrate::MigrateDatabase, postgres::PgPoolOptions};
+
+use crate::database::{
+    Database,
+    crypto::{CryptData, CryptoKey, KdfParams, Keyring},
+    migrations,
+    model::{
+        AuditAction, AuditChain, AuditEntry, AuditFilter, AuditRecord, ChainHead,
+        ConversationFilter, ConversationId, ConversationStatus, DbError, DeliveryStatus, Message,
+        MessageId, Product, ProductId, Store, StoreId, UserId, UserProfile,
+    },
+    sealing::{
+        ChainLink, KeyStore, Sealer, StoredMessage, WrappedKey, Written, chain_hash, kdf_params,
+        link_message, message_aad, open_message, unwrap_raw_key,
+    },
+};
+
+pub struct PostgresDB {
+    pool: Pool<Postgres>,
+    sealer: Sealer,
+}
+
+impl PostgresDB {
+    pub async fn new(url: &str, keys: impl Into<Keyring>) -> anyhow::Result<Self> {
+        if !Postgres::database_exists(url).await? {
+            Postgres::create_database(url).await?;
+        }
+        let pool = PgPoolOptions::new().connect(url).await?;
+        migrations::run(&migrations::POSTGRES, &pool).await?;
+
+        let sealer = Sealer::open(&mut *pool.acquire().await?, keys.into()).await?;
+        let db = Self { pool, sealer };
+
+        let admin_profile = UserProfile::new_clone(1, "admin", "Admin");
+        db.add_user(&admin_profile).await?;
+        Ok(db)
+    }
+
+    /// Argon2 parameters the master keys of the database at `url` were derived with, by key id.
+    /// Empty for a database that doesn't exist yet.
+    pub async fn stored_kdf(url: &str) -> anyhow::Result<BTreeMap<u32, KdfParams>> {
+        if !Postgres::database_exists(url).await? {
+            return Ok(BTreeMap::new());
+        }
+        let pool = PgPoolOptions::new().max_connections(1).connect(url).await?;
+        let table: bool = sqlx::query_scalar("SELECT to_regclass('master_key') IS NOT NULL")
+            .fetch_one(&pool)
+            .await?;
+        if !table {
+            return Ok(BTreeMap::new());
+        }
+        let rows: Vec<(i64, String, i64, i64, i64, i64)> = sqlx::query_as(
+            r"
+            SELECT id, kdf_algorithm, kdf_version, kdf_memory_kib, kdf_iterations, kdf_parallelism
+            FROM master_key
+            WHERE kdf_algorithm IS NOT NULL
+        ",
+        )
+        .fetch_all(&pool)
+        .await?;
+        pool.close().await;
+
+        let mut stored = BTreeMap::new();
+        for (id, algorithm, version, memory_kib, iterations, parallelism) in rows {
+            let kdf = kdf_params(
+                id,
+                &algorithm,
+                version,
+                [memory_kib, iterations, parallelism],
+            )?;
+            stored.insert(u32::try_from(id)?, kdf);
+        }
+        Ok(stored)
+    }
+
+    /// Appends `entry` to the audit log within `conn`'s transaction.
+    async fn audit(&self, conn: &mut PgConnection, entry: &AuditEntry) -> Result<(), DbError> {
+        // Appends are serialized, or two of them could chain onto the same entry.
+        sqlx::query("LOCK TABLE audit_log IN EXCLUSIVE MODE")
+            .execute(&mut *conn)
+            .await?;
+        let prev_hash: Vec<u8> =
+            sqlx::query_scalar("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1")
+                .fetch_optional(&mut *conn)
+                .await?
+                .unwrap_or_else(|| vec![0; 32]);
+
+        let timestamp = Utc::now().to_rfc3339();
+        let hash = entry.hash(&self.sealer.audit_key, &prev_hash, &timestamp)?;
+        sqlx::query(
+            r"
+            INSERT INTO audit_log (timestamp, actor_id, action, subject_id, conversation_id,
+                message_id, reason, prev_hash, hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ",
+        )
+        .bind(timestamp)
+        .bind(entry.actor_id)
+        .bind(entry.action)
+        .bind(entry.subject_id)
+        .bind(entry.conversation_id)
+        .bind(entry.message_id)
+        .bind(&entry.reason)
+        .bind(prev_hash)
+        .bind(hash)
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Key a new message of `user` in `conversation` is encrypted with, `None` when it is
+    /// end-to-end encrypted by the client instead.
+    async fn message_key(
+        &self,
+        conn: &mut PgConnection,
+        user: &UserId,
+        conversation: &ConversationId,
+        e2e: bool,
+    ) -> Result<Option<CryptoKey>, DbError> {
+        if !e2e {
+            return Ok(Some(
+                self.sealer.sender_key(conn, user, conversation).await?,
+            ));
+        }
+        let erased: Option<DateTime<Utc>> =
+            sqlx::query_scalar(r#"SELECT erased_at FROM "user" WHERE id = $1"#)
+                .bind(user)
+                .fetch_one(&mut *conn)
+                .await?;
+        if erased.is_some() {
+            return Err(DbError::PermissionDenied);
+        }
+        Ok(None)
+    }
+}
+
+/// A message joined with its sender's wrapped data key, see [`message_row!`].
+#[derive(sqlx::FromRow)]
+struct MessageRow {
+    id: i64,
+    sender_id: i64,
+    conversation_id: i64,
+    content: Option<Vec<u8>>,
+    timestamp: DateTime<Utc>,
+    previous_message_id: Option<i64>,
+    e2e: bool,
+    status: DeliveryStatus,
+    sender_key: Option<Vec<u8>>,
+    sender_key_id: Option<i64>,
+}
+
+impl From<MessageRow> for StoredMessage {
+    fn from(row: MessageRow) -> Self {
+        // Nonces are always inside the ciphertext here.
+        Self {
+            id: row.id,
+            sender_id: row.sender_id,
+            conversation_id: row.conversation_id,
+            content: row.content,
+            salt: vec![],
+            timestamp: row.timestamp.naive_utc(),
+            previous_message_id: row.previous_message_id,
+            e2e: row.e2e,
+            status: row.status,
+            sender_key_nonce: row.sender_key.as_ref().map(|_| vec![]),
+            sender_key: row.sender_key,
+            sender_key_id: row.sender_key_id,
+        }
+    }
+}
+
+/// Selects [`MessageRow`]s from `message`, to be followed by a `WHERE` clause. A macro so that the
+/// queries built on it can be constants.
+macro_rules! message_row {
+    () => {
+        r"
+    SELECT message.id, message.sender_id, message.conversation_id, message.content,
+        message.timestamp, message.previous_message_id, message.e2e,
+        CASE WHEN message.read_at IS NOT NULL THEN 'read'
+            WHEN message.delivered_at IS NOT NULL THEN 'delivered'
+            ELSE 'sent' END AS status,
+        conversation_key.key AS sender_key, conversation_key.key_id AS sender_key_id
+    FROM message LEFT JOIN conversation_key
+        ON conversation_key.user_id = message.sender_id
+        AND conversation_key.conversation_id = message.conversation_id
+"
+    };
+}
+
+// Queries whose plans the tests check, as the methods run them.
+
+/// The CTE shadows `message` in [`message_row!`] with the 32 latest ones.
+const MOST_RECENT_MESSAGES: &str = concat!(
+    r"
+    WITH message AS (
+        SELECT * FROM message WHERE conversation_id = $1 ORDER BY id DESC LIMIT 32
+    )",
+    message_row!(),
+    "ORDER BY message.id"
+);
+
+const MESSAGES_AFTER: &str = concat!(
+    message_row!(),
+    r"
+    WHERE message.conversation_id = $1 AND message.id > $2
+    ORDER BY message.id
+    LIMIT $3
+"
+);
+
+const FILTER_CONVERSATIONS: &str = r"
+    SELECT id
+    FROM conversation
+    WHERE (client_id = $1 OR seller_id = $1
+            -- Evaluated once up front, unlike `IN`, so each branch of the OR uses an index.
+            OR store_id = ANY(ARRAY(SELECT store_id FROM store_member WHERE user_id = $1)))
+        AND ($2::TEXT IS NULL OR status = $2)
+        AND ($3::TEXT IS NULL OR id IN (
+            SELECT conversation_id
+            FROM conversation_label
+            WHERE user_id = $1 AND label = $3
+        ))
+    ORDER BY id
+";
+
+const FIND_CONVERSATION: &str = r"
+    SELECT id
+    FROM conversation
+    WHERE ((client_id = $1 AND seller_id = $2) OR (seller_id = $1 AND client_id = $2)
+        OR (client_id = $1 AND store_id = $3)) AND product_id = $4
+    ORDER BY id
+    LIMIT 1
+";
+
+const MARK_DELIVERED: &str = r"
+    UPDATE message
+    SET delivered_at = $1
+    FROM conversation
+    WHERE conversation.id = message.conversation_id AND message.conversation_id = $2
+        AND message.id = ANY($3) AND delivered_at IS NULL
+        AND (message.sender_id = conversation.client_id) != ($4 = conversation.client_id)
+    RETURNING message.id
+";
+
+const MESSAGE_CHAIN: &str = r"
+    SELECT id, sender_id, timestamp, content, previous_message_id, content_hash, chain_hash
+    FROM message
+    WHERE conversation_id = $1
+    ORDER BY id
+";
+
+impl KeyStore for PgConnection {
+    async fn canary(&mut self, id: u32) -> Result<Option<Vec<u8>>, DbError> {
+        Ok(
+            sqlx::query_scalar("SELECT canary FROM master_key WHERE id = $1")
+                .bind(i64::from(id))
+                .fetch_optional(&mut *self)
+                .await?,
+        )
+    }
+
+    async fn store_canary(
+        &mut self,
+        id: u32,
+        canary: &[u8],
+        kdf: Option<&KdfParams>,
+    ) -> Result<(), DbError> {
+        sqlx::query(
+            r"
+            INSERT INTO master_key (id, canary, kdf_algorithm, kdf_version, kdf_memory_kib,
+                kdf_iterations, kdf_parallelism, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ",
+        )
+        .bind(i64::from(id))
+        .bind(canary)
+        .bind(kdf.map(|kdf| kdf.algorithm.as_str()))
+        .bind(kdf.map(|kdf| i64::from(u32::from(kdf.version))))
+        .bind(kdf.map(|kdf| i64::from(kdf.memory_kib)))
+        .bind(kdf.map(|kdf| i64::from(kdf.iterations)))
+        .bind(kdf.map(|kdf| i64::from(kdf.parallelism)))
+        .bind(Utc::now())
+        .execute(&mut *self)
+        .await?;
+        Ok(())
+    }
+
+    /// Wrapped data keys. There are no databases predating canaries here, nor messages from
+    /// before data keys.
+    async fn written_with(&mut self, id: u32) -> Result<Written, DbError> {
+        let wrapped: Option<Vec<u8>> =
+            sqlx::query_scalar("SELECT key FROM conversation_key WHERE key_id = $1 LIMIT 1")
+                .bind(i64::from(id))
+                .fetch_optional(&mut *self)
+                .await?;
+        if let Some(key) = wrapped {
+            return Ok(Written::ConversationKey(WrappedKey {
+                key,
+                nonce: vec![],
+                key_id: id.into(),
+            }));
+        }
+        let wrapped: Option<(String, Vec<u8>)> =
+            sqlx::query_as("SELECT purpose, key FROM metadata_key WHERE key_id = $1 LIMIT 1")
+                .bind(i64::from(id))
+                .fetch_optional(&mut *self)
+                .await?;
+        Ok(
+            wrapped.map_or(Written::Nothing, |(purpose, key)| Written::MetadataKey {
+                purpose,
+                key,
+            }),
+        )
+    }
+
+    async fn metadata_key(&mut self, purpose: &str) -> Result<Option<(Vec<u8>, i64)>, DbError> {
+        Ok(
+            sqlx::query_as("SELECT key, key_id FROM metadata_key WHERE purpose = $1")
+                .bind(purpose)
+                .fetch_optional(&mut *self)
+                .await?,
+        )
+    }
+
+    async fn store_metadata_key(
+        &mut self,
+        purpose: &str,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError> {
+        sqlx::query(
+            "INSERT INTO metadata_key (purpose, key, key_id) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+        )
+        .bind(purpose)
+        .bind(wrapped)
+        .bind(i64::from(key_id))
+        .execute(&mut *self)
+        .await?;
+        Ok(())
+    }
+
+    async fn stale_metadata_keys(
+        &mut self,
+        active: u32,
+    ) -> Result<Vec<(String, Vec<u8>, i64)>, DbError> {
+        Ok(
+            sqlx::query_as("SELECT purpose, key, key_id FROM metadata_key WHERE key_id != $1")
+                .bind(i64::from(active))
+                .fetch_all(&mut *self)
+                .await?,
+        )
+    }
+
+    async fn rewrap_metadata_key(
+        &mut self,
+        purpose: &str,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError> {
+        sqlx::query("UPDATE metadata_key SET key = $1, key_id = $2 WHERE purpose = $3")
+            .bind(wrapped)
+            .bind(i64::from(key_id))
+            .bind(purpose)
+            .execute(&mut *self)
+            .await?;
+        Ok(())
+    }
+
+    async fn conversation_key(
+        &mut self,
+        user: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<(bool, Option<WrappedKey>), DbError> {
+        let (erased_at, key, key_id): (Option<DateTime<Utc>>, Option<Vec<u8>>, Option<i64>) =
+            sqlx::query_as(
+                r#"
+                SELECT "user".erased_at, conversation_key.key, conversation_key.key_id
+                FROM "user" LEFT JOIN conversation_key
+                    ON conversation_key.user_id = "user".id AND conversation_key.conversation_id = $1
+                WHERE "user".id = $2
+            "#,
+            )
+            .bind(conversation)
+            .bind(user)
+            .fetch_one(&mut *self)
+            .await?;
+        let wrapped = key.zip(key_id).map(|(key, key_id)| WrappedKey {
+            key,
+            nonce: vec![],
+            key_id,
+        });
+        Ok((erased_at.is_some(), wrapped))
+    }
+
+    async fn store_conversation_key(
+        &mut self,
+        user: &UserId,
+        conversation: &ConversationId,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError> {
+        sqlx::query(
+            "INSERT INTO conversation_key (conversation_id, user_id, key, key_id) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(conversation)
+        .bind(user)
+        .bind(wrapped)
+        .bind(i64::from(key_id))
+        .execute(&mut *self)
+        .await?;
+        Ok(())
+    }
+
+    async fn chain_link(&mut self, id: i64) -> Result<ChainLink, DbError> {
+        #[allow(clippy::type_complexity)]
+        let (sender_id, timestamp, content, prev_hash): (
+            i64,
+            DateTime<Utc>,
+            Option<Vec<u8>>,
+            Option<Vec<u8>>,
+        ) = sqlx::query_as(
+            r"
+            SELECT message.sender_id, message.timestamp, message.content, prev.chain_hash
+            FROM message LEFT JOIN message AS prev ON prev.id = message.previous_message_id
+            WHERE message.id = $1
+        ",
+        )
+        .bind(id)
+        .fetch_one(&mut *self)
+        .await?;
+        Ok(ChainLink {
+            sender_id,
+            timestamp: timestamp.naive_utc(),
+            content,
+            prev_hash,
+        })
+    }
+
+    async fn store_chain_hash(
+        &mut self,
+        id: i64,
+        content_hash: &[u8],
+        hash: &[u8],
+    ) -> Result<(), DbError> {
+        sqlx::query("UPDATE message SET content_hash = $1, chain_hash = $2 WHERE id = $3")
+            .bind(content_hash)
+            .bind(hash)
+            .bind(id)
+            .execute(&mut *self)
+            .await?;
+        Ok(())
+    }
+}
+
+/// A row of `audit_log`.
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    id: i64,
+    timestamp: String,
+    actor_id: Option<i64>,
+    action: AuditAction,
+    subject_id: Option<i64>,
+    conversation_id: Option<i64>,
+    message_id: Option<i64>,
+    reason: String,
+    prev_hash: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl AuditRow {
+    fn entry(&self) -> AuditEntry {
+        AuditEntry {
+            actor_id: self.actor_id.map(UserId),
+            action: self.action,
+            subject_id: self.subject_id.map(UserId),
+            conversation_id: self.conversation_id.map(ConversationId),
+            message_id: self.message_id.map(MessageId),
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+impl Database for PostgresDB {
+    type Error = DbError;
+
+    type UserId = UserId;
+
+    type UserProfile = UserProfile;
+
+    type ConversationId = ConversationId;
+
+    type MessageId = MessageId;
+
+    type Message = Message;
+
+    type ProductId = ProductId;
+
+    type Product = Product;
+
+    type StoreId = StoreId;
+
+    type Store = Store;
+
+    type ConversationStatus = ConversationStatus;
+
+    type ConversationFilter = ConversationFilter;
+
+    type AuditEntry = AuditEntry;
+
+    type AuditRecord = AuditRecord;
+
+    type AuditFilter = AuditFilter;
+
+    type ChainHead = ChainHead;
+
+    type Querier<'a> = &'a Pool<Postgres>;
+
+    async fn get_conversations(
+        &self,
+        my_id: &Self::UserId,
+    ) -> Result<Vec<Self::ConversationId>, Self::Error> {
+        Ok(sqlx::query_scalar(
+            r"
+            SELECT id
+            FROM conversation
+            WHERE client_id = $1 OR seller_id = $1
+                OR store_id IN (SELECT store_id FROM store_member WHERE user_id = $1)
+            ORDER BY id
+        ",
+        )
+        .bind(my_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn filter_conversations(
+        &self,
+        my_id: &Self::UserId,
+        filter: &Self::ConversationFilter,
+    ) -> Result<Vec<Self::ConversationId>, Self::Error> {
+        Ok(sqlx::query_scalar(FILTER_CONVERSATIONS)
+            .bind(my_id)
+            .bind(filter.status)
+            .bind(&filter.label)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    async fn get_peer(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::UserId, Self::Error> {
+        let record: Option<(UserId, UserId)> = sqlx::query_as(
+            r"
+            SELECT client_id, seller_id
+            FROM conversation
+            WHERE id = $1 AND (client_id = $2 OR seller_id = $2
+                OR store_id IN (SELECT store_id FROM store_member WHERE user_id = $2))
+        ",
+        )
+        .bind(conversation)
+        .bind(my_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        // Staff members of the store answer on behalf of the seller, so their peer is the client.
+        match record {
+            Some((client, seller)) if client == *my_id => Ok(seller),
+            Some((client, _)) => Ok(client),
+            None => Err(DbError::PermissionDenied),
+        }
+    }
+
+    async fn get_user_id_from_username(&self, username: &str) -> Result<Self::UserId, Self::Error> {
+        let index = self.sealer.username_index.digest(username);
+        Ok(
+            sqlx::query_scalar(r#"SELECT id FROM "user" WHERE username_index = $1"#)
+                .bind(index)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn get_user_profile(
+        &self,
+        their_id: &Self::UserId,
+    ) -> Result<Self::UserProfile, Self::Error> {
+        #[allow(clippy::type_complexity)]
+        let (id, username, name, erased_at): (
+            i64,
+            Option<Vec<u8>>,
+            Option<Vec<u8>>,
+            Option<DateTime<Utc>>,
+        ) = sqlx::query_as(r#"SELECT id, username, name, erased_at FROM "user" WHERE id = $1"#)
+            .bind(their_id)
+            .fetch_one(&self.pool)
+            .await?;
+        if erased_at.is_some() {
+            return Ok(UserProfile::erased(id));
+        }
+        let open = |value: Option<Vec<u8>>, column| {
+            value
+                .map(|value| self.sealer.open_field(value, column, id))
+                .transpose()
+                .map(Option::unwrap_or_default)
+        };
+        Ok(UserProfile::new(
+            id,
+            open(username, "user.username")?,
+            open(name, "user.name")?,
+        ))
+    }
+
+    async fn get_message(
+        &self,
+        message: &Self::MessageId,
+    ) -> Result<(Self::UserId, Self::Message, Option<Self::MessageId>), Self::Error> {
+        let row: MessageRow = sqlx::query_as(concat!(message_row!(), " WHERE message.id = $1"))
+            .bind(message)
+            .fetch_one(&self.pool)
+            .await?;
+        let sender = UserId(row.sender_id);
+        let previous = row.previous_message_id.map(MessageId);
+        Ok((
+            sender,
+            open_message(&self.sealer.keys, row.into())?,
+            previous,
+        ))
+    }
+
+    async fn get_messages(
+        &self,
+        messages: &[Self::MessageId],
+    ) -> Result<
+        Vec<(
+            Self::MessageId,
+            Self::UserId,
+            Self::Message,
+            Option<Self::MessageId>,
+        )>,
+        Self::Error,
+    > {
+        let ids: Vec<i64> = messages.iter().map(|id| id.0).collect();
+        let rows: Vec<MessageRow> = sqlx::query_as(concat!(
+            message_row!(),
+            " WHERE message.id = ANY($1) ORDER BY message.id"
+        ))
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let (id, sender) = (MessageId(row.id), UserId(row.sender_id));
+                let previous = row.previous_message_id.map(MessageId);
+                Ok((
+                    id,
+                    sender,
+                    open_message(&self.sealer.keys, row.into())?,
+                    previous,
+                ))
+            })
+            .collect()
+    }
+
+    async fn get_most_recent_messages(
+        &self,
+        conversation_id: &Self::ConversationId,
+    ) -> Result<
+        (
+            Vec<(Self::MessageId, Self::UserId, Self::Message)>,
+            Option<Self::MessageId>,
+        ),
+        Self::Error,
+    > {
+        let rows: Vec<MessageRow> = sqlx::query_as(MOST_RECENT_MESSAGES)
+            .bind(conversation_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let previous = rows
+            .first()
+            .and_then(|x| x.previous_message_id)
+            .map(MessageId);
+        let messages = rows
+            .into_iter()
+            .map(|row| {
+                let (id, sender) = (MessageId(row.id), UserId(row.sender_id));
+                Ok((id, sender, open_message(&self.sealer.keys, row.into())?))
+            })
+            .collect::<Result<Vec<_>, DbError>>()?;
+        Ok((messages, previous))
+    }
+
+    async fn get_messages_after(
+        &self,
+        conversation_id: &Self::ConversationId,
+        after: Option<&Self::MessageId>,
+        limit: u32,
+    ) -> Result<Vec<(Self::MessageId, Self::UserId, Self::Message)>, Self::Error> {
+        let after = after.map_or(0, |x| x.0);
+        let rows: Vec<MessageRow> = sqlx::query_as(MESSAGES_AFTER)
+            .bind(conversation_id)
+            .bind(after)
+            .bind(i64::from(limit))
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let (id, sender) = (MessageId(row.id), UserId(row.sender_id));
+                Ok((id, sender, open_message(&self.sealer.keys, row.into())?))
+            })
+            .collect()
+    }
+
+    async fn get_querier(&self) -> Result<Self::Querier<'_>, Self::Error> {
+        Ok(&self.pool)
+    }
+
+    async fn get_product(&self, prod_id: &Self::ProductId) -> Result<Self::Product, Self::Error> {
+        let (name, id, seller_id): (Vec<u8>, i64, UserId) =
+            sqlx::query_as("SELECT name, id, seller_id FROM product WHERE id = $1")
+                .bind(prod_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(Product::new(
+            self.sealer.open_field(name, "product.name", id)?,
+            seller_id,
+            id,
+        ))
+    }
+
+    async fn get_product_id_from_conversation_id(
+        &self,
+        conversation_id: &Self::ConversationId,
+    ) -> Result<Self::ProductId, Self::Error> {
+        Ok(
+            sqlx::query_scalar("SELECT product_id FROM conversation WHERE id = $1")
+                .bind(conversation_id)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn add_product(&self, product: &Self::Product) -> Result<Self::ProductId, Self::Error> {
+        let name = self
+            .sealer
+            .seal_field(&product.name, "product.name", product.jumpseller_id)?;
+        Ok(sqlx::query_scalar(
+            r"
+            INSERT INTO product (id, name, seller_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE SET name = excluded.name, seller_id = excluded.seller_id
+            RETURNING id
+        ",
+        )
+        .bind(product.jumpseller_id)
+        .bind(name)
+        .bind(product.seller_id)
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn add_user(&self, profile: &Self::UserProfile) -> Result<Self::UserId, Self::Error> {
+        let id = profile.id().0;
+        let username = self
+            .sealer
+            .seal_field(&profile.username(), "user.username", id)?;
+        let name = self.sealer.seal_field(&profile.name(), "user.name", id)?;
+        let username_index = self.sealer.username_index.digest(&profile.username());
+        // Erased users keep their pseudonym, whatever Jumpseller still says about them.
+        sqlx::query(
+            r#"
+            INSERT INTO "user" (id, username, name, username_index)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (id) DO UPDATE
+                SET username = excluded.username, name = excluded.name,
+                    username_index = excluded.username_index
+                WHERE "user".erased_at IS NULL
+        "#,
+        )
+        .bind(id)
+        .bind(username)
+        .bind(name)
+        .bind(username_index)
+        .execute(&self.pool)
+        .await?;
+        Ok(UserId(id))
+    }
+
+    async fn start_conversation(
+        &self,
+        my_id: &Self::UserId,
+        their_id: &Self::UserId,
+        prod_id: &Self::ProductId,
+    ) -> Result<Self::ConversationId, Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        // Conversations about a seller's products go to the shared inbox of their primary store.
+        let store_id: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT "user".primary_store_id
+            FROM product
+            JOIN "user" ON "user".id = product.seller_id
+            WHERE product.id = $1
+        "#,
+        )
+        .bind(prod_id)
+        .fetch_optional(&mut *transaction)
+        .await?
+        .flatten();
+
+        let existing: Option<ConversationId> = sqlx::query_scalar(FIND_CONVERSATION)
+            .bind(my_id)
+            .bind(their_id)
+            .bind(store_id)
+            .bind(prod_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+        if let Some(convo) = existing {
+            return Ok(convo);
+        }
+
+        // A concurrent call may have created it since the lookup, then it's the one to return.
+        let created: Option<ConversationId> = sqlx::query_scalar(
+            r"
+            INSERT INTO conversation (client_id, seller_id, product_id, store_id)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT ON CONSTRAINT conversation_participants DO NOTHING
+            RETURNING id
+        ",
+        )
+        .bind(my_id)
+        .bind(their_id)
+        .bind(prod_id)
+        .bind(store_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        let id = match created {
+            Some(id) => id,
+            None => {
+                sqlx::query_scalar(
+                    "SELECT id FROM conversation
+                    WHERE client_id = $1 AND seller_id = $2 AND product_id = $3",
+                )
+                .bind(my_id)
+                .bind(their_id)
+                .bind(prod_id)
+                .fetch_one(&mut *transaction)
+                .await?
+            }
+        };
+        transaction.commit().await?;
+        Ok(id)
+    }
+
+    async fn post_msg(
+        &self,
+        msg: Self::Message,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::MessageId, Self::Error> {
+        self.post_msg_idempotent(msg, my_id, conversation, None)
+            .await
+            .map(|(id, _)| id)
+    }
+
+    async fn post_msg_idempotent(
+        &self,
+        msg: Self::Message,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        client_message_id: Option<&str>,
+    ) -> Result<(Self::MessageId, bool), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        // Locked until committed: concurrent posts would otherwise fork the chain of messages.
+        let (prev_id, e2e): (Option<i64>, bool) = sqlx::query_as(
+            "SELECT last_message_id, e2e FROM conversation WHERE id = $1 FOR UPDATE",
+        )
+        .bind(conversation)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        let existing: Option<MessageId> = sqlx::query_scalar(
+            r"
+            SELECT id
+            FROM message
+            WHERE sender_id = $1 AND conversation_id = $2 AND client_message_id = $3
+        ",
+        )
+        .bind(my_id)
+        .bind(conversation)
+        .bind(client_message_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if let Some(msg) = existing {
+            return Ok((msg, false));
+        }
+
+        let key = self
+            .message_key(&mut transaction, my_id, conversation, e2e)
+            .await?;
+        // Already encrypted by the client, for keys the server never sees: stored as is.
+        let e2e_payload = e2e.then(|| msg.contents().as_bytes().to_vec());
+
+        let msg_id: i64 = sqlx::query_scalar(
+            r"
+            INSERT INTO message (content, sender_id, conversation_id, previous_message_id,
+                timestamp, client_message_id, e2e)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id
+        ",
+        )
+        .bind(e2e_payload)
+        .bind(my_id)
+        .bind(conversation)
+        .bind(prev_id)
+        .bind(msg.timestamp())
+        .bind(client_message_id)
+        .bind(e2e)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        // The ciphertext is bound to the row's id, so it can only be written once the row exists.
+        if let Some(key) = key {
+            let aad = message_aad(msg_id, conversation.0, my_id.0);
+            let contents = CryptData::encrypt(
+                msg.contents().to_owned(),
+                &key,
+                &aad,
+                &mut *self.sealer.rng(),
+            )?;
+            sqlx::query("UPDATE message SET content = $1 WHERE id = $2")
+                .bind(contents)
+                .bind(msg_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        link_message(&mut *transaction, msg_id).await?;
+
+        sqlx::query("UPDATE conversation SET last_message_id = $1 WHERE id = $2")
+            .bind(msg_id)
+            .bind(conversation)
+            .execute(&mut *transaction)
+            .await?;
+
+        // A message from the other side reopens a conversation that was waiting or resolved. The
+        // seller and the store's staff are on the same side.
+        sqlx::query(
+            r"
+            UPDATE conversation
+            SET status = 'open', status_updated_by = NULL
+            WHERE id = $1 AND status != 'open'
+                AND (status_updated_by IS NULL OR (status_updated_by = client_id) != ($2 = client_id))
+        ",
+        )
+        .bind(conversation)
+        .bind(my_id)
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok((MessageId(msg_id), true))
+    }
+
+    async fn get_latest_message(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        Ok(
+            sqlx::query_scalar("SELECT last_message_id FROM conversation WHERE id = $1")
+                .bind(conversation)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn belongs_to_conversation(
+        &self,
+        id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<(), Self::Error> {
+        let is_there: bool = sqlx::query_scalar(
+            r"
+            SELECT EXISTS (
+                SELECT id
+                FROM conversation
+                WHERE id = $1 AND (client_id = $2 OR seller_id = $2
+                    OR store_id IN (SELECT store_id FROM store_member WHERE user_id = $2))
+            )
+        ",
+        )
+        .bind(conversation)
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+        if is_there {
+            Ok(())
+        } else {
+            Err(DbError::PermissionDenied)
+        }
+    }
+
+    async fn get_seller_products(
+        &self,
+        seller: &Self::UserId,
+    ) -> Result<Vec<Self::Product>, Self::Error> {
+        let rows: Vec<(Vec<u8>, i64)> =
+            sqlx::query_as("SELECT name, id FROM product WHERE seller_id = $1 ORDER BY id")
+                .bind(seller)
+                .fetch_all(&self.pool)
+                .await?;
+        rows.into_iter()
+            .map(|(name, id)| {
+                let name = self.sealer.open_field(name, "product.name", id)?;
+                Ok(Product::new(name, *seller, id))
+            })
+            .collect()
+    }
+
+    async fn belongs_to_seller(
+        &self,
+        seller_id: &Self::UserId,
+        product_id: &Self::ProductId,
+    ) -> Result<(), Self::Error> {
+        let record: Option<i64> =
+            sqlx::query_scalar("SELECT seller_id FROM product WHERE id = $1 AND seller_id = $2")
+                .bind(product_id)
+                .bind(seller_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        match record {
+            Some(_) => Ok(()),
+            None => Err(DbError::PermissionDenied),
+        }
+    }
+
+    async fn get_conversation_from_message(
+        &self,
+        msg_id: &Self::MessageId,
+    ) -> Result<Self::ConversationId, Self::Error> {
+        Ok(
+            sqlx::query_scalar("SELECT conversation_id FROM message WHERE id = $1")
+                .bind(msg_id)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn get_conversations_from_messages(
+        &self,
+        msg_ids: &[Self::MessageId],
+    ) -> Result<Vec<(Self::MessageId, Self::ConversationId)>, Self::Error> {
+        let ids: Vec<i64> = msg_ids.iter().map(|id| id.0).collect();
+        Ok(
+            sqlx::query_as("SELECT id, conversation_id FROM message WHERE id = ANY($1)")
+                .bind(ids)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn add_store(
+        &self,
+        owner_id: &Self::UserId,
+        store: &Self::Store,
+    ) -> Result<Self::StoreId, Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let store_id: StoreId =
+            sqlx::query_scalar("INSERT INTO store (name) VALUES ($1) RETURNING id")
+                .bind(&store.name)
+                .fetch_one(&mut *transaction)
+                .await?;
+        sqlx::query("INSERT INTO store_member (store_id, user_id, owner) VALUES ($1, $2, TRUE)")
+            .bind(store_id)
+            .bind(owner_id)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(
+            r#"UPDATE "user" SET primary_store_id = $1 WHERE id = $2 AND primary_store_id IS NULL"#,
+        )
+        .bind(store_id)
+        .bind(owner_id)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(store_id)
+    }
+
+    async fn add_store_member(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+        user_id: &Self::UserId,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let is_owner: Option<bool> = sqlx::query_scalar(
+            "SELECT owner FROM store_member WHERE store_id = $1 AND user_id = $2",
+        )
+        .bind(store_id)
+        .bind(my_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if is_owner != Some(true) {
+            return Err(DbError::PermissionDenied);
+        }
+        sqlx::query(
+            "INSERT INTO store_member (store_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(store_id)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(
+            r#"UPDATE "user" SET primary_store_id = $1 WHERE id = $2 AND primary_store_id IS NULL"#,
+        )
+        .bind(store_id)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn set_primary_store(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error> {
+        let updated = sqlx::query(
+            r#"
+            UPDATE "user" SET primary_store_id = $1
+            WHERE id = $2 AND EXISTS (
+                SELECT user_id FROM store_member WHERE store_id = $1 AND user_id = $2
+            )
+        "#,
+        )
+        .bind(store_id)
+        .bind(my_id)
+        .execute(&self.pool)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Err(DbError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    async fn get_store_members(
+        &self,
+        store_id: &Self::StoreId,
+    ) -> Result<Vec<Self::UserId>, Self::Error> {
+        Ok(sqlx::query_scalar(
+            "SELECT user_id FROM store_member WHERE store_id = $1 ORDER BY user_id",
+        )
+        .bind(store_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn get_user_stores(
+        &self,
+        user: &Self::UserId,
+    ) -> Result<Vec<(Self::StoreId, Self::Store)>, Self::Error> {
+        let rows: Vec<(StoreId, String)> = sqlx::query_as(
+            r"
+            SELECT store.id, store.name
+            FROM store JOIN store_member ON store_member.store_id = store.id
+            WHERE store_member.user_id = $1
+            ORDER BY store.id
+        ",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, name)| (id, Store::new(name)))
+            .collect())
+    }
+
+    async fn belongs_to_store(
+        &self,
+        id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error> {
+        let record: Option<i64> = sqlx::query_scalar(
+            "SELECT user_id FROM store_member WHERE store_id = $1 AND user_id = $2",
+        )
+        .bind(store_id)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        match record {
+            Some(_) => Ok(()),
+            None => Err(DbError::PermissionDenied),
+        }
+    }
+
+    async fn assign_conversation(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        assignee: Option<&Self::UserId>,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let (seller_id, store_id): (UserId, Option<i64>) =
+            sqlx::query_as("SELECT seller_id, store_id FROM conversation WHERE id = $1")
+                .bind(conversation)
+                .fetch_one(&mut *transaction)
+                .await?;
+
+        // Only the seller's side of the conversation may assign it, and only to one of themselves.
+        for user in std::iter::once(my_id).chain(assignee) {
+            let is_staff: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT user_id FROM store_member WHERE store_id = $1 AND user_id = $2)",
+            )
+            .bind(store_id)
+            .bind(user)
+            .fetch_one(&mut *transaction)
+            .await?;
+            if *user != seller_id && !is_staff {
+                return Err(DbError::PermissionDenied);
+            }
+        }
+
+        sqlx::query("UPDATE conversation SET assignee_id = $1 WHERE id = $2")
+            .bind(assignee)
+            .bind(conversation)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_assignee(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::UserId>, Self::Error> {
+        Ok(
+            sqlx::query_scalar("SELECT assignee_id FROM conversation WHERE id = $1")
+                .bind(conversation)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn mark_delivered(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+        messages: &[Self::MessageId],
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let messages: Vec<i64> = messages.iter().map(|id| id.0).collect();
+        let ids: Vec<i64> = sqlx::query_scalar(MARK_DELIVERED)
+            .bind(Utc::now())
+            .bind(conversation)
+            .bind(messages)
+            .bind(reader)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(ids.into_iter().max().map(MessageId))
+    }
+
+    async fn mark_read(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let ids: Vec<i64> = sqlx::query_scalar(
+            r"
+            UPDATE message
+            SET read_at = $1, delivered_at = COALESCE(delivered_at, $1)
+            FROM conversation
+            WHERE conversation.id = message.conversation_id AND message.conversation_id = $2
+                AND read_at IS NULL
+                AND (message.sender_id = conversation.client_id) != ($3 = conversation.client_id)
+            RETURNING message.id
+        ",
+        )
+        .bind(Utc::now())
+        .bind(conversation)
+        .bind(reader)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids.into_iter().max().map(MessageId))
+    }
+
+    async fn get_status(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::ConversationStatus, Self::Error> {
+        Ok(
+            sqlx::query_scalar("SELECT status FROM conversation WHERE id = $1")
+                .bind(conversation)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn set_status(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        status: Self::ConversationStatus,
+    ) -> Result<(), Self::Error> {
+        let updated_by = (status != ConversationStatus::Open).then_some(my_id);
+        sqlx::query("UPDATE conversation SET status = $1, status_updated_by = $2 WHERE id = $3")
+            .bind(status)
+            .bind(updated_by)
+            .bind(conversation)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_labels(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Vec<String>, Self::Error> {
+        Ok(sqlx::query_scalar(
+            r"
+            SELECT label
+            FROM conversation_label
+            WHERE conversation_id = $1 AND user_id = $2
+            ORDER BY label
+        ",
+        )
+        .bind(conversation)
+        .bind(my_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    async fn add_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error> {
+        sqlx::query(
+            r"
+            INSERT INTO conversation_label (conversation_id, user_id, label)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+        ",
+        )
+        .bind(conversation)
+        .bind(my_id)
+        .bind(label)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error> {
+        sqlx::query(
+            "DELETE FROM conversation_label WHERE conversation_id = $1 AND user_id = $2 AND label = $3",
+        )
+        .bind(conversation)
+        .bind(my_id)
+        .bind(label)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_public_key(&self, user: &Self::UserId, key: &str) -> Result<(), Self::Error> {
+        sqlx::query(
+            r"
+            INSERT INTO public_key (user_id, key, updated_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE SET key = excluded.key, updated_at = excluded.updated_at
+        ",
+        )
+        .bind(user)
+        .bind(key)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_public_key(&self, user: &Self::UserId) -> Result<String, Self::Error> {
+        Ok(
+            sqlx::query_scalar("SELECT key FROM public_key WHERE user_id = $1")
+                .bind(user)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn is_e2e(&self, conversation: &Self::ConversationId) -> Result<bool, Self::Error> {
+        Ok(
+            sqlx::query_scalar("SELECT e2e FROM conversation WHERE id = $1")
+                .bind(conversation)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn enable_e2e(&self, conversation: &Self::ConversationId) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let (store_id, keys): (Option<i64>, i64) = sqlx::query_as(
+            r"
+            SELECT conversation.store_id,
+                (SELECT COUNT(*) FROM public_key
+                    WHERE user_id IN (conversation.client_id, conversation.seller_id))
+            FROM conversation
+            WHERE id = $1
+        ",
+        )
+        .bind(conversation)
+        .fetch_one(&mut *transaction)
+        .await?;
+        // Staff of a shared inbox come and go, there is no single key to encrypt for.
+        if store_id.is_some() {
+            return Err(DbError::E2EUnavailable("conversation is in a shared inbox"));
+        }
+        if keys < 2 {
+            return Err(DbError::E2EUnavailable(
+                "both participants must register a public key",
+            ));
+        }
+        sqlx::query("UPDATE conversation SET e2e = TRUE WHERE id = $1")
+            .bind(conversation)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn erase_user(
+        &self,
+        user: &Self::UserId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        // The row stays so the peer's conversations keep pointing somewhere.
+        let erased = sqlx::query(
+            r#"
+            UPDATE "user"
+            SET username = NULL, name = NULL, username_index = NULL, primary_store_id = NULL,
+                erased_at = COALESCE(erased_at, $1)
+            WHERE id = $2
+        "#,
+        )
+        .bind(Utc::now())
+        .bind(user)
+        .execute(&mut *transaction)
+        .await?;
+        if erased.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+
+        // Crypto-shredding: without the data key the ciphertext, and any backup of it, is noise.
+        // Dead rows linger until vacuumed, which is what the key destruction is for.
+        for statement in [
+            "DELETE FROM conversation_key WHERE user_id = $1",
+            "DELETE FROM public_key WHERE user_id = $1",
+            "UPDATE message SET content = NULL WHERE sender_id = $1",
+            "DELETE FROM conversation_label WHERE user_id = $1",
+            "DELETE FROM store_member WHERE user_id = $1",
+            "UPDATE conversation SET assignee_id = NULL WHERE assignee_id = $1",
+        ] {
+            sqlx::query(statement)
+                .bind(user)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        self.audit(&mut transaction, audit).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn shred_conversation(
+        &self,
+        conversation: &Self::ConversationId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query_scalar::<_, i64>("SELECT id FROM conversation WHERE id = $1")
+            .bind(conversation)
+            .fetch_one(&mut *transaction)
+            .await?;
+        for statement in [
+            "DELETE FROM conversation_key WHERE conversation_id = $1",
+            "UPDATE message SET content = NULL WHERE conversation_id = $1",
+        ] {
+            sqlx::query(statement)
+                .bind(conversation)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        self.audit(&mut transaction, audit).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn verify_message_chain(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let last: Option<i64> =
+            sqlx::query_scalar("SELECT last_message_id FROM conversation WHERE id = $1")
+                .bind(conversation)
+                .fetch_one(&self.pool)
+                .await?;
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            i64,
+            i64,
+            DateTime<Utc>,
+            Option<Vec<u8>>,
+            Option<i64>,
+            Option<Vec<u8>>,
+            Option<Vec<u8>>,
+        )> = sqlx::query_as(MESSAGE_CHAIN)
+            .bind(conversation)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut prev: Option<(i64, Vec<u8>)> = None;
+        for (id, sender_id, timestamp, content, previous_message_id, content_hash, stored) in rows {
+            let broken = Ok(Some(MessageId(id)));
+            if previous_message_id != prev.as_ref().map(|(id, _)| *id) {
+                return broken;
+            }
+            let Some(content_hash) = content_hash else {
+                return broken;
+            };
+            if content.is_some_and(|content| Sha256::digest(content).as_slice() != content_hash) {
+                return broken;
+            }
+            let prev_hash = prev.map_or_else(|| vec![0; 32], |(_, hash)| hash);
+            let hash = chain_hash(&prev_hash, sender_id, timestamp.naive_utc(), &content_hash);
+            if stored.as_ref() != Some(&hash) {
+                return broken;
+            }
+            prev = Some((id, hash));
+        }
+        // A removed tail leaves the conversation pointing past the end of the chain.
+        Ok(match (last, prev) {
+            (Some(last), Some((id, _))) if last == id => None,
+            (None, None) => None,
+            (Some(last), _) | (None, Some((last, _))) => Some(MessageId(last)),
+        })
+    }
+
+    async fn get_chain_head(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::ChainHead>, Self::Error> {
+        let (id, hash): (Option<i64>, Option<Vec<u8>>) = sqlx::query_as(
+            r"
+            SELECT message.id, message.chain_hash
+            FROM conversation LEFT JOIN message ON message.id = conversation.last_message_id
+            WHERE conversation.id = $1
+        ",
+        )
+        .bind(conversation)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id.zip(hash).map(|(id, hash)| ChainHead {
+            message_id: MessageId(id),
+            hash: hex::encode(hash),
+        }))
+    }
+
+    async fn reencrypt_batch(&self, batch: u32) -> Result<u64, Self::Error> {
+        let active = i64::from(self.sealer.keys.active_id());
+        let mut transaction = self.pool.begin().await?;
+        let metadata = self.sealer.rewrap_metadata_keys(&mut *transaction).await?;
+        let stale: Vec<(i64, i64, Vec<u8>, i64)> = sqlx::query_as(
+            r"
+            SELECT conversation_id, user_id, key, key_id
+            FROM conversation_key
+            WHERE key_id != $1
+            LIMIT $2
+            FOR UPDATE
+        ",
+        )
+        .bind(active)
+        .bind(i64::from(batch))
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        for (conversation_id, user_id, key, key_id) in &stale {
+            let raw = unwrap_raw_key(&self.sealer.keys, key.clone(), &[], *key_id)?;
+            let wrapped =
+                CryptData::encrypt(raw, self.sealer.keys.active(), &[], &mut *self.sealer.rng())?;
+            sqlx::query(
+                r"
+                UPDATE conversation_key
+                SET key = $1, key_id = $2
+                WHERE conversation_id = $3 AND user_id = $4
+            ",
+            )
+            .bind(wrapped)
+            .bind(active)
+            .bind(conversation_id)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok((metadata + stale.len()) as u64)
+    }
+
+    async fn append_audit(&self, entry: &Self::AuditEntry) -> Result<(), Self::Error> {
+        let mut transaction = self.pool.begin().await?;
+        self.audit(&mut transaction, entry).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_audit_log(
+        &self,
+        filter: &Self::AuditFilter,
+    ) -> Result<Vec<Self::AuditRecord>, Self::Error> {
+        let rows: Vec<AuditRow> = sqlx::query_as(
+            r"
+            SELECT *
+            FROM audit_log
+            WHERE ($1::BIGINT IS NULL OR actor_id = $1) AND ($2::BIGINT IS NULL OR subject_id = $2)
+                AND ($3::BIGINT IS NULL OR conversation_id = $3)
+            ORDER BY id DESC
+            LIMIT $4
+        ",
+        )
+        .bind(filter.actor_id)
+        .bind(filter.subject_id)
+        .bind(filter.conversation_id)
+        .bind(i64::from(filter.limit))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| AuditRecord {
+                entry: row.entry(),
+                id: row.id,
+                timestamp: row.timestamp,
+                hash: hex::encode(row.hash),
+            })
+            .collect())
+    }
+
+    async fn verify_audit_log(&self) -> Result<Option<i64>, Self::Error> {
+        let rows: Vec<AuditRow> = sqlx::query_as("SELECT * FROM audit_log ORDER BY id")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut chain = AuditChain::new(&self.sealer.audit_key);
+        for row in rows {
+            if !chain.links(&row.entry(), &row.timestamp, &row.prev_hash, &row.hash)? {
+                return Ok(Some(row.id));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::database::{
+        conformance::{alice_and_bob, erasure},
+        crypto::tests::{key, key_from},
+    };
+
+    /// Opens a fresh database named `name` on the server at `$POSTGRES_TEST_URL`, e.g.
+    /// `postgres://postgres@localhost:5432`. The tests using it are ignored by default: run them
+    /// with `cargo test -- --ignored` once it is set.
+    pub(crate) async fn fresh_db(
+        name: &str,
+        key: CryptoKey,
+    ) -> anyhow::Result<(String, PostgresDB)> {
+        let server = std::env::var("POSTGRES_TEST_URL")
+            .map_err(|_| anyhow!("POSTGRES_TEST_URL must point to a Postgres server"))?;
+        let url = format!(
+            "{}/{name}_{}",
+            server.trim_end_matches('/'),
+            std::process::id()
+        );
+        if Postgres::database_exists(&url).await? {
+            Postgres::force_drop_database(&url).await?;
+        }
+        let db = PostgresDB::new(&url, key).await?;
+        Ok((url, db))
+    }
+
+    /// What the conformance suite can't check: the audit log refusing deletes at the database
+    /// level, and the key and its parameters persisting across reopens.
+    #[tokio::test]
+    #[ignore = "needs POSTGRES_TEST_URL"]
+    async fn test_postgres() -> anyhow::Result<()> {
+        let (url, db) = fresh_db("ds_test_postgres", key()?).await?;
+        let (alice_id, _, _, convo_id) = alice_and_bob(&db).await?;
+        let hello = db
+            .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+
+        db.append_audit(&erasure(alice_id)).await?;
+        assert!(
+            sqlx::query("DELETE FROM audit_log")
+                .execute(&db.pool)
+                .await
+                .is_err()
+        );
+        db.pool.close().await;
+
+        // Reopened, only with the key it was written with.
+        let wrong = PostgresDB::new(&url, key_from("password")?).await;
+        assert!(wrong.is_err());
+        let db = PostgresDB::new(&url, key()?).await?;
+        assert_eq!(db.get_message(&hello).await?.1.contents(), "Hello Bob!");
+        assert!(PostgresDB::stored_kdf(&url).await?.contains_key(&1));
+        db.pool.close().await;
+        // Connections rolling back a failed transaction may linger a little.
+        Postgres::force_drop_database(&url).await?;
+        Ok(())
+    }
+
+    /// Checks that the lookups of the methods below, which used to scan `message` or
+    /// `conversation`, go through an index.
+    async fn assert_indexed(db: &PostgresDB) -> anyhow::Result<()> {
+        let [recent, after, filter, find, delivered, chain] = [
+            MOST_RECENT_MESSAGES,
+            MESSAGES_AFTER,
+            FILTER_CONVERSATIONS,
+            FIND_CONVERSATION,
+            MARK_DELIVERED,
+            MESSAGE_CHAIN,
+        ]
+        .map(|sql| format!("EXPLAIN {sql}"));
+        let query = |sql| sqlx::query_scalar::<_, String>(sql);
+        let lookups = [
+            (
+                "get_most_recent_messages",
+                query(&recent).bind(7_i64),
+                "message_conversation_id",
+            ),
+            (
+                "get_messages_after",
+                query(&after).bind(7_i64).bind(1_000_i64).bind(100_i64),
+                "message_conversation_id",
+            ),
+            (
+                "filter_conversations",
+                query(&filter)
+                    .bind(17_i64)
+                    .bind(None::<String>)
+                    .bind(None::<String>),
+                "conversation_seller_id",
+            ),
+            (
+                "start_conversation",
+                query(&find)
+                    .bind(17_i64)
+                    .bind(2_i64)
+                    .bind(Some(1_i64))
+                    .bind(1_i64),
+                "conversation_participants",
+            ),
+            (
+                "mark_delivered",
+                query(&delivered)
+                    .bind(Utc::now())
+                    .bind(7_i64)
+                    .bind(vec![1_000_i64])
+                    .bind(2_i64),
+                "message_conversation_id",
+            ),
+            (
+                "verify_message_chain",
+                query(&chain).bind(7_i64),
+                "message_conversation_id",
+            ),
+        ];
+        // Tables this small are scanned either way, unlike `message` and `conversation`.
+        for (method, query, index) in lookups {
+            let plan = query.fetch_all(&db.pool).await?.join("\n");
+            assert!(
+                plan.contains(index) && !plan.contains("Seq Scan on message"),
+                "{method} doesn't use {index}: {plan}"
+            );
+            assert!(
+                !plan.contains("Seq Scan on conversation "),
+                "{method} scans: {plan}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs POSTGRES_TEST_URL"]
+    async fn test_postgres_query_plans() -> anyhow::Result<()> {
+        let (url, db) = fresh_db("ds_test_plans", key()?).await?;
+        let seller = db
+            .add_user(&UserProfile::new_clone(2, "seller", "Seller"))
+            .await?;
+        let product = db
+            .add_product(&Product::new("Dill Dough".to_owned(), seller, 1))
+            .await?;
+
+        // Same rows as the sqlite test, with statistics for the planner to go by.
+        sqlx::raw_sql(
+            r#"
+            INSERT INTO "user" (id) SELECT 10 + i FROM generate_series(1, 2000) AS i;
+            INSERT INTO conversation (client_id, seller_id, product_id)
+                SELECT id, 2, 1 FROM "user" WHERE id >= 10 ORDER BY id;
+            INSERT INTO message (sender_id, conversation_id, timestamp)
+                SELECT 11 + i % 2000, 1 + i % 2000, now() FROM generate_series(0, 199999) AS i;
+            ANALYZE;
+        "#,
+        )
+        .execute(&db.pool)
+        .await?;
+
+        assert_indexed(&db).await?;
+
+        let conversations = db.get_conversations(&UserId(17)).await?;
+        assert_eq!(conversations, [ConversationId(7)]);
+        assert_eq!(
+            db.start_conversation(&UserId(17), &seller, &product)
+                .await?,
+            ConversationId(7)
+        );
+        let duplicate = sqlx::query(
+            "INSERT INTO conversation (client_id, seller_id, product_id) VALUES (17, $1, $2)",
+        )
+        .bind(seller)
+        .bind(product)
+        .execute(&db.pool)
+        .await;
+        assert!(duplicate.is_err());
+
+        db.pool.close().await;
+        Postgres::force_drop_database(&url).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+SELECT id as "id!"
+FROM conversation
+WHERE (client_id = ?1 OR seller_id = ?1
+        OR store_id IN (SELECT store_id FROM store_member WHERE user_id = ?1))
+    AND (?2 IS NULL OR status = ?2)
+    AND (?3 IS NULL OR id IN (
+        SELECT conversation_id
+        FROM conversation_label
+        WHERE user_id = ?1 AND label = ?3
+    ))
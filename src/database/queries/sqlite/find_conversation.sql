@@ -0,0 +1,4 @@
+SELECT id as "id!"
+FROM conversation
+WHERE ((client_id = ? AND seller_id = ?) OR (seller_id = ? AND client_id = ?)
+    OR (client_id = ? AND store_id = ?)) AND product_id = ?
@@ -0,0 +1,5 @@
+UPDATE message
+SET delivered_at = ?1
+WHERE conversation_id = ?2 AND id IN (SELECT value FROM json_each(?3)) AND delivered_at IS NULL
+    AND (SELECT (message.sender_id = client_id) != (?4 = client_id) FROM conversation WHERE id = ?2)
+RETURNING id as "id!"
@@ -0,0 +1,5 @@
+SELECT id as "id!", sender_id, timestamp, content, previous_message_id, content_hash,
+    chain_hash
+FROM message
+WHERE conversation_id = ?
+ORDER BY id
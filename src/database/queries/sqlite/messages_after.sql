@@ -0,0 +1,9 @@
+SELECT id as "id!", sender_id as "sender_id!", message.conversation_id as "conversation_id!",
+    content as "content?", salt as "salt!", timestamp as "timestamp!", previous_message_id, e2e as "e2e: bool",
+    CASE WHEN read_at IS NOT NULL THEN 'read' WHEN delivered_at IS NOT NULL THEN 'delivered' ELSE 'sent' END as "status!: DeliveryStatus",
+    conversation_key.key as "sender_key?", conversation_key.nonce as "sender_key_nonce?", conversation_key.key_id as "sender_key_id?"
+FROM message LEFT JOIN conversation_key
+    ON conversation_key.user_id = message.sender_id AND conversation_key.conversation_id = message.conversation_id
+WHERE message.conversation_id = ? AND id > ?
+ORDER BY id
+LIMIT ?
@@ -0,0 +1,14 @@
+WITH id_asc as (
+    SELECT id, sender_id, conversation_id, content, salt, timestamp, previous_message_id, delivered_at, read_at, e2e
+    FROM message
+    WHERE conversation_id = ?
+    ORDER BY id desc
+    LIMIT 32
+)
+SELECT id as "id!", sender_id as "sender_id!", id_asc.conversation_id as "conversation_id!",
+    content as "content?", salt as "salt!", timestamp as "timestamp!", previous_message_id, e2e as "e2e: bool",
+    CASE WHEN read_at IS NOT NULL THEN 'read' WHEN delivered_at IS NOT NULL THEN 'delivered' ELSE 'sent' END as "status!: DeliveryStatus",
+    conversation_key.key as "sender_key?", conversation_key.nonce as "sender_key_nonce?", conversation_key.key_id as "sender_key_id?"
+FROM id_asc LEFT JOIN conversation_key
+    ON conversation_key.user_id = id_asc.sender_id AND conversation_key.conversation_id = id_asc.conversation_id
+ORDER BY id
@@ -0,0 +1,454 @@
+//! Encryption and hash chains as every backend does them: only the SQL differs between them,
+//! behind [`KeyStore`].
+//!
+//! Messages are encrypted with a data key per conversation and sender, user and product names
+//! with a metadata key, and the audit log is chained with another. Data and metadata keys are
+//! stored wrapped with a master key, checked against a canary the first time it is loaded.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use rand::{RngCore, SeedableRng, rngs::StdRng};
+use sha2::{Digest, Sha256};
+
+use crate::database::{
+    crypto::{AuditKey, BlindIndex, CryptData, CryptError, CryptoKey, KdfParams, Keyring, RawKey},
+    model::{ConversationId, DbError, DeliveryStatus, Message, MessageId, UserId},
+};
+
+/// The queries key management and hash chains need, implemented by each backend's connection.
+#[allow(async_fn_in_trait)]
+pub(super) trait KeyStore {
+    /// Canary stored for master key `id`, `None` until the key is first loaded.
+    async fn canary(&mut self, id: u32) -> Result<Option<Vec<u8>>, DbError>;
+
+    async fn store_canary(
+        &mut self,
+        id: u32,
+        canary: &[u8],
+        kdf: Option<&KdfParams>,
+    ) -> Result<(), DbError>;
+
+    /// Something encrypted with master key `id` from before it had a canary.
+    async fn written_with(&mut self, id: u32) -> Result<Written, DbError>;
+
+    /// Wrapped metadata key for `purpose`, along with the id of the master key wrapping it.
+    async fn metadata_key(&mut self, purpose: &str) -> Result<Option<(Vec<u8>, i64)>, DbError>;
+
+    /// Stores a metadata key, unless another server stored one meanwhile.
+    async fn store_metadata_key(
+        &mut self,
+        purpose: &str,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError>;
+
+    /// Metadata keys wrapped with another master key than `active`, by purpose.
+    async fn stale_metadata_keys(
+        &mut self,
+        active: u32,
+    ) -> Result<Vec<(String, Vec<u8>, i64)>, DbError>;
+
+    async fn rewrap_metadata_key(
+        &mut self,
+        purpose: &str,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError>;
+
+    /// Whether `user` was erased, and their wrapped data key for `conversation` if they have one.
+    async fn conversation_key(
+        &mut self,
+        user: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<(bool, Option<WrappedKey>), DbError>;
+
+    async fn store_conversation_key(
+        &mut self,
+        user: &UserId,
+        conversation: &ConversationId,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError>;
+
+    /// What message `id` is hashed from, along with the hash of the message before it.
+    async fn chain_link(&mut self, id: i64) -> Result<ChainLink, DbError>;
+
+    async fn store_chain_hash(
+        &mut self,
+        id: i64,
+        content_hash: &[u8],
+        hash: &[u8],
+    ) -> Result<(), DbError>;
+}
+
+/// A data key as stored, wrapped with master key `key_id`. Keys wrapped before the versioned
+/// format keep their nonce apart.
+pub(super) struct WrappedKey {
+    pub(super) key: Vec<u8>,
+    pub(super) nonce: Vec<u8>,
+    pub(super) key_id: i64,
+}
+
+/// Something written with a master key before it had a canary, to check the key against.
+pub(super) enum Written {
+    Nothing,
+    ConversationKey(WrappedKey),
+    MetadataKey {
+        purpose: String,
+        key: Vec<u8>,
+    },
+    /// Messages from before data keys, encrypted with the first master key itself, along with
+    /// their nonce. A few of them, so that one damaged message doesn't refuse the right key.
+    LegacyMessages(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+impl Written {
+    fn opens_with(self, key: &CryptoKey) -> bool {
+        match self {
+            Written::Nothing => true,
+            Written::ConversationKey(wrapped) => CryptData::<RawKey>::from(wrapped.key)
+                .decrypt_any(key, &wrapped.nonce, &[])
+                .is_ok(),
+            Written::MetadataKey {
+                purpose,
+                key: wrapped,
+            } => CryptData::<RawKey>::from(wrapped)
+                .decrypt(key, purpose.as_bytes())
+                .is_ok(),
+            Written::LegacyMessages(messages) => messages.into_iter().any(|(content, nonce)| {
+                CryptData::<String>::from(content)
+                    .decrypt_legacy(key, &nonce, &[])
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+/// Inputs of a message's [`chain_hash`].
+pub(super) struct ChainLink {
+    pub(super) sender_id: i64,
+    pub(super) timestamp: NaiveDateTime,
+    pub(super) content: Option<Vec<u8>>,
+    /// `None` for the first message of a conversation.
+    pub(super) prev_hash: Option<Vec<u8>>,
+}
+
+/// Master keys along with the keys loaded with them.
+pub(super) struct Sealer {
+    pub(super) keys: Keyring,
+    /// Shared by every request, locked only while drawing nonces and keys.
+    pub(super) rng: Mutex<StdRng>,
+    /// Encrypts user and product names.
+    profile_key: CryptoKey,
+    pub(super) username_index: BlindIndex,
+    pub(super) audit_key: AuditKey,
+}
+
+impl Sealer {
+    /// Checks `keys` against the database and loads the metadata keys, creating them on first
+    /// boot.
+    pub(super) async fn open(store: &mut impl KeyStore, keys: Keyring) -> Result<Self, DbError> {
+        let mut rng = StdRng::from_os_rng();
+        check_master_keys(store, &keys, &mut rng).await?;
+        let profile_key = load_metadata_key(store, &keys, &mut rng, "profile").await?;
+        let username_index = load_metadata_key(store, &keys, &mut rng, "blind_index").await?;
+        let audit_key = load_metadata_key(store, &keys, &mut rng, "audit").await?;
+        Ok(Self {
+            profile_key: CryptoKey::from_raw(&profile_key)?,
+            username_index: BlindIndex::from_raw(&username_index)?,
+            audit_key: AuditKey::from_raw(&audit_key)?,
+            keys,
+            rng: Mutex::new(rng),
+        })
+    }
+
+    pub(super) fn rng(&self) -> MutexGuard<'_, StdRng> {
+        // Drawing random bytes can't leave the generator half-updated: a panic elsewhere while it
+        // was locked doesn't make it unusable.
+        self.rng.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Encrypts a user or product field, bound to the column and row it is stored in.
+    pub(super) fn seal_field(
+        &self,
+        value: &str,
+        column: &str,
+        id: i64,
+    ) -> Result<CryptData<String>, DbError> {
+        Ok(CryptData::encrypt(
+            value.to_owned(),
+            &self.profile_key,
+            &field_aad(column, id),
+            &mut *self.rng(),
+        )?)
+    }
+
+    pub(super) fn open_field(
+        &self,
+        value: Vec<u8>,
+        column: &str,
+        id: i64,
+    ) -> Result<String, DbError> {
+        Ok(CryptData::from(value).decrypt(&self.profile_key, &field_aad(column, id))?)
+    }
+
+    /// Data key the messages of `user` in `conversation` are encrypted with, created along with
+    /// the first one.
+    ///
+    /// Keys are per conversation and per sender: shredding a conversation or erasing a user is
+    /// deleting their keys, and a leaked key exposes one side of one conversation.
+    /// Erased users have no keys and get none, so nothing can be written on their behalf.
+    pub(super) async fn sender_key(
+        &self,
+        store: &mut impl KeyStore,
+        user: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<CryptoKey, DbError> {
+        let (erased, wrapped) = store.conversation_key(user, conversation).await?;
+        if erased {
+            return Err(DbError::PermissionDenied);
+        }
+        if let Some(wrapped) = wrapped {
+            return unwrap_key(&self.keys, wrapped.key, &wrapped.nonce, wrapped.key_id);
+        }
+
+        let (raw, key) = CryptoKey::generate(&mut *self.rng());
+        let wrapped = CryptData::encrypt(raw, self.keys.active(), &[], &mut *self.rng())?;
+        store
+            .store_conversation_key(user, conversation, &wrapped, self.keys.active_id())
+            .await?;
+        Ok(key)
+    }
+
+    /// Rewraps the metadata keys still wrapped with a retired master key.
+    pub(super) async fn rewrap_metadata_keys(
+        &self,
+        store: &mut impl KeyStore,
+    ) -> Result<usize, DbError> {
+        let active = self.keys.active_id();
+        let stale = store.stale_metadata_keys(active).await?;
+        for (purpose, key, key_id) in &stale {
+            let master = master_key(&self.keys, *key_id)?;
+            let raw: RawKey = CryptData::from(key.clone()).decrypt(master, purpose.as_bytes())?;
+            let wrapped = CryptData::encrypt(
+                raw,
+                self.keys.active(),
+                purpose.as_bytes(),
+                &mut *self.rng(),
+            )?;
+            store.rewrap_metadata_key(purpose, &wrapped, active).await?;
+        }
+        Ok(stale.len())
+    }
+}
+
+/// Refuses master keys other than those the database was written with, which is what a wrong
+/// password or salt derives: running with one would write data nothing can read back.
+///
+/// Keys are checked against a canary stored the first time they are loaded. Databases that
+/// predate canaries are checked against what they hold encrypted with the same key, if anything.
+pub(super) async fn check_master_keys(
+    store: &mut impl KeyStore,
+    keys: &Keyring,
+    rng: &mut StdRng,
+) -> Result<(), DbError> {
+    for (id, key) in keys.iter() {
+        if let Some(stored) = store.canary(id).await? {
+            let canary = CryptData::<Vec<u8>>::from(stored).decrypt(key, &canary_aad(id));
+            if !canary.is_ok_and(|canary| canary == CANARY) {
+                return Err(CryptError::WrongKey(id).into());
+            }
+            continue;
+        }
+        if !store.written_with(id).await?.opens_with(key) {
+            return Err(CryptError::WrongKey(id).into());
+        }
+        let canary = CryptData::encrypt(CANARY.to_vec(), key, &canary_aad(id), rng)?;
+        store.store_canary(id, &canary, key.kdf()).await?;
+    }
+    Ok(())
+}
+
+/// Raw data key for `purpose`, generated and stored wrapped with the active master key on first
+/// use. The wrapping is bound to the purpose so keys can't be swapped around.
+pub(super) async fn load_metadata_key(
+    store: &mut impl KeyStore,
+    keys: &Keyring,
+    rng: &mut StdRng,
+    purpose: &str,
+) -> Result<RawKey, DbError> {
+    if store.metadata_key(purpose).await?.is_none() {
+        let mut raw = RawKey::new(vec![0; 32]);
+        rng.fill_bytes(&mut raw);
+        let wrapped = CryptData::encrypt(raw, keys.active(), purpose.as_bytes(), rng)?;
+        // Several servers may boot at once: whichever stores its key first wins.
+        store
+            .store_metadata_key(purpose, &wrapped, keys.active_id())
+            .await?;
+    }
+    let (key, key_id) = store
+        .metadata_key(purpose)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+    let master = master_key(keys, key_id)?;
+    Ok(CryptData::from(key).decrypt(master, purpose.as_bytes())?)
+}
+
+/// Links message `id` to the one before it, once its contents are final.
+pub(super) async fn link_message(store: &mut impl KeyStore, id: i64) -> Result<(), DbError> {
+    let link = store.chain_link(id).await?;
+    let content_hash = Sha256::digest(link.content.unwrap_or_default()).to_vec();
+    let prev_hash = link.prev_hash.unwrap_or_else(|| vec![0; 32]);
+    let hash = chain_hash(&prev_hash, link.sender_id, link.timestamp, &content_hash);
+    store.store_chain_hash(id, &content_hash, &hash).await
+}
+
+/// Decrypts a data key with the master key `key_id`.
+pub(super) fn unwrap_key(
+    keys: &Keyring,
+    key: Vec<u8>,
+    nonce: &[u8],
+    key_id: i64,
+) -> Result<CryptoKey, DbError> {
+    Ok(CryptoKey::from_raw(&unwrap_raw_key(
+        keys, key, nonce, key_id,
+    )?)?)
+}
+
+pub(super) fn unwrap_raw_key(
+    keys: &Keyring,
+    key: Vec<u8>,
+    nonce: &[u8],
+    key_id: i64,
+) -> Result<RawKey, DbError> {
+    let master = master_key(keys, key_id)?;
+    Ok(CryptData::<RawKey>::from(key).decrypt_any(master, nonce, &[])?)
+}
+
+/// Decrypts a message with its sender's data key. End-to-end encrypted messages are returned as
+/// the client sent them.
+///
+/// Messages of erased senders come back empty: both the key and the ciphertext are gone. A
+/// ciphertext left without its key is an error, not an empty message.
+pub(super) fn open_message(keys: &Keyring, row: StoredMessage) -> Result<Message, DbError> {
+    let timestamp = row.timestamp.and_utc();
+    let contents = match row {
+        StoredMessage {
+            e2e: true, content, ..
+        } => String::from_utf8_lossy(&content.unwrap_or_default()).into_owned(),
+        StoredMessage {
+            id,
+            sender_id,
+            conversation_id,
+            content: Some(content),
+            salt,
+            sender_key: Some(key),
+            sender_key_nonce: Some(key_nonce),
+            sender_key_id: Some(key_id),
+            ..
+        } => {
+            let aad = message_aad(id, conversation_id, sender_id);
+            unwrap_key(keys, key, &key_nonce, key_id)
+                .and_then(|key| Ok(CryptData::from(content).decrypt_any(&key, &salt, &aad)?))
+                .map_err(|e| match e {
+                    DbError::Crypto(CryptError::ChaCha(_) | CryptError::Malformed) => {
+                        DbError::Decryption(MessageId(id))
+                    }
+                    e => e,
+                })?
+        }
+        StoredMessage { content: None, .. } => String::new(),
+        StoredMessage { id, .. } => return Err(DbError::Decryption(MessageId(id))),
+    };
+    Ok(Message::new(contents, timestamp)
+        .with_status(row.status)
+        .end_to_end(row.e2e))
+}
+
+/// Link of a conversation's hash chain. Each one commits to the previous, so altering, removing
+/// or reordering a message breaks every link after it.
+///
+/// The ciphertext is committed to through its hash, which outlives it: erasing messages doesn't
+/// break the chain.
+pub(super) fn chain_hash(
+    prev_hash: &[u8],
+    sender_id: i64,
+    timestamp: NaiveDateTime,
+    content_hash: &[u8],
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash);
+    hasher.update(sender_id.to_be_bytes());
+    hasher.update(timestamp.and_utc().timestamp_micros().to_be_bytes());
+    hasher.update(content_hash);
+    hasher.finalize().to_vec()
+}
+
+/// What a user or product field is bound to: its column, e.g. `user.name`, and row.
+pub(super) fn field_aad(column: &str, id: i64) -> Vec<u8> {
+    let mut aad = column.as_bytes().to_vec();
+    aad.extend_from_slice(&id.to_be_bytes());
+    aad
+}
+
+pub(super) fn master_key(keys: &Keyring, key_id: i64) -> Result<&CryptoKey, CryptError> {
+    u32::try_from(key_id)
+        .map_err(|_| CryptError::UnknownKey(u32::MAX))
+        .and_then(|id| keys.get(id))
+}
+
+/// Argon2 parameters of master key `id` as stored next to it, costs being memory in KiB,
+/// iterations and parallelism.
+pub(super) fn kdf_params(
+    id: i64,
+    algorithm: &str,
+    version: i64,
+    [memory_kib, iterations, parallelism]: [i64; 3],
+) -> anyhow::Result<KdfParams> {
+    let invalid = |e| anyhow!("Invalid KDF parameters for key #{id}: {e}");
+    Ok(KdfParams {
+        algorithm: algorithm.parse().map_err(|e| invalid(format!("{e}")))?,
+        version: u32::try_from(version)
+            .ok()
+            .and_then(|v| argon2::Version::try_from(v).ok())
+            .ok_or_else(|| invalid(format!("version {version}")))?,
+        memory_kib: u32::try_from(memory_kib).map_err(|e| invalid(e.to_string()))?,
+        iterations: u32::try_from(iterations).map_err(|e| invalid(e.to_string()))?,
+        parallelism: u32::try_from(parallelism).map_err(|e| invalid(e.to_string()))?,
+    })
+}
+
+/// Known plaintext of the master key canaries.
+pub(super) const CANARY: &[u8] = b"ds-prototype master key";
+
+pub(super) fn canary_aad(key_id: u32) -> Vec<u8> {
+    field_aad("master_key.canary", key_id.into())
+}
+
+/// Associated data of a message's ciphertext. Moving the ciphertext to another row, conversation
+/// or sender makes it fail to decrypt.
+pub(super) fn message_aad(id: i64, conversation_id: i64, sender_id: i64) -> [u8; 24] {
+    let mut aad = [0; 24];
+    aad[..8].copy_from_slice(&id.to_be_bytes());
+    aad[8..16].copy_from_slice(&conversation_id.to_be_bytes());
+    aad[16..].copy_from_slice(&sender_id.to_be_bytes());
+    aad
+}
+
+/// A message row along with its sender's wrapped data key, as read from the database.
+pub(super) struct StoredMessage {
+    pub(super) id: i64,
+    pub(super) sender_id: i64,
+    pub(super) conversation_id: i64,
+    pub(super) content: Option<Vec<u8>>,
+    pub(super) salt: Vec<u8>,
+    pub(super) timestamp: NaiveDateTime,
+    pub(super) previous_message_id: Option<i64>,
+    pub(super) e2e: bool,
+    pub(super) status: DeliveryStatus,
+    pub(super) sender_key: Option<Vec<u8>>,
+    pub(super) sender_key_nonce: Option<Vec<u8>>,
+    pub(super) sender_key_id: Option<i64>,
+}
@@ -1,31 +1,54 @@
-use std::ops::Deref;
+use std::{collections::BTreeMap, ops::Deref, str::FromStr, sync::Mutex, time::Duration};
 
 use crate::database::{
     Database,
-    crypto::{CryptData, CryptError, CryptoKey},
+    crypto::{CryptData, CryptoKey, KdfParams, Keyring},
+    migrations,
+    model::{
+        AuditAction, AuditChain, AuditEntry, AuditFilter, AuditRecord, ChainHead,
+        ConversationFilter, ConversationId, ConversationStatus, DbError, DeliveryStatus, Message,
+        MessageId, Product, ProductId, Store, StoreId, UserId, UserProfile,
+    },
+    sealing::{
+        ChainLink, KeyStore, Sealer, StoredMessage, WrappedKey, Written, chain_hash, kdf_params,
+        link_message, message_aad, open_message, unwrap_raw_key,
+    },
 };
-use actix_web::{ResponseError, http::StatusCode};
-use chrono::{DateTime, Utc};
-use rand::{SeedableRng, rngs::StdRng};
-use serde;
-use sqlx::{Pool, Sqlite, migrate::MigrateDatabase, sqlite::SqlitePoolOptions};
+use chrono::{NaiveDateTime, Utc};
+use rand::rngs::StdRng;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    Pool, Sqlite, SqliteConnection, Transaction,
+    migrate::MigrateDatabase,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+};
+
+/// How long a writer waits for another process to commit before giving up with `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct SQLiteDB {
+    /// Readers, which write-ahead logging lets run alongside the writer.
     pool: Pool<Sqlite>,
-    suite: CryptoKey,
-    rng: StdRng,
+    /// The single connection writes go through. sqlite only has one writer at a time anyway, and
+    /// queueing for the connection is cheaper than polling the database lock from several.
+    writer: Pool<Sqlite>,
+    sealer: Sealer,
 }
 
 impl SQLiteDB {
-    pub async fn new(url: &str, suite: CryptoKey) -> anyhow::Result<Self> {
+    pub async fn new(url: &str, keys: impl Into<Keyring>) -> anyhow::Result<Self> {
         if !Sqlite::database_exists(url).await? {
             Sqlite::create_database(url).await?;
         }
-        let rng = StdRng::from_os_rng();
-        let pool = SqlitePoolOptions::new().connect(url).await?;
-
-        let mut db = Self { pool, suite, rng };
-        db.set_schema().await?;
+        let options = SqliteConnectOptions::from_str(url)?
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await?;
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        let db = Self::open(pool, writer, keys.into()).await?;
 
         let admin_profile = UserProfile::new_clone(1, "admin", "Admin");
 
@@ -34,252 +57,534 @@ impl SQLiteDB {
         Ok(db)
     }
 
-    pub async fn kiosk(suite: CryptoKey) -> anyhow::Result<Self> {
-        let pool = SqlitePoolOptions::new()
-            .max_lifetime(None)
-            .idle_timeout(None)
-            .min_connections(1)
-            .connect_lazy("sqlite::memory:")?;
-        let rng = StdRng::from_os_rng();
-        let mut db = Self { pool, suite, rng };
-        db.set_schema().await?;
-        for user in Self::kiosk_users() {
-            db.add_user(&user).await?;
+    /// Argon2 parameters the master keys of the database at `url` were derived with, by key id.
+    /// Empty for a database that doesn't exist yet.
+    pub async fn stored_kdf(url: &str) -> anyhow::Result<BTreeMap<u32, KdfParams>> {
+        if !Sqlite::database_exists(url).await? {
+            return Ok(BTreeMap::new());
         }
-        for product in Self::kiosk_products() {
-            db.add_product(&product).await?;
+        let pool = SqlitePoolOptions::new().connect(url).await?;
+        let table = sqlx::query!(
+            r#"SELECT COUNT(*) as "n!: i64" FROM sqlite_master WHERE type = 'table' AND name = 'master_key'"#
+        )
+        .fetch_one(&pool)
+        .await?;
+        if table.n == 0 {
+            return Ok(BTreeMap::new());
         }
+        let rows = sqlx::query!(
+            r#"
+            SELECT id as "id!", kdf_algorithm as "algorithm!", kdf_version as "version!",
+                kdf_memory_kib as "memory_kib!", kdf_iterations as "iterations!",
+                kdf_parallelism as "parallelism!"
+            FROM master_key
+            WHERE kdf_algorithm IS NOT NULL
+        "#
+        )
+        .fetch_all(&pool)
+        .await?;
+        pool.close().await;
 
-        for (my_id, their_id, prod_id) in Self::kiosk_conversations() {
-            db.start_conversation(&my_id, &their_id, &prod_id).await?;
+        let mut stored = BTreeMap::new();
+        for row in rows {
+            let costs = [row.memory_kib, row.iterations, row.parallelism];
+            let kdf = kdf_params(row.id, &row.algorithm, row.version, costs)?;
+            stored.insert(u32::try_from(row.id)?, kdf);
         }
-        for (msg, sender, convo) in Self::kiosk_messages() {
-            db.post_msg(msg, &sender, &convo).await?;
-        }
-        Ok(db)
-    }
-
-    fn kiosk_products() -> Vec<Product> {
-        vec![
-            Product {
-                name: "Orange".to_owned(),
-                seller_id: UserId(2),
-                jumpseller_id: 9_347_673,
-            },
-            Product {
-                name: "Orange Cake".to_owned(),
-                seller_id: UserId(1),
-                jumpseller_id: 9_347_699,
-            },
-        ]
+        Ok(stored)
     }
 
-    fn kiosk_users() -> Vec<UserProfile> {
-        vec![
-            UserProfile::new_clone(1, "john", "John Doe"),
-            UserProfile::new_clone(2, "jane", "Jane Doe"),
-            UserProfile::new_clone(3, "fred", "Fred Nerk"),
-        ]
-    }
-
-    fn kiosk_conversations() -> Vec<(UserId, UserId, ProductId)> {
-        vec![
-            (UserId(1), UserId(2), ProductId(9_347_673)),
-            (UserId(3), UserId(1), ProductId(9_347_699)),
-        ]
-    }
-
-    fn kiosk_messages() -> Vec<(Message, UserId, ConversationId)> {
-        vec![
-            (
-                Message::from("Hi Jane! I would like to buy a few oranges, are they fresh?"),
-                UserId(1),
-                ConversationId(1),
-            ),
-            (
-                Message::from("Yes John! I just collected them this morning!"),
-                UserId(2),
-                ConversationId(1),
-            ),
-            (
-                Message::from("Thank you for the clarification!"),
-                UserId(1),
-                ConversationId(1),
-            ),
-            (
-                Message::from("Hi John! Is your orange cake made from fresh oranges?"),
-                UserId(3),
-                ConversationId(2),
-            ),
-            (
-                Message::from("Yes Fred! I bought them today from Jane!"),
-                UserId(1),
-                ConversationId(2),
-            ),
-            (
-                Message::from("Amazing! That makes me relieved, thank you!"),
-                UserId(3),
-                ConversationId(2),
-            ),
-        ]
+    /// Brings the schema up to date and loads the metadata keys, creating them on first boot.
+    async fn open(pool: Pool<Sqlite>, writer: Pool<Sqlite>, keys: Keyring) -> anyhow::Result<Self> {
+        migrations::adopt_legacy(&writer).await?;
+        migrations::run(&migrations::SQLITE, &writer).await?;
+        let sealer = Sealer::open(&mut *writer.acquire().await?, keys).await?;
+        let db = Self {
+            pool,
+            writer,
+            sealer,
+        };
+        db.bind_legacy_messages().await?;
+        db.chain_legacy_messages().await?;
+        db.encrypt_legacy_profiles().await?;
+        Ok(db)
     }
 
-    async fn set_schema(&mut self) -> anyhow::Result<()> {
-        sqlx::query_file!("src/database/schema.sql")
-            .execute(&self.pool)
+    /// Links the messages written before the hash chain. Messages erased before then are linked
+    /// with empty contents.
+    async fn chain_legacy_messages(&self) -> anyhow::Result<()> {
+        let mut total = 0;
+        loop {
+            let mut transaction = self.begin().await?;
+            // In order, so that previous messages are always linked first.
+            let rows = sqlx::query!(
+                r#"SELECT id as "id!" FROM message WHERE chain_hash IS NULL ORDER BY id LIMIT 256"#
+            )
+            .fetch_all(&mut *transaction)
             .await?;
+            if rows.is_empty() {
+                break;
+            }
+            for row in &rows {
+                link_message(&mut *transaction, row.id).await?;
+            }
+            transaction.commit().await?;
+            total += rows.len();
+        }
+        if total > 0 {
+            log::info!("Linked {total} legacy messages into their conversation's hash chain.");
+        }
         Ok(())
     }
-}
 
-#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
-#[sqlx(transparent)]
-pub struct UserId(pub i64);
+    /// Encrypts the user and product names stored in the clear by earlier versions.
+    async fn encrypt_legacy_profiles(&self) -> anyhow::Result<()> {
+        let mut transaction = self.begin().await?;
+        // Erased users used to keep a pseudonym, now computed when read.
+        sqlx::query!(
+            r#"
+            UPDATE user SET username = NULL, name = NULL, username_index = NULL
+            WHERE erased_at IS NOT NULL AND username IS NOT NULL
+        "#
+        )
+        .execute(&mut *transaction)
+        .await?;
 
-#[derive(Debug, sqlx::Type, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
-pub struct UserProfile {
-    id: i64,
-    username: String,
-    name: String,
-}
+        let users = sqlx::query!(
+            r#"
+            SELECT id as "id!", CAST(username AS TEXT) as "username?: String",
+                CAST(name AS TEXT) as "name?: String"
+            FROM user
+            WHERE typeof(username) = 'text' OR typeof(name) = 'text'
+        "#
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        for user in &users {
+            let index = user
+                .username
+                .as_deref()
+                .map(|username| self.sealer.username_index.digest(username));
+            let username = user
+                .username
+                .as_deref()
+                .map(|username| self.sealer.seal_field(username, "user.username", user.id))
+                .transpose()?;
+            let name = user
+                .name
+                .as_deref()
+                .map(|name| self.sealer.seal_field(name, "user.name", user.id))
+                .transpose()?;
+            sqlx::query!(
+                "UPDATE user SET username = ?, name = ?, username_index = ? WHERE id = ?",
+                username,
+                name,
+                index,
+                user.id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
 
-impl UserProfile {
-    #[allow(dead_code)]
-    pub fn new(jumpseller_id: i64, username: String, name: String) -> Self {
-        Self {
-            username,
-            name,
-            id: jumpseller_id,
+        let products = sqlx::query!(
+            r#"
+            SELECT id as "id!", CAST(name AS TEXT) as "name!: String"
+            FROM product
+            WHERE typeof(name) = 'text'
+        "#
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        for product in &products {
+            let name = self
+                .sealer
+                .seal_field(&product.name, "product.name", product.id)?;
+            sqlx::query!("UPDATE product SET name = ? WHERE id = ?", name, product.id)
+                .execute(&mut *transaction)
+                .await?;
         }
-    }
+        transaction.commit().await?;
 
-    pub fn new_clone(id: i64, username: &str, name: &str) -> Self {
-        Self {
-            username: username.to_owned(),
-            name: name.to_owned(),
-            id,
+        if !users.is_empty() || !products.is_empty() {
+            log::info!(
+                "Encrypted {} legacy user profiles and {} product names.",
+                users.len(),
+                products.len()
+            );
         }
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn username(&self) -> String {
-        self.username.clone()
+    /// Starts a transaction on the writer, taking the database lock right away so that another
+    /// process writing meanwhile makes it wait rather than fail to upgrade a read.
+    async fn begin(&self) -> Result<Transaction<'static, Sqlite>, sqlx::Error> {
+        self.writer.begin_with("BEGIN IMMEDIATE").await
     }
 
-    pub fn id(&self) -> UserId {
-        self.id.into()
-    }
+    /// Re-encrypts the messages written by earlier versions with their sender's data key, bound to
+    /// their metadata (see [`message_aad`]). Those from before data keys were encrypted with the
+    /// master key itself: their sender gets a data key for the conversation.
+    async fn bind_legacy_messages(&self) -> anyhow::Result<()> {
+        // Nothing to bind in erased and end-to-end encrypted messages.
+        sqlx::query!(
+            "UPDATE message SET aad_bound = 1 WHERE aad_bound = 0 AND (content IS NULL OR e2e = 1)"
+        )
+        .execute(&self.writer)
+        .await?;
 
-    #[allow(dead_code)]
-    pub fn name(&self) -> String {
-        self.name.clone()
+        let (mut total, mut last) = (0, 0);
+        loop {
+            let mut transaction = self.begin().await?;
+            let rows = sqlx::query!(
+                r#"
+                SELECT id as "id!", sender_id, conversation_id, content as "content!", salt
+                FROM message
+                WHERE aad_bound = 0 AND id > ?
+                ORDER BY id
+                LIMIT 256
+            "#,
+                last
+            )
+            .fetch_all(&mut *transaction)
+            .await?;
+            let Some(row) = rows.last() else {
+                break;
+            };
+            last = row.id;
+            for row in &rows {
+                let (sender, conversation) =
+                    (UserId(row.sender_id), ConversationId(row.conversation_id));
+                let key = self
+                    .sealer
+                    .sender_key(&mut *transaction, &sender, &conversation)
+                    .await?;
+                let contents: Option<String> = std::iter::once(&key)
+                    .chain(self.sealer.keys.iter().map(|(_, master)| master))
+                    .find_map(|key| {
+                        CryptData::from(row.content.clone())
+                            .decrypt_any(key, &row.salt, &[])
+                            .ok()
+                    });
+                // Left unbound, reading it fails like reading any message that doesn't decrypt.
+                let Some(contents) = contents else {
+                    log::error!("Message #{} doesn't decrypt, left as it is.", row.id);
+                    continue;
+                };
+                let aad = message_aad(row.id, row.conversation_id, row.sender_id);
+                let contents = CryptData::encrypt(contents, &key, &aad, &mut *self.sealer.rng())?;
+                sqlx::query!(
+                    "UPDATE message SET content = ?, salt = x'', aad_bound = 1 WHERE id = ?",
+                    contents,
+                    row.id
+                )
+                .execute(&mut *transaction)
+                .await?;
+                total += 1;
+            }
+            transaction.commit().await?;
+        }
+        if total > 0 {
+            log::info!("Bound {total} legacy messages to their metadata.");
+        }
+        Ok(())
     }
-}
-
-#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
-#[sqlx(transparent)]
-pub struct ConversationId(pub i64);
 
-#[derive(Debug, sqlx::Type, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Message {
-    contents: String,
-    timestamp: DateTime<Utc>,
-}
+    /// Appends `entry` to the audit log within `conn`'s transaction. Transactions on the writer
+    /// are serialized, so no two entries chain onto the same one.
+    async fn audit(&self, conn: &mut SqliteConnection, entry: &AuditEntry) -> Result<(), DbError> {
+        let prev_hash = sqlx::query!("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1")
+            .fetch_optional(&mut *conn)
+            .await?
+            .map_or_else(|| vec![0; 32], |r| r.hash);
 
-impl Message {
-    pub fn new(contents: String, timestamp: DateTime<Utc>) -> Self {
-        Self {
-            contents,
+        let timestamp = Utc::now().to_rfc3339();
+        let hash = entry.hash(&self.sealer.audit_key, &prev_hash, &timestamp)?;
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_log (timestamp, actor_id, action, subject_id, conversation_id, message_id, reason, prev_hash, hash)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
             timestamp,
-        }
-    }
-
-    pub fn contents(&self) -> &str {
-        &self.contents
+            entry.actor_id,
+            entry.action,
+            entry.subject_id,
+            entry.conversation_id,
+            entry.message_id,
+            entry.reason,
+            prev_hash,
+            hash
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
     }
 
-    pub fn timestamp(&self) -> &DateTime<Utc> {
-        &self.timestamp
+    /// Key a new message of `user` in `conversation` is encrypted with, `None` when it is
+    /// end-to-end encrypted by the client instead.
+    async fn message_key(
+        &self,
+        conn: &mut SqliteConnection,
+        user: &UserId,
+        conversation: &ConversationId,
+        e2e: bool,
+    ) -> Result<Option<CryptoKey>, DbError> {
+        if !e2e {
+            return Ok(Some(
+                self.sealer.sender_key(conn, user, conversation).await?,
+            ));
+        }
+        let erased = sqlx::query!("SELECT erased_at FROM user WHERE id = ?", user)
+            .fetch_one(&mut *conn)
+            .await?
+            .erased_at;
+        if erased.is_some() {
+            return Err(DbError::PermissionDenied);
+        }
+        Ok(None)
     }
 }
 
-impl From<&str> for Message {
-    fn from(value: &str) -> Self {
-        let owned = value.to_owned();
-        let timestamp = Utc::now();
-        Self::new(owned, timestamp)
+impl KeyStore for SqliteConnection {
+    async fn canary(&mut self, id: u32) -> Result<Option<Vec<u8>>, DbError> {
+        let stored = sqlx::query!("SELECT canary FROM master_key WHERE id = ?", id)
+            .fetch_optional(&mut *self)
+            .await?;
+        Ok(stored.map(|stored| stored.canary))
     }
-}
 
-#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
-#[sqlx(transparent)]
-pub struct MessageId(pub i64);
+    async fn store_canary(
+        &mut self,
+        id: u32,
+        canary: &[u8],
+        kdf: Option<&KdfParams>,
+    ) -> Result<(), DbError> {
+        let (algorithm, version) = (
+            kdf.map(|kdf| kdf.algorithm.as_str()),
+            kdf.map(|kdf| u32::from(kdf.version)),
+        );
+        let (memory, iterations, parallelism) = (
+            kdf.map(|kdf| kdf.memory_kib),
+            kdf.map(|kdf| kdf.iterations),
+            kdf.map(|kdf| kdf.parallelism),
+        );
+        let now = Utc::now();
+        sqlx::query!(
+            r#"
+            INSERT INTO master_key (id, canary, kdf_algorithm, kdf_version, kdf_memory_kib,
+                kdf_iterations, kdf_parallelism, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+            id,
+            canary,
+            algorithm,
+            version,
+            memory,
+            iterations,
+            parallelism,
+            now
+        )
+        .execute(&mut *self)
+        .await?;
+        Ok(())
+    }
 
-#[derive(Debug, sqlx::Type, PartialEq, Copy, Clone, serde::Serialize, serde::Deserialize)]
-#[sqlx(transparent)]
-pub struct ProductId(pub i64);
+    /// Wrapped data keys, or messages from before data keys, all written with the first key.
+    async fn written_with(&mut self, id: u32) -> Result<Written, DbError> {
+        let wrapped = sqlx::query!(
+            "SELECT key, nonce FROM conversation_key WHERE key_id = ? LIMIT 1",
+            id
+        )
+        .fetch_optional(&mut *self)
+        .await?;
+        if let Some(wrapped) = wrapped {
+            return Ok(Written::ConversationKey(WrappedKey {
+                key: wrapped.key,
+                nonce: wrapped.nonce,
+                key_id: id.into(),
+            }));
+        }
+        let wrapped = sqlx::query!(
+            r#"SELECT purpose as "purpose!", key FROM metadata_key WHERE key_id = ? LIMIT 1"#,
+            id
+        )
+        .fetch_optional(&mut *self)
+        .await?;
+        if let Some(wrapped) = wrapped {
+            return Ok(Written::MetadataKey {
+                purpose: wrapped.purpose,
+                key: wrapped.key,
+            });
+        }
+        if id != 1 {
+            return Ok(Written::Nothing);
+        }
+        let messages = sqlx::query!(
+            r#"
+            SELECT content as "content!", salt
+            FROM message
+            WHERE content IS NOT NULL AND e2e = 0 AND length(salt) = 12
+            LIMIT 16
+        "#
+        )
+        .fetch_all(&mut *self)
+        .await?;
+        Ok(if messages.is_empty() {
+            Written::Nothing
+        } else {
+            Written::LegacyMessages(messages.into_iter().map(|m| (m.content, m.salt)).collect())
+        })
+    }
 
-#[derive(Debug, sqlx::Type, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Product {
-    pub(crate) name: String,
-    pub(crate) seller_id: UserId,
-    pub(crate) jumpseller_id: i64,
-}
+    async fn metadata_key(&mut self, purpose: &str) -> Result<Option<(Vec<u8>, i64)>, DbError> {
+        let record = sqlx::query!(
+            "SELECT key, key_id FROM metadata_key WHERE purpose = ?",
+            purpose
+        )
+        .fetch_optional(&mut *self)
+        .await?;
+        Ok(record.map(|record| (record.key, record.key_id)))
+    }
 
-impl Product {
-    pub fn new(name: String, seller_id: UserId, jumpseller_id: i64) -> Self {
-        Self {
-            name,
-            seller_id,
-            jumpseller_id,
-        }
+    async fn store_metadata_key(
+        &mut self,
+        purpose: &str,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError> {
+        sqlx::query!(
+            "INSERT INTO metadata_key (purpose, key, key_id) VALUES (?, ?, ?) ON CONFLICT DO NOTHING",
+            purpose,
+            wrapped,
+            key_id
+        )
+        .execute(&mut *self)
+        .await?;
+        Ok(())
     }
-    pub fn product_info(&self) -> i64 {
-        self.jumpseller_id
+
+    async fn stale_metadata_keys(
+        &mut self,
+        active: u32,
+    ) -> Result<Vec<(String, Vec<u8>, i64)>, DbError> {
+        let stale = sqlx::query!(
+            r#"SELECT purpose as "purpose!", key, key_id FROM metadata_key WHERE key_id != ?"#,
+            active
+        )
+        .fetch_all(&mut *self)
+        .await?;
+        Ok(stale
+            .into_iter()
+            .map(|row| (row.purpose, row.key, row.key_id))
+            .collect())
     }
-}
 
-impl From<i64> for UserId {
-    fn from(value: i64) -> Self {
-        Self(value)
+    async fn rewrap_metadata_key(
+        &mut self,
+        purpose: &str,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError> {
+        sqlx::query!(
+            "UPDATE metadata_key SET key = ?, key_id = ? WHERE purpose = ?",
+            wrapped,
+            key_id,
+            purpose
+        )
+        .execute(&mut *self)
+        .await?;
+        Ok(())
     }
-}
 
-impl From<i64> for ConversationId {
-    fn from(value: i64) -> Self {
-        Self(value)
+    async fn conversation_key(
+        &mut self,
+        user: &UserId,
+        conversation: &ConversationId,
+    ) -> Result<(bool, Option<WrappedKey>), DbError> {
+        let record = sqlx::query!(
+            r#"
+            SELECT user.erased_at, conversation_key.key as "key?", conversation_key.nonce as "nonce?",
+                conversation_key.key_id as "key_id?"
+            FROM user LEFT JOIN conversation_key
+                ON conversation_key.user_id = user.id AND conversation_key.conversation_id = ?
+            WHERE user.id = ?
+        "#,
+            conversation,
+            user
+        )
+        .fetch_one(&mut *self)
+        .await?;
+        let wrapped = match (record.key, record.nonce, record.key_id) {
+            (Some(key), Some(nonce), Some(key_id)) => Some(WrappedKey { key, nonce, key_id }),
+            _ => None,
+        };
+        Ok((record.erased_at.is_some(), wrapped))
     }
-}
 
-impl From<i64> for ProductId {
-    fn from(value: i64) -> Self {
-        Self(value)
+    async fn store_conversation_key(
+        &mut self,
+        user: &UserId,
+        conversation: &ConversationId,
+        wrapped: &[u8],
+        key_id: u32,
+    ) -> Result<(), DbError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO conversation_key (conversation_id, user_id, key, nonce, key_id)
+            VALUES (?, ?, ?, x'', ?)
+        "#,
+            conversation,
+            user,
+            wrapped,
+            key_id
+        )
+        .execute(&mut *self)
+        .await?;
+        Ok(())
     }
-}
 
-impl From<i64> for MessageId {
-    fn from(value: i64) -> Self {
-        Self(value)
+    async fn chain_link(&mut self, id: i64) -> Result<ChainLink, DbError> {
+        let row = sqlx::query!(
+            r#"
+            SELECT message.sender_id as "sender_id!", message.timestamp as "timestamp!: NaiveDateTime",
+                message.content as "content?", prev.chain_hash as "prev_hash?"
+            FROM message LEFT JOIN message AS prev ON prev.id = message.previous_message_id
+            WHERE message.id = ?
+        "#,
+            id
+        )
+        .fetch_one(&mut *self)
+        .await?;
+        Ok(ChainLink {
+            sender_id: row.sender_id,
+            timestamp: row.timestamp,
+            content: row.content,
+            prev_hash: row.prev_hash,
+        })
     }
-}
 
-#[derive(Debug, thiserror::Error)]
-pub enum DbError {
-    #[error(transparent)]
-    Db(#[from] sqlx::Error),
-    #[error("Attempted to access something without needed priviledges")]
-    PermissionDenied,
-    #[error("Wrong Salt Size. Must be exactly 12 bytes.")]
-    SaltWrongSize,
-    #[error(transparent)]
-    Crypto(#[from] CryptError),
+    async fn store_chain_hash(
+        &mut self,
+        id: i64,
+        content_hash: &[u8],
+        hash: &[u8],
+    ) -> Result<(), DbError> {
+        sqlx::query!(
+            "UPDATE message SET content_hash = ?, chain_hash = ? WHERE id = ?",
+            content_hash,
+            hash,
+            id
+        )
+        .execute(&mut *self)
+        .await?;
+        Ok(())
+    }
 }
 
 #[allow(dead_code)]
 pub struct Querier<'a> {
     q: &'a Pool<Sqlite>,
     key: &'a CryptoKey,
-    rng: &'a StdRng,
+    rng: &'a Mutex<StdRng>,
 }
 
 impl Deref for Querier<'_> {
@@ -290,43 +595,6 @@ impl Deref for Querier<'_> {
     }
 }
 
-impl ResponseError for DbError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
-        match &self {
-            DbError::Db(error) => match error {
-                sqlx::Error::InvalidSavePointStatement | sqlx::Error::InvalidArgument(_) => {
-                    StatusCode::BAD_REQUEST
-                }
-                sqlx::Error::ColumnIndexOutOfBounds { index: _, len: _ }
-                | sqlx::Error::ColumnDecode {
-                    index: _,
-                    source: _,
-                }
-                | sqlx::Error::Encode(_)
-                | sqlx::Error::Decode(_)
-                | sqlx::Error::AnyDriverError(_)
-                | sqlx::Error::PoolTimedOut
-                | sqlx::Error::PoolClosed
-                | sqlx::Error::Migrate(_)
-                | sqlx::Error::BeginFailed
-                | sqlx::Error::ColumnNotFound(_)
-                | sqlx::Error::TypeNotFound { type_name: _ }
-                | sqlx::Error::Configuration(_)
-                | sqlx::Error::Database(_)
-                | sqlx::Error::Io(_)
-                | sqlx::Error::Tls(_)
-                | sqlx::Error::Protocol(_) => StatusCode::INTERNAL_SERVER_ERROR,
-
-                sqlx::Error::WorkerCrashed => StatusCode::TOO_MANY_REQUESTS,
-                sqlx::Error::RowNotFound => StatusCode::NO_CONTENT,
-                _ => StatusCode::IM_A_TEAPOT,
-            },
-            DbError::PermissionDenied => StatusCode::FORBIDDEN,
-            DbError::SaltWrongSize => StatusCode::INTERNAL_SERVER_ERROR,
-            DbError::Crypto(e) => e.status_code(),
-        }
-    }
-}
 impl Database for SQLiteDB {
     type Error = DbError;
 
@@ -344,6 +612,22 @@ impl Database for SQLiteDB {
 
     type Product = Product;
 
+    type StoreId = StoreId;
+
+    type Store = Store;
+
+    type ConversationStatus = ConversationStatus;
+
+    type ConversationFilter = ConversationFilter;
+
+    type AuditEntry = AuditEntry;
+
+    type AuditRecord = AuditRecord;
+
+    type AuditFilter = AuditFilter;
+
+    type ChainHead = ChainHead;
+
     type Querier<'a> = Querier<'a>;
 
     async fn get_conversations(
@@ -354,8 +638,10 @@ impl Database for SQLiteDB {
             r#"
             SELECT id as "id!"
             FROM conversation 
-            WHERE client_id = ? OR seller_id = ?;
+            WHERE client_id = ? OR seller_id = ?
+                OR store_id IN (SELECT store_id FROM store_member WHERE user_id = ?);
         "#,
+            my_id,
             my_id,
             my_id
         )
@@ -365,6 +651,23 @@ impl Database for SQLiteDB {
         Ok(record.iter().map(|r| ConversationId(r.id)).collect())
     }
 
+    async fn filter_conversations(
+        &self,
+        my_id: &Self::UserId,
+        filter: &Self::ConversationFilter,
+    ) -> Result<Vec<Self::ConversationId>, Self::Error> {
+        let record = sqlx::query_file!(
+            "src/database/queries/sqlite/filter_conversations.sql",
+            my_id,
+            filter.status,
+            filter.label
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(record.iter().map(|r| ConversationId(r.id)).collect())
+    }
+
     async fn get_peer(
         &self,
         my_id: &Self::UserId,
@@ -374,21 +677,22 @@ impl Database for SQLiteDB {
             r#"
             SELECT client_id as "client_id!", seller_id as "seller_id!"
             FROM conversation
-            WHERE id = ? AND (client_id = ? OR seller_id = ?)
+            WHERE id = ? AND (client_id = ? OR seller_id = ?
+                OR store_id IN (SELECT store_id FROM store_member WHERE user_id = ?))
         "#,
             conversation,
             my_id,
+            my_id,
             my_id
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        if record.client_id == my_id.0 {
-            Ok(UserId(record.seller_id))
-        } else if record.seller_id == my_id.0 {
-            Ok(UserId(record.client_id))
-        } else {
-            Err(DbError::PermissionDenied)
+        // Staff members of the store answer on behalf of the seller, so their peer is the client.
+        match record {
+            Some(r) if r.client_id == my_id.0 => Ok(UserId(r.seller_id)),
+            Some(r) => Ok(UserId(r.client_id)),
+            None => Err(DbError::PermissionDenied),
         }
     }
 
@@ -396,107 +700,174 @@ impl Database for SQLiteDB {
         &self,
         their_id: &Self::UserId,
     ) -> Result<Self::UserProfile, Self::Error> {
-        sqlx::query_as!(
-            UserProfile,
+        let record = sqlx::query!(
             r#"
-            SELECT username as "username!", name as "name!", id as "id!"
+            SELECT id as "id!", username as "username: Vec<u8>", name as "name: Vec<u8>", erased_at
             FROM user
             WHERE id = ?
         "#,
             their_id
         )
         .fetch_one(&self.pool)
-        .await
-        .map_err(Into::into)
+        .await?;
+        if record.erased_at.is_some() {
+            return Ok(UserProfile::erased(record.id));
+        }
+        let open = |value: Option<Vec<u8>>, column| {
+            value
+                .map(|value| self.sealer.open_field(value, column, record.id))
+                .transpose()
+                .map(Option::unwrap_or_default)
+        };
+        Ok(UserProfile {
+            id: record.id,
+            username: open(record.username, "user.username")?,
+            name: open(record.name, "user.name")?,
+        })
     }
 
     async fn get_message(
         &self,
         message: &Self::MessageId,
     ) -> Result<(Self::UserId, Self::Message, Option<Self::MessageId>), Self::Error> {
-        let result = sqlx::query!(
+        let row = sqlx::query_as!(
+            StoredMessage,
             r#"
-            SELECT sender_id as "sender_id!", content as "content!", timestamp as "timestamp!", salt as "salt!", previous_message_id
-            FROM message
+            SELECT id as "id!", sender_id as "sender_id!", message.conversation_id as "conversation_id!",
+                content as "content?", salt as "salt!", timestamp as "timestamp!", previous_message_id, e2e as "e2e: bool",
+                CASE WHEN read_at IS NOT NULL THEN 'read' WHEN delivered_at IS NOT NULL THEN 'delivered' ELSE 'sent' END as "status!: DeliveryStatus",
+                conversation_key.key as "sender_key?", conversation_key.nonce as "sender_key_nonce?", conversation_key.key_id as "sender_key_id?"
+            FROM message LEFT JOIN conversation_key
+                ON conversation_key.user_id = message.sender_id AND conversation_key.conversation_id = message.conversation_id
             WHERE id = ?
         "#,
             message
         )
         .fetch_one(&self.pool)
-        .await;
+        .await?;
 
-        match result {
-            Ok(res) => {
-                let contents = CryptData::from(res.content).decrypt(
-                    &self.suite,
-                    &res.salt.try_into().map_err(|_| DbError::SaltWrongSize)?,
-                )?;
-                Ok((
-                    UserId(res.sender_id),
-                    Message::new(contents, res.timestamp.and_utc()),
-                    res.previous_message_id.map(MessageId),
-                ))
-            }
-            Err(e) => Err(e.into()),
-        }
+        let sender = UserId(row.sender_id);
+        let previous = row.previous_message_id.map(MessageId);
+        Ok((sender, open_message(&self.sealer.keys, row)?, previous))
+    }
+
+    async fn get_messages(
+        &self,
+        messages: &[Self::MessageId],
+    ) -> Result<
+        Vec<(
+            Self::MessageId,
+            Self::UserId,
+            Self::Message,
+            Option<Self::MessageId>,
+        )>,
+        Self::Error,
+    > {
+        let ids = serde_json::to_string(&messages).map_err(|e| sqlx::Error::Encode(e.into()))?;
+        let rows = sqlx::query_as!(
+            StoredMessage,
+            r#"
+            SELECT id as "id!", sender_id as "sender_id!", message.conversation_id as "conversation_id!",
+                content as "content?", salt as "salt!", timestamp as "timestamp!", previous_message_id, e2e as "e2e: bool",
+                CASE WHEN read_at IS NOT NULL THEN 'read' WHEN delivered_at IS NOT NULL THEN 'delivered' ELSE 'sent' END as "status!: DeliveryStatus",
+                conversation_key.key as "sender_key?", conversation_key.nonce as "sender_key_nonce?", conversation_key.key_id as "sender_key_id?"
+            FROM message LEFT JOIN conversation_key
+                ON conversation_key.user_id = message.sender_id AND conversation_key.conversation_id = message.conversation_id
+            WHERE id IN (SELECT value FROM json_each(?))
+            ORDER BY id
+        "#,
+            ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let (id, sender) = (MessageId(row.id), UserId(row.sender_id));
+                let previous = row.previous_message_id.map(MessageId);
+                Ok((id, sender, open_message(&self.sealer.keys, row)?, previous))
+            })
+            .collect()
     }
 
     async fn get_most_recent_messages(
         &self,
         conversation_id: &Self::ConversationId,
-    ) -> Result<(Vec<(Self::UserId, Self::Message)>, Option<Self::MessageId>), Self::Error> {
-        let result = sqlx::query!(r#"
-            WITH id_asc as (
-                SELECT id, sender_id, content, salt, timestamp, previous_message_id
-                FROM message
-                WHERE conversation_id = ?
-                ORDER BY id desc
-                LIMIT 32
-            )
-            SELECT sender_id as "sender_id!", content as "content!", salt as "salt!", timestamp as "timestamp!", previous_message_id FROM id_asc ORDER BY id
-        "#,
+    ) -> Result<
+        (
+            Vec<(Self::MessageId, Self::UserId, Self::Message)>,
+            Option<Self::MessageId>,
+        ),
+        Self::Error,
+    > {
+        let rows = sqlx::query_file_as!(
+            StoredMessage,
+            "src/database/queries/sqlite/most_recent_messages.sql",
             conversation_id
-        ).fetch_all(&self.pool)
-        .await;
+        )
+        .fetch_all(&self.pool)
+        .await?;
 
-        match result {
-            Ok(res) => Ok((
-                res.iter()
-                    .map(|record| -> Result<(Self::UserId, Self::Message), DbError> {
-                        let contents = CryptData::from(record.content.clone()).decrypt(
-                            &self.suite,
-                            &record
-                                .salt
-                                .clone()
-                                .try_into()
-                                .map_err(|_| DbError::SaltWrongSize)?,
-                        )?;
-                        let timestamp = record.timestamp.and_utc();
-                        Ok((UserId(record.sender_id), Message::new(contents, timestamp)))
-                    })
-                    .collect::<Result<Vec<_>, DbError>>()?,
-                res.first()
-                    .and_then(|x| x.previous_message_id)
-                    .map(MessageId),
-            )),
-            Err(e) => Err(e.into()),
-        }
+        let previous = rows
+            .first()
+            .and_then(|x| x.previous_message_id)
+            .map(MessageId);
+        let messages = rows
+            .into_iter()
+            .map(|row| {
+                let (id, sender) = (MessageId(row.id), UserId(row.sender_id));
+                Ok((id, sender, open_message(&self.sealer.keys, row)?))
+            })
+            .collect::<Result<Vec<_>, DbError>>()?;
+        Ok((messages, previous))
+    }
+
+    async fn get_messages_after(
+        &self,
+        conversation_id: &Self::ConversationId,
+        after: Option<&Self::MessageId>,
+        limit: u32,
+    ) -> Result<Vec<(Self::MessageId, Self::UserId, Self::Message)>, Self::Error> {
+        let after = after.map_or(0, |x| x.0);
+        let rows = sqlx::query_file_as!(
+            StoredMessage,
+            "src/database/queries/sqlite/messages_after.sql",
+            conversation_id,
+            after,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                let (id, sender) = (MessageId(row.id), UserId(row.sender_id));
+                Ok((id, sender, open_message(&self.sealer.keys, row)?))
+            })
+            .collect()
     }
 
     async fn get_querier(&self) -> Result<Self::Querier<'_>, Self::Error> {
         Ok(Querier {
             q: &self.pool,
-            key: &self.suite,
-            rng: &self.rng,
+            key: self.sealer.keys.active(),
+            rng: &self.sealer.rng,
         })
     }
 
-    async fn add_user(&mut self, profile: &Self::UserProfile) -> Result<Self::UserId, Self::Error> {
-        let mut transaction = self.pool.begin().await?;
+    async fn add_user(&self, profile: &Self::UserProfile) -> Result<Self::UserId, Self::Error> {
+        let username = self
+            .sealer
+            .seal_field(&profile.username, "user.username", profile.id)?;
+        let name = self
+            .sealer
+            .seal_field(&profile.name, "user.name", profile.id)?;
+        let username_index = self.sealer.username_index.digest(&profile.username);
+        let mut transaction = self.begin().await?;
 
         let record = sqlx::query!(
             r#"
-            SELECT id as "id!"
+            SELECT id as "id!", erased_at
             FROM user
             WHERE id = ?;
         "#,
@@ -506,30 +877,37 @@ impl Database for SQLiteDB {
         .await?;
 
         if let Some(user) = record {
+            // Erased users keep their pseudonym, whatever Jumpseller still says about them.
+            if user.erased_at.is_some() {
+                return Ok(UserId(user.id));
+            }
             sqlx::query!(
                 r#"
                 UPDATE user
-                SET username = ?, name = ?
+                SET username = ?, name = ?, username_index = ?
                 WHERE id = ?;
             "#,
-                profile.username,
-                profile.name,
+                username,
+                name,
+                username_index,
                 user.id
             )
             .execute(&mut *transaction)
             .await?;
+            transaction.commit().await?;
             return Ok(UserId(user.id));
         }
 
         let record = sqlx::query!(
             r#"
-            INSERT INTO user (id, username, name)
-            VALUES (?, ?, ?)
+            INSERT INTO user (id, username, name, username_index)
+            VALUES (?, ?, ?, ?)
             RETURNING id as "id!"
         "#,
             profile.id,
-            profile.username,
-            profile.name
+            username,
+            name,
+            username_index
         )
         .fetch_one(&mut *transaction)
         .await;
@@ -539,23 +917,35 @@ impl Database for SQLiteDB {
     }
 
     async fn start_conversation(
-        &mut self,
+        &self,
         my_id: &Self::UserId,
         their_id: &Self::UserId,
         prod_id: &Self::ProductId,
     ) -> Result<Self::ConversationId, Self::Error> {
-        let mut transaction = self.pool.begin().await?;
+        let mut transaction = self.begin().await?;
 
-        let record = sqlx::query!(
+        // Conversations about a seller's products go to the shared inbox of their primary store.
+        let store_id = sqlx::query!(
             r#"
-            SELECT id as "id!"
-            FROM conversation
-            WHERE ((client_id = ? AND seller_id = ?) OR (seller_id = ? AND client_id = ?)) AND product_id = ?;
+            SELECT user.primary_store_id
+            FROM product
+            JOIN user ON user.id = product.seller_id
+            WHERE product.id = ?;
         "#,
+            prod_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .and_then(|r| r.primary_store_id);
+
+        let record = sqlx::query_file!(
+            "src/database/queries/sqlite/find_conversation.sql",
             my_id,
             their_id,
             my_id,
             their_id,
+            my_id,
+            store_id,
             prod_id,
         )
         .fetch_optional(&mut *transaction)
@@ -567,13 +957,14 @@ impl Database for SQLiteDB {
 
         let record = sqlx::query!(
             r#"
-            INSERT INTO conversation (client_id, seller_id, product_id)
-            VALUES (?, ?, ?)
+            INSERT INTO conversation (client_id, seller_id, product_id, store_id)
+            VALUES (?, ?, ?, ?)
             RETURNING id as "id!"
         "#,
             my_id,
             their_id,
-            prod_id
+            prod_id,
+            store_id
         )
         .fetch_one(&mut *transaction)
         .await;
@@ -583,48 +974,99 @@ impl Database for SQLiteDB {
     }
 
     async fn post_msg(
-        &mut self,
+        &self,
         msg: Self::Message,
         my_id: &Self::UserId,
         conversation: &Self::ConversationId,
     ) -> Result<Self::MessageId, Self::Error> {
-        let mut transaction = self.pool.begin().await?;
+        self.post_msg_idempotent(msg, my_id, conversation, None)
+            .await
+            .map(|(id, _)| id)
+    }
+
+    async fn post_msg_idempotent(
+        &self,
+        msg: Self::Message,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        client_message_id: Option<&str>,
+    ) -> Result<(Self::MessageId, bool), Self::Error> {
+        let mut transaction = self.begin().await?;
 
-        let prev_id = sqlx::query!(
+        let existing = sqlx::query!(
             r#"
-            SELECT last_message_id
+            SELECT id as "id!"
+            FROM message
+            WHERE sender_id = ? AND conversation_id = ? AND client_message_id = ?;
+        "#,
+            my_id,
+            conversation,
+            client_message_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        if let Some(msg) = existing {
+            return Ok((MessageId(msg.id), false));
+        }
+
+        let record = sqlx::query!(
+            r#"
+            SELECT last_message_id, e2e as "e2e: bool"
             FROM conversation
             WHERE id = ?;
         "#,
             conversation
         )
         .fetch_one(&mut *transaction)
-        .await?
-        .last_message_id;
+        .await?;
+        let prev_id = record.last_message_id;
 
-        let (contents, salt) =
-            CryptData::encrypt(msg.contents().to_owned(), &self.suite, &mut self.rng)?;
-        let salt = salt.to_vec();
+        let key = self
+            .message_key(&mut transaction, my_id, conversation, record.e2e)
+            .await?;
+        // Already encrypted by the client, for keys the server never sees: stored as is.
+        let e2e_payload = record.e2e.then(|| msg.contents().as_bytes().to_vec());
 
         let timestamp = *msg.timestamp();
 
         let msg_id = sqlx::query!(
             r#"
-            INSERT INTO message (content, salt, sender_id, conversation_id, previous_message_id, timestamp)
-            VALUES (?, ?, ?, ?, ?,?)
+            INSERT INTO message (content, salt, sender_id, conversation_id, previous_message_id, timestamp, client_message_id, e2e, aad_bound)
+            VALUES (?, x'', ?, ?, ?, ?, ?, ?, 1)
             RETURNING id as "id!"
         "#,
-            contents,
-            salt,
+            e2e_payload,
             my_id,
             conversation,
             prev_id,
             timestamp,
+            client_message_id,
+            record.e2e,
         )
         .fetch_one(&mut *transaction)
         .await?
         .id;
 
+        // The ciphertext is bound to the row's id, so it can only be written once the row exists.
+        if let Some(key) = key {
+            let aad = message_aad(msg_id, conversation.0, my_id.0);
+            let contents = CryptData::encrypt(
+                msg.contents().to_owned(),
+                &key,
+                &aad,
+                &mut *self.sealer.rng(),
+            )?;
+            sqlx::query!(
+                "UPDATE message SET content = ? WHERE id = ?",
+                contents,
+                msg_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        link_message(&mut *transaction, msg_id).await?;
+
         sqlx::query!(
             r#"
             UPDATE conversation
@@ -637,8 +1079,23 @@ impl Database for SQLiteDB {
         .execute(&mut *transaction)
         .await?;
 
+        // A message from the other side reopens a conversation that was waiting or resolved. The
+        // seller and the store's staff are on the same side.
+        sqlx::query!(
+            r#"
+            UPDATE conversation
+            SET status = 'open', status_updated_by = NULL
+            WHERE id = ? AND status != 'open'
+                AND (status_updated_by IS NULL OR (status_updated_by = client_id) != (? = client_id));
+        "#,
+            conversation,
+            my_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
         transaction.commit().await?;
-        Ok(MessageId(msg_id))
+        Ok((MessageId(msg_id), true))
     }
 
     async fn get_latest_message(
@@ -660,13 +1117,14 @@ impl Database for SQLiteDB {
     }
 
     async fn get_user_id_from_username(&self, username: &str) -> Result<Self::UserId, Self::Error> {
+        let index = self.sealer.username_index.digest(username);
         let record = sqlx::query!(
             r#"
             SELECT id as "id!"
             FROM user
-            WHERE user.username = ?;  
+            WHERE user.username_index = ?;
           "#,
-            username
+            index
         )
         .fetch_one(&self.pool)
         .await?;
@@ -683,11 +1141,13 @@ impl Database for SQLiteDB {
             SELECT EXISTS (
                 SELECT conversation.id as "id!"
                 FROM conversation
-                WHERE conversation.id = ? AND (client_id = ? OR seller_id = ?)
+                WHERE conversation.id = ? AND (client_id = ? OR seller_id = ?
+                    OR store_id IN (SELECT store_id FROM store_member WHERE user_id = ?))
             ) as is_there
           "#,
             conversation,
             id,
+            id,
             id
         )
         .fetch_one(&self.pool)
@@ -716,18 +1176,45 @@ impl Database for SQLiteDB {
         Ok(ConversationId(record.conversation_id))
     }
 
+    async fn get_conversations_from_messages(
+        &self,
+        msg_ids: &[Self::MessageId],
+    ) -> Result<Vec<(Self::MessageId, Self::ConversationId)>, Self::Error> {
+        let ids = serde_json::to_string(&msg_ids).map_err(|e| sqlx::Error::Encode(e.into()))?;
+        let record = sqlx::query!(
+            r#"
+            SELECT id as "id!", conversation_id as "conversation_id!"
+            FROM message
+            WHERE id IN (SELECT value FROM json_each(?))
+            "#,
+            ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(record
+            .iter()
+            .map(|r| (MessageId(r.id), ConversationId(r.conversation_id)))
+            .collect())
+    }
+
     async fn get_product(&self, prod_id: &Self::ProductId) -> Result<Self::Product, Self::Error> {
-        Ok(sqlx::query_as!(
-            Product,
+        let record = sqlx::query!(
             r#"
-                SELECT name as "name!", id as "jumpseller_id!", seller_id as "seller_id!" 
+                SELECT name as "name: Vec<u8>", id as "jumpseller_id!", seller_id
                 FROM product
                 WHERE product.id = ?
             "#,
             prod_id
         )
         .fetch_one(&self.pool)
-        .await?)
+        .await?;
+        Ok(Product {
+            name: self
+                .sealer
+                .open_field(record.name, "product.name", record.jumpseller_id)?,
+            seller_id: UserId(record.seller_id),
+            jumpseller_id: record.jumpseller_id,
+        })
     }
 
     async fn get_product_id_from_conversation_id(
@@ -747,11 +1234,11 @@ impl Database for SQLiteDB {
         Ok(ProductId(record.product_id))
     }
 
-    async fn add_product(
-        &mut self,
-        product: &Self::Product,
-    ) -> Result<Self::ProductId, Self::Error> {
-        let mut transaction = self.pool.begin().await?;
+    async fn add_product(&self, product: &Self::Product) -> Result<Self::ProductId, Self::Error> {
+        let name = self
+            .sealer
+            .seal_field(&product.name, "product.name", product.jumpseller_id)?;
+        let mut transaction = self.begin().await?;
 
         let record = sqlx::query!(
             r#"
@@ -771,7 +1258,7 @@ impl Database for SQLiteDB {
                     SET name = ?, seller_id = ?
                     WHERE id = ?
                 "#,
-                product.name,
+                name,
                 product.seller_id,
                 r.id
             )
@@ -785,7 +1272,7 @@ impl Database for SQLiteDB {
                     VALUES(?,?,?)
                     RETURNING id as "id!"
                 "#,
-                product.name,
+                name,
                 product.jumpseller_id,
                 product.seller_id
             )
@@ -797,6 +1284,32 @@ impl Database for SQLiteDB {
         Ok(prod)
     }
 
+    async fn get_seller_products(
+        &self,
+        seller: &Self::UserId,
+    ) -> Result<Vec<Self::Product>, Self::Error> {
+        let records = sqlx::query!(
+            r#"
+                SELECT name as "name: Vec<u8>", id as "jumpseller_id!"
+                FROM product
+                WHERE seller_id = ?
+                ORDER BY id
+            "#,
+            seller
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        records
+            .into_iter()
+            .map(|r| {
+                let name = self
+                    .sealer
+                    .open_field(r.name, "product.name", r.jumpseller_id)?;
+                Ok(Product::new(name, *seller, r.jumpseller_id))
+            })
+            .collect()
+    }
+
     async fn belongs_to_seller(
         &self,
         seller_id: &Self::UserId,
@@ -818,156 +1331,876 @@ impl Database for SQLiteDB {
             None => Err(DbError::PermissionDenied),
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use anyhow::anyhow;
+    async fn add_store(
+        &self,
+        owner_id: &Self::UserId,
+        store: &Self::Store,
+    ) -> Result<Self::StoreId, Self::Error> {
+        let mut transaction = self.begin().await?;
 
-    use crate::database::Database;
-    use crate::database::crypto::CryptoKey;
-    use crate::database::sqlite::*;
+        let store_id = sqlx::query!(
+            r#"
+                INSERT INTO store (name)
+                VALUES (?)
+                RETURNING id as "id!"
+            "#,
+            store.name
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .id;
 
-    type ResultInfoNeededDecrypt = Result<(CryptData<String>, [u8; 12], DateTime<Utc>), DbError>;
+        sqlx::query!(
+            r#"
+                INSERT INTO store_member (store_id, user_id, owner)
+                VALUES (?, ?, TRUE);
+                UPDATE user SET primary_store_id = ? WHERE id = ? AND primary_store_id IS NULL;
+            "#,
+            store_id,
+            owner_id,
+            store_id,
+            owner_id
+        )
+        .execute(&mut *transaction)
+        .await?;
 
-    #[tokio::test]
-    async fn test_sqlite() -> anyhow::Result<()> {
-        let alice = UserProfile {
-            id: 1,
-            username: "alice_11".to_owned(),
-            name: "Alice Arnold".to_owned(),
-        };
+        transaction.commit().await?;
+        Ok(StoreId(store_id))
+    }
 
-        let bob = UserProfile {
-            id: 2,
-            username: "bobert22".to_owned(),
-            name: "Bob Bellows".to_owned(),
-        };
+    async fn add_store_member(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+        user_id: &Self::UserId,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.begin().await?;
 
-        let password = "very_$ecure_and_$trong_P4$$w0rd_in_2025";
-        let salt = "even_more_$ecure_$alt";
-        let suite = CryptoKey::new(password, salt).map_err(|e| anyhow!("Error: {e}"))?;
+        let is_owner = sqlx::query!(
+            r#"
+                SELECT owner as "owner: bool"
+                FROM store_member
+                WHERE store_id = ? AND user_id = ?
+            "#,
+            store_id,
+            my_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .is_some_and(|r| r.owner);
+        if !is_owner {
+            return Err(DbError::PermissionDenied);
+        }
 
-        let mut db = SQLiteDB::new("sqlite::memory:", suite).await?;
+        sqlx::query!(
+            r#"
+                INSERT OR IGNORE INTO store_member (store_id, user_id)
+                VALUES (?, ?);
+                UPDATE user SET primary_store_id = ? WHERE id = ? AND primary_store_id IS NULL;
+            "#,
+            store_id,
+            user_id,
+            store_id,
+            user_id
+        )
+        .execute(&mut *transaction)
+        .await?;
 
-        let alice_id = db.add_user(&alice).await?;
-        let bob_id = db.add_user(&bob).await?;
-        assert_ne!(alice_id, bob_id);
+        transaction.commit().await?;
+        Ok(())
+    }
 
-        let alice_again = db.add_user(&alice).await?;
-        assert_eq!(alice_id, alice_again);
+    async fn set_primary_store(
+        &self,
+        my_id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error> {
+        let updated = sqlx::query!(
+            r#"
+                UPDATE user SET primary_store_id = ?1
+                WHERE id = ?2 AND EXISTS (
+                    SELECT user_id FROM store_member WHERE store_id = ?1 AND user_id = ?2
+                )
+            "#,
+            store_id,
+            my_id
+        )
+        .execute(&self.writer)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Err(DbError::PermissionDenied);
+        }
+        Ok(())
+    }
 
-        let prod = Product {
-            name: "Dill Dough".to_string(),
-            seller_id: 1.into(),
-            jumpseller_id: 1,
-        };
-        let prod_id = db.add_product(&prod).await?;
+    async fn get_store_members(
+        &self,
+        store_id: &Self::StoreId,
+    ) -> Result<Vec<Self::UserId>, Self::Error> {
+        let record = sqlx::query!(
+            r#"
+                SELECT user_id as "user_id!"
+                FROM store_member
+                WHERE store_id = ?
+                ORDER BY user_id
+            "#,
+            store_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(record.iter().map(|r| UserId(r.user_id)).collect())
+    }
 
-        let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
-        let same_id = db.start_conversation(&bob_id, &alice_id, &prod_id).await?;
+    async fn get_user_stores(
+        &self,
+        user: &Self::UserId,
+    ) -> Result<Vec<(Self::StoreId, Self::Store)>, Self::Error> {
+        let record = sqlx::query!(
+            r#"
+                SELECT store.id as "id!", store.name
+                FROM store JOIN store_member ON store_member.store_id = store.id
+                WHERE store_member.user_id = ?
+                ORDER BY store.id
+            "#,
+            user
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(record
+            .into_iter()
+            .map(|r| (StoreId(r.id), Store::new(r.name)))
+            .collect())
+    }
 
-        assert_eq!(convo_id, same_id);
+    async fn belongs_to_store(
+        &self,
+        id: &Self::UserId,
+        store_id: &Self::StoreId,
+    ) -> Result<(), Self::Error> {
+        let record = sqlx::query!(
+            r#"
+                SELECT user_id
+                FROM store_member
+                WHERE store_id = ? AND user_id = ?
+            "#,
+            store_id,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        match record {
+            Some(_) => Ok(()),
+            None => Err(DbError::PermissionDenied),
+        }
+    }
 
-        let hello = Message::from("Hello Bob!");
-        let hello_id = db.post_msg(hello.clone(), &alice_id, &convo_id).await?;
+    async fn assign_conversation(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        assignee: Option<&Self::UserId>,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.begin().await?;
 
-        // Example queries
-        let alice_bob_messages: Result<Vec<Message>, _> = {
-            let querier = db.get_querier().await?;
-            // Count all messages between Alice and Bob
-            let msg_count = sqlx::query_scalar!(
-                r#"
-                SELECT COUNT(*) as "count!: i64"
-                FROM message
-                WHERE conversation_id = ?;
-            "#,
-                convo_id
-            )
-            .fetch_one(querier.q)
-            .await?;
-            assert_eq!(msg_count, 1);
-            // Make sure that the only message is the 'Hello Bob!' one.
-            let msg_id = sqlx::query!(
-                r#"
-                SELECT id as "id!"
-                FROM message
-                WHERE conversation_id = ?;
+        let record = sqlx::query!(
+            r#"
+                SELECT seller_id as "seller_id!", store_id
+                FROM conversation
+                WHERE id = ?
             "#,
-                convo_id
-            )
-            .fetch_one(querier.q)
-            .await?;
-            assert!(MessageId(msg_id.id) == hello_id);
-            // Retrieve all messages between Alice and Bob
-            let messages = sqlx::query!(
+            conversation
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        // Only the seller's side of the conversation may assign it, and only to one of themselves.
+        for user in std::iter::once(my_id).chain(assignee) {
+            let is_staff = sqlx::query!(
                 r#"
-                SELECT content as "content!", salt as "salt!", timestamp as "timestamp!"
-                FROM message
-                WHERE conversation_id = ?;
-            "#,
-                convo_id
+                    SELECT EXISTS (
+                        SELECT user_id
+                        FROM store_member
+                        WHERE store_id = ? AND user_id = ?
+                    ) as is_there
+                "#,
+                record.store_id,
+                user
             )
-            .fetch_all(querier.q)
-            .await?;
-            messages
-                .into_iter()
-                .map(|m| -> ResultInfoNeededDecrypt {
-                    Ok((
-                        CryptData::from(m.content),
-                        m.salt.try_into().map_err(|_| DbError::SaltWrongSize)?,
-                        m.timestamp.and_utc(),
-                    ))
-                })
-                .map(|m| -> Result<Message, DbError> {
-                    let (c, s, t) = m?;
-                    Ok(Message::new(c.decrypt(querier.key, &s)?, t))
-                })
-                .collect()
-        };
+            .fetch_one(&mut *transaction)
+            .await?
+            .is_there
+                == 1;
+            if user.0 != record.seller_id && !is_staff {
+                return Err(DbError::PermissionDenied);
+            }
+        }
 
-        assert_eq!(alice_bob_messages?, vec![hello],);
+        sqlx::query!(
+            r#"
+                UPDATE conversation
+                SET assignee_id = ?
+                WHERE id = ?
+            "#,
+            assignee,
+            conversation
+        )
+        .execute(&mut *transaction)
+        .await?;
 
+        transaction.commit().await?;
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_conversation_pointer() -> anyhow::Result<()> {
-        // Preparation
-        let alice = UserProfile {
-            id: 1,
-            username: "alice_11".to_owned(),
-            name: "Alice Arnold".to_owned(),
-        };
-
-        let bob = UserProfile {
-            id: 2,
-            username: "bobert22".to_owned(),
-            name: "Bob Bellows".to_owned(),
-        };
-        let password = "very_$ecure_and_$trong_P4$$w0rd_in_2025";
-        let salt = "even_more_$ecure_$alt";
-        let suite = CryptoKey::new(password, salt).map_err(|e| anyhow!("Error: {e}"))?;
+    async fn get_assignee(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::UserId>, Self::Error> {
+        let record = sqlx::query!(
+            r#"
+                SELECT assignee_id
+                FROM conversation
+                WHERE id = ?
+            "#,
+            conversation
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(record.assignee_id.map(UserId))
+    }
 
-        let mut db = SQLiteDB::new("sqlite::memory:", suite).await?;
+    async fn get_status(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Self::ConversationStatus, Self::Error> {
+        let record = sqlx::query!(
+            r#"
+                SELECT status as "status!: ConversationStatus"
+                FROM conversation
+                WHERE id = ?
+            "#,
+            conversation
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(record.status)
+    }
 
-        let alice_id = db.add_user(&alice).await?;
-        let bob_id = db.add_user(&bob).await?;
+    async fn set_status(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        status: Self::ConversationStatus,
+    ) -> Result<(), Self::Error> {
+        let updated_by = (status != ConversationStatus::Open).then_some(my_id);
+        sqlx::query!(
+            r#"
+                UPDATE conversation
+                SET status = ?, status_updated_by = ?
+                WHERE id = ?
+            "#,
+            status,
+            updated_by,
+            conversation
+        )
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_labels(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Vec<String>, Self::Error> {
+        let record = sqlx::query!(
+            r#"
+                SELECT label as "label!"
+                FROM conversation_label
+                WHERE conversation_id = ? AND user_id = ?
+                ORDER BY label
+            "#,
+            conversation,
+            my_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(record.into_iter().map(|r| r.label).collect())
+    }
+
+    async fn add_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error> {
+        sqlx::query!(
+            r#"
+                INSERT OR IGNORE INTO conversation_label (conversation_id, user_id, label)
+                VALUES (?, ?, ?)
+            "#,
+            conversation,
+            my_id,
+            label
+        )
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_label(
+        &self,
+        my_id: &Self::UserId,
+        conversation: &Self::ConversationId,
+        label: &str,
+    ) -> Result<(), Self::Error> {
+        sqlx::query!(
+            r#"
+                DELETE FROM conversation_label
+                WHERE conversation_id = ? AND user_id = ? AND label = ?
+            "#,
+            conversation,
+            my_id,
+            label
+        )
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_public_key(&self, user: &Self::UserId, key: &str) -> Result<(), Self::Error> {
+        let now = Utc::now();
+        sqlx::query!(
+            r#"
+                INSERT INTO public_key (user_id, key, updated_at)
+                VALUES (?, ?, ?)
+                ON CONFLICT(user_id) DO UPDATE SET key = excluded.key, updated_at = excluded.updated_at
+            "#,
+            user,
+            key,
+            now
+        )
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_public_key(&self, user: &Self::UserId) -> Result<String, Self::Error> {
+        let record = sqlx::query!("SELECT key FROM public_key WHERE user_id = ?", user)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(record.key)
+    }
+
+    async fn is_e2e(&self, conversation: &Self::ConversationId) -> Result<bool, Self::Error> {
+        let record = sqlx::query!(
+            r#"SELECT e2e as "e2e: bool" FROM conversation WHERE id = ?"#,
+            conversation
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(record.e2e)
+    }
+
+    async fn enable_e2e(&self, conversation: &Self::ConversationId) -> Result<(), Self::Error> {
+        let mut transaction = self.begin().await?;
+        let record = sqlx::query!(
+            r#"
+                SELECT conversation.store_id,
+                    (SELECT COUNT(*) FROM public_key
+                        WHERE user_id IN (conversation.client_id, conversation.seller_id)) as "keys!: i64"
+                FROM conversation
+                WHERE id = ?
+            "#,
+            conversation
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        // Staff of a shared inbox come and go, there is no single key to encrypt for.
+        if record.store_id.is_some() {
+            return Err(DbError::E2EUnavailable("conversation is in a shared inbox"));
+        }
+        if record.keys < 2 {
+            return Err(DbError::E2EUnavailable(
+                "both participants must register a public key",
+            ));
+        }
+        sqlx::query!("UPDATE conversation SET e2e = 1 WHERE id = ?", conversation)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn mark_delivered(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+        messages: &[Self::MessageId],
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let now = Utc::now();
+        let ids = serde_json::to_string(&messages).map_err(|e| sqlx::Error::Encode(e.into()))?;
+        let record = sqlx::query_file!(
+            "src/database/queries/sqlite/mark_delivered.sql",
+            now,
+            conversation,
+            ids,
+            reader
+        )
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(record.iter().map(|r| r.id).max().map(MessageId))
+    }
+
+    async fn mark_read(
+        &self,
+        reader: &Self::UserId,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let now = Utc::now();
+        let record = sqlx::query!(
+            r#"
+                UPDATE message
+                SET read_at = ?1, delivered_at = COALESCE(delivered_at, ?1)
+                WHERE conversation_id = ?2 AND read_at IS NULL
+                    AND (SELECT (message.sender_id = client_id) != (?3 = client_id) FROM conversation WHERE id = ?2)
+                RETURNING id as "id!"
+            "#,
+            now,
+            conversation,
+            reader
+        )
+        .fetch_all(&self.writer)
+        .await?;
+        Ok(record.iter().map(|r| r.id).max().map(MessageId))
+    }
+
+    async fn append_audit(&self, entry: &Self::AuditEntry) -> Result<(), Self::Error> {
+        let mut transaction = self.begin().await?;
+        self.audit(&mut transaction, entry).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn get_audit_log(
+        &self,
+        filter: &Self::AuditFilter,
+    ) -> Result<Vec<Self::AuditRecord>, Self::Error> {
+        let records = sqlx::query!(
+            r#"
+            SELECT id as "id!", timestamp as "timestamp!: String", actor_id, action as "action!: AuditAction",
+                subject_id, conversation_id, message_id, reason, hash
+            FROM audit_log
+            WHERE (?1 IS NULL OR actor_id = ?1) AND (?2 IS NULL OR subject_id = ?2)
+                AND (?3 IS NULL OR conversation_id = ?3)
+            ORDER BY id DESC
+            LIMIT ?4
+        "#,
+            filter.actor_id,
+            filter.subject_id,
+            filter.conversation_id,
+            filter.limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records
+            .into_iter()
+            .map(|r| AuditRecord {
+                id: r.id,
+                timestamp: r.timestamp,
+                entry: AuditEntry {
+                    actor_id: r.actor_id.map(UserId),
+                    action: r.action,
+                    subject_id: r.subject_id.map(UserId),
+                    conversation_id: r.conversation_id.map(ConversationId),
+                    message_id: r.message_id.map(MessageId),
+                    reason: r.reason,
+                },
+                hash: hex::encode(r.hash),
+            })
+            .collect())
+    }
+
+    async fn verify_audit_log(&self) -> Result<Option<i64>, Self::Error> {
+        let records = sqlx::query!(
+            r#"
+            SELECT id as "id!", timestamp as "timestamp!: String", actor_id, action as "action!: AuditAction",
+                subject_id, conversation_id, message_id, reason, prev_hash, hash
+            FROM audit_log
+            ORDER BY id
+        "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut chain = AuditChain::new(&self.sealer.audit_key);
+        for r in records {
+            let entry = AuditEntry {
+                actor_id: r.actor_id.map(UserId),
+                action: r.action,
+                subject_id: r.subject_id.map(UserId),
+                conversation_id: r.conversation_id.map(ConversationId),
+                message_id: r.message_id.map(MessageId),
+                reason: r.reason,
+            };
+            if !chain.links(&entry, &r.timestamp, &r.prev_hash, &r.hash)? {
+                return Ok(Some(r.id));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn verify_message_chain(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::MessageId>, Self::Error> {
+        let last = sqlx::query!(
+            "SELECT last_message_id FROM conversation WHERE id = ?",
+            conversation
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .last_message_id;
+        let rows = sqlx::query_file!(
+            "src/database/queries/sqlite/message_chain.sql",
+            conversation
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut prev: Option<(i64, Vec<u8>)> = None;
+        for r in rows {
+            let broken = Ok(Some(MessageId(r.id)));
+            if r.previous_message_id != prev.as_ref().map(|(id, _)| *id) {
+                return broken;
+            }
+            let Some(content_hash) = r.content_hash else {
+                return broken;
+            };
+            if r.content
+                .is_some_and(|content| Sha256::digest(content).as_slice() != content_hash)
+            {
+                return broken;
+            }
+            let prev_hash = prev.map_or_else(|| vec![0; 32], |(_, hash)| hash);
+            let hash = chain_hash(&prev_hash, r.sender_id, r.timestamp, &content_hash);
+            if r.chain_hash.as_ref() != Some(&hash) {
+                return broken;
+            }
+            prev = Some((r.id, hash));
+        }
+        // A removed tail leaves the conversation pointing past the end of the chain.
+        Ok(match (last, prev) {
+            (Some(last), Some((id, _))) if last == id => None,
+            (None, None) => None,
+            (Some(last), _) | (None, Some((last, _))) => Some(MessageId(last)),
+        })
+    }
+
+    async fn get_chain_head(
+        &self,
+        conversation: &Self::ConversationId,
+    ) -> Result<Option<Self::ChainHead>, Self::Error> {
+        let record = sqlx::query!(
+            r#"
+            SELECT message.id as "id?", message.chain_hash as "chain_hash?"
+            FROM conversation LEFT JOIN message ON message.id = conversation.last_message_id
+            WHERE conversation.id = ?
+        "#,
+            conversation
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(record
+            .id
+            .zip(record.chain_hash)
+            .map(|(id, hash)| ChainHead {
+                message_id: MessageId(id),
+                hash: hex::encode(hash),
+            }))
+    }
+
+    async fn reencrypt_batch(&self, batch: u32) -> Result<u64, Self::Error> {
+        let active = self.sealer.keys.active_id();
+        let mut transaction = self.begin().await?;
+        let metadata = self.sealer.rewrap_metadata_keys(&mut *transaction).await?;
+        let stale = sqlx::query!(
+            r#"
+            SELECT conversation_id, user_id, key, nonce, key_id
+            FROM conversation_key
+            WHERE key_id != ? OR length(nonce) > 0
+            LIMIT ?
+        "#,
+            active,
+            batch
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        for row in &stale {
+            let raw = unwrap_raw_key(&self.sealer.keys, row.key.clone(), &row.nonce, row.key_id)?;
+            let wrapped =
+                CryptData::encrypt(raw, self.sealer.keys.active(), &[], &mut *self.sealer.rng())?;
+            sqlx::query!(
+                r#"
+                UPDATE conversation_key
+                SET key = ?, nonce = x'', key_id = ?
+                WHERE conversation_id = ? AND user_id = ?
+            "#,
+                wrapped,
+                active,
+                row.conversation_id,
+                row.user_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok((metadata + stale.len()) as u64)
+    }
+
+    async fn erase_user(
+        &self,
+        user: &Self::UserId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error> {
+        let now = Utc::now();
+        let mut transaction = self.begin().await?;
+
+        // Overwrite freed pages so the destroyed key doesn't linger in the database file.
+        sqlx::query!("PRAGMA secure_delete = ON")
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        // The row stays so the peer's conversations keep pointing somewhere.
+        let erased = sqlx::query!(
+            r#"
+            UPDATE user
+            SET username = NULL, name = NULL, username_index = NULL, primary_store_id = NULL,
+                erased_at = COALESCE(erased_at, ?)
+            WHERE id = ?
+        "#,
+            now,
+            user
+        )
+        .execute(&mut *transaction)
+        .await?;
+        if erased.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+
+        // Crypto-shredding: without the data key the ciphertext, and any backup of it, is noise.
+        sqlx::query!(
+            r#"
+            DELETE FROM conversation_key WHERE user_id = ?;
+            DELETE FROM public_key WHERE user_id = ?;
+            UPDATE message SET content = NULL WHERE sender_id = ?;
+            DELETE FROM conversation_label WHERE user_id = ?;
+            DELETE FROM store_member WHERE user_id = ?;
+            UPDATE conversation SET assignee_id = NULL WHERE assignee_id = ?;
+        "#,
+            user,
+            user,
+            user,
+            user,
+            user,
+            user
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        self.audit(&mut transaction, audit).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn shred_conversation(
+        &self,
+        conversation: &Self::ConversationId,
+        audit: &Self::AuditEntry,
+    ) -> Result<(), Self::Error> {
+        let mut transaction = self.begin().await?;
+        sqlx::query!("PRAGMA secure_delete = ON")
+            .fetch_optional(&mut *transaction)
+            .await?;
+        let shredded = sqlx::query!(
+            r#"
+            DELETE FROM conversation_key WHERE conversation_id = ?;
+            UPDATE message SET content = NULL WHERE conversation_id = ?;
+        "#,
+            conversation,
+            conversation
+        )
+        .execute(&mut *transaction)
+        .await?;
+        if shredded.rows_affected() == 0 {
+            sqlx::query!("SELECT id FROM conversation WHERE id = ?", conversation)
+                .fetch_one(&mut *transaction)
+                .await?;
+        }
+        self.audit(&mut transaction, audit).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+
+    use chrono::DateTime;
+    use rand::SeedableRng;
+
+    use crate::database::Database;
+    use crate::database::conformance::{ADMIN, alice_and_bob, erasure, shredding};
+    use crate::database::crypto::{
+        AuditKey, CryptError, CryptoKey, Keyring, RawKey,
+        tests::{PASSWORD, SALT, key, key_from},
+    };
+    use crate::database::sealing::{check_master_keys, load_metadata_key, unwrap_key};
+    use crate::database::sqlite::*;
+
+    type ResultInfoNeededDecrypt = Result<
+        (
+            CryptData<String>,
+            Vec<u8>,
+            [u8; 24],
+            CryptoKey,
+            DateTime<Utc>,
+        ),
+        DbError,
+    >;
+
+    /// Fresh in-memory database, where Alice (11) has a conversation with Bob (22) about his
+    /// product.
+    async fn fixture() -> anyhow::Result<(SQLiteDB, UserId, UserId, ProductId, ConversationId)> {
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+        let (alice_id, bob_id, prod_id, convo_id) = alice_and_bob(&db).await?;
+        Ok((db, alice_id, bob_id, prod_id, convo_id))
+    }
+
+    #[tokio::test]
+    async fn test_sqlite() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, prod_id, convo_id) = fixture().await?;
         assert_ne!(alice_id, bob_id);
 
+        let alice = UserProfile::new_clone(11, "alice_11", "Alice Arnold");
         let alice_again = db.add_user(&alice).await?;
         assert_eq!(alice_id, alice_again);
 
-        let prod = Product {
-            name: "Dill Dough".to_string(),
-            seller_id: 1.into(),
-            jumpseller_id: 1,
-        };
-        let prod_id = db.add_product(&prod).await?;
+        let same_id = db.start_conversation(&bob_id, &alice_id, &prod_id).await?;
+
+        assert_eq!(convo_id, same_id);
+
+        let hello = Message::from("Hello Bob!");
+        let hello_id = db.post_msg(hello.clone(), &alice_id, &convo_id).await?;
+
+        // Example queries
+        let alice_bob_messages: Result<Vec<Message>, _> = {
+            let querier = db.get_querier().await?;
+            // Count all messages between Alice and Bob
+            let msg_count = sqlx::query_scalar!(
+                r#"
+                SELECT COUNT(*) as "count!: i64"
+                FROM message
+                WHERE conversation_id = ?;
+            "#,
+                convo_id
+            )
+            .fetch_one(querier.q)
+            .await?;
+            assert_eq!(msg_count, 1);
+            // Make sure that the only message is the 'Hello Bob!' one.
+            let msg_id = sqlx::query!(
+                r#"
+                SELECT id as "id!"
+                FROM message
+                WHERE conversation_id = ?;
+            "#,
+                convo_id
+            )
+            .fetch_one(querier.q)
+            .await?;
+            assert!(MessageId(msg_id.id) == hello_id);
+            // Retrieve all messages between Alice and Bob, along with their senders' data keys
+            let messages = sqlx::query!(
+                r#"
+                SELECT message.id as "id!", sender_id, message.conversation_id,
+                    content as "content!", salt as "salt!", timestamp as "timestamp!",
+                    conversation_key.key as "key!", conversation_key.nonce as "key_nonce!"
+                FROM message JOIN conversation_key
+                    ON conversation_key.user_id = message.sender_id
+                    AND conversation_key.conversation_id = message.conversation_id
+                WHERE message.conversation_id = ?;
+            "#,
+                convo_id
+            )
+            .fetch_all(querier.q)
+            .await?;
+            messages
+                .into_iter()
+                .map(|m| -> ResultInfoNeededDecrypt {
+                    let key = CryptData::<RawKey>::from(m.key).decrypt_any(
+                        querier.key,
+                        &m.key_nonce,
+                        &[],
+                    )?;
+                    Ok((
+                        CryptData::from(m.content),
+                        m.salt,
+                        message_aad(m.id, m.conversation_id, m.sender_id),
+                        CryptoKey::from_raw(&key)?,
+                        m.timestamp.and_utc(),
+                    ))
+                })
+                .map(|m| -> Result<Message, DbError> {
+                    let (contents, salt, aad, key, timestamp) = m?;
+                    Ok(Message::new(
+                        contents.decrypt_any(&key, &salt, &aad)?,
+                        timestamp,
+                    ))
+                })
+                .collect()
+        };
+
+        assert_eq!(alice_bob_messages?, vec![hello],);
+
+        Ok(())
+    }
+
+    /// `add_user` used to return from updating a known user without committing: the transaction
+    /// rolled back when dropped, losing every change Jumpseller sent for an existing profile.
+    #[tokio::test]
+    async fn test_profile_update_is_committed() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("profiles_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let _ = std::fs::remove_file(&path);
+
+        let db = SQLiteDB::new(&url, key()?).await?;
+        let alice_id = db
+            .add_user(&UserProfile::new_clone(11, "alice_11", "Alice Arnold"))
+            .await?;
+        let renamed = UserProfile::new_clone(11, "alice_a", "Alice Abbott");
+        assert_eq!(db.add_user(&renamed).await?, alice_id);
+        db.pool.close().await;
+        db.writer.close().await;
+
+        // Still there once the database is reopened, on other connections.
+        let db = SQLiteDB::new(&url, key()?).await?;
+        assert_eq!(db.get_user_profile(&alice_id).await?, renamed);
+        assert_eq!(db.get_user_id_from_username("alice_a").await?, alice_id);
+        db.pool.close().await;
+        db.writer.close().await;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
 
-        let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
+    #[tokio::test]
+    async fn test_conversation_pointer() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, convo_id) = fixture().await?;
 
-        // Test
         let last_msg = db.get_latest_message(&convo_id).await?;
         assert_eq!(last_msg, None);
 
@@ -987,6 +2220,1000 @@ mod test {
 
         let (_, _, last_msg) = db.get_message(&last_msg).await?;
         assert_eq!(last_msg, Some(first_hello_id));
+
+        let page = db.get_messages_after(&convo_id, None, 1).await?;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].0, first_hello_id);
+        let page = db
+            .get_messages_after(&convo_id, Some(&first_hello_id), 32)
+            .await?;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].0, second_hello_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_inbox() -> anyhow::Result<()> {
+        let alice = UserProfile::new_clone(1, "alice_11", "Alice Arnold");
+        let bob = UserProfile::new_clone(2, "bobert22", "Bob Bellows");
+        let carol = UserProfile::new_clone(3, "carol33", "Carol Carver");
+        let dave = UserProfile::new_clone(4, "dave44", "Dave Dawson");
+
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+
+        let alice_id = db.add_user(&alice).await?;
+        let bob_id = db.add_user(&bob).await?;
+        let carol_id = db.add_user(&carol).await?;
+        let dave_id = db.add_user(&dave).await?;
+
+        let store_id = db
+            .add_store(&bob_id, &Store::new("Bellows & Co.".to_owned()))
+            .await?;
+        db.add_store_member(&bob_id, &store_id, &carol_id).await?;
+        assert_eq!(
+            db.get_store_members(&store_id).await?,
+            vec![bob_id, carol_id]
+        );
+
+        let prod_id = db
+            .add_product(&Product::new("Dill Dough".to_owned(), bob_id, 1))
+            .await?;
+
+        let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
+        let same_id = db
+            .start_conversation(&alice_id, &carol_id, &prod_id)
+            .await?;
+        assert_eq!(convo_id, same_id);
+
+        // Any staff member can see and answer the conversation.
+        assert!(
+            db.belongs_to_conversation(&carol_id, &convo_id)
+                .await
+                .is_ok()
+        );
+        assert!(
+            db.belongs_to_conversation(&dave_id, &convo_id)
+                .await
+                .is_err()
+        );
+        assert_eq!(db.get_conversations(&carol_id).await?, vec![convo_id]);
+        assert_eq!(db.get_peer(&carol_id, &convo_id).await?, alice_id);
+        assert_eq!(db.get_peer(&alice_id, &convo_id).await?, bob_id);
+
+        let reply_id = db
+            .post_msg(Message::from("Hi, Carol here!"), &carol_id, &convo_id)
+            .await?;
+        let (sender, _, _) = db.get_message(&reply_id).await?;
+        assert_eq!(sender, carol_id);
+
+        // Assignment is restricted to staff, on both ends.
+        assert_eq!(db.get_assignee(&convo_id).await?, None);
+        db.assign_conversation(&bob_id, &convo_id, Some(&carol_id))
+            .await?;
+        assert_eq!(db.get_assignee(&convo_id).await?, Some(carol_id));
+        assert!(
+            db.assign_conversation(&bob_id, &convo_id, Some(&dave_id))
+                .await
+                .is_err()
+        );
+        assert!(
+            db.assign_conversation(&alice_id, &convo_id, None)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_labels_and_status() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, dough_convo) = fixture().await?;
+        let cake = db
+            .add_product(&Product::new("Carrot Cake".to_owned(), bob_id, 2))
+            .await?;
+        let cake_convo = db.start_conversation(&alice_id, &bob_id, &cake).await?;
+
+        // Labels are private to whoever set them.
+        db.add_label(&bob_id, &dough_convo, "wholesale").await?;
+        db.add_label(&bob_id, &dough_convo, "priority").await?;
+        assert_eq!(
+            db.get_labels(&bob_id, &dough_convo).await?,
+            vec!["priority".to_owned(), "wholesale".to_owned()]
+        );
+        assert!(db.get_labels(&alice_id, &dough_convo).await?.is_empty());
+        db.remove_label(&bob_id, &dough_convo, "priority").await?;
+
+        let wholesale = ConversationFilter {
+            label: Some("wholesale".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            db.filter_conversations(&bob_id, &wholesale).await?,
+            vec![dough_convo]
+        );
+        assert!(
+            db.filter_conversations(&alice_id, &wholesale)
+                .await?
+                .is_empty()
+        );
+
+        // Resolving a conversation and getting a reply from the peer reopens it.
+        assert_eq!(db.get_status(&cake_convo).await?, ConversationStatus::Open);
+        db.set_status(&bob_id, &cake_convo, ConversationStatus::Resolved)
+            .await?;
+        let resolved = ConversationFilter {
+            status: Some(ConversationStatus::Resolved),
+            ..Default::default()
+        };
+        assert_eq!(
+            db.filter_conversations(&bob_id, &resolved).await?,
+            vec![cake_convo]
+        );
+
+        db.post_msg(Message::from("Anything else?"), &bob_id, &cake_convo)
+            .await?;
+        assert_eq!(
+            db.get_status(&cake_convo).await?,
+            ConversationStatus::Resolved
+        );
+        db.post_msg(Message::from("Actually, yes!"), &alice_id, &cake_convo)
+            .await?;
+        assert_eq!(db.get_status(&cake_convo).await?, ConversationStatus::Open);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_post() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, convo_id) = fixture().await?;
+
+        let client_id = Some("1b4e28ba-2fa1-11d2-883f-0016d3cca427");
+        let (first, inserted) = db
+            .post_msg_idempotent(Message::from("Hello Bob!"), &alice_id, &convo_id, client_id)
+            .await?;
+        assert!(inserted);
+        let (retry, inserted) = db
+            .post_msg_idempotent(Message::from("Hello Bob!"), &alice_id, &convo_id, client_id)
+            .await?;
+        assert!(!inserted);
+        assert_eq!(first, retry);
+        assert_eq!(db.get_latest_message(&convo_id).await?, Some(first));
+
+        // The same client id from another sender is a different message.
+        let (reply, inserted) = db
+            .post_msg_idempotent(Message::from("Hello Alice!"), &bob_id, &convo_id, client_id)
+            .await?;
+        assert!(inserted);
+        assert_ne!(first, reply);
+
+        // Messages without a client id are never deduplicated.
+        let a = db
+            .post_msg(Message::from("Ping"), &alice_id, &convo_id)
+            .await?;
+        let b = db
+            .post_msg(Message::from("Ping"), &alice_id, &convo_id)
+            .await?;
+        assert_ne!(a, b);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_get() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, convo_id) = fixture().await?;
+
+        let hello = Message::from("Hello Bob!");
+        let hello_id = db.post_msg(hello.clone(), &alice_id, &convo_id).await?;
+        let reply = Message::from("Hello Alice!");
+        let reply_id = db.post_msg(reply.clone(), &bob_id, &convo_id).await?;
+
+        let ids = [reply_id, MessageId(404), hello_id];
+        assert_eq!(db.get_conversations_from_messages(&ids).await?.len(), 2);
+        let messages = db.get_messages(&ids).await?;
+        assert_eq!(
+            messages,
+            vec![
+                (hello_id, alice_id, hello, None),
+                (reply_id, bob_id, reply, Some(hello_id)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delivery_status() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, convo_id) = fixture().await?;
+
+        let first = db
+            .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+        let second = db
+            .post_msg(Message::from("Are you there?"), &alice_id, &convo_id)
+            .await?;
+        let (_, msg, _) = db.get_message(&second).await?;
+        assert_eq!(msg.status(), DeliveryStatus::Sent);
+
+        // The sender fetching their own messages doesn't count.
+        assert_eq!(
+            db.mark_delivered(&alice_id, &convo_id, &[first, second])
+                .await?,
+            None
+        );
+        assert_eq!(
+            db.mark_delivered(&bob_id, &convo_id, &[first]).await?,
+            Some(first)
+        );
+        assert_eq!(
+            db.mark_delivered(&bob_id, &convo_id, &[second]).await?,
+            Some(second)
+        );
+        assert_eq!(
+            db.mark_delivered(&bob_id, &convo_id, &[second]).await?,
+            None
+        );
+        let (_, msg, _) = db.get_message(&second).await?;
+        assert_eq!(msg.status(), DeliveryStatus::Delivered);
+
+        assert_eq!(db.mark_read(&bob_id, &convo_id).await?, Some(second));
+        assert_eq!(db.mark_read(&bob_id, &convo_id).await?, None);
+        let (messages, _) = db.get_most_recent_messages(&convo_id).await?;
+        assert!(
+            messages
+                .iter()
+                .all(|(_, _, m)| m.status() == DeliveryStatus::Read)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_erase_user() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, convo_id) = fixture().await?;
+        let hello_id = db
+            .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+        db.post_msg(Message::from("Hello Alice!"), &bob_id, &convo_id)
+            .await?;
+        db.add_label(&alice_id, &convo_id, "bread").await?;
+
+        db.erase_user(&alice_id, &erasure(alice_id)).await?;
+
+        let profile = db.get_user_profile(&alice_id).await?;
+        assert_eq!(profile.username(), "erased_11");
+        assert_eq!(profile.name(), "Deleted user");
+        // Jumpseller can't bring the profile back.
+        db.add_user(&UserProfile::new_clone(11, "alice_11", "Alice Arnold"))
+            .await?;
+        assert_eq!(
+            db.get_user_profile(&alice_id).await?.username(),
+            "erased_11"
+        );
+
+        // Bob keeps the conversation, minus what Alice said.
+        assert_eq!(db.get_peer(&bob_id, &convo_id).await?, alice_id);
+        let (messages, _) = db.get_most_recent_messages(&convo_id).await?;
+        let contents: Vec<_> = messages.iter().map(|(_, _, m)| m.contents()).collect();
+        assert_eq!(contents, ["", "Hello Alice!"]);
+        let (sender, _, _) = db.get_message(&hello_id).await?;
+        assert_eq!(sender, alice_id);
+
+        assert!(db.get_labels(&alice_id, &convo_id).await?.is_empty());
+        assert!(matches!(
+            db.post_msg(Message::from("I'm back"), &alice_id, &convo_id)
+                .await,
+            Err(DbError::PermissionDenied)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_profiles() -> anyhow::Result<()> {
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+        let contains =
+            |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|w| w == needle);
+
+        let alice = UserProfile::new_clone(11, "alice_11", "Alice Arnold");
+        let alice_id = db.add_user(&alice).await?;
+        let prod_id = db
+            .add_product(&Product::new("Dill Dough".to_owned(), alice_id, 1))
+            .await?;
+
+        let raw = sqlx::query!(
+            r#"SELECT username as "username: Vec<u8>", name as "name: Vec<u8>" FROM user WHERE id = 11"#
+        )
+            .fetch_one(&db.pool)
+            .await?;
+        assert!(!contains(&raw.username.unwrap_or_default(), b"alice_11"));
+        assert!(!contains(&raw.name.unwrap_or_default(), b"Alice"));
+        let raw = sqlx::query!(r#"SELECT name as "name: Vec<u8>" FROM product WHERE id = 1"#)
+            .fetch_one(&db.pool)
+            .await?;
+        assert!(!contains(&raw.name, b"Dill"));
+
+        assert_eq!(db.get_user_profile(&alice_id).await?, alice);
+        assert_eq!(db.get_product(&prod_id).await?.name, "Dill Dough");
+        assert_eq!(db.get_user_id_from_username("alice_11").await?, alice_id);
+        assert!(db.get_user_id_from_username("alice").await.is_err());
+
+        // Names can't be moved to another row.
+        sqlx::query("INSERT INTO user (id, username, name) SELECT 22, username, name FROM user WHERE id = 11")
+            .execute(&db.pool)
+            .await?;
+        assert!(db.get_user_profile(&UserId(22)).await.is_err());
+
+        // Rows written in the clear by earlier versions are encrypted on boot.
+        sqlx::query(
+            "UPDATE user SET username = 'bobert22', name = 'Bob Bellows', username_index = NULL WHERE id = 22",
+        )
+        .execute(&db.pool)
+        .await?;
+        db.encrypt_legacy_profiles().await?;
+        assert_eq!(db.get_user_id_from_username("bobert22").await?, UserId(22));
+        assert_eq!(
+            db.get_user_profile(&UserId(22)).await?.name(),
+            "Bob Bellows"
+        );
+
+        db.erase_user(&alice_id, &erasure(alice_id)).await?;
+        let raw = sqlx::query!("SELECT username, name, username_index FROM user WHERE id = 11")
+            .fetch_one(&db.pool)
+            .await?;
+        assert!(raw.username.is_none() && raw.name.is_none() && raw.username_index.is_none());
+        assert!(db.get_user_id_from_username("alice_11").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shred_conversation() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, shredded) = fixture().await?;
+        let cake = db
+            .add_product(&Product::new("Carrot Cake".to_owned(), bob_id, 2))
+            .await?;
+        let kept = db.start_conversation(&alice_id, &bob_id, &cake).await?;
+        for convo in [&shredded, &kept] {
+            db.post_msg(Message::from("Hello Bob!"), &alice_id, convo)
+                .await?;
+            db.post_msg(Message::from("Hello Alice!"), &bob_id, convo)
+                .await?;
+        }
+
+        // Each side of each conversation has its own key.
+        let querier = db.get_querier().await?;
+        let keys = sqlx::query!("SELECT COUNT(*) as n FROM conversation_key")
+            .fetch_one(querier.q)
+            .await?;
+        assert_eq!(keys.n, 4);
+
+        db.shred_conversation(&shredded, &shredding(shredded))
+            .await?;
+
+        let (messages, _) = db.get_most_recent_messages(&shredded).await?;
+        let contents: Vec<_> = messages.iter().map(|(_, _, m)| m.contents()).collect();
+        assert_eq!(contents, ["", ""]);
+        let (messages, _) = db.get_most_recent_messages(&kept).await?;
+        let contents: Vec<_> = messages.iter().map(|(_, _, m)| m.contents()).collect();
+        assert_eq!(contents, ["Hello Bob!", "Hello Alice!"]);
+
+        // The conversation can go on, under new keys.
+        db.post_msg(Message::from("Still there?"), &alice_id, &shredded)
+            .await?;
+        let (messages, _) = db.get_most_recent_messages(&shredded).await?;
+        assert_eq!(messages[2].2.contents(), "Still there?");
+
+        assert!(matches!(
+            db.shred_conversation(&ConversationId(404), &shredding(ConversationId(404)))
+                .await,
+            Err(DbError::Db(sqlx::Error::RowNotFound))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_e2e() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, convo_id) = fixture().await?;
+        db.post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+
+        assert!(!db.is_e2e(&convo_id).await?);
+        db.set_public_key(&alice_id, "alice-old").await?;
+        db.set_public_key(&alice_id, "alice-pk").await?;
+        assert_eq!(db.get_public_key(&alice_id).await?, "alice-pk");
+        assert!(matches!(
+            db.enable_e2e(&convo_id).await,
+            Err(DbError::E2EUnavailable(_))
+        ));
+        db.set_public_key(&bob_id, "bob-pk").await?;
+        db.enable_e2e(&convo_id).await?;
+        assert!(db.is_e2e(&convo_id).await?);
+
+        let sealed_id = db
+            .post_msg(
+                Message::from("c2VhbGVkIGZvciBBbGljZQ=="),
+                &bob_id,
+                &convo_id,
+            )
+            .await?;
+
+        // Stored and returned exactly as the client sent it, with no server-side key.
+        let (_, sealed, _) = db.get_message(&sealed_id).await?;
+        assert!(sealed.is_e2e());
+        assert_eq!(sealed.contents(), "c2VhbGVkIGZvciBBbGljZQ==");
+        let querier = db.get_querier().await?;
+        let stored = sqlx::query!("SELECT content FROM message WHERE id = ?", sealed_id)
+            .fetch_one(querier.q)
+            .await?;
+        assert_eq!(
+            stored.content.as_deref(),
+            Some(b"c2VhbGVkIGZvciBBbGljZQ==".as_slice())
+        );
+        let bob_keys = sqlx::query!(
+            "SELECT COUNT(*) as n FROM conversation_key WHERE user_id = ?",
+            bob_id
+        )
+        .fetch_one(querier.q)
+        .await?;
+        assert_eq!(bob_keys.n, 0);
+
+        // Earlier messages are still the server's to read.
+        let (messages, _) = db.get_most_recent_messages(&convo_id).await?;
+        let flags: Vec<_> = messages
+            .iter()
+            .map(|(_, _, m)| (m.contents(), m.is_e2e()))
+            .collect();
+        assert_eq!(
+            flags,
+            [("Hello Bob!", false), ("c2VhbGVkIGZvciBBbGljZQ==", true)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_aad() -> anyhow::Result<()> {
+        let (db, alice_id, _, _, convo_id) = fixture().await?;
+        let offer = db
+            .post_msg(Message::from("I'll pay 5€"), &alice_id, &convo_id)
+            .await?;
+        let counter = db
+            .post_msg(Message::from("I'll pay 50€"), &alice_id, &convo_id)
+            .await?;
+
+        // Same key, but the ciphertext of one row doesn't open in another.
+        let querier = db.get_querier().await?;
+        sqlx::query!(
+            r#"
+            UPDATE message
+            SET (content, salt) = (SELECT content, salt FROM message WHERE id = ?)
+            WHERE id = ?
+        "#,
+            counter,
+            offer
+        )
+        .execute(querier.q)
+        .await?;
+        assert!(matches!(
+            db.get_message(&offer).await,
+            Err(DbError::Decryption(id)) if id == offer
+        ));
+        assert_eq!(db.get_message(&counter).await?.1.contents(), "I'll pay 50€");
+
+        // Rows written before the binding are upgraded on boot.
+        let key_row = sqlx::query!(
+            "SELECT key, nonce, key_id FROM conversation_key WHERE user_id = ?",
+            alice_id
+        )
+        .fetch_one(querier.q)
+        .await?;
+        let key = unwrap_key(&db.sealer.keys, key_row.key, &key_row.nonce, key_row.key_id)?;
+        let legacy = CryptData::encrypt(
+            "Old times".to_owned(),
+            &key,
+            &[],
+            &mut StdRng::from_os_rng(),
+        )?;
+        sqlx::query!(
+            "UPDATE message SET content = ?, aad_bound = 0 WHERE id = ?",
+            legacy,
+            offer
+        )
+        .execute(querier.q)
+        .await?;
+        assert!(db.get_message(&offer).await.is_err());
+        db.bind_legacy_messages().await?;
+        assert_eq!(db.get_message(&offer).await?.1.contents(), "Old times");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() -> anyhow::Result<()> {
+        let new = || key_from("an_even_$tronger_P4$$w0rd_in_2026");
+
+        let (mut db, alice_id, bob_id, _, convo_id) = fixture().await?;
+        db.post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+        db.post_msg(Message::from("Hello Alice!"), &bob_id, &convo_id)
+            .await?;
+
+        // Restart with key #2 active and key #1 retired: old data stays readable, new data uses #2.
+        db.sealer.keys = Keyring::new(2, new()?).with_retired(1, key()?);
+        let (messages, _) = db.get_most_recent_messages(&convo_id).await?;
+        let contents: Vec<_> = messages.iter().map(|(_, _, m)| m.contents()).collect();
+        assert_eq!(contents, ["Hello Bob!", "Hello Alice!"]);
+
+        // All three metadata keys come along with the first batch.
+        assert_eq!(db.reencrypt_batch(1).await?, 4);
+        assert_eq!(db.reencrypt_batch(1).await?, 1);
+        assert_eq!(db.reencrypt_batch(1).await?, 0);
+
+        // The retired key is no longer needed.
+        db.sealer.keys = Keyring::new(2, new()?);
+        let mut rng = StdRng::from_os_rng();
+        load_metadata_key(
+            &mut *db.pool.acquire().await?,
+            &db.sealer.keys,
+            &mut rng,
+            "profile",
+        )
+        .await?;
+        let (messages, _) = db.get_most_recent_messages(&convo_id).await?;
+        let contents: Vec<_> = messages.iter().map(|(_, _, m)| m.contents()).collect();
+        assert_eq!(contents, ["Hello Bob!", "Hello Alice!"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_check() -> anyhow::Result<()> {
+        let wrong = || key_from("password");
+        let mut rng = StdRng::from_os_rng();
+
+        let (db, alice_id, _, _, convo_id) = fixture().await?;
+        db.post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+
+        check_master_keys(&mut *db.pool.acquire().await?, &key()?.into(), &mut rng).await?;
+        let wrong_key =
+            check_master_keys(&mut *db.pool.acquire().await?, &wrong()?.into(), &mut rng).await;
+        assert!(matches!(
+            wrong_key,
+            Err(DbError::Crypto(CryptError::WrongKey(1)))
+        ));
+        let kdf = sqlx::query!("SELECT kdf_algorithm, kdf_memory_kib FROM master_key WHERE id = 1")
+            .fetch_one(&db.pool)
+            .await?;
+        assert_eq!(kdf.kdf_algorithm.as_deref(), Some("argon2id"));
+        assert_eq!(kdf.kdf_memory_kib, Some(19 * 1024));
+
+        // Without a canary yet, the keys it wrapped tell.
+        sqlx::query!("DELETE FROM master_key")
+            .execute(&db.pool)
+            .await?;
+        let wrong_key =
+            check_master_keys(&mut *db.pool.acquire().await?, &wrong()?.into(), &mut rng).await;
+        assert!(matches!(
+            wrong_key,
+            Err(DbError::Crypto(CryptError::WrongKey(1)))
+        ));
+        check_master_keys(&mut *db.pool.acquire().await?, &key()?.into(), &mut rng).await?;
+        check_master_keys(&mut *db.pool.acquire().await?, &key()?.into(), &mut rng).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_kdf() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("kdf_test_{}.sqlite3", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        assert!(SQLiteDB::stored_kdf(&url).await?.is_empty());
+
+        let kdf = KdfParams {
+            memory_kib: 1024,
+            iterations: 1,
+            ..KdfParams::default()
+        };
+        let derived = CryptoKey::derive(PASSWORD, SALT, kdf).map_err(|e| anyhow!("Error: {e}"))?;
+        SQLiteDB::new(&url, derived).await?;
+        assert_eq!(
+            SQLiteDB::stored_kdf(&url).await?,
+            BTreeMap::from([(1, kdf)])
+        );
+
+        // The same password with other parameters is another key.
+        let reopened = SQLiteDB::new(&url, key()?).await;
+        std::fs::remove_file(&path)?;
+        assert!(reopened.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_chain() -> anyhow::Result<()> {
+        let (db, alice_id, bob_id, _, convo_id) = fixture().await?;
+        assert_eq!(db.get_chain_head(&convo_id).await?, None);
+        assert_eq!(db.verify_message_chain(&convo_id).await?, None);
+
+        let first = db
+            .post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+        let offer = db
+            .post_msg(Message::from("5 loaves for 10$"), &bob_id, &convo_id)
+            .await?;
+        let head = db.get_chain_head(&convo_id).await?;
+        assert_eq!(head.as_ref().map(|h| h.message_id), Some(offer));
+        assert_eq!(db.verify_message_chain(&convo_id).await?, None);
+
+        // Erasing contents keeps the chain whole.
+        db.erase_user(&alice_id, &erasure(alice_id)).await?;
+        assert_eq!(db.verify_message_chain(&convo_id).await?, None);
+        assert_eq!(db.get_chain_head(&convo_id).await?, head);
+
+        // Rewriting history doesn't.
+        let later = Utc::now();
+        sqlx::query!(
+            "UPDATE message SET timestamp = ? WHERE id = ?",
+            later,
+            offer
+        )
+        .execute(&db.pool)
+        .await?;
+        assert_eq!(db.verify_message_chain(&convo_id).await?, Some(offer));
+        sqlx::query!(
+            "UPDATE message SET previous_message_id = NULL WHERE id = ?; DELETE FROM message WHERE id = ?",
+            offer,
+            first
+        )
+        .execute(&db.pool)
+        .await?;
+        assert_eq!(db.verify_message_chain(&convo_id).await?, Some(offer));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() -> anyhow::Result<()> {
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+        assert_eq!(db.verify_audit_log().await?, None);
+
+        let export = AuditEntry::new(
+            Some(UserId(11)),
+            AuditAction::TranscriptExport,
+            "Participant export".to_owned(),
+        )
+        .conversation(ConversationId(1));
+        db.append_audit(&export).await?;
+        let erasure = AuditEntry::new(
+            Some(ADMIN),
+            AuditAction::UserErasure,
+            "Customer deleted their account".to_owned(),
+        )
+        .subject(UserId(11));
+        db.append_audit(&erasure).await?;
+        db.append_audit(&erasure).await?;
+
+        let all = db.get_audit_log(&AuditFilter::default()).await?;
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].entry, export);
+        let by_admin = AuditFilter {
+            actor_id: Some(ADMIN.0),
+            ..AuditFilter::default()
+        };
+        assert_eq!(db.get_audit_log(&by_admin).await?.len(), 2);
+        assert_eq!(db.verify_audit_log().await?, None);
+
+        // Append-only...
+        let querier = db.get_querier().await?;
+        let rewrite = sqlx::query!("UPDATE audit_log SET reason = 'Nothing to see here'")
+            .execute(querier.q)
+            .await;
+        assert!(rewrite.is_err());
+        assert!(
+            sqlx::query!("DELETE FROM audit_log")
+                .execute(querier.q)
+                .await
+                .is_err()
+        );
+
+        // ...and tampering around the triggers breaks the chain.
+        sqlx::query!("DROP TRIGGER audit_log_no_update")
+            .execute(querier.q)
+            .await?;
+        sqlx::query!("UPDATE audit_log SET reason = 'Nothing to see here' WHERE id = 2")
+            .execute(querier.q)
+            .await?;
+        assert_eq!(db.verify_audit_log().await?, Some(2));
+        Ok(())
+    }
+
+    /// Appends `entry` to the audit log the way someone writing to the database directly would,
+    /// chained with `key`.
+    async fn forge_audit(db: &SQLiteDB, entry: &AuditEntry, key: &AuditKey) -> anyhow::Result<()> {
+        let prev_hash = sqlx::query_scalar!("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1")
+            .fetch_optional(&db.pool)
+            .await?
+            .unwrap_or_else(|| vec![0; 32]);
+        let timestamp = Utc::now().to_rfc3339();
+        let hash = entry.hash(key, &prev_hash, &timestamp)?;
+        sqlx::query!(
+            r#"
+            INSERT INTO audit_log (timestamp, actor_id, action, subject_id, reason, prev_hash, hash)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+            timestamp,
+            entry.actor_id,
+            entry.action,
+            entry.subject_id,
+            entry.reason,
+            prev_hash,
+            hash
+        )
+        .execute(&db.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keyed_audit_log() -> anyhow::Result<()> {
+        let entry = erasure(UserId(11));
+        let other_key = AuditKey::random(&mut StdRng::from_os_rng());
+
+        // Without the key, an entry can't be added...
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+        db.append_audit(&entry).await?;
+        forge_audit(&db, &entry, &other_key).await?;
+        assert_eq!(db.verify_audit_log().await?, Some(2));
+
+        // ...nor the whole log written anew.
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+        forge_audit(&db, &entry, &other_key).await?;
+        forge_audit(&db, &entry, &other_key).await?;
+        assert_eq!(db.verify_audit_log().await?, Some(1));
+        Ok(())
+    }
+
+    // The very queries of the methods, to check their plans.
+    const MOST_RECENT_MESSAGES: &str = include_str!("queries/sqlite/most_recent_messages.sql");
+    const MESSAGES_AFTER: &str = include_str!("queries/sqlite/messages_after.sql");
+    const FILTER_CONVERSATIONS: &str = include_str!("queries/sqlite/filter_conversations.sql");
+    const FIND_CONVERSATION: &str = include_str!("queries/sqlite/find_conversation.sql");
+    const MARK_DELIVERED: &str = include_str!("queries/sqlite/mark_delivered.sql");
+    const MESSAGE_CHAIN: &str = include_str!("queries/sqlite/message_chain.sql");
+
+    /// Steps of sqlite's plan for `sql`, run with `binds` as its parameters.
+    async fn query_plan(db: &SQLiteDB, sql: &str, binds: &[i64]) -> anyhow::Result<Vec<String>> {
+        let explain = format!("EXPLAIN QUERY PLAN {sql}");
+        let mut query = sqlx::query_as::<_, (i64, i64, i64, String)>(&explain);
+        for bind in binds {
+            query = query.bind(bind);
+        }
+        let steps = query.fetch_all(&db.pool).await?;
+        Ok(steps.into_iter().map(|(_, _, _, detail)| detail).collect())
+    }
+
+    /// Writes one conversation with `seller` about `product` for each of `conversations` new
+    /// clients, sharing `messages` between them. Written straight in: erased messages need no keys.
+    async fn seed_conversations(
+        db: &SQLiteDB,
+        seller: UserId,
+        product: ProductId,
+        conversations: i64,
+        messages: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?)
+            INSERT INTO user (id, username, name) SELECT 10 + i, x'', x'' FROM n",
+        )
+        .bind(conversations)
+        .execute(&db.writer)
+        .await?;
+        sqlx::query(
+            "INSERT INTO conversation (client_id, seller_id, product_id)
+            SELECT id, ?, ? FROM user WHERE id >= 10",
+        )
+        .bind(seller)
+        .bind(product)
+        .execute(&db.writer)
+        .await?;
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i + 1 < ?)
+            INSERT INTO message (sender_id, conversation_id, salt, timestamp)
+            SELECT 11 + i % ?2, 1 + i % ?2, x'', datetime('now') FROM n",
+        )
+        .bind(messages)
+        .bind(conversations)
+        .execute(&db.writer)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_plans() -> anyhow::Result<()> {
+        // Enough rows that a scan shows.
+        const CONVERSATIONS: i64 = 2_000;
+        const MESSAGES: i64 = 200_000;
+
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+        let seller = db
+            .add_user(&UserProfile::new_clone(2, "seller", "Seller"))
+            .await?;
+        let product = db
+            .add_product(&Product::new("Dill Dough".to_owned(), seller, 1))
+            .await?;
+
+        seed_conversations(&db, seller, product, CONVERSATIONS, MESSAGES).await?;
+
+        // The lookups of the methods below, which used to scan `message` or `conversation`.
+        let lookups: [(&str, &str, &[i64]); 6] = [
+            ("get_most_recent_messages", MOST_RECENT_MESSAGES, &[7]),
+            ("get_messages_after", MESSAGES_AFTER, &[7, 1_000, 100]),
+            ("filter_conversations", FILTER_CONVERSATIONS, &[17]),
+            (
+                "start_conversation",
+                FIND_CONVERSATION,
+                &[17, 2, 17, 2, 17, 1, 1],
+            ),
+            ("mark_delivered", MARK_DELIVERED, &[0, 7, 1_000, 2]),
+            ("verify_message_chain", MESSAGE_CHAIN, &[7]),
+        ];
+        for (method, sql, binds) in lookups {
+            let plan = query_plan(&db, sql, binds).await?;
+            // Scanning the few rows a query already picked out is fine.
+            assert!(
+                plan.iter().all(|step| !matches!(
+                    step.split(' ').take(2).collect::<Vec<_>>()[..],
+                    ["SCAN", "message" | "conversation"]
+                )),
+                "{method} scans: {plan:?}"
+            );
+        }
+
+        let (messages, _) = db.get_most_recent_messages(&ConversationId(7)).await?;
+        assert_eq!(messages.len(), 32);
+        let conversations = db.get_conversations(&UserId(17)).await?;
+        assert_eq!(conversations, [ConversationId(7)]);
+        assert_eq!(
+            db.start_conversation(&UserId(17), &seller, &product)
+                .await?,
+            ConversationId(7)
+        );
+
+        // Even written around `start_conversation`, a conversation can't be duplicated.
+        let duplicate = sqlx::query(
+            "INSERT INTO conversation (client_id, seller_id, product_id) VALUES (17, ?, ?)",
+        )
+        .bind(seller)
+        .bind(product)
+        .execute(&db.writer)
+        .await;
+        assert!(duplicate.is_err());
+        Ok(())
+    }
+
+    const BENCH_CLIENTS: i64 = 32;
+    /// Clients poll far more often than they post.
+    const BENCH_POLLS: usize = 4;
+
+    /// Fresh database at `path` with a conversation per client, about the same product.
+    async fn bench_db(
+        path: &std::path::Path,
+    ) -> anyhow::Result<(SQLiteDB, Vec<(UserId, ConversationId)>)> {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        let db = SQLiteDB::new(&format!("sqlite:{}", path.display()), key()?).await?;
+
+        let seller = db
+            .add_user(&UserProfile::new_clone(2, "seller", "Seller"))
+            .await?;
+        let product = db
+            .add_product(&Product::new("Dill Dough".to_owned(), seller, 1))
+            .await?;
+        let mut conversations = Vec::new();
+        for id in 10..10 + BENCH_CLIENTS {
+            let profile = UserProfile::new_clone(id, &format!("client{id}"), "Client");
+            let client = db.add_user(&profile).await?;
+            let convo = db.start_conversation(&client, &seller, &product).await?;
+            conversations.push((client, convo));
+        }
+        Ok((db, conversations))
+    }
+
+    /// Posts and polls `rounds` times from every client at once, first serialized by a lock around
+    /// the database like the server used to, then straight on the pools. Each on a database of its
+    /// own, left for the caller to check. Returns how long both took.
+    async fn concurrent_traffic(
+        path: &std::path::Path,
+        rounds: usize,
+    ) -> anyhow::Result<(
+        std::time::Duration,
+        std::time::Duration,
+        SQLiteDB,
+        Vec<(UserId, ConversationId)>,
+    )> {
+        let (db, conversations) = bench_db(path).await?;
+        let lock = tokio::sync::RwLock::new(&db);
+        let start = std::time::Instant::now();
+        futures_util::future::try_join_all(conversations.iter().map(|(client, convo)| {
+            let lock = &lock;
+            async move {
+                for round in 0..rounds {
+                    let msg = Message::new(format!("#{round}"), Utc::now());
+                    lock.write().await.post_msg(msg, client, convo).await?;
+                    for _ in 0..BENCH_POLLS {
+                        lock.read().await.get_conversations(client).await?;
+                        lock.read().await.get_most_recent_messages(convo).await?;
+                    }
+                }
+                Ok::<_, DbError>(())
+            }
+        }))
+        .await?;
+        let locked = start.elapsed();
+        db.pool.close().await;
+        db.writer.close().await;
+
+        let (db, conversations) = bench_db(path).await?;
+        let start = std::time::Instant::now();
+        futures_util::future::try_join_all(conversations.iter().map(|(client, convo)| {
+            let db = &db;
+            async move {
+                for round in 0..rounds {
+                    let msg = Message::new(format!("#{round}"), Utc::now());
+                    db.post_msg(msg, client, convo).await?;
+                    for _ in 0..BENCH_POLLS {
+                        db.get_conversations(client).await?;
+                        db.get_most_recent_messages(convo).await?;
+                    }
+                }
+                Ok::<_, DbError>(())
+            }
+        }))
+        .await?;
+        Ok((locked, start.elapsed(), db, conversations))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_traffic_keeps_every_message() -> anyhow::Result<()> {
+        const ROUNDS: usize = 3;
+        let path = std::env::temp_dir().join(format!("traffic_{}.sqlite3", std::process::id()));
+
+        let (_, _, db, conversations) = concurrent_traffic(&path, ROUNDS).await?;
+        for (client, convo) in &conversations {
+            let messages = db.get_messages_after(convo, None, 100).await?;
+            let contents: Vec<_> = messages.iter().map(|(_, _, m)| m.contents()).collect();
+            assert_eq!(contents, ["#0", "#1", "#2"]);
+            assert!(messages.iter().all(|(_, sender, _)| sender == client));
+            assert_eq!(db.verify_message_chain(convo).await?, None);
+        }
+
+        db.pool.close().await;
+        db.writer.close().await;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    /// Run with `cargo test --release bench_concurrent_traffic -- --ignored --nocapture`.
+    ///
+    /// Requests are CPU-bound: a poll spends about as long in sqlite as decrypting the messages it
+    /// returns, and a post about as long committing. On a single core, going concurrent gains
+    /// nothing (1.0x): WAL lets reads carry on while the one writer commits, which only pays off
+    /// with cores to spare or while a commit waits on the disk. The shared rng isn't contended,
+    /// it's locked for the ~20ns of drawing a nonce.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn bench_concurrent_traffic() -> anyhow::Result<()> {
+        const ROUNDS: usize = 50;
+        let path = std::env::temp_dir().join(format!("bench_{}.sqlite3", std::process::id()));
+
+        let (locked, concurrent, db, conversations) = concurrent_traffic(&path, ROUNDS).await?;
+        let requests = u32::try_from(conversations.len() * ROUNDS * (1 + 2 * BENCH_POLLS))?;
+        let rate = |elapsed: std::time::Duration| f64::from(requests) / elapsed.as_secs_f64();
+        let speedup = locked.as_secs_f64() / concurrent.as_secs_f64();
+        println!(
+            "{requests} requests from {BENCH_CLIENTS} clients on {} cores: {:.0}/s locked, {:.0}/s concurrent ({speedup:.1}x)",
+            std::thread::available_parallelism()?,
+            rate(locked),
+            rate(concurrent),
+        );
+        for (_, convo) in &conversations {
+            assert_eq!(db.verify_message_chain(convo).await?, None);
+        }
+        // Generous, timings are noisy: dropping the lock must at least not make things worse.
+        assert!(speedup > 0.8, "concurrent traffic is {speedup:.1}x as fast");
+
+        db.pool.close().await;
+        db.writer.close().await;
+        std::fs::remove_file(&path)?;
         Ok(())
     }
 }
@@ -0,0 +1,492 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{
+    Backend,
+    model::{
+        ChainHead, ConversationId, ConversationStatus, DbError, DeliveryStatus, Message, MessageId,
+        Product, StoreId, UserId, UserProfile,
+    },
+};
+
+/// Messages decrypted per database round-trip while exporting.
+pub const PAGE_SIZE: u32 = 256;
+
+/// Bumped whenever the layout of [`PersonalData`] changes in an incompatible way.
+pub const PERSONAL_DATA_FORMAT_VERSION: u32 = 1;
+
+/// Formats a conversation transcript can be exported in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptFormat {
+    #[default]
+    Json,
+    Html,
+    Txt,
+}
+
+/// Everything about a conversation that goes before its messages.
+#[derive(Debug, Serialize)]
+pub struct TranscriptHeader {
+    pub conversation: ConversationId,
+    pub product: Product,
+    pub requested_by: UserId,
+    pub peer: UserProfile,
+    pub exported_at: DateTime<Utc>,
+    /// Proves, later on, that the history up to it wasn't altered.
+    pub chain_head: Option<ChainHead>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TranscriptEntry<'a> {
+    pub id: MessageId,
+    pub sender_id: UserId,
+    pub sender_name: &'a str,
+    pub timestamp: &'a DateTime<Utc>,
+    pub contents: &'a str,
+    pub status: DeliveryStatus,
+}
+
+impl<'a> TranscriptEntry<'a> {
+    pub fn new(id: MessageId, sender_id: UserId, sender_name: &'a str, msg: &'a Message) -> Self {
+        Self {
+            id,
+            sender_id,
+            sender_name,
+            timestamp: msg.timestamp(),
+            contents: msg.contents(),
+            status: msg.status(),
+        }
+    }
+}
+
+impl TranscriptFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TranscriptFormat::Json => "application/json",
+            TranscriptFormat::Html => "text/html; charset=utf-8",
+            TranscriptFormat::Txt => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TranscriptFormat::Json => "json",
+            TranscriptFormat::Html => "html",
+            TranscriptFormat::Txt => "txt",
+        }
+    }
+
+    /// Opens the document. The JSON format leaves the `messages` array open.
+    pub fn header(self, header: &TranscriptHeader) -> Result<String, serde_json::Error> {
+        let product = &header.product;
+        let peer = &header.peer;
+        let chain_head = header.chain_head.as_ref().map_or_else(String::new, |head| {
+            format!("History hash: {} (up to #{})", head.hash, head.message_id.0)
+        });
+        Ok(match self {
+            TranscriptFormat::Json => {
+                let mut json = serde_json::to_string(header)?;
+                // Reopen the object to append the messages as they are streamed.
+                json.pop();
+                json.push_str(r#","messages":["#);
+                json
+            }
+            TranscriptFormat::Html => format!(
+                "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Conversation #{id}</title></head>\n<body>\n\
+                 <h1>Conversation #{id}</h1>\n\
+                 <p>Product: {product} (#{product_id})</p>\n\
+                 <p>Peer: {peer} (@{username}, #{peer_id})</p>\n\
+                 <p>Exported at {exported_at} by #{requested_by}</p>\n\
+                 <p>{chain_head}</p>\n\
+                 <table>\n<tr><th>Time</th><th>Sender</th><th>Message</th><th>Status</th></tr>\n",
+                id = header.conversation.0,
+                product = escape_html(&product.name),
+                product_id = product.product_info(),
+                peer = escape_html(&peer.name()),
+                username = escape_html(&peer.username()),
+                peer_id = peer.id().0,
+                exported_at = header.exported_at,
+                requested_by = header.requested_by.0,
+            ),
+            TranscriptFormat::Txt => format!(
+                "Conversation #{id}\nProduct: {product} (#{product_id})\nPeer: {peer} (@{username}, #{peer_id})\nExported at {exported_at} by #{requested_by}\n{chain_head}\n\n",
+                id = header.conversation.0,
+                product = escape_txt(&product.name),
+                product_id = product.product_info(),
+                peer = escape_txt(&peer.name()),
+                username = escape_txt(&peer.username()),
+                peer_id = peer.id().0,
+                exported_at = header.exported_at,
+                requested_by = header.requested_by.0,
+            ),
+        })
+    }
+
+    /// A single message. `first` tells whether it is the first one in the transcript.
+    pub fn entry(self, entry: &TranscriptEntry, first: bool) -> Result<String, serde_json::Error> {
+        Ok(match self {
+            TranscriptFormat::Json => {
+                let json = serde_json::to_string(entry)?;
+                if first { json } else { format!(",{json}") }
+            }
+            TranscriptFormat::Html => format!(
+                "<tr><td>{}</td><td>{} (#{})</td><td>{}</td><td>{}</td></tr>\n",
+                entry.timestamp,
+                escape_html(entry.sender_name),
+                entry.sender_id.0,
+                escape_html(entry.contents),
+                entry.status,
+            ),
+            TranscriptFormat::Txt => format!(
+                "[{}] {} (#{}): {}\n",
+                entry.timestamp,
+                escape_txt(entry.sender_name),
+                entry.sender_id.0,
+                escape_txt(entry.contents)
+            ),
+        })
+    }
+
+    /// Closes whatever `header` opened.
+    pub fn footer(self) -> String {
+        match self {
+            TranscriptFormat::Json => "]}".to_owned(),
+            TranscriptFormat::Html => "</table>\n</body>\n</html>\n".to_owned(),
+            TranscriptFormat::Txt => String::new(),
+        }
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Keeps `text` from forging lines of a plain text transcript: line breaks go on to an indented
+/// continuation line, and other control characters are written as escapes.
+pub fn escape_txt(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' | '\u{2028}' | '\u{2029}' => escaped.push_str("\n    "),
+            '\t' => escaped.push(c),
+            c if c.is_control() => escaped.extend(c.escape_default()),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Everything we hold about a user, as handed out on a data access request.
+///
+/// Serialized as a single JSON document:
+///
+/// ```text
+/// {
+///   "format_version": 1,
+///   "exported_at": "<RFC 3339>",
+///   "user": { "id", "username", "name" },
+///   "stores": [{ "id", "name" }],
+///   "products": [{ "name", "seller_id", "jumpseller_id" }],
+///   "conversations": [{
+///     "id", "status": "open|waiting|resolved", "labels": ["..."], "assigned": true|false,
+///     "peer": { "id", "username", "name" },
+///     "product": { "name", "seller_id", "jumpseller_id" },
+///     "chain_head": { "message_id", "hash" } | null,
+///     "messages": [{ "id", "sender_id", "timestamp", "contents", "status": "sent|delivered|read" }]
+///   }]
+/// }
+/// ```
+///
+/// Messages are in chronological order and include both the ones the user sent and received.
+/// In the conversations they only see as staff of a store, the messages are the user's own: the
+/// client's and the other staff's aren't theirs to hand out.
+#[derive(Debug, Serialize)]
+pub struct PersonalData {
+    pub format_version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub user: UserProfile,
+    /// Stores the user is staff of.
+    pub stores: Vec<StoreMembership>,
+    /// Products the user sells.
+    pub products: Vec<Product>,
+    pub conversations: Vec<ConversationArchive>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoreMembership {
+    pub id: StoreId,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationArchive {
+    pub id: ConversationId,
+    pub status: ConversationStatus,
+    /// Only the labels the user themselves set.
+    pub labels: Vec<String>,
+    /// Whether the conversation is assigned to the user in their store's inbox.
+    pub assigned: bool,
+    pub peer: UserProfile,
+    pub product: Product,
+    /// Latest link of the message chain when exported.
+    pub chain_head: Option<ChainHead>,
+    pub messages: Vec<ArchivedMessage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchivedMessage {
+    pub id: MessageId,
+    pub sender_id: UserId,
+    pub timestamp: DateTime<Utc>,
+    pub contents: String,
+    pub status: DeliveryStatus,
+}
+
+impl PersonalData {
+    /// Gathers the profile, stores, products, conversations and decrypted messages of `user`.
+    ///
+    /// # Errors
+    /// Fails if the user does not exist or the database cannot be read.
+    pub async fn collect(db: &impl Backend, user: &UserId) -> Result<Self, DbError> {
+        let profile = db.get_user_profile(user).await?;
+        let stores = db
+            .get_user_stores(user)
+            .await?
+            .into_iter()
+            .map(|(id, store)| StoreMembership {
+                id,
+                name: store.name,
+            })
+            .collect();
+        let mut conversations = vec![];
+        for convo_id in db.get_conversations(user).await? {
+            let peer = db.get_peer(user, &convo_id).await?;
+            // The peer of a participant's peer is the participant; staff answer for the seller.
+            let staff_only = db.get_peer(&peer, &convo_id).await? != *user;
+            let product_id = db.get_product_id_from_conversation_id(&convo_id).await?;
+            let chain_head = db.get_chain_head(&convo_id).await?;
+
+            let mut messages = vec![];
+            loop {
+                let page = db
+                    .get_messages_after(
+                        &convo_id,
+                        messages.last().map(|m: &ArchivedMessage| &m.id),
+                        PAGE_SIZE,
+                    )
+                    .await?;
+                let done = page.len() < PAGE_SIZE as usize;
+                messages.extend(
+                    page.into_iter()
+                        .map(|(id, sender_id, msg)| ArchivedMessage {
+                            id,
+                            sender_id,
+                            timestamp: *msg.timestamp(),
+                            contents: msg.contents().to_owned(),
+                            status: msg.status(),
+                        }),
+                );
+                if done {
+                    break;
+                }
+            }
+            if staff_only {
+                messages.retain(|m| m.sender_id == *user);
+            }
+
+            conversations.push(ConversationArchive {
+                id: convo_id,
+                status: db.get_status(&convo_id).await?,
+                labels: db.get_labels(user, &convo_id).await?,
+                assigned: db.get_assignee(&convo_id).await? == Some(*user),
+                peer: db.get_user_profile(&peer).await?,
+                product: db.get_product(&product_id).await?,
+                chain_head,
+                messages,
+            });
+        }
+
+        Ok(Self {
+            format_version: PERSONAL_DATA_FORMAT_VERSION,
+            exported_at: Utc::now(),
+            user: profile,
+            stores,
+            products: db.get_seller_products(user).await?,
+            conversations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::database::{Database, crypto::tests::key, model::Store, sqlite::SQLiteDB};
+
+    #[test]
+    fn json_transcript_is_valid() -> anyhow::Result<()> {
+        let header = TranscriptHeader {
+            conversation: ConversationId(1),
+            product: Product::new("Dill Dough".to_owned(), UserId(2), 3),
+            requested_by: UserId(1),
+            peer: UserProfile::new_clone(2, "bobert22", "Bob Bellows"),
+            exported_at: Utc::now(),
+            chain_head: Some(ChainHead {
+                message_id: MessageId(2),
+                hash: "ab".repeat(32),
+            }),
+        };
+        let hello = Message::from("Hello \"Bob\"!");
+        let reply = Message::from("<b>Hi</b>");
+
+        let format = TranscriptFormat::Json;
+        let mut doc = format.header(&header)?;
+        doc += &format.entry(
+            &TranscriptEntry::new(MessageId(1), UserId(1), "Alice", &hello),
+            true,
+        )?;
+        doc += &format.entry(
+            &TranscriptEntry::new(MessageId(2), UserId(2), "Bob", &reply),
+            false,
+        )?;
+        doc += &format.footer();
+
+        let json: serde_json::Value = serde_json::from_str(&doc)?;
+        assert_eq!(json["peer"]["username"], "bobert22");
+        assert_eq!(json["messages"][0]["contents"], "Hello \"Bob\"!");
+        assert_eq!(json["messages"][1]["sender_id"], 2);
+        assert_eq!(json["chain_head"]["message_id"], 2);
+
+        let html = TranscriptFormat::Html.entry(
+            &TranscriptEntry::new(MessageId(2), UserId(2), "Bob", &reply),
+            true,
+        )?;
+        assert!(html.contains("&lt;b&gt;Hi&lt;/b&gt;"));
+        Ok(())
+    }
+
+    #[test]
+    fn txt_transcript_lines_cant_be_forged() -> anyhow::Result<()> {
+        let forged =
+            Message::from("Deal?\r\n[2025-01-01 00:00:00 UTC] Bob (#2): Free, keep it\x1b[2K");
+        let txt = TranscriptFormat::Txt.entry(
+            &TranscriptEntry::new(MessageId(1), UserId(1), "Alice\nBob", &forged),
+            true,
+        )?;
+        let lines: Vec<_> = txt.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("] Alice"));
+        assert_eq!(lines[1], "    Bob (#1): Deal?");
+        assert_eq!(
+            lines[2],
+            "    [2025-01-01 00:00:00 UTC] Bob (#2): Free, keep it\\u{1b}[2K"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn personal_data_has_both_sides() -> anyhow::Result<()> {
+        let alice = UserProfile::new_clone(11, "alice_11", "Alice Arnold");
+        let bob = UserProfile::new_clone(22, "bobert22", "Bob Bellows");
+        let carol = UserProfile::new_clone(33, "carol33", "Carol Cooper");
+
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+
+        let alice_id = db.add_user(&alice).await?;
+        let bob_id = db.add_user(&bob).await?;
+        let carol_id = db.add_user(&carol).await?;
+        let prod_id = db
+            .add_product(&Product::new("Dill Dough".to_owned(), bob_id, 1))
+            .await?;
+        let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
+        let other_convo = db.start_conversation(&carol_id, &bob_id, &prod_id).await?;
+
+        db.post_msg(Message::from("Hello Bob!"), &alice_id, &convo_id)
+            .await?;
+        db.post_msg(Message::from("Hello Alice!"), &bob_id, &convo_id)
+            .await?;
+        db.post_msg(Message::from("Hi, it's Carol"), &carol_id, &other_convo)
+            .await?;
+
+        let archive = PersonalData::collect(&db, &alice_id).await?;
+        assert_eq!(archive.user.username(), "alice_11");
+        assert_eq!(archive.conversations.len(), 1);
+        let convo = &archive.conversations[0];
+        assert_eq!(convo.peer.id(), bob_id);
+        let contents: Vec<_> = convo.messages.iter().map(|m| m.contents.as_str()).collect();
+        assert_eq!(contents, ["Hello Bob!", "Hello Alice!"]);
+
+        let json = serde_json::to_value(&archive)?;
+        assert_eq!(json["format_version"], PERSONAL_DATA_FORMAT_VERSION);
+        assert_eq!(json["conversations"][0]["product"]["name"], "Dill Dough");
+        assert_eq!(
+            json["conversations"][0]["chain_head"]["message_id"],
+            convo.messages[1].id.0
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn personal_data_of_staff() -> anyhow::Result<()> {
+        let db = SQLiteDB::new("sqlite::memory:", key()?).await?;
+
+        let alice_id = db
+            .add_user(&UserProfile::new_clone(11, "alice_11", "Alice Arnold"))
+            .await?;
+        let bob_id = db
+            .add_user(&UserProfile::new_clone(22, "bobert22", "Bob Bellows"))
+            .await?;
+        let dave_id = db
+            .add_user(&UserProfile::new_clone(44, "dave44", "Dave Dawson"))
+            .await?;
+        let store_id = db
+            .add_store(&bob_id, &Store::new("Bakery".to_owned()))
+            .await?;
+        db.add_store_member(&bob_id, &store_id, &dave_id).await?;
+        let prod_id = db
+            .add_product(&Product::new("Dill Dough".to_owned(), bob_id, 1))
+            .await?;
+        let convo_id = db.start_conversation(&alice_id, &bob_id, &prod_id).await?;
+        db.post_msg(Message::from("Is it fresh?"), &alice_id, &convo_id)
+            .await?;
+        db.post_msg(Message::from("Baked today"), &dave_id, &convo_id)
+            .await?;
+        db.post_msg(Message::from("By me"), &bob_id, &convo_id)
+            .await?;
+        db.assign_conversation(&bob_id, &convo_id, Some(&dave_id))
+            .await?;
+
+        let archive = PersonalData::collect(&db, &dave_id).await?;
+        assert_eq!(archive.stores.len(), 1);
+        assert_eq!(archive.stores[0].id, store_id);
+        assert_eq!(archive.stores[0].name, "Bakery");
+        assert!(archive.products.is_empty());
+        let convo = &archive.conversations[0];
+        assert!(convo.assigned);
+        let contents: Vec<_> = convo.messages.iter().map(|m| m.contents.as_str()).collect();
+        assert_eq!(contents, ["Baked today"]);
+
+        // The seller is a participant, staff or not.
+        let archive = PersonalData::collect(&db, &bob_id).await?;
+        assert_eq!(archive.products[0].name, "Dill Dough");
+        let convo = &archive.conversations[0];
+        assert!(!convo.assigned);
+        assert_eq!(convo.messages.len(), 3);
+        Ok(())
+    }
+}
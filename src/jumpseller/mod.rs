@@ -1,7 +1,7 @@
 use actix_web::http::StatusCode as ActixStatusCode;
 use anyhow::anyhow;
 
-use crate::{JumpSellerCredentials, database::sqlite::UserProfile};
+use crate::{JumpSellerCredentials, database::model::UserProfile};
 
 pub enum Client {
     Dummy,
@@ -0,0 +1,246 @@
+//! Where master keys come from.
+
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{Context, anyhow};
+use zeroize::Zeroizing;
+
+use crate::database::crypto::{CryptoKey, KdfParams};
+
+/// Source of master key material. Whatever is read to build a key is wiped once it is built.
+pub trait KeyProvider {
+    /// Master key `id`, derived with `kdf` when it comes from a password.
+    async fn master_key(&self, id: u32, kdf: KdfParams) -> anyhow::Result<CryptoKey>;
+}
+
+/// Password and salt, each in its own file.
+pub struct PasswordFiles {
+    pub password: PathBuf,
+    pub salt: PathBuf,
+}
+
+impl KeyProvider for PasswordFiles {
+    async fn master_key(&self, _: u32, kdf: KdfParams) -> anyhow::Result<CryptoKey> {
+        let read = |path: &PathBuf| {
+            std::fs::read_to_string(path)
+                .map(Zeroizing::new)
+                .with_context(|| format!("Reading {}", path.display()))
+        };
+        derive(&read(&self.password)?, &read(&self.salt)?, kdf)
+    }
+}
+
+/// Password and salt, each in its own environment variable.
+pub struct PasswordEnv {
+    pub password: String,
+    pub salt: String,
+}
+
+impl KeyProvider for PasswordEnv {
+    async fn master_key(&self, _: u32, kdf: KdfParams) -> anyhow::Result<CryptoKey> {
+        let read = |var: &str| {
+            std::env::var(var)
+                .map(Zeroizing::new)
+                .with_context(|| format!("Reading ${var}"))
+        };
+        derive(&read(&self.password)?, &read(&self.salt)?, kdf)
+    }
+}
+
+/// The 32 bytes of the key itself in a file, used as is.
+pub struct RawKeyFile(pub PathBuf);
+
+impl KeyProvider for RawKeyFile {
+    async fn master_key(&self, _: u32, _: KdfParams) -> anyhow::Result<CryptoKey> {
+        let raw = std::fs::read(&self.0)
+            .map(Zeroizing::new)
+            .with_context(|| format!("Reading {}", self.0.display()))?;
+        Ok(CryptoKey::from_raw(&raw)?)
+    }
+}
+
+/// Key management service holding the keys: `GET {url}/keys/{id}` answers
+/// `{"key": "<64 hex digits>"}`, with `token` sent as a bearer token if any.
+pub struct Kms {
+    pub url: String,
+    pub token: Option<Zeroizing<String>>,
+}
+
+#[derive(serde::Deserialize)]
+struct KmsKey {
+    key: String,
+}
+
+impl KeyProvider for Kms {
+    async fn master_key(&self, id: u32, _: KdfParams) -> anyhow::Result<CryptoKey> {
+        let url = format!("{}/keys/{id}", self.url.trim_end_matches('/'));
+        let mut request = reqwest::Client::new().get(&url);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token.as_str());
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("{url} answered {status}"));
+        }
+        let body = Zeroizing::new(response.text().await?);
+        let key = Zeroizing::new(serde_json::from_str::<KmsKey>(&body)?.key);
+        let raw = Zeroizing::new(hex::decode(key.as_str())?);
+        Ok(CryptoKey::from_raw(&raw)?)
+    }
+}
+
+fn derive(password: &str, salt: &str, kdf: KdfParams) -> anyhow::Result<CryptoKey> {
+    CryptoKey::derive(password.trim(), salt.trim(), kdf).map_err(|e| anyhow!("Error: {e}"))
+}
+
+/// A key provider as given on the command line:
+/// - `file:PASSWORD_FILE:SALT_FILE`
+/// - `env:PASSWORD_VAR:SALT_VAR`
+/// - `raw:KEY_FILE`
+/// - `kms:URL`, authenticated with `$KMS_TOKEN` if set
+#[derive(Clone, Debug)]
+pub enum KeySource {
+    Files { password: PathBuf, salt: PathBuf },
+    Env { password: String, salt: String },
+    Raw(PathBuf),
+    Kms(String),
+}
+
+impl KeyProvider for KeySource {
+    async fn master_key(&self, id: u32, kdf: KdfParams) -> anyhow::Result<CryptoKey> {
+        match self {
+            Self::Files { password, salt } => {
+                PasswordFiles {
+                    password: password.clone(),
+                    salt: salt.clone(),
+                }
+                .master_key(id, kdf)
+                .await
+            }
+            Self::Env { password, salt } => {
+                PasswordEnv {
+                    password: password.clone(),
+                    salt: salt.clone(),
+                }
+                .master_key(id, kdf)
+                .await
+            }
+            Self::Raw(path) => RawKeyFile(path.clone()).master_key(id, kdf).await,
+            Self::Kms(url) => {
+                Kms {
+                    url: url.clone(),
+                    token: std::env::var("KMS_TOKEN").ok().map(Zeroizing::new),
+                }
+                .master_key(id, kdf)
+                .await
+            }
+        }
+    }
+}
+
+impl FromStr for KeySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+        let pair = || {
+            rest.split_once(':')
+                .filter(|(a, b)| !a.is_empty() && !b.is_empty())
+                .ok_or_else(|| format!("expected {kind}:PASSWORD:SALT"))
+        };
+        match kind {
+            "file" => pair().map(|(password, salt)| Self::Files {
+                password: password.into(),
+                salt: salt.into(),
+            }),
+            "env" => pair().map(|(password, salt)| Self::Env {
+                password: password.to_owned(),
+                salt: salt.to_owned(),
+            }),
+            "raw" if !rest.is_empty() => Ok(Self::Raw(rest.into())),
+            "kms" if !rest.is_empty() => Ok(Self::Kms(rest.to_owned())),
+            _ => Err(
+                "expected file:, env:, raw: or kms: followed by where to find the key".to_owned(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use zeroize::Zeroizing;
+
+    use crate::{
+        database::crypto::{CryptData, KdfParams},
+        keys::{KeyProvider, KeySource, Kms},
+    };
+
+    #[test]
+    fn parse_sources() {
+        assert!(matches!(
+            "file:pass.txt:salt.txt".parse(),
+            Ok(KeySource::Files { .. })
+        ));
+        assert!(matches!("env:PASS:SALT".parse(), Ok(KeySource::Env { .. })));
+        assert!(matches!("raw:master.key".parse(), Ok(KeySource::Raw(_))));
+        assert!(matches!(
+            "kms:http://localhost:9000".parse(),
+            Ok(KeySource::Kms(url)) if url == "http://localhost:9000"
+        ));
+        assert!("env:PASS".parse::<KeySource>().is_err());
+        assert!("raw:".parse::<KeySource>().is_err());
+        assert!("pass.txt".parse::<KeySource>().is_err());
+    }
+
+    /// Stands in for the key management service, answering a single request.
+    async fn kms_stub(key: [u8; 32]) -> anyhow::Result<(String, tokio::task::JoinHandle<String>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let handle = tokio::spawn(async move {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return String::new();
+            };
+            let mut request = vec![0; 4096];
+            let n = stream.read(&mut request).await.unwrap_or_default();
+            let body = format!(r#"{{"key": "{}"}}"#, hex::encode(key));
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            String::from_utf8_lossy(&request[..n]).into_owned()
+        });
+        Ok((url, handle))
+    }
+
+    #[tokio::test]
+    async fn kms() -> anyhow::Result<()> {
+        let raw = [7u8; 32];
+        let (url, stub) = kms_stub(raw).await?;
+        let kms = Kms {
+            url,
+            token: Some(Zeroizing::new("s3cr3t".to_owned())),
+        };
+        let key = kms.master_key(2, KdfParams::default()).await?;
+        let request = stub.await?;
+        assert!(request.starts_with("GET /keys/2 "));
+        assert!(request.contains("Bearer s3cr3t"));
+
+        // Same bytes, same key.
+        let rng = &mut rand::rng();
+        let enc = CryptData::encrypt(b"hi".to_vec(), &key, &[], rng)?;
+        let file = std::env::temp_dir().join(format!("raw_key_{}", std::process::id()));
+        std::fs::write(&file, raw)?;
+        let same = KeySource::Raw(file.clone())
+            .master_key(2, KdfParams::default())
+            .await;
+        std::fs::remove_file(&file)?;
+        assert_eq!(enc.decrypt(&same?, &[])?, b"hi");
+        Ok(())
+    }
+}
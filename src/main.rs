@@ -6,15 +6,19 @@
 #![deny(clippy::expect_used)]
 
 use crate::database::{
-    Database,
-    crypto::CryptoKey,
-    sqlite::{ConversationId, DbError, MessageId, SQLiteDB, UserId},
+    Backend,
+    memory::MemoryDB,
+    model::{
+        AuditFilter, ConversationId, ConversationStatus, DbError, DeliveryStatus, MessageId, UserId,
+    },
+    postgres::PostgresDB,
+    sqlite::SQLiteDB,
 };
+use crate::maintenance::{BackupKeyArgs, DbArgs, KeyArgs};
 use actix_cors::Cors;
 use actix_identity::IdentityMiddleware;
 use actix_session::{SessionMiddleware, config::PersistentSession, storage::CookieSessionStore};
 use actix_web::{App, HttpServer, middleware, web};
-use anyhow::anyhow;
 use clap::Parser;
 use cookie::{Key, time::Duration};
 use gcloud_googleapis::pubsub::v1::PubsubMessage;
@@ -23,10 +27,13 @@ use log::info;
 use prost::Message;
 use serde::{Deserialize, Serialize};
 use std::{ffi::OsString, fmt::Debug, path::PathBuf};
-use tokio::sync::RwLock;
 
+mod backup;
 mod database;
+mod export;
 mod jumpseller;
+mod keys;
+mod maintenance;
 mod pubsub;
 mod rest;
 
@@ -42,13 +49,23 @@ struct Cli {
 impl Cli {
     fn startup_log(&self) {
         let mode = match self.command {
-            Commands::Kiosk => "Demonstration",
+            Commands::Kiosk { admins: _ } => "Demonstration",
             Commands::Run {
-                password: _,
-                salt: _,
+                keys: _,
                 db_url: _,
                 jumpseller_cred_file: _,
+                admins: _,
             } => "Production",
+            Commands::ExportUserData { .. }
+            | Commands::EraseUser { .. }
+            | Commands::Audit { .. }
+            | Commands::VerifyChain { .. }
+            | Commands::Reencrypt { .. }
+            | Commands::Backup { .. }
+            | Commands::Restore { .. }
+            | Commands::Migrate { .. } => {
+                return;
+            }
         };
         let port = self.port;
         info!("Starting in {mode} mode on 'http://localhost:{port}'...");
@@ -58,19 +75,125 @@ impl Cli {
 #[derive(clap::Subcommand, Clone, Debug)]
 enum Commands {
     /// Run in demonstration mode (default mode for development)
-    Kiosk,
+    Kiosk {
+        /// Jumpseller id of a user allowed to use the `/admin` endpoints, can be repeated
+        #[arg(long = "admin", value_name = "USER_ID")]
+        admins: Vec<i64>,
+    },
     /// Run in production mode
     Run {
-        /// File containing the password
-        password: PathBuf,
-        /// File containing the hash
-        salt: PathBuf,
+        #[command(flatten)]
+        keys: KeyArgs,
         /// File containing json for the `JumpSeller` credentials.
         #[arg(default_value = OsString::from("local/jumpseller_cred.json"))]
         jumpseller_cred_file: PathBuf,
+        /// Database URL, `sqlite:PATH` or `postgres://...`
+        #[arg(short, long, default_value_t = String::from("sqlite:.sqlite3"))]
+        db_url: String,
+        /// Jumpseller id of a user allowed to use the `/admin` endpoints, can be repeated
+        #[arg(long = "admin", value_name = "USER_ID")]
+        admins: Vec<i64>,
+    },
+    /// Write all the data held about a user as a JSON archive (same as `/me/export`)
+    ExportUserData {
+        #[command(flatten)]
+        db: DbArgs,
+        /// Jumpseller id of the user
+        #[arg(short, long)]
+        user_id: i64,
+        /// Why, recorded in the audit log
+        #[arg(short, long)]
+        reason: String,
+        /// Where to write the archive, defaults to stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Erase a user: pseudonymize their profile and make their messages unrecoverable
+    EraseUser {
+        #[command(flatten)]
+        db: DbArgs,
+        /// Jumpseller id of the user
+        #[arg(short, long)]
+        user_id: i64,
+        /// Why, recorded in the audit log
+        #[arg(short, long)]
+        reason: String,
+    },
+    /// Query the audit log of privileged accesses, newest first
+    Audit {
+        #[command(flatten)]
+        db: DbArgs,
+        /// Only entries by this user
+        #[arg(long)]
+        actor_id: Option<i64>,
+        /// Only entries about this user
+        #[arg(long)]
+        subject_id: Option<i64>,
+        /// Only entries about this conversation
+        #[arg(long)]
+        conversation_id: Option<i64>,
+        #[arg(short, long, default_value_t = 100)]
+        limit: u32,
+        /// Check the hash chain instead of listing entries
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Check that the message history of conversations was not altered
+    VerifyChain {
+        #[command(flatten)]
+        db: DbArgs,
+        /// Conversations to check
+        #[arg(required = true)]
+        conversation_ids: Vec<i64>,
+    },
+    /// Rewrap all data still encrypted under a retired master key with the active one
+    Reencrypt {
+        #[command(flatten)]
+        db: DbArgs,
+        /// Keys rewrapped per transaction
+        #[arg(short, long, default_value_t = 500)]
+        batch_size: u32,
+    },
+    /// Snapshot the database, even while the server runs, into an encrypted and compressed backup
+    Backup {
+        /// Path to sqlite db
+        #[arg(short, long, default_value_t = String::from("sqlite:.sqlite3"))]
+        db_url: String,
+        /// Directory the backups are written to
+        dir: PathBuf,
+        #[command(flatten)]
+        key: BackupKeyArgs,
+        /// Take a backup every this many seconds instead of only once
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        every: Option<u64>,
+        /// Only keep this many backups in DIR, deleting the oldest
+        #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        keep: Option<usize>,
+    },
+    /// Replace the database with a backup, once it is known to be intact
+    Restore {
+        /// Backup file
+        backup: PathBuf,
         /// Path to sqlite db
         #[arg(short, long, default_value_t = String::from("sqlite:.sqlite3"))]
         db_url: String,
+        #[command(flatten)]
+        key: BackupKeyArgs,
+        /// Replace the database if it exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Bring the database schema up to date (also done when the server starts)
+    Migrate {
+        /// Database URL, `sqlite:PATH` or `postgres://...`
+        #[arg(short, long, default_value_t = String::from("sqlite:.sqlite3"))]
+        db_url: String,
+        /// List the migrations that would be applied without applying them
+        #[arg(long)]
+        dry_run: bool,
+        /// List every migration and whether it was applied
+        #[arg(long, conflicts_with = "dry_run")]
+        status: bool,
     },
 }
 
@@ -100,32 +223,64 @@ impl IsProd {
     }
 }
 
+/// Users allowed to use the `/admin` endpoints, none unless given on the command line.
+pub struct Admins(Vec<UserId>);
+
+impl Admins {
+    fn new(ids: &[i64]) -> Self {
+        Self(ids.iter().copied().map(UserId).collect())
+    }
+
+    #[must_use]
+    pub fn contains(&self, user: UserId) -> bool {
+        self.0.contains(&user)
+    }
+}
+
 async fn run_user_facing_code(cli: Cli, utils: BackendInfoUpdater) -> anyhow::Result<()> {
-    let (db, js_cred, is_prod) = match cli.command {
-        Commands::Kiosk => {
-            let suite = CryptoKey::new("demonstration_password", "demonstration_salt")
-                .map_err(|e| anyhow!("Error: {e}"))?;
-            (
-                SQLiteDB::kiosk(suite).await?,
-                get_jumpseller_credentials("local/jumpseller_cred.json".into()),
-                IsProd(false),
-            )
+    match cli.command {
+        Commands::Kiosk { admins } => {
+            let db = MemoryDB::kiosk().await?;
+            let js_cred = get_jumpseller_credentials("local/jumpseller_cred.json".into());
+            let admins = Admins::new(&admins);
+            serve(cli.port, db, js_cred, IsProd(false), admins, utils).await
         }
         Commands::Run {
-            password,
-            salt,
+            keys,
             db_url,
             jumpseller_cred_file,
+            admins,
         } => {
-            let p = std::fs::read_to_string(password)?;
-            let s = std::fs::read_to_string(salt)?;
-            let suite = CryptoKey::new(p.trim(), s.trim()).map_err(|e| anyhow!("Error: {e}"))?;
+            let keys = keys.load(&db_url).await?;
             let js_f = get_jumpseller_credentials(jumpseller_cred_file);
-
-            (SQLiteDB::new(&db_url, suite).await?, js_f, IsProd(true))
+            let admins = Admins::new(&admins);
+            if database::is_postgres(&db_url) {
+                let db = PostgresDB::new(&db_url, keys).await?;
+                serve(cli.port, db, js_f, IsProd(true), admins, utils).await
+            } else {
+                let db = SQLiteDB::new(&db_url, keys).await?;
+                serve(cli.port, db, js_f, IsProd(true), admins, utils).await
+            }
         }
-    };
+        Commands::ExportUserData { .. }
+        | Commands::EraseUser { .. }
+        | Commands::Audit { .. }
+        | Commands::VerifyChain { .. }
+        | Commands::Reencrypt { .. }
+        | Commands::Backup { .. }
+        | Commands::Restore { .. }
+        | Commands::Migrate { .. } => Ok(()),
+    }
+}
 
+async fn serve<D: Backend>(
+    port: u16,
+    db: D,
+    js_cred: Option<JumpSellerCredentials>,
+    is_prod: IsProd,
+    admins: Admins,
+    utils: BackendInfoUpdater,
+) -> anyhow::Result<()> {
     let js_client = if let Some(s) = js_cred {
         jumpseller::Client::from(s)
     } else {
@@ -135,13 +290,15 @@ async fn run_user_facing_code(cli: Cli, utils: BackendInfoUpdater) -> anyhow::Re
 
     let jsc = web::Data::new(js_client);
 
-    let wd = web::Data::new(RwLock::new(db));
+    let wd = web::Data::new(db);
+    tokio::task::spawn_local(maintenance::reencrypt_in_background(wd.clone()));
 
     let utils = web::Data::new(utils);
 
     let secret_key = Key::generate();
 
     let is_prod = web::Data::new(is_prod);
+    let admins = web::Data::new(admins);
 
     HttpServer::new(move || {
         App::new()
@@ -149,7 +306,8 @@ async fn run_user_facing_code(cli: Cli, utils: BackendInfoUpdater) -> anyhow::Re
             .app_data(wd.clone())
             .app_data(jsc.clone())
             .app_data(is_prod.clone())
-            .service(rest::create_services())
+            .app_data(admins.clone())
+            .service(rest::create_services::<D>())
             // .service(Files::new("/", "frontend/dist").index_file("index.html"))
             .wrap(IdentityMiddleware::default())
             .wrap(
@@ -176,7 +334,7 @@ async fn run_user_facing_code(cli: Cli, utils: BackendInfoUpdater) -> anyhow::Re
             .wrap(middleware::NormalizePath::trim())
             .wrap(middleware::Logger::default())
     })
-    .bind(("0.0.0.0", cli.port))?
+    .bind(("0.0.0.0", port))?
     .run()
     .await?;
 
@@ -239,59 +397,7 @@ async fn run_backend_code(
     let pm_publisher = private_messages_topic.new_publisher(None);
 
     while let Some(F2BRequest { msg, callback }) = receiver.recv().await {
-        use pubsub::priv_msgs_v1::{PrivateMessageSchema, private_message_schema};
-        let pubsub_msg = match msg {
-            F2BRequestType::NewMessage {
-                product_info,
-                uid,
-                timestamp,
-                preview,
-                sender_id,
-                receiver_id,
-            } => {
-                let pubsub_msg = private_message_schema::NewMessage {
-                    uid,
-                    sender_id,
-                    receiver_id,
-                    product_info,
-                    timestamp,
-                    preview,
-                };
-
-                let pubsub_msg = PrivateMessageSchema {
-                    contents: Some(private_message_schema::Contents::NewMessage(pubsub_msg)),
-                };
-
-                PubsubMessage {
-                    data: pubsub_msg.encode_to_vec(),
-                    ..Default::default()
-                }
-            }
-            F2BRequestType::NewConvo {
-                uid,
-                seller,
-                buyer,
-                product_info,
-            } => {
-                let pubsub_msg = private_message_schema::NewConversation {
-                    uid,
-                    seller_id: seller,
-                    buyer_id: buyer,
-                    product_info,
-                };
-
-                let pubsub_msg = PrivateMessageSchema {
-                    contents: Some(private_message_schema::Contents::NewConversation(
-                        pubsub_msg,
-                    )),
-                };
-
-                PubsubMessage {
-                    data: pubsub_msg.encode_to_vec(),
-                    ..Default::default()
-                }
-            }
-        };
+        let pubsub_msg = msg.into_pubsub();
         let waiter = pm_publisher.publish(pubsub_msg).await.get().await;
 
         match waiter {
@@ -334,6 +440,97 @@ enum F2BRequestType {
         /// jumpseller id
         product_info: i64,
     },
+    StatusChanged {
+        uid: i64,
+        /// `None` when the conversation was reopened by a new message
+        changed_by: Option<i64>,
+        status: String,
+        timestamp: String,
+    },
+    DeliveryStatusChanged {
+        uid: i64,
+        reader_id: i64,
+        up_to_message_id: i64,
+        status: String,
+        timestamp: String,
+    },
+    UserErased {
+        user_id: i64,
+        timestamp: String,
+    },
+}
+
+impl F2BRequestType {
+    fn into_pubsub(self) -> PubsubMessage {
+        use pubsub::priv_msgs_v1::{
+            PrivateMessageSchema,
+            private_message_schema::{self, Contents},
+        };
+        let contents = match self {
+            Self::NewMessage {
+                product_info,
+                uid,
+                timestamp,
+                preview,
+                sender_id,
+                receiver_id,
+            } => Contents::NewMessage(private_message_schema::NewMessage {
+                uid,
+                sender_id,
+                receiver_id,
+                product_info,
+                timestamp,
+                preview,
+            }),
+            Self::NewConvo {
+                uid,
+                seller,
+                buyer,
+                product_info,
+            } => Contents::NewConversation(private_message_schema::NewConversation {
+                uid,
+                seller_id: seller,
+                buyer_id: buyer,
+                product_info,
+            }),
+            Self::StatusChanged {
+                uid,
+                changed_by,
+                status,
+                timestamp,
+            } => Contents::StatusChanged(private_message_schema::StatusChanged {
+                uid,
+                changed_by,
+                status,
+                timestamp,
+            }),
+            Self::DeliveryStatusChanged {
+                uid,
+                reader_id,
+                up_to_message_id,
+                status,
+                timestamp,
+            } => Contents::DeliveryStatusChanged(private_message_schema::DeliveryStatusChanged {
+                uid,
+                reader_id,
+                up_to_message_id,
+                status,
+                timestamp,
+            }),
+            Self::UserErased { user_id, timestamp } => {
+                Contents::UserErased(private_message_schema::UserErased { user_id, timestamp })
+            }
+        };
+
+        let pubsub_msg = PrivateMessageSchema {
+            contents: Some(contents),
+        };
+
+        PubsubMessage {
+            data: pubsub_msg.encode_to_vec(),
+            ..Default::default()
+        }
+    }
 }
 
 struct F2BRequest {
@@ -349,7 +546,7 @@ impl BackendInfoUpdater {
     /// This function may fail if the Database state is buggy or when the database has a bug
     pub async fn new_message(
         &self,
-        database: &SQLiteDB,
+        database: &impl Backend,
         message_id: &MessageId,
         convo_id: &ConversationId,
         divulge: bool,
@@ -365,7 +562,12 @@ impl BackendInfoUpdater {
         let product_info = product.product_info();
         let fst_32 = message.contents().chars().take(32).collect::<String>();
 
-        let message_sum = if divulge { Some(fst_32) } else { None };
+        // The server can't read end-to-end encrypted messages, let alone summarize them.
+        let message_sum = if divulge && !message.is_e2e() {
+            Some(fst_32)
+        } else {
+            None
+        };
 
         let msg_type = F2BRequestType::NewMessage {
             sender_id: sender.0,
@@ -389,7 +591,7 @@ impl BackendInfoUpdater {
     /// This function may fail if the Database state is buggy or when the database has a bug
     pub async fn new_convo(
         &self,
-        database: &SQLiteDB,
+        database: &impl Backend,
         convo_id: &ConversationId,
         buyer: &UserId,
     ) -> Result<CallBack, DbError> {
@@ -417,12 +619,140 @@ impl BackendInfoUpdater {
 
         Ok(r)
     }
+
+    pub async fn status_changed(
+        &self,
+        convo_id: &ConversationId,
+        changed_by: Option<&UserId>,
+        status: ConversationStatus,
+    ) -> CallBack {
+        let (s, r) = tokio::sync::oneshot::channel();
+
+        let msg_type = F2BRequestType::StatusChanged {
+            uid: convo_id.0,
+            changed_by: changed_by.map(|x| x.0),
+            status: status.to_string(),
+            timestamp: chrono::Utc::now().to_string(),
+        };
+
+        let msg = F2BRequest {
+            msg: msg_type,
+            callback: s,
+        };
+        _ = self.0.send(msg).await;
+
+        r
+    }
+
+    pub async fn delivery_status_changed(
+        &self,
+        convo_id: &ConversationId,
+        reader: &UserId,
+        up_to: &MessageId,
+        status: DeliveryStatus,
+    ) -> CallBack {
+        let (s, r) = tokio::sync::oneshot::channel();
+
+        let msg_type = F2BRequestType::DeliveryStatusChanged {
+            uid: convo_id.0,
+            reader_id: reader.0,
+            up_to_message_id: up_to.0,
+            status: status.to_string(),
+            timestamp: chrono::Utc::now().to_string(),
+        };
+
+        let msg = F2BRequest {
+            msg: msg_type,
+            callback: s,
+        };
+        _ = self.0.send(msg).await;
+
+        r
+    }
+
+    pub async fn user_erased(&self, user: &UserId) -> CallBack {
+        let (s, r) = tokio::sync::oneshot::channel();
+
+        let msg_type = F2BRequestType::UserErased {
+            user_id: user.0,
+            timestamp: chrono::Utc::now().to_string(),
+        };
+
+        let msg = F2BRequest {
+            msg: msg_type,
+            callback: s,
+        };
+        _ = self.0.send(msg).await;
+
+        r
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::builder().parse_default_env().init();
     let cli = Cli::parse();
+    match &cli.command {
+        Commands::ExportUserData {
+            db,
+            user_id,
+            reason,
+            output,
+        } => {
+            return maintenance::export_user_data(db, UserId(*user_id), reason, output.as_deref())
+                .await;
+        }
+        Commands::EraseUser {
+            db,
+            user_id,
+            reason,
+        } => return maintenance::erase_user(cli.clone(), db, UserId(*user_id), reason).await,
+        Commands::Audit {
+            db,
+            actor_id,
+            subject_id,
+            conversation_id,
+            limit,
+            verify,
+        } => {
+            let filter = AuditFilter {
+                actor_id: *actor_id,
+                subject_id: *subject_id,
+                conversation_id: *conversation_id,
+                limit: *limit,
+            };
+            return maintenance::audit(db, &filter, *verify).await;
+        }
+        Commands::VerifyChain {
+            db,
+            conversation_ids,
+        } => return maintenance::verify_chain(db, conversation_ids).await,
+        Commands::Reencrypt { db, batch_size } => {
+            return maintenance::reencrypt(db, *batch_size).await;
+        }
+        Commands::Backup {
+            db_url,
+            dir,
+            key,
+            every,
+            keep,
+        } => {
+            let every = every.map(std::time::Duration::from_secs);
+            return maintenance::backup(db_url, dir, key, every, *keep).await;
+        }
+        Commands::Restore {
+            backup,
+            db_url,
+            key,
+            force,
+        } => return maintenance::restore(backup, db_url, key, *force).await,
+        Commands::Migrate {
+            db_url,
+            dry_run,
+            status,
+        } => return maintenance::migrate(db_url, *dry_run, *status).await,
+        Commands::Kiosk { .. } | Commands::Run { .. } => {}
+    }
     cli.startup_log();
     let cli1 = cli.clone();
 
@@ -432,7 +762,13 @@ async fn main() -> anyhow::Result<()> {
     let local = tokio::task::LocalSet::new();
     let ufc = local.run_until(async {
         let cli = cli1;
-        tokio::task::spawn_local(run_user_facing_code(cli, frontend_util)).await
+        tokio::task::spawn_local(async move {
+            // Logged here as well: with pub/sub disabled the process exits as soon as this returns.
+            run_user_facing_code(cli, frontend_util)
+                .await
+                .inspect_err(|e| log::error!("{e:#}"))
+        })
+        .await
     });
 
     let backend = tokio::task::spawn(run_backend_code(cli, rcv));
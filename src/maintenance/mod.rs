@@ -0,0 +1,461 @@
+//! One-shot commands run from the command line instead of the web server.
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use actix_web::web;
+use anyhow::anyhow;
+use log::info;
+use sqlx::{
+    Pool, Postgres, Sqlite,
+    migrate::{Migrate, MigrateDatabase, Migrator},
+};
+
+use crate::{
+    BackendInfoUpdater, Cli, F2BRequest, F2BResponse, backup,
+    database::{
+        self, Backend, Database,
+        crypto::{CryptoKey, KdfParams, Keyring},
+        migrations,
+        model::{AuditAction, AuditEntry, AuditFilter, ConversationId, UserId},
+        postgres::PostgresDB,
+        sqlite::SQLiteDB,
+    },
+    export::PersonalData,
+    keys::{KeyProvider, KeySource},
+    run_backend_code,
+};
+
+/// Master key material: the active key plus any retired keys still needed to read old data.
+#[derive(clap::Args, Clone, Debug)]
+pub struct KeyArgs {
+    /// File containing the password
+    #[arg(required_unless_present = "key", conflicts_with = "key")]
+    password: Option<PathBuf>,
+    /// File containing the hash
+    #[arg(required_unless_present = "key", conflicts_with = "key")]
+    salt: Option<PathBuf>,
+    /// Where to get the active key instead of PASSWORD and SALT: `file:PASSWORD_FILE:SALT_FILE`,
+    /// `env:PASSWORD_VAR:SALT_VAR`, `raw:KEY_FILE` (32 bytes) or `kms:URL` (token in `$KMS_TOKEN`)
+    #[arg(long)]
+    key: Option<KeySource>,
+    /// Id under which the active key is recorded next to the data it encrypts
+    #[arg(long, default_value_t = 1)]
+    key_id: u32,
+    /// Retired key kept for decryption only, as `ID:PASSWORD_FILE:SALT_FILE` or `ID:SOURCE` with
+    /// SOURCE as in --key (repeatable)
+    #[arg(long = "retired-key")]
+    retired_keys: Vec<RetiredKey>,
+    #[command(flatten)]
+    kdf: KdfArgs,
+}
+
+impl KeyArgs {
+    /// Derives the keys with the Argon2 parameters `db_url` stores for them, or those given on
+    /// the command line for keys it doesn't know yet.
+    pub async fn load(&self, db_url: &str) -> anyhow::Result<Keyring> {
+        let stored = if database::is_postgres(db_url) {
+            PostgresDB::stored_kdf(db_url).await?
+        } else {
+            SQLiteDB::stored_kdf(db_url).await?
+        };
+        let kdf = |id| match stored.get(&id) {
+            Some(stored) if self.kdf.is_set() && self.kdf.params() != *stored => Err(anyhow!(
+                "Key #{id} is derived with {stored:?}, --kdf-* options only apply to new keys"
+            )),
+            Some(stored) => Ok(*stored),
+            None => Ok(self.kdf.params()),
+        };
+
+        let source = match (&self.key, &self.password, &self.salt) {
+            (Some(source), _, _) => source.clone(),
+            (None, Some(password), Some(salt)) => KeySource::Files {
+                password: password.clone(),
+                salt: salt.clone(),
+            },
+            _ => return Err(anyhow!("No master key given")),
+        };
+        let active = source.master_key(self.key_id, kdf(self.key_id)?).await?;
+        let mut keys = Keyring::new(self.key_id, active);
+        for retired in &self.retired_keys {
+            if retired.id == self.key_id {
+                return Err(anyhow!("Key #{} is both active and retired", retired.id));
+            }
+            let key = retired
+                .source
+                .master_key(retired.id, kdf(retired.id)?)
+                .await?;
+            keys = keys.with_retired(retired.id, key);
+        }
+        Ok(keys)
+    }
+}
+
+/// Argon2 parameters for keys new to the database, which stores them along with the key.
+#[derive(clap::Args, Clone, Debug)]
+pub struct KdfArgs {
+    /// Argon2 variant of new keys: argon2id, argon2i or argon2d [default: argon2id]
+    #[arg(long = "kdf-algorithm", value_parser = parse_algorithm)]
+    algorithm: Option<argon2::Algorithm>,
+    /// Memory cost of new keys, in KiB [default: 19456]
+    #[arg(long = "kdf-memory-kib")]
+    memory_kib: Option<u32>,
+    /// Number of passes of new keys [default: 2]
+    #[arg(long = "kdf-iterations")]
+    iterations: Option<u32>,
+    /// Degree of parallelism of new keys [default: 1]
+    #[arg(long = "kdf-parallelism")]
+    parallelism: Option<u32>,
+}
+
+impl KdfArgs {
+    fn is_set(&self) -> bool {
+        self.algorithm.is_some()
+            || self.memory_kib.is_some()
+            || self.iterations.is_some()
+            || self.parallelism.is_some()
+    }
+
+    fn params(&self) -> KdfParams {
+        let default = KdfParams::default();
+        KdfParams {
+            algorithm: self.algorithm.unwrap_or(default.algorithm),
+            version: default.version,
+            memory_kib: self.memory_kib.unwrap_or(default.memory_kib),
+            iterations: self.iterations.unwrap_or(default.iterations),
+            parallelism: self.parallelism.unwrap_or(default.parallelism),
+        }
+    }
+}
+
+fn parse_algorithm(s: &str) -> Result<argon2::Algorithm, String> {
+    s.parse().map_err(|e: argon2::Error| e.to_string())
+}
+
+#[derive(Clone, Debug)]
+pub struct RetiredKey {
+    id: u32,
+    source: KeySource,
+}
+
+impl FromStr for RetiredKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((id, source)) = s.split_once(':') else {
+            return Err("expected ID:PASSWORD_FILE:SALT_FILE or ID:SOURCE".to_owned());
+        };
+        // Anything that isn't a source is the password and salt files it was before sources.
+        let source = source
+            .parse()
+            .or_else(|_| format!("file:{source}").parse())?;
+        Ok(Self {
+            id: id
+                .parse()
+                .map_err(|e| format!("invalid key id '{id}': {e}"))?,
+            source,
+        })
+    }
+}
+
+/// Key of the backups, which must not be the master key: whoever holds a backup and its key can
+/// read it all, so the two are kept in different places.
+#[derive(clap::Args, Clone, Debug)]
+pub struct BackupKeyArgs {
+    /// Where to get the backup key, as in --key of the other commands
+    #[arg(long)]
+    backup_key: KeySource,
+    /// Id recorded in backups to tell which key they need
+    #[arg(long, default_value_t = 1)]
+    backup_key_id: u32,
+}
+
+impl BackupKeyArgs {
+    /// Backup key `id`, derived with `kdf` when it comes from a password.
+    async fn load(&self, id: u32, kdf: KdfParams) -> anyhow::Result<CryptoKey> {
+        self.backup_key.master_key(id, kdf).await
+    }
+}
+
+/// Database access for the maintenance commands.
+#[derive(clap::Args, Clone, Debug)]
+pub struct DbArgs {
+    #[command(flatten)]
+    keys: KeyArgs,
+    /// Database URL, `sqlite:PATH` or `postgres://...`
+    #[arg(short, long, default_value_t = String::from("sqlite:.sqlite3"))]
+    db_url: String,
+}
+
+/// Runs `$body` with `$db` bound to the backend `$args.db_url` selects. The body is compiled once
+/// per backend, there being no common type to return them as.
+macro_rules! with_db {
+    ($args:expr, $db:ident => $body:block) => {{
+        let keys = $args.keys.load(&$args.db_url).await?;
+        if database::is_postgres(&$args.db_url) {
+            let $db = PostgresDB::new(&$args.db_url, keys).await?;
+            $body
+        } else {
+            let $db = SQLiteDB::new(&$args.db_url, keys).await?;
+            $body
+        }
+    }};
+}
+
+pub async fn export_user_data(
+    db: &DbArgs,
+    user: UserId,
+    reason: &str,
+    output: Option<&Path>,
+) -> anyhow::Result<()> {
+    let audit =
+        AuditEntry::new(None, AuditAction::PersonalDataExport, reason.to_owned()).subject(user);
+    let archive = with_db!(db, db => {
+        db.append_audit(&audit).await?;
+        PersonalData::collect(&db, &user).await?
+    });
+    match output {
+        Some(path) => serde_json::to_writer_pretty(std::fs::File::create(path)?, &archive)?,
+        None => serde_json::to_writer_pretty(std::io::stdout().lock(), &archive)?,
+    }
+    Ok(())
+}
+
+/// Erases `user` and announces it over pub/sub. Publishing failures are only logged.
+pub async fn erase_user(cli: Cli, db: &DbArgs, user: UserId, reason: &str) -> anyhow::Result<()> {
+    let audit = AuditEntry::new(None, AuditAction::UserErasure, reason.to_owned()).subject(user);
+    with_db!(db, db => {
+        db.erase_user(&user, &audit).await?;
+    });
+    info!("Erased user #{}", user.0);
+
+    let (tcv, rcv) = tokio::sync::mpsc::channel::<F2BRequest>(1);
+    let backend = tokio::task::spawn(run_backend_code(cli, rcv));
+    let callback = BackendInfoUpdater(tcv).user_erased(&user).await;
+    match callback.await? {
+        F2BResponse::Ok => {}
+        F2BResponse::GoogleCloud(e) => log::error!("Failed to publish erasure: {e}."),
+        F2BResponse::Unrecoverable(e) => log::error!("Failed to publish erasure: {e}."),
+    }
+    backend.await?
+}
+
+/// Prints the matching audit entries as JSON lines, or checks the hash chain when `verify`.
+pub async fn audit(db: &DbArgs, filter: &AuditFilter, verify: bool) -> anyhow::Result<()> {
+    with_db!(db, db => { audit_with(&db, filter, verify).await })
+}
+
+async fn audit_with(db: &impl Backend, filter: &AuditFilter, verify: bool) -> anyhow::Result<()> {
+    if verify {
+        return match db.verify_audit_log().await? {
+            None => {
+                info!("Audit log is intact.");
+                Ok(())
+            }
+            Some(id) => Err(anyhow!("Audit log was tampered with at entry #{id}")),
+        };
+    }
+    for record in db.get_audit_log(filter).await? {
+        println!("{}", serde_json::to_string(&record)?);
+    }
+    Ok(())
+}
+
+/// Checks the message hash chain of each conversation, printing its head as a JSON line to compare
+/// with the one in earlier exports. Fails if any chain is broken.
+pub async fn verify_chain(db: &DbArgs, conversations: &[i64]) -> anyhow::Result<()> {
+    with_db!(db, db => { verify_chain_with(&db, conversations).await })
+}
+
+async fn verify_chain_with(db: &impl Backend, conversations: &[i64]) -> anyhow::Result<()> {
+    let mut broken = vec![];
+    for &id in conversations {
+        let conversation = ConversationId(id);
+        let first_invalid_id = db.verify_message_chain(&conversation).await?;
+        let chain_head = db.get_chain_head(&conversation).await?;
+        let report = serde_json::json!({
+            "conversation": id,
+            "valid": first_invalid_id.is_none(),
+            "first_invalid_id": first_invalid_id,
+            "chain_head": chain_head,
+        });
+        println!("{report}");
+        if let Some(message) = first_invalid_id {
+            broken.push(format!("#{id} (at message #{})", message.0));
+        }
+    }
+    if broken.is_empty() {
+        info!("All message chains are intact.");
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Conversations were tampered with: {}",
+            broken.join(", ")
+        ))
+    }
+}
+
+/// Rewraps every per-user key still under a retired master key, `batch` keys per transaction.
+pub async fn reencrypt(db: &DbArgs, batch: u32) -> anyhow::Result<()> {
+    let total = with_db!(db, db => {
+        let mut total = 0;
+        loop {
+            let done = db.reencrypt_batch(batch).await?;
+            if done == 0 {
+                break;
+            }
+            total += done;
+            info!("Re-encrypted {total} keys so far...");
+        }
+        total
+    });
+    info!("All keys use the active master key ({total} re-encrypted).");
+    Ok(())
+}
+
+/// Same as [`reencrypt`] but for a running server, whose requests are served in between batches.
+pub async fn reencrypt_in_background<D: Backend>(db: web::Data<D>) {
+    const BATCH: u32 = 100;
+    let mut total = 0;
+    loop {
+        let result = db.reencrypt_batch(BATCH).await;
+        match result {
+            Ok(0) => break,
+            Ok(done) => total += done,
+            Err(e) => {
+                log::error!("Re-encryption stopped after {total} keys: {e}");
+                return;
+            }
+        }
+        tokio::task::yield_now().await;
+    }
+    if total > 0 {
+        info!("Re-encrypted {total} keys with the active master key.");
+    }
+}
+
+/// Backs up `db_url` into `dir`, then keeps doing so `every` so often if given. Failures of
+/// scheduled backups are only logged.
+pub async fn backup(
+    db_url: &str,
+    dir: &Path,
+    key: &BackupKeyArgs,
+    every: Option<Duration>,
+    keep: Option<usize>,
+) -> anyhow::Result<()> {
+    let id = key.backup_key_id;
+    // Recorded in every backup, to derive the key with again whatever the defaults become.
+    let key = key.load(id, KdfParams::default()).await?;
+    let take = async || -> anyhow::Result<()> {
+        let path = backup::create(db_url, dir, id, &key).await?;
+        info!("Backed up {db_url} to {}", path.display());
+        if let Some(keep) = keep {
+            let pruned = backup::prune(dir, keep)?;
+            if pruned > 0 {
+                info!("Deleted {pruned} old backups.");
+            }
+        }
+        Ok(())
+    };
+
+    let Some(every) = every else {
+        return take().await;
+    };
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if let Err(e) = take().await {
+            log::error!("Backup failed: {e:#}");
+        }
+    }
+}
+
+/// Restores `backup` as the database of `db_url`. The server must be stopped.
+pub async fn restore(
+    backup: &Path,
+    db_url: &str,
+    key: &BackupKeyArgs,
+    force: bool,
+) -> anyhow::Result<()> {
+    let (id, kdf) = backup::key_params(backup)?;
+    let key = key.load(id, kdf.unwrap_or_default()).await?;
+    backup::restore(backup, db_url, &key, force).await?;
+    info!("Restored {} to {db_url}", backup.display());
+    Ok(())
+}
+
+/// Applies the pending schema migrations of `db_url`, or only prints them with `dry_run`.
+/// `status` prints every migration instead, applied or not.
+///
+/// sqlite databases from before migrations are only adopted when applying them: until then, every
+/// migration shows as pending.
+pub async fn migrate(db_url: &str, dry_run: bool, status: bool) -> anyhow::Result<()> {
+    if database::is_postgres(db_url) {
+        return migrate_with::<Postgres>(&migrations::POSTGRES, db_url, dry_run, status).await;
+    }
+    if !dry_run && !status && Sqlite::database_exists(db_url).await? {
+        let pool = Pool::<Sqlite>::connect(db_url).await?;
+        let adopted = migrations::adopt_legacy(&pool).await;
+        pool.close().await;
+        adopted?;
+    }
+    migrate_with::<Sqlite>(&migrations::SQLITE, db_url, dry_run, status).await
+}
+
+async fn migrate_with<DB>(
+    migrator: &Migrator,
+    db_url: &str,
+    dry_run: bool,
+    status: bool,
+) -> anyhow::Result<()>
+where
+    DB: sqlx::Database + MigrateDatabase,
+    DB::Connection: Migrate,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'q> DB::Arguments<'q>: sqlx::IntoArguments<'q, DB>,
+    (i64, i64, i64, i64): for<'r> sqlx::FromRow<'r, DB::Row>,
+{
+    let exists = DB::database_exists(db_url).await?;
+    let migrations = if dry_run || status {
+        // Only looking: don't create a missing database.
+        if exists {
+            let pool = Pool::<DB>::connect(db_url).await?;
+            let migrations = migrations::status(migrator, &pool).await;
+            pool.close().await;
+            migrations?
+        } else {
+            migrations::unapplied(migrator)
+        }
+    } else {
+        if !exists {
+            DB::create_database(db_url).await?;
+        }
+        let pool = Pool::<DB>::connect(db_url).await?;
+        let applied = migrations::run(migrator, &pool).await;
+        pool.close().await;
+        applied?
+    };
+
+    for migration in migrations
+        .iter()
+        .filter(|m| status || !dry_run || m.state == migrations::State::Pending)
+    {
+        println!("{}", serde_json::to_string(migration)?);
+    }
+    if dry_run || status {
+        let pending = migrations
+            .iter()
+            .filter(|m| m.state == migrations::State::Pending)
+            .count();
+        info!("{pending} of {} migrations pending.", migrations.len());
+    } else {
+        info!(
+            "Schema of {db_url} is at version {}.",
+            migrations::latest(migrator)
+        );
+    }
+    Ok(())
+}
@@ -1,29 +1,33 @@
-use std::num::ParseIntError;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    num::ParseIntError,
+};
 
 use crate::{
-    BackendInfoUpdater, IsProd,
+    Admins, BackendInfoUpdater, CallBack, IsProd,
     database::{
-        Database,
-        sqlite::{
-            ConversationId, DbError, Message, MessageId, Product, ProductId, SQLiteDB, UserId,
-            UserProfile,
+        Backend,
+        model::{
+            AuditAction, AuditEntry, AuditFilter, ChainHead, ConversationFilter, ConversationId,
+            ConversationStatus, DbError, DeliveryStatus, Message, MessageId, Product, ProductId,
+            Store, StoreId, UserId, UserProfile,
         },
     },
+    export::{self, PersonalData, TranscriptEntry, TranscriptFormat, TranscriptHeader},
     jumpseller::{self, JumpSellerErr},
 };
 use actix_identity::Identity;
 use actix_web::{
     HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError, Result,
     error::ErrorInternalServerError,
-    get, post,
-    web::{Data, Form, Json, Path, Query},
+    web::{Bytes, Data, Form, Json, Path, Query, get, post},
 };
+use futures_util::{StreamExt, future, stream};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
 
-async fn jumpseller_update_product(
-    db: &RwLock<SQLiteDB>,
+async fn jumpseller_update_product<D: Backend>(
+    db: &D,
     js: &jumpseller::Client,
     seller_id: &UserId,
     product_id: i64,
@@ -31,9 +35,7 @@ async fn jumpseller_update_product(
     let p = js.get_product(product_id).await.w();
     match p {
         Ok(ref prod) => {
-            db.write()
-                .await
-                .add_product(&Product::new(prod.name.clone(), *seller_id, prod.id))
+            db.add_product(&Product::new(prod.name.clone(), *seller_id, prod.id))
                 .await
                 .w()?;
         }
@@ -48,80 +50,149 @@ async fn jumpseller_update_product(
     Ok(())
 }
 
-async fn jumpseller_update_user(
-    db: &RwLock<SQLiteDB>,
+async fn jumpseller_update_user<D: Backend>(
+    db: &D,
     js: &jumpseller::Client,
     user_id: i64,
 ) -> Result<(), DbError> {
     match js.get_user(user_id).await.w() {
         Ok(profile) => {
-            db.write().await.add_user(&profile).await.w()?;
+            db.add_user(&profile).await.w()?;
         }
         Err(JumpSellerErr::ResponseErr(_, Some(reqwest::StatusCode::NOT_FOUND))) => {
             // User not found
-            let found = db
-                .read()
-                .await
-                .get_user_profile(&UserId(user_id))
-                .await
-                .w()
-                .is_ok();
+            let found = db.get_user_profile(&UserId(user_id)).await.w().is_ok();
             if !found {
                 let profile =
                     UserProfile::new(user_id, "notfound".to_string(), "Not Found".to_string());
-                db.write().await.add_user(&profile).await.w()?;
+                db.add_user(&profile).await.w()?;
             }
         }
         Err(_) => {
-            let found = db
-                .read()
-                .await
-                .get_user_profile(&UserId(user_id))
-                .await
-                .w()
-                .is_ok();
+            let found = db.get_user_profile(&UserId(user_id)).await.w().is_ok();
             if !found {
                 let profile = UserProfile::new(
                     user_id,
                     "js_error".to_string(),
                     "JumpSeller Failure".to_string(),
                 );
-                db.write().await.add_user(&profile).await.w()?;
+                db.add_user(&profile).await.w()?;
             }
         }
     }
     Ok(())
 }
 
-pub fn create_services() -> actix_web::Scope {
+pub fn create_services<D: Backend>() -> actix_web::Scope {
     info!("Installing REST API services...");
     actix_web::web::scope("/api/chat")
         // DONE: Doc'ed
-        .service(login)
+        .route("/login", get().to(login::<D>))
+        // DONE: Doc'ed
+        .route("/me", get().to(me))
+        // DONE: Doc'ed
+        .route("/conversation", get().to(get_conversations::<D>))
+        // DONE: Doc'ed
+        .route("/conversation/{convo_id}/peer", get().to(get_peer::<D>))
+        // DONE: Doc'ed
+        .route("/user/{user_id}", get().to(get_user_profile::<D>))
+        // DONE: Doc'ed
+        .route("/message/{msg_id}", get().to(get_message::<D>))
+        // DONE: Doc'ed
+        .route("/messages:batchGet", post().to(batch_get_messages::<D>))
+        // DONE: Doc'ed
+        .route(
+            "/conversation/{convo_id}/latest",
+            get().to(get_latest_message::<D>),
+        )
+        // DONE: Doc'ed
+        .route(
+            "/conversation/{convo_id}/recent",
+            get().to(get_most_recent_messages::<D>),
+        )
+        // DONE: Doc'ed
+        .route("/conversation", post().to(start_conversation::<D>))
+        // DONE: Doc'ed
+        .route("/conversation/{convo_id}/message", post().to(post_msg::<D>))
         // DONE: Doc'ed
-        .service(me)
+        .route("/conversation/{convo_id}/read", post().to(mark_read::<D>))
         // DONE: Doc'ed
-        .service(get_conversations)
+        .route(
+            "/conversation/{convo_id}/export",
+            get().to(export_conversation::<D>),
+        )
         // DONE: Doc'ed
-        .service(get_peer)
+        .route("/me/export", get().to(export_personal_data::<D>))
         // DONE: Doc'ed
-        .service(get_user_profile)
+        .route("/admin/user/{user_id}/erase", post().to(erase_user::<D>))
         // DONE: Doc'ed
-        .service(get_message)
+        .route(
+            "/admin/conversation/{convo_id}/shred",
+            post().to(shred_conversation::<D>),
+        )
         // DONE: Doc'ed
-        .service(get_latest_message)
+        .route("/admin/audit", get().to(get_audit_log::<D>))
         // DONE: Doc'ed
-        .service(get_most_recent_messages)
+        .route("/admin/audit/verify", get().to(verify_audit_log::<D>))
         // DONE: Doc'ed
-        .service(start_conversation)
+        .route(
+            "/admin/conversation/{convo_id}/verify",
+            get().to(verify_message_chain::<D>),
+        )
         // DONE: Doc'ed
-        .service(post_msg)
+        .route("/product", post().to(add_product::<D>))
         // DONE: Doc'ed
-        .service(add_product)
+        .route("/product/{prod_id}", get().to(get_product::<D>))
         // DONE: Doc'ed
-        .service(get_product)
+        .route(
+            "/conversation/{convo_id}/product",
+            get().to(get_product_in_conversation::<D>),
+        )
         // DONE: Doc'ed
-        .service(get_product_in_conversation)
+        .route(
+            "/conversation/{convo_id}/assign",
+            post().to(assign_conversation::<D>),
+        )
+        // DONE: Doc'ed
+        .route(
+            "/conversation/{convo_id}/assignee",
+            get().to(get_assignee::<D>),
+        )
+        // DONE: Doc'ed
+        .route("/store", post().to(add_store::<D>))
+        // DONE: Doc'ed
+        .route("/store/{store_id}/member", post().to(add_store_member::<D>))
+        // DONE: Doc'ed
+        .route("/store/{store_id}/member", get().to(get_store_members::<D>))
+        // DONE: Doc'ed
+        .route(
+            "/store/{store_id}/primary",
+            post().to(set_primary_store::<D>),
+        )
+        // DONE: Doc'ed
+        .route("/conversation/{convo_id}/status", get().to(get_status::<D>))
+        // DONE: Doc'ed
+        .route(
+            "/conversation/{convo_id}/status",
+            post().to(set_status::<D>),
+        )
+        // DONE: Doc'ed
+        .route("/me/public_key", post().to(set_public_key::<D>))
+        // DONE: Doc'ed
+        .route("/user/{user_id}/public_key", get().to(get_public_key::<D>))
+        // DONE: Doc'ed
+        .route("/conversation/{convo_id}/e2e", get().to(get_e2e::<D>))
+        // DONE: Doc'ed
+        .route("/conversation/{convo_id}/e2e", post().to(enable_e2e::<D>))
+        // DONE: Doc'ed
+        .route("/conversation/{convo_id}/label", get().to(get_labels::<D>))
+        // DONE: Doc'ed
+        .route("/conversation/{convo_id}/label", post().to(add_label::<D>))
+        // DONE: Doc'ed
+        .route(
+            "/conversation/{convo_id}/unlabel",
+            post().to(remove_label::<D>),
+        )
         .default_service(actix_web::web::to(default_service))
 }
 
@@ -156,16 +227,36 @@ async fn default_service() -> impl Responder {
                     /api/chat
                              |- /login                              ---> Enables internal cookie.
                              |- /me                                 ---> Returns the user id given the user cookie.
-                             |- /conversation                       ---> (GET) Lists conversations a user is in. (POST) Starts a conversation.
+                                 |- /export                         ---> Downloads all the data we hold about you.
+                                 |- /public_key                     ---> Registers your public key, for end-to-end encryption.
+                             |- /conversation                       ---> (GET) Lists conversations a user is in, filtered by '?status=' and '?label='. (POST) Starts a conversation.
                                              |- /{convo_id}/peer    ---> Gets the jumpseller_id of the peer.
                                              |- /{convo_id}/latest  ---> Gets the latest message.
                                              |- /{convo_id}/recent  ---> Gets the 32 most recent messages.
                                              |- /{convo_id}/product ---> Gets the product associated with the conversation.
                                              |- /{convo_id}/message ---> Posts a new message into the chat.
+                                             |- /{convo_id}/read    ---> Marks the peer's messages as read.
+                                             |- /{convo_id}/export  ---> Downloads the whole conversation ('?format=json|html|txt').
+                                             |- /{convo_id}/assign  ---> Assigns the conversation to a staff member.
+                                             |- /{convo_id}/assignee ---> Gets the staff member the conversation is assigned to.
+                                             |- /{convo_id}/status  ---> (GET) Gets the status. (POST) Sets it to open, waiting or resolved.
+                                             |- /{convo_id}/label   ---> (GET) Lists your labels. (POST) Adds a label.
+                                             |- /{convo_id}/unlabel ---> Removes a label.
+                                             |- /{convo_id}/e2e     ---> (GET) Whether it is end-to-end encrypted. (POST) Enables it.
                              |- /message/{msg_id}                   ---> Gets the message with ID 'msg_id'.
+                             |- /messages:batchGet                  ---> Gets several messages at once, keyed by ID.
                              |- /user/{js_id}                       ---> Gets the profile of user with id 'js_id'.
+                                    |- /public_key                  ---> Gets their public key.
                              |- /product                            ---> Posts a new product into the database.
                              |- /product/{prod_id}                  ---> Gets the product with id 'prod_id'.
+                             |- /store                              ---> Creates a store with a shared inbox.
+                             |- /store/{store_id}/member            ---> (GET) Lists the staff of a store. (POST) Adds a staff member.
+                             |- /store/{store_id}/primary           ---> Makes conversations about your products land in the store's inbox.
+                             |- /admin/user/{js_id}/erase           ---> (Admin) Erases a user and their messages.
+                             |- /admin/conversation/{convo_id}/shred ---> (Admin) Destroys the messages of a conversation.
+                             |- /admin/conversation/{convo_id}/verify --> (Admin) Checks a conversation's hash chain.
+                             |- /admin/audit                        ---> (Admin) Lists privileged accesses, newest first.
+                                    |- /verify                      ---> (Admin) Checks the audit log's hash chain.
                 </textarea>
             </div></body>        
         </html>
@@ -240,9 +331,39 @@ impl ResponseError for ProductionAuthMissing {
     }
 }
 
-#[get("/login")]
-async fn login(
-    db: Data<RwLock<SQLiteDB>>,
+/// Waits for the backend to publish an event. Publishing failures are only logged.
+async fn wait_for_publish(callback: CallBack) -> Result<()> {
+    match callback.await.map_err(ErrorInternalServerError)? {
+        crate::F2BResponse::Ok => {}
+        crate::F2BResponse::GoogleCloud(error) => {
+            log::error!("Failed to publish message: {error}.");
+        }
+        crate::F2BResponse::Unrecoverable(error) => {
+            log::error!("Failed to publish message: {error}.");
+        }
+    }
+    Ok(())
+}
+
+/// Publishes that the messages of `convo_id` up to `up_to` reached `reader`, if any did.
+async fn notify_delivery_status(
+    utils: &BackendInfoUpdater,
+    convo_id: &ConversationId,
+    reader: &UserId,
+    up_to: Option<MessageId>,
+    status: DeliveryStatus,
+) -> Result<()> {
+    if let Some(up_to) = up_to {
+        let callback = utils
+            .delivery_status_changed(convo_id, reader, &up_to, status)
+            .await;
+        wait_for_publish(callback).await?;
+    }
+    Ok(())
+}
+
+async fn login<D: Backend>(
+    db: Data<D>,
     js: Data<jumpseller::Client>,
     prod: Data<IsProd>,
     auth: Query<AuthService>,
@@ -250,7 +371,7 @@ async fn login(
     req: HttpRequest,
 ) -> Result<impl Responder> {
     let user_id = if let Some(user_id) = auth.auth_service_user_id {
-        jumpseller_update_user(&db, &js, user_id).await?;
+        jumpseller_update_user(db.get_ref(), &js, user_id).await?;
         user_id
     } else {
         if prod.is_prod() {
@@ -259,7 +380,7 @@ async fn login(
         let Some(user_id) = user.id else {
             return Err(ProductionAuthMissing.into());
         };
-        jumpseller_update_user(&db, &js, user_id).await?;
+        jumpseller_update_user(db.get_ref(), &js, user_id).await?;
         user_id
     };
 
@@ -269,7 +390,6 @@ async fn login(
     Ok(HttpResponse::Ok())
 }
 
-#[get("/me")]
 async fn me(
     user: Identity,
     prod: Data<IsProd>,
@@ -292,12 +412,12 @@ async fn me(
 }
 
 // FIXME: usr_id needs be usr_token
-#[get("/conversation")]
-async fn get_conversations(
+async fn get_conversations<D: Backend>(
     user: Identity,
-    db: Data<RwLock<SQLiteDB>>,
+    db: Data<D>,
     auth: Query<AuthService>,
     prod: Data<IsProd>,
+    filter: Query<ConversationFilter>,
 ) -> Result<impl Responder> {
     // SAFETY: No need to refetch info, it is about ourselves.
     let user_id = parse_cookie(user.id()?)?;
@@ -315,20 +435,17 @@ async fn get_conversations(
 
     let user_id = UserId(user_id);
 
-    let res = db
-        .read()
-        .await
-        .get_conversations(&user_id)
-        .await
-        .map(Json)
-        .w()?;
-    Ok(res)
+    let res = if filter.is_empty() {
+        db.get_conversations(&user_id).await
+    } else {
+        db.filter_conversations(&user_id, &filter).await
+    };
+    Ok(res.map(Json).w()?)
 }
 
 // FIXME: usr_id needs be usr_token
-#[get("/conversation/{convo_id}/peer")]
-async fn get_peer(
-    data: Data<RwLock<SQLiteDB>>,
+async fn get_peer<D: Backend>(
+    data: Data<D>,
     user: Identity,
     convo_id: Path<i64>,
     auth: Query<AuthService>,
@@ -355,13 +472,11 @@ async fn get_peer(
     let user_id = UserId(user_id);
 
     let convo_id = ConversationId(*convo_id);
-    data.read()
-        .await
-        .belongs_to_conversation(&user_id, &convo_id)
+    data.belongs_to_conversation(&user_id, &convo_id)
         .await
         .w()?;
-    let peer_id = data.read().await.get_peer(&user_id, &convo_id).await?;
-    let profile = data.read().await.get_user_profile(&peer_id).await?;
+    let peer_id = data.get_peer(&user_id, &convo_id).await?;
+    let profile = data.get_user_profile(&peer_id).await?;
     // SAFETY: no need to update the peer, as we are only getting their id
 
     let profile = UserIdWrapper { id: profile.id().0 };
@@ -369,21 +484,14 @@ async fn get_peer(
     Ok(Json(profile))
 }
 
-#[get("/user/{user_id}")]
-async fn get_user_profile(
-    db: Data<RwLock<SQLiteDB>>,
+async fn get_user_profile<D: Backend>(
+    db: Data<D>,
     user_id: Path<i64>,
     js: Data<jumpseller::Client>,
 ) -> Result<impl Responder> {
     let user_id = UserId(*user_id);
-    jumpseller_update_user(&db, &js, user_id.0).await?;
-    let res = db
-        .read()
-        .await
-        .get_user_profile(&user_id)
-        .await
-        .map(Json)
-        .w()?;
+    jumpseller_update_user(db.get_ref(), &js, user_id.0).await?;
+    let res = db.get_user_profile(&user_id).await.map(Json).w()?;
     Ok(res)
 }
 
@@ -391,6 +499,7 @@ async fn get_user_profile(
 struct MessageContent {
     sender_jsid: i64,
     msg: Message,
+    status: DeliveryStatus,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -409,7 +518,12 @@ struct MessageFormat {
 
 impl MessageContent {
     fn new(sender_jsid: i64, msg: Message) -> Self {
-        Self { sender_jsid, msg }
+        let status = msg.status();
+        Self {
+            sender_jsid,
+            msg,
+            status,
+        }
     }
 }
 
@@ -429,9 +543,9 @@ impl MessageFormat {
     }
 }
 
-#[get("/message/{msg_id}")]
-async fn get_message(
-    data: Data<RwLock<SQLiteDB>>,
+async fn get_message<D: Backend>(
+    utils: Data<BackendInfoUpdater>,
+    data: Data<D>,
     user: Identity,
     msg_id: Path<i64>,
     auth: Query<AuthService>,
@@ -449,29 +563,131 @@ async fn get_message(
     }
 
     let msg_id = MessageId(*msg_id);
-    let convo_id = data
-        .read()
+    let convo_id = data.get_conversation_from_message(&msg_id).await.w()?;
+    data.belongs_to_conversation(&user_id, &convo_id)
         .await
-        .get_conversation_from_message(&msg_id)
+        .log(|e| warn!("{e}"))?;
+    let (sender_id, msg, prev_id) = data.get_message(&msg_id).await.w()?;
+    // Only marked if the other side sent it: staff are on the seller's side.
+    let delivered = data
+        .mark_delivered(&user_id, &convo_id, &[msg_id])
         .await
         .w()?;
-    data.read()
-        .await
-        .belongs_to_conversation(&user_id, &convo_id)
-        .await
-        .log(|e| warn!("{e}"))?;
-    let (sender_id, msg, prev_id) = data.read().await.get_message(&msg_id).await.w()?;
+    notify_delivery_status(
+        &utils,
+        &convo_id,
+        &user_id,
+        delivered,
+        DeliveryStatus::Delivered,
+    )
+    .await?;
     let msg = MessageContent::new(sender_id.0, msg);
     Ok(Json(MessageFormat::one(msg, prev_id)))
 }
 
+/// Upper bound on how many messages can be fetched by a single batch request.
+const BATCH_GET_LIMIT: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchGetRequest {
+    ids: Vec<MessageId>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Too many messages requested: at most {BATCH_GET_LIMIT} can be fetched at once.")]
+struct BatchTooLarge;
+
+impl ResponseError for BatchTooLarge {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
+}
+
+async fn batch_get_messages<D: Backend>(
+    utils: Data<BackendInfoUpdater>,
+    data: Data<D>,
+    user: Identity,
+    req: Json<BatchGetRequest>,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+    if req.ids.len() > BATCH_GET_LIMIT {
+        return Err(BatchTooLarge.into());
+    }
+
+    let db = data.get_ref();
+    let locations = db.get_conversations_from_messages(&req.ids).await.w()?;
+
+    // Check each conversation only once; messages from the others are silently left out.
+    let mut allowed_convos = HashMap::new();
+    let mut allowed_ids = Vec::new();
+    let mut ids_in_convo = HashMap::<_, Vec<_>>::new();
+    for (msg_id, convo_id) in locations {
+        let allowed = if let Some(allowed) = allowed_convos.get(&convo_id.0) {
+            *allowed
+        } else {
+            let allowed = match db.belongs_to_conversation(&user_id, &convo_id).await {
+                Ok(()) => true,
+                Err(DbError::PermissionDenied) => false,
+                Err(e) => return Err(e.into()),
+            };
+            allowed_convos.insert(convo_id.0, allowed);
+            allowed
+        };
+        if allowed {
+            allowed_ids.push(msg_id);
+            ids_in_convo.entry(convo_id.0).or_default().push(msg_id);
+        }
+    }
+
+    let res = db
+        .get_messages(&allowed_ids)
+        .await
+        .w()?
+        .into_iter()
+        .map(|(msg_id, sender_id, msg, prev_id)| {
+            let msg = MessageContent::new(sender_id.0, msg);
+            (msg_id.0, MessageFormat::one(msg, prev_id))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut delivered = Vec::new();
+    for (convo_id, ids) in ids_in_convo {
+        let convo_id = ConversationId(convo_id);
+        let up_to = db.mark_delivered(&user_id, &convo_id, &ids).await.w()?;
+        delivered.push((convo_id, up_to));
+    }
+    for (convo_id, up_to) in delivered {
+        notify_delivery_status(
+            &utils,
+            &convo_id,
+            &user_id,
+            up_to,
+            DeliveryStatus::Delivered,
+        )
+        .await?;
+    }
+
+    Ok(Json(res))
+}
+
 // #[post("/user")]
 // async fn add_user(
-//     data: Data<RwLock<SQLiteDB>>,
+//     data: Data<D>,
 //     user_profile: Form<UserProfile>,
 // ) -> Result<impl Responder> {
 //     let user_profile = user_profile.0;
-//     Ok(data.write().await.add_user(&user_profile).await.map(Json)?)
+//     Ok(data.add_user(&user_profile).await.map(Json)?)
 // }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -490,10 +706,9 @@ fn parse_cookie<I: AsRef<str>>(identity: I) -> Result<i64, CookieParseError> {
 
 impl ResponseError for CookieParseError {}
 
-#[post("/conversation")]
-async fn start_conversation(
+async fn start_conversation<D: Backend>(
     utils: Data<BackendInfoUpdater>,
-    data: Data<RwLock<SQLiteDB>>,
+    data: Data<D>,
     jumpseller: Data<jumpseller::Client>,
     user: Identity,
     form: Form<ConversationForm>,
@@ -505,10 +720,10 @@ async fn start_conversation(
     let user_id = user.id().map(parse_cookie)?.map(UserId)?;
     let their_id = form.their_userid;
 
-    jumpseller_update_user(&data, &jumpseller, user_id.0).await?;
-    jumpseller_update_user(&data, &jumpseller, their_id).await?;
+    jumpseller_update_user(data.get_ref(), &jumpseller, user_id.0).await?;
+    jumpseller_update_user(data.get_ref(), &jumpseller, their_id).await?;
     jumpseller_update_product(
-        &data,
+        data.get_ref(),
         &jumpseller,
         &UserId(their_id),
         form.product_jumpseller_id,
@@ -517,30 +732,19 @@ async fn start_conversation(
 
     let their_id = UserId(their_id);
 
-    // data.read()
-    //     .await
+    // data
     //     .belongs_to_seller(&their_id, &form.product_jumpseller_id.into())
     //     .await
     //     .w()?;
     let res = data
-        .write()
-        .await
         .start_conversation(&user_id, &their_id, &form.product_jumpseller_id.into())
         .await
         .w()?;
 
     // Don't divulge for now.
-    let callback = utils.new_convo(&*data.read().await, &res, &user_id).await?;
+    let callback = utils.new_convo(data.get_ref(), &res, &user_id).await?;
 
-    match callback.await.map_err(ErrorInternalServerError)? {
-        crate::F2BResponse::Ok => {}
-        crate::F2BResponse::GoogleCloud(error) => {
-            log::error!("Failed to publish message: {error}.");
-        }
-        crate::F2BResponse::Unrecoverable(error) => {
-            log::error!("Failed to publish message: {error}.");
-        }
-    }
+    wait_for_publish(callback).await?;
 
     let res = ConversationIdWrapper { id: res.0 };
 
@@ -551,12 +755,37 @@ async fn start_conversation(
 #[allow(dead_code)]
 struct MessageForm {
     message: String,
+    /// UUID generated by the client, so that retries don't post the message twice.
+    client_message_id: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("'{0}' is not a valid UUID.")]
+struct InvalidClientMessageId(String);
+
+impl ResponseError for InvalidClientMessageId {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
+}
+
+/// Checks that `id` is a hyphenated UUID and returns it in lowercase.
+fn parse_client_message_id(id: &str) -> Result<String, InvalidClientMessageId> {
+    let is_uuid = id.len() == 36
+        && id.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    if is_uuid {
+        Ok(id.to_ascii_lowercase())
+    } else {
+        Err(InvalidClientMessageId(id.to_owned()))
+    }
 }
 
-#[post("/conversation/{convo_id}/message")]
-async fn post_msg(
+async fn post_msg<D: Backend>(
     utils: Data<BackendInfoUpdater>,
-    data: Data<RwLock<SQLiteDB>>,
+    data: Data<D>,
     user: Identity,
     conversation: Path<i64>,
     form: Form<MessageForm>,
@@ -567,32 +796,38 @@ async fn post_msg(
     }
     let user_id = user.id().map(parse_cookie)?.map(UserId)?;
     let convo_id = ConversationId(conversation.into_inner());
-    data.read()
-        .await
-        .belongs_to_conversation(&user_id, &convo_id)
+    data.belongs_to_conversation(&user_id, &convo_id)
         .await
         .w()?;
-    let msg = Message::from(form.into_inner().message.as_str());
-    let res = data
-        .write()
-        .await
-        .post_msg(msg, &user_id, &convo_id)
+    let form = form.into_inner();
+    let client_message_id = form
+        .client_message_id
+        .as_deref()
+        .map(parse_client_message_id)
+        .transpose()?;
+    let msg = Message::from(form.message.as_str());
+    let status = data.get_status(&convo_id).await.w()?;
+    let (res, inserted) = data
+        .post_msg_idempotent(msg, &user_id, &convo_id, client_message_id.as_deref())
         .await
         .w()?;
 
+    // A retry of a message that was already posted: it was also already published.
+    if !inserted {
+        return Ok(Json(MessageIdWrapper { id: res.0 }));
+    }
+
     // Don't divulge for now.
     let callback = utils
-        .new_message(&*data.read().await, &res, &convo_id, false)
+        .new_message(data.get_ref(), &res, &convo_id, false)
         .await?;
 
-    match callback.await.map_err(ErrorInternalServerError)? {
-        crate::F2BResponse::Ok => {}
-        crate::F2BResponse::GoogleCloud(error) => {
-            log::error!("Failed to publish message: {error}.");
-        }
-        crate::F2BResponse::Unrecoverable(error) => {
-            log::error!("Failed to publish message: {error}.");
-        }
+    wait_for_publish(callback).await?;
+
+    let new_status = data.get_status(&convo_id).await.w()?;
+    if new_status != status {
+        let callback = utils.status_changed(&convo_id, None, new_status).await;
+        wait_for_publish(callback).await?;
     }
 
     let res = MessageIdWrapper { id: res.0 };
@@ -600,9 +835,8 @@ async fn post_msg(
     Ok(Json(res))
 }
 
-#[get("/conversation/{convo_id}/latest")]
-async fn get_latest_message(
-    data: Data<RwLock<SQLiteDB>>,
+async fn get_latest_message<D: Backend>(
+    data: Data<D>,
     user: Identity,
     convo_id: Path<i64>,
     auth: Query<AuthService>,
@@ -624,12 +858,10 @@ async fn get_latest_message(
     }
 
     let convo_id = ConversationId(*convo_id);
-    data.read()
-        .await
-        .belongs_to_conversation(&user_id, &convo_id)
+    data.belongs_to_conversation(&user_id, &convo_id)
         .await
         .w()?;
-    let res = data.read().await.get_latest_message(&convo_id).await.w()?;
+    let res = data.get_latest_message(&convo_id).await.w()?;
 
     let res = MaybeMsgIdWrapper {
         id: res.map(|x| x.0),
@@ -638,9 +870,9 @@ async fn get_latest_message(
     Ok(Json(res))
 }
 
-#[get("/conversation/{convo_id}/recent")]
-async fn get_most_recent_messages(
-    data: Data<RwLock<SQLiteDB>>,
+async fn get_most_recent_messages<D: Backend>(
+    utils: Data<BackendInfoUpdater>,
+    data: Data<D>,
     user: Identity,
     convo_id: Path<i64>,
     auth: Query<AuthService>,
@@ -658,44 +890,41 @@ async fn get_most_recent_messages(
     }
 
     let convo_id = ConversationId(*convo_id);
-    data.read()
-        .await
-        .belongs_to_conversation(&user_id, &convo_id)
-        .await
-        .w()?;
-    let (messages, prev_id) = data
-        .read()
-        .await
-        .get_most_recent_messages(&convo_id)
+    data.belongs_to_conversation(&user_id, &convo_id)
         .await
         .w()?;
+    let (messages, prev_id) = data.get_most_recent_messages(&convo_id).await.w()?;
+    // Only what was fetched was delivered, not whatever was posted meanwhile or before the page.
+    let ids: Vec<_> = messages.iter().map(|(id, _, _)| *id).collect();
+    let delivered = data.mark_delivered(&user_id, &convo_id, &ids).await.w()?;
+    notify_delivery_status(
+        &utils,
+        &convo_id,
+        &user_id,
+        delivered,
+        DeliveryStatus::Delivered,
+    )
+    .await?;
     let mut msgs = Vec::new();
-    for (sender_id, msg) in messages {
+    for (_, sender_id, msg) in messages {
         msgs.push(MessageContent::new(sender_id.0, msg));
     }
     Ok(Json(MessageFormat::many(msgs, prev_id)))
 }
 
-#[get("/product/{prod_id}")]
-async fn get_product(
-    data: Data<RwLock<SQLiteDB>>,
+async fn get_product<D: Backend>(
+    data: Data<D>,
     jumpseller: Data<jumpseller::Client>,
     prod_id: Path<i64>,
 ) -> Result<impl Responder> {
-    let seller_id = data
-        .read()
-        .await
-        .get_product(&ProductId(*prod_id))
-        .await?
-        .seller_id;
-    jumpseller_update_product(&data, &jumpseller, &seller_id, *prod_id).await?;
-    let prod = data.read().await.get_product(&ProductId(*prod_id)).await?;
+    let seller_id = data.get_product(&ProductId(*prod_id)).await?.seller_id;
+    jumpseller_update_product(data.get_ref(), &jumpseller, &seller_id, *prod_id).await?;
+    let prod = data.get_product(&ProductId(*prod_id)).await?;
     Ok(Json(prod))
 }
 
-#[get("/conversation/{convo_id}/product")]
-async fn get_product_in_conversation(
-    data: Data<RwLock<SQLiteDB>>,
+async fn get_product_in_conversation<D: Backend>(
+    data: Data<D>,
     convo_id: Path<i64>,
     user: Identity,
     auth: Query<AuthService>,
@@ -716,14 +945,10 @@ async fn get_product_in_conversation(
         return Err(ProductionAuthMissing.into());
     }
 
-    data.read()
-        .await
-        .belongs_to_conversation(&user_id, &ConversationId(*convo_id))
+    data.belongs_to_conversation(&user_id, &ConversationId(*convo_id))
         .await
         .w()?;
     let prod = data
-        .read()
-        .await
         .get_product_id_from_conversation_id(&ConversationId(*convo_id))
         .await
         .w()?;
@@ -746,17 +971,666 @@ impl From<ImportProductForm> for Product {
     }
 }
 
-#[post("/product")]
-async fn add_product(
-    data: Data<RwLock<SQLiteDB>>,
+async fn add_product<D: Backend>(
+    data: Data<D>,
     form: Form<ImportProductForm>,
 ) -> Result<impl Responder> {
     let product = form.0.into();
-    Ok(data
-        .write()
+    Ok(data.add_product(&product).await.w().map(Json)?)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AssignForm {
+    assignee_id: Option<i64>,
+}
+
+async fn assign_conversation<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    form: Form<AssignForm>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let convo_id = ConversationId(*convo_id);
+    let assignee = form.assignee_id.map(UserId);
+    data.assign_conversation(&user_id, &convo_id, assignee.as_ref())
+        .await
+        .w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn get_assignee<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    #[derive(Serialize)]
+    struct MaybeUserIdWrapper {
+        id: Option<i64>,
+    }
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    let res = data.get_assignee(&convo_id).await.w()?;
+
+    Ok(Json(MaybeUserIdWrapper {
+        id: res.map(|x| x.0),
+    }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreForm {
+    name: String,
+}
+
+async fn add_store<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    form: Form<StoreForm>,
+) -> Result<impl Responder> {
+    #[derive(Serialize)]
+    struct StoreIdWrapper {
+        id: i64,
+    }
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let store = Store::new(form.into_inner().name);
+    let res = data.add_store(&user_id, &store).await.w()?;
+    Ok(Json(StoreIdWrapper { id: res.0 }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreMemberForm {
+    user_id: i64,
+}
+
+async fn add_store_member<D: Backend>(
+    data: Data<D>,
+    jumpseller: Data<jumpseller::Client>,
+    user: Identity,
+    store_id: Path<i64>,
+    form: Form<StoreMemberForm>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let store_id = StoreId(*store_id);
+    data.belongs_to_store(&user_id, &store_id).await.w()?;
+    jumpseller_update_user(data.get_ref(), &jumpseller, form.user_id).await?;
+    data.add_store_member(&user_id, &store_id, &UserId(form.user_id))
+        .await
+        .w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn set_primary_store<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    store_id: Path<i64>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    data.set_primary_store(&user_id, &StoreId(*store_id))
         .await
-        .add_product(&product)
+        .w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn get_store_members<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    store_id: Path<i64>,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+
+    let store_id = StoreId(*store_id);
+    data.belongs_to_store(&user_id, &store_id).await.w()?;
+    let res = data
+        .get_store_members(&store_id)
         .await
-        .w()
-        .map(Json)?)
+        .w()?
+        .into_iter()
+        .map(|x| x.0)
+        .collect::<Vec<_>>();
+    Ok(Json(res))
+}
+
+async fn get_status<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    #[derive(Serialize)]
+    struct StatusWrapper {
+        status: ConversationStatus,
+    }
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    let status = data.get_status(&convo_id).await.w()?;
+
+    Ok(Json(StatusWrapper { status }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StatusForm {
+    status: ConversationStatus,
+}
+
+async fn set_status<D: Backend>(
+    utils: Data<BackendInfoUpdater>,
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    form: Form<StatusForm>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    let status = data.get_status(&convo_id).await.w()?;
+    data.set_status(&user_id, &convo_id, form.status)
+        .await
+        .w()?;
+
+    if status != form.status {
+        let callback = utils
+            .status_changed(&convo_id, Some(&user_id), form.status)
+            .await;
+        wait_for_publish(callback).await?;
+    }
+
+    Ok(HttpResponse::Ok())
+}
+
+/// Large enough for any public key format in use, small enough to not be abused as storage.
+const MAX_PUBLIC_KEY_LEN: usize = 4096;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PublicKeyForm {
+    /// Opaque to the server, in whatever encoding the clients agree on.
+    public_key: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("A public key must be non-empty and at most {MAX_PUBLIC_KEY_LEN} bytes long.")]
+struct InvalidPublicKey;
+
+impl ResponseError for InvalidPublicKey {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
+}
+
+async fn set_public_key<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    form: Form<PublicKeyForm>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let key = form.public_key.trim();
+    if key.is_empty() || key.len() > MAX_PUBLIC_KEY_LEN {
+        return Err(InvalidPublicKey.into());
+    }
+    data.set_public_key(&user_id, key).await.w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn get_public_key<D: Backend>(data: Data<D>, user_id: Path<i64>) -> Result<impl Responder> {
+    let public_key = data.get_public_key(&UserId(*user_id)).await.w()?;
+    Ok(Json(PublicKeyForm { public_key }))
+}
+
+async fn get_e2e<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    #[derive(Serialize)]
+    struct E2EWrapper {
+        enabled: bool,
+    }
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    let enabled = data.is_e2e(&convo_id).await.w()?;
+
+    Ok(Json(E2EWrapper { enabled }))
+}
+
+async fn enable_e2e<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    data.enable_e2e(&convo_id).await.w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn get_labels<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    let labels = data.get_labels(&user_id, &convo_id).await.w()?;
+
+    Ok(Json(labels))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LabelForm {
+    label: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("A label must have something else than whitespace.")]
+struct EmptyLabel;
+
+impl ResponseError for EmptyLabel {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::BAD_REQUEST
+    }
+}
+
+async fn add_label<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    form: Form<LabelForm>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    let label = form.label.trim();
+    if label.is_empty() {
+        return Err(EmptyLabel.into());
+    }
+    data.add_label(&user_id, &convo_id, label).await.w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn remove_label<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    form: Form<LabelForm>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    data.remove_label(&user_id, &convo_id, form.label.trim())
+        .await
+        .w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn mark_read<D: Backend>(
+    utils: Data<BackendInfoUpdater>,
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    let convo_id = ConversationId(*convo_id);
+    data.belongs_to_conversation(&user_id, &convo_id)
+        .await
+        .w()?;
+    let read = data.mark_read(&user_id, &convo_id).await.w()?;
+    notify_delivery_status(&utils, &convo_id, &user_id, read, DeliveryStatus::Read).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: TranscriptFormat,
+}
+
+struct ExportCursor<D> {
+    data: Data<D>,
+    convo_id: ConversationId,
+    last: Option<MessageId>,
+    first: bool,
+    done: bool,
+    sender_names: HashMap<i64, String>,
+}
+
+/// Renders the next page of messages, or `None` once the whole conversation was sent.
+async fn next_transcript_page<D: Backend>(
+    format: TranscriptFormat,
+    mut cursor: ExportCursor<D>,
+) -> anyhow::Result<Option<(Bytes, ExportCursor<D>)>> {
+    if cursor.done {
+        return Ok(None);
+    }
+    let db = cursor.data.get_ref();
+    let page = db
+        .get_messages_after(&cursor.convo_id, cursor.last.as_ref(), export::PAGE_SIZE)
+        .await
+        .w()?;
+    let Some((last, _, _)) = page.last() else {
+        return Ok(None);
+    };
+    cursor.last = Some(*last);
+    cursor.done = page.len() < export::PAGE_SIZE as usize;
+
+    let mut chunk = String::new();
+    for (msg_id, sender_id, msg) in &page {
+        let name = match cursor.sender_names.entry(sender_id.0) {
+            Entry::Occupied(name) => name.into_mut(),
+            Entry::Vacant(slot) => slot.insert(db.get_user_profile(sender_id).await.w()?.name()),
+        };
+        let entry = TranscriptEntry::new(*msg_id, *sender_id, name, msg);
+        chunk.push_str(&format.entry(&entry, cursor.first)?);
+        cursor.first = false;
+    }
+    Ok(Some((Bytes::from(chunk), cursor)))
+}
+
+async fn export_conversation<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    convo_id: Path<i64>,
+    query: Query<ExportQuery>,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+
+    let convo_id = ConversationId(*convo_id);
+    let header = {
+        let db = data.get_ref();
+        db.belongs_to_conversation(&user_id, &convo_id).await.w()?;
+        let peer_id = db.get_peer(&user_id, &convo_id).await.w()?;
+        let product_id = db
+            .get_product_id_from_conversation_id(&convo_id)
+            .await
+            .w()?;
+        TranscriptHeader {
+            conversation: convo_id,
+            product: db.get_product(&product_id).await.w()?,
+            requested_by: user_id,
+            peer: db.get_user_profile(&peer_id).await.w()?,
+            exported_at: chrono::Utc::now(),
+            chain_head: db.get_chain_head(&convo_id).await.w()?,
+        }
+    };
+    let audit = AuditEntry::new(
+        Some(user_id),
+        AuditAction::TranscriptExport,
+        "Participant export".to_owned(),
+    )
+    .conversation(convo_id);
+    data.append_audit(&audit).await.w()?;
+
+    let format = query.format;
+    let head = format.header(&header).map_err(ErrorInternalServerError)?;
+    let cursor = ExportCursor {
+        data: data.clone(),
+        convo_id,
+        last: None,
+        first: true,
+        done: false,
+        sender_names: HashMap::new(),
+    };
+    let body = stream::once(future::ok::<_, anyhow::Error>(Bytes::from(head)))
+        .chain(stream::try_unfold(cursor, move |cursor| {
+            next_transcript_page(format, cursor)
+        }))
+        .chain(stream::once(future::ok(Bytes::from(format.footer()))));
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"conversation-{}.{}\"",
+                convo_id.0,
+                format.extension()
+            ),
+        ))
+        .streaming(body))
+}
+
+async fn export_personal_data<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+) -> Result<impl Responder> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod()
+        && let Some(authid) = auth.auth_service_user_id
+        && authid != user_id.0
+    {
+        return Err(ProductionAuthMissing.into());
+    }
+    if prod.is_prod() && auth.auth_service_user_id.is_none() {
+        return Err(ProductionAuthMissing.into());
+    }
+
+    let audit = AuditEntry::new(
+        Some(user_id),
+        AuditAction::PersonalDataExport,
+        "Data access request".to_owned(),
+    )
+    .subject(user_id);
+    data.append_audit(&audit).await.w()?;
+
+    let archive = PersonalData::collect(data.get_ref(), &user_id).await.w()?;
+    Ok(Json(archive).customize().insert_header((
+        "Content-Disposition",
+        format!("attachment; filename=\"user-{}.json\"", user_id.0),
+    )))
+}
+
+/// Identifies the caller, refusing anyone but the admins.
+fn require_admin(
+    user: &Identity,
+    auth: &AuthService,
+    prod: &IsProd,
+    admins: &Admins,
+) -> Result<UserId> {
+    let user_id = user.id().map(parse_cookie)?.map(UserId)?;
+    if prod.is_prod() && auth.auth_service_user_id != Some(user_id.0) {
+        return Err(ProductionAuthMissing.into());
+    }
+    if !admins.contains(user_id) {
+        return Err(DbError::PermissionDenied.into());
+    }
+    Ok(user_id)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReasonForm {
+    /// Recorded in the audit log.
+    reason: String,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn erase_user<D: Backend>(
+    data: Data<D>,
+    utils: Data<BackendInfoUpdater>,
+    user: Identity,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+    admins: Data<Admins>,
+    user_id: Path<i64>,
+    form: Form<ReasonForm>,
+) -> Result<impl Responder> {
+    let admin_id = require_admin(&user, &auth, &prod, &admins)?;
+    let erased = UserId(*user_id);
+    let audit = AuditEntry::new(
+        Some(admin_id),
+        AuditAction::UserErasure,
+        form.into_inner().reason,
+    )
+    .subject(erased);
+    data.erase_user(&erased, &audit).await.w()?;
+    wait_for_publish(utils.user_erased(&erased).await).await?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn shred_conversation<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+    admins: Data<Admins>,
+    convo_id: Path<i64>,
+    form: Form<ReasonForm>,
+) -> Result<impl Responder> {
+    let admin_id = require_admin(&user, &auth, &prod, &admins)?;
+    let convo_id = ConversationId(*convo_id);
+    let audit = AuditEntry::new(
+        Some(admin_id),
+        AuditAction::ConversationShred,
+        form.into_inner().reason,
+    )
+    .conversation(convo_id);
+    data.shred_conversation(&convo_id, &audit).await.w()?;
+    Ok(HttpResponse::Ok())
+}
+
+async fn get_audit_log<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+    admins: Data<Admins>,
+    filter: Query<AuditFilter>,
+) -> Result<impl Responder> {
+    require_admin(&user, &auth, &prod, &admins)?;
+    let res = data.get_audit_log(&filter).await.w()?;
+    Ok(Json(res))
+}
+
+async fn verify_message_chain<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+    admins: Data<Admins>,
+    convo_id: Path<i64>,
+) -> Result<impl Responder> {
+    #[derive(Serialize)]
+    struct Verification {
+        valid: bool,
+        first_invalid_id: Option<MessageId>,
+        chain_head: Option<ChainHead>,
+    }
+    require_admin(&user, &auth, &prod, &admins)?;
+    let convo_id = ConversationId(*convo_id);
+    let db = data.get_ref();
+    let first_invalid_id = db.verify_message_chain(&convo_id).await.w()?;
+    Ok(Json(Verification {
+        valid: first_invalid_id.is_none(),
+        first_invalid_id,
+        chain_head: db.get_chain_head(&convo_id).await.w()?,
+    }))
+}
+
+async fn verify_audit_log<D: Backend>(
+    data: Data<D>,
+    user: Identity,
+    auth: Query<AuthService>,
+    prod: Data<IsProd>,
+    admins: Data<Admins>,
+) -> Result<impl Responder> {
+    #[derive(Serialize)]
+    struct Verification {
+        valid: bool,
+        first_invalid_id: Option<i64>,
+    }
+    require_admin(&user, &auth, &prod, &admins)?;
+    let first_invalid_id = data.verify_audit_log().await.w()?;
+    Ok(Json(Verification {
+        valid: first_invalid_id.is_none(),
+        first_invalid_id,
+    }))
 }